@@ -0,0 +1,28 @@
+//! Stops the build with a clear error when a key the firmware compiles in is missing, rather than
+//! at the `include_bytes!` that reads it
+
+use std::path::Path;
+
+/// The files it needs from `private/`, which is kept out of git, and their sizes
+const KEYS: [(&str, u64); 1] = [("auth-key.bin", 16)];
+
+fn main() {
+    for (name, size) in KEYS {
+        let path = Path::new("../private").join(name);
+        println!("cargo:rerun-if-changed={}", path.display());
+        match std::fs::metadata(&path) {
+            Ok(metadata) if metadata.len() == size => {}
+            Ok(metadata) => panic!(
+                "{} is {} bytes, expected {}. Remove it and run Software/make-keys.sh",
+                path.display(),
+                metadata.len(),
+                size
+            ),
+            Err(_) => panic!(
+                "{} is missing. Run Software/make-keys.sh, or copy private/ from where the other \
+                 boards and the desktop were built, as they all need the same keys",
+                path.display()
+            ),
+        }
+    }
+}
@@ -0,0 +1,17 @@
+MEMORY
+{
+  /* NOTE K = KiBi = 1024 bytes */
+  /* The node's image starts right after, see radio_test_tx/memory.x */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 6K
+  /* The same as the node's, so that a fault here is reported once the node starts */
+  RAM : ORIGIN = 0x20000000, LENGTH = 20K - 128
+  FAULT : ORIGIN = 0x20004F80, LENGTH = 128
+}
+
+_fault_start = ORIGIN(FAULT);
+_fault_end = ORIGIN(FAULT) + LENGTH(FAULT);
+
+/* This is where the call stack will be allocated. */
+/* The stack is of the full descending type. */
+/* NOTE Do NOT modify `_stack_start` unless you know what you are doing */
+_stack_start = ORIGIN(RAM) + LENGTH(RAM);
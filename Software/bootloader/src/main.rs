@@ -0,0 +1,85 @@
+//! Runs before the node's firmware at every reset. Installs an update that the firmware received,
+//! or puts the old image back if the new one did not confirm itself, see [`common::boot`], then
+//! starts whatever is in the active slot.
+//!
+//! Flashed once with `cargo run --release`, before the node's firmware. Updates are built from
+//! radio_test_tx with `objcopy -O binary -R .key` and sent with the desktop tool's `ota` command.
+
+#![no_std]
+#![no_main]
+
+use common::{Flash, BOOT_LOG_PAGES};
+use cortex_m_rt::entry;
+use stm32f1xx_hal::flash::{Error, FlashSize, FlashWriter, SectorSize};
+use stm32f1xx_hal::{pac, prelude::*};
+
+// Records our panics and HardFaults for the node to report
+use fault_log as _;
+
+const FLASH_START: u32 = 0x0800_0000;
+/// The active slot, where the node's image starts. Must match radio_test_tx's memory.x
+const APP: u32 = 0x0800_1800;
+const PAGE_SIZE: usize = 1024;
+/// Each slot, then the scratch page and the update log. The message index log follows
+const SLOT_PAGES: usize = 32;
+const PAGES: usize = 2 * SLOT_PAGES + 1 + BOOT_LOG_PAGES;
+
+/// The pages [`common::boot`] works on, starting at the active slot
+struct Slots<'a> {
+    writer: FlashWriter<'a>,
+}
+
+impl Slots<'_> {
+    fn offset(page: usize, word: usize) -> u32 {
+        APP - FLASH_START + (page * PAGE_SIZE + word * 4) as u32
+    }
+}
+
+impl Flash for Slots<'_> {
+    type Error = Error;
+
+    fn pages(&self) -> usize {
+        PAGES
+    }
+
+    fn page_words(&self) -> usize {
+        PAGE_SIZE / 4
+    }
+
+    fn read(&self, page: usize, word: usize) -> u32 {
+        // Reading only fails outside of the flash
+        let bytes = self.writer.read(Self::offset(page, word), 4).unwrap();
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    fn write(&mut self, page: usize, word: usize, value: u32) -> Result<(), Error> {
+        self.writer
+            .write(Self::offset(page, word), &value.to_le_bytes())
+    }
+
+    fn erase(&mut self, page: usize) -> Result<(), Error> {
+        self.writer.erase(Self::offset(page, 0), PAGE_SIZE)
+    }
+}
+
+#[entry]
+fn main() -> ! {
+    let dp = pac::Peripherals::take().unwrap();
+    let cp = cortex_m::Peripherals::take().unwrap();
+    let mut flash = dp.FLASH.constrain();
+    let auth_key = *include_bytes!("../../private/auth-key.bin");
+
+    let mut slots = Slots {
+        writer: flash.writer(SectorSize::Sz1K, FlashSize::Sz128K),
+    };
+    // A flash error leaves the log where it was, so the next reset tries again. Until then the
+    // active slot is the best there is
+    let _ = common::boot(&mut slots, &auth_key);
+
+    // SAFETY: The active slot starts with the image's vector table, and nothing of ours runs
+    // after the jump
+    unsafe {
+        cp.SCB.vtor.write(APP);
+        cortex_m::asm::bootload(APP as *const u32)
+    }
+}
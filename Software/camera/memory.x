@@ -1,9 +1,10 @@
-
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
   FLASH : ORIGIN = 0x08000000, LENGTH = 1M
-  RAM : ORIGIN = 0x20000000, LENGTH = 192K
+  /* SRAM1 and SRAM2. The other 64K is core coupled memory at 0x10000000, which the DMA cannot
+     reach, so the frame buffers could not go there */
+  RAM : ORIGIN = 0x20000000, LENGTH = 128K
 }
 
 /* This is where the call stack will be allocated. */
@@ -0,0 +1,435 @@
+//! Frame capture through the DCMI, which the HAL has no driver for.
+//!
+//! DMA2 stream 1 moves every word the DCMI reads into one of two frame buffers, in double buffer
+//! mode so that the stream knows both. Each frame goes into one buffer. When it ends the DCMI
+//! interrupt stops the stream, hands the filled buffer to the firmware with [`Dcmi::take_frame`]
+//! and starts the next frame in the other buffer, unless the firmware still has that one, in
+//! which case the new frame is dropped and the same buffer is used again.
+
+use hal::gpio::{
+    gpioa::{PA4, PA6},
+    gpiob::{PB6, PB7},
+    gpioc::{PC10, PC12, PC6, PC7, PC8, PC9},
+    gpioe::{PE4, PE5, PE6},
+    Floating, Input,
+};
+use hal::pac::{Interrupt, DCMI, DMA2, NVIC, RCC};
+use stm32f4xx_hal as hal;
+
+/// Words in each frame buffer. A JPEG frame at QVGA is well under this, uncompressed frames
+/// above QQVGA are not
+pub const FRAME_WORDS: usize = 12 * 1024;
+
+/// Where the DMA reads from
+const DCMI_DR: u32 = 0x5005_0028;
+
+// DCMI_CR
+const CR_CAPTURE: u32 = 1 << 0;
+const CR_SNAPSHOT: u32 = 1 << 1;
+const CR_JPEG: u32 = 1 << 3;
+const CR_PCK_RISING: u32 = 1 << 5;
+const CR_HSYNC_HIGH: u32 = 1 << 6;
+const CR_VSYNC_HIGH: u32 = 1 << 7;
+const CR_EDM_SHIFT: u32 = 10;
+const CR_ENABLE: u32 = 1 << 14;
+
+// DCMI_IER, DCMI_MIS and DCMI_ICR
+const DCMI_FRAME: u32 = 1 << 0;
+const DCMI_OVERRUN: u32 = 1 << 1;
+const DCMI_SYNC_ERROR: u32 = 1 << 2;
+
+/// The DCMI is on channel 1 of this stream
+const STREAM: usize = 1;
+// DMA_SxCR
+const SCR_EN: u32 = 1 << 0;
+const SCR_DMEIE: u32 = 1 << 1;
+const SCR_TEIE: u32 = 1 << 2;
+const SCR_TCIE: u32 = 1 << 4;
+const SCR_MINC: u32 = 1 << 10;
+const SCR_PSIZE_WORD: u32 = 2 << 11;
+const SCR_MSIZE_WORD: u32 = 2 << 13;
+const SCR_PL_VERY_HIGH: u32 = 3 << 16;
+const SCR_DBM: u32 = 1 << 18;
+const SCR_CT: u32 = 1 << 19;
+const SCR_CHANNEL_1: u32 = 1 << 25;
+/// DMA_SxFCR: through the FIFO, written out once it is full
+const FCR_FIFO_FULL: u32 = 1 << 2 | 3;
+// Stream 1's flags in DMA_LISR and DMA_LIFCR
+const DMA_DIRECT_ERROR: u32 = 1 << 8;
+const DMA_TRANSFER_ERROR: u32 = 1 << 9;
+const DMA_COMPLETE: u32 = 1 << 11;
+/// Every flag of stream 1, FIFO and half transfer included
+const DMA_FLAGS: u32 = 0x0F40;
+
+/// How often the DCMI captures
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaptureMode {
+    /// Every frame until [`Dcmi::stop`]
+    Continuous,
+    /// One frame each [`Dcmi::start`]
+    Snapshot,
+}
+
+/// The level of a sync signal while data is not valid
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    Low,
+    High,
+}
+
+/// The pixel clock edge data is sampled on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockEdge {
+    Falling,
+    Rising,
+}
+
+/// Data lines sampled on each pixel clock. Anything wider than 8 takes two bytes a sample
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataWidth {
+    Bits8 = 0,
+    Bits10 = 1,
+    Bits12 = 2,
+    Bits14 = 3,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub mode: CaptureMode,
+    pub pixel_clock: ClockEdge,
+    pub vsync: Polarity,
+    pub hsync: Polarity,
+    pub width: DataWidth,
+    /// For compressed data of varying length, where HSYNC marks the valid bytes
+    pub jpeg: bool,
+}
+
+impl Config {
+    /// What the CubeMX project in Hardware/CameraMCU sets up
+    pub const CUBEMX: Config = Config {
+        mode: CaptureMode::Continuous,
+        pixel_clock: ClockEdge::Falling,
+        vsync: Polarity::Low,
+        hsync: Polarity::Low,
+        width: DataWidth::Bits10,
+        jpeg: false,
+    };
+
+    fn cr(&self) -> u32 {
+        let mut cr = (self.width as u32) << CR_EDM_SHIFT;
+        if self.mode == CaptureMode::Snapshot {
+            cr |= CR_SNAPSHOT;
+        }
+        if self.pixel_clock == ClockEdge::Rising {
+            cr |= CR_PCK_RISING;
+        }
+        if self.hsync == Polarity::High {
+            cr |= CR_HSYNC_HIGH;
+        }
+        if self.vsync == Polarity::High {
+            cr |= CR_VSYNC_HIGH;
+        }
+        if self.jpeg {
+            cr |= CR_JPEG;
+        }
+        cr
+    }
+}
+
+/// The pins CameraMCU.ioc gives the DCMI, moved to alternate function 13. Holding one shows that
+/// nothing else uses them
+pub struct Pins {
+    _private: (),
+}
+
+/// How the pins come out of the HAL's `split`
+type Reset = Input<Floating>;
+
+impl Pins {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        hsync: PA4<Reset>,
+        pixclk: PA6<Reset>,
+        vsync: PB7<Reset>,
+        d0: PC6<Reset>,
+        d1: PC7<Reset>,
+        d2: PC8<Reset>,
+        d3: PC9<Reset>,
+        d4: PE4<Reset>,
+        d5: PB6<Reset>,
+        d6: PE5<Reset>,
+        d7: PE6<Reset>,
+        d8: PC10<Reset>,
+        d9: PC12<Reset>,
+    ) -> Self {
+        macro_rules! af13 {
+            ($($pin:ident),*) => {$(
+                let _ = $pin.into_alternate::<13>();
+            )*};
+        }
+        af13!(hsync, pixclk, vsync, d0, d1, d2, d3, d4, d5, d6, d7, d8, d9);
+        Self { _private: () }
+    }
+}
+
+/// A captured frame, lent to the firmware until it goes back with [`Dcmi::release`]
+pub struct Frame {
+    index: usize,
+    data: &'static [u8],
+}
+
+impl Frame {
+    /// Everything the DCMI read, in whole words. A JPEG frame may have padding after its end
+    /// of image marker
+    pub fn data(&self) -> &[u8] {
+        self.data
+    }
+}
+
+/// Where each frame buffer is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Slot {
+    /// The DMA is writing the current frame to it
+    Capturing,
+    Free,
+    /// Holds a frame of this many words for the firmware
+    Ready(usize),
+    /// With the firmware as a [`Frame`]
+    Lent,
+}
+
+/// Counts of what happened since the start
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub frames: u32,
+    /// Captured but never taken, or with nowhere to go
+    pub dropped: u32,
+    /// The DMA fell behind the camera
+    pub overruns: u32,
+    /// Frames larger than a buffer
+    pub too_big: u32,
+    /// DMA transfer errors, and embedded sync errors
+    pub errors: u32,
+}
+
+pub struct Dcmi {
+    dcmi: DCMI,
+    dma: DMA2,
+    config: Config,
+    buffers: [*mut u32; 2],
+    slots: [Slot; 2],
+    /// The buffer the current frame goes to
+    capturing: usize,
+    /// Something went wrong with the current frame, which is dropped when it ends
+    spoiled: bool,
+    stats: Stats,
+}
+
+// SAFETY: The buffers are only reached through this
+unsafe impl Send for Dcmi {}
+
+impl Dcmi {
+    /// Sets up the DCMI and the DMA and turns on their interrupts, which have to call
+    /// [`Dcmi::on_dcmi_interrupt`] and [`Dcmi::on_dma_interrupt`]. Nothing is captured until
+    /// [`Dcmi::start`]
+    pub fn new(
+        dcmi: DCMI,
+        dma: DMA2,
+        _pins: Pins,
+        config: Config,
+        buffers: &'static mut [[u32; FRAME_WORDS]; 2],
+    ) -> Self {
+        // SAFETY: Only sets the enables of our own peripherals
+        let rcc = unsafe { &*RCC::ptr() };
+        rcc.ahb2enr.modify(|_, w| w.dcmien().set_bit());
+        rcc.ahb1enr.modify(|_, w| w.dma2en().set_bit());
+
+        let [first, second] = buffers;
+        let mut dcmi = Self {
+            dcmi,
+            dma,
+            config,
+            buffers: [first.as_mut_ptr(), second.as_mut_ptr()],
+            slots: [Slot::Capturing, Slot::Free],
+            capturing: 0,
+            spoiled: false,
+            stats: Stats::default(),
+        };
+        let stream = &dcmi.dma.st[STREAM];
+        // SAFETY: The DCMI's data register and the stream's settings, see the reference manual
+        stream.par.write(|w| unsafe { w.bits(DCMI_DR) });
+        stream.fcr.write(|w| unsafe { w.bits(FCR_FIFO_FULL) });
+        dcmi.arm(0);
+
+        dcmi.dcmi.cr.write(|w| unsafe { w.bits(config.cr()) });
+        dcmi.dcmi
+            .ier
+            .write(|w| unsafe { w.bits(DCMI_FRAME | DCMI_OVERRUN | DCMI_SYNC_ERROR) });
+        dcmi.dcmi
+            .cr
+            .modify(|r, w| unsafe { w.bits(r.bits() | CR_ENABLE) });
+        // SAFETY: Their handlers only reach the driver through the same lock as the firmware
+        unsafe {
+            NVIC::unmask(Interrupt::DCMI);
+            NVIC::unmask(Interrupt::DMA2_STREAM1);
+        }
+        dcmi
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Starts capturing, or captures the next frame in snapshot mode
+    pub fn start(&mut self) {
+        self.dcmi
+            .cr
+            .modify(|r, w| unsafe { w.bits(r.bits() | CR_CAPTURE) });
+    }
+
+    /// Stops capturing at the end of the current frame
+    pub fn stop(&mut self) {
+        self.dcmi
+            .cr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !CR_CAPTURE) });
+    }
+
+    /// The latest frame, if there is one the firmware has not seen
+    pub fn take_frame(&mut self) -> Option<Frame> {
+        let (index, words) = self
+            .slots
+            .iter()
+            .enumerate()
+            .find_map(|(i, slot)| match slot {
+                Slot::Ready(words) => Some((i, *words)),
+                _ => None,
+            })?;
+        self.slots[index] = Slot::Lent;
+        // SAFETY: The DMA is kept away from lent buffers, see `arm`, until they come back
+        let data =
+            unsafe { core::slice::from_raw_parts(self.buffers[index] as *const u8, words * 4) };
+        Some(Frame { index, data })
+    }
+
+    /// Gives a frame's buffer back for capturing into
+    pub fn release(&mut self, frame: Frame) {
+        self.slots[frame.index] = Slot::Free;
+    }
+
+    /// Handles the end of a frame and the DCMI's errors
+    pub fn on_dcmi_interrupt(&mut self) {
+        let flags = self.dcmi.mis.read().bits();
+        // SAFETY: Writing ones clears the flags
+        self.dcmi.icr.write(|w| unsafe { w.bits(flags) });
+        if flags & DCMI_OVERRUN != 0 {
+            self.stats.overruns += 1;
+            self.spoiled = true;
+        }
+        if flags & DCMI_SYNC_ERROR != 0 {
+            self.stats.errors += 1;
+            self.spoiled = true;
+        }
+        if flags & DCMI_FRAME != 0 {
+            self.frame_done();
+        }
+    }
+
+    /// Handles a frame that filled its buffer and DMA errors
+    pub fn on_dma_interrupt(&mut self) {
+        let flags = self.dma.lisr.read().bits() & DMA_FLAGS;
+        // SAFETY: Writing ones clears the flags
+        self.dma.lifcr.write(|w| unsafe { w.bits(flags) });
+        if flags & DMA_COMPLETE != 0 {
+            self.stats.too_big += 1;
+            self.spoiled = true;
+        }
+        if flags & (DMA_TRANSFER_ERROR | DMA_DIRECT_ERROR) != 0 {
+            // The stream turned itself off, so the rest of the frame is lost
+            self.stats.errors += 1;
+            self.spoiled = true;
+        }
+    }
+
+    fn frame_done(&mut self) {
+        let words = self.halt();
+        let (done, next) = (self.capturing, 1 - self.capturing);
+        if core::mem::take(&mut self.spoiled) {
+            self.stats.dropped += 1;
+            self.arm(done);
+            return;
+        }
+        self.stats.frames += 1;
+        match self.slots[next] {
+            Slot::Lent => {
+                // The firmware is still on the last one
+                self.stats.dropped += 1;
+                self.arm(done);
+            }
+            Slot::Free | Slot::Ready(_) | Slot::Capturing => {
+                if matches!(self.slots[next], Slot::Ready(_)) {
+                    self.stats.dropped += 1;
+                }
+                self.slots[done] = Slot::Ready(words);
+                self.slots[next] = Slot::Capturing;
+                self.arm(next);
+            }
+        }
+    }
+
+    /// Stops the stream, flushing its FIFO, and returns the words it wrote
+    fn halt(&mut self) -> usize {
+        let stream = &self.dma.st[STREAM];
+        stream
+            .cr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !SCR_EN) });
+        while stream.cr.read().bits() & SCR_EN != 0 {}
+        FRAME_WORDS - stream.ndtr.read().bits() as usize
+    }
+
+    /// Points the stream at buffer `index` and turns it on. The address the stream would move
+    /// on to once the buffer is full is the other buffer only if it is free, so that a frame
+    /// too big for its buffer cannot run into one the firmware has
+    fn arm(&mut self, index: usize) {
+        let other = 1 - index;
+        let spill = if self.slots[other] == Slot::Free {
+            other
+        } else {
+            index
+        };
+        let (m0, m1, ct) = if index == 0 {
+            (index, spill, 0)
+        } else {
+            (spill, index, SCR_CT)
+        };
+        self.halt();
+        let stream = &self.dma.st[STREAM];
+        // SAFETY: The stream is off, and both addresses are buffers of FRAME_WORDS that nothing
+        // else is reading
+        unsafe {
+            self.dma.lifcr.write(|w| w.bits(DMA_FLAGS));
+            stream.m0ar.write(|w| w.bits(self.buffers[m0] as u32));
+            stream.m1ar.write(|w| w.bits(self.buffers[m1] as u32));
+            stream.ndtr.write(|w| w.bits(FRAME_WORDS as u32));
+            stream.cr.write(|w| {
+                w.bits(
+                    SCR_CHANNEL_1
+                        | ct
+                        | SCR_DBM
+                        | SCR_PL_VERY_HIGH
+                        | SCR_MSIZE_WORD
+                        | SCR_PSIZE_WORD
+                        | SCR_MINC
+                        | SCR_TCIE
+                        | SCR_TEIE
+                        | SCR_DMEIE,
+                )
+            });
+            stream.cr.modify(|r, w| w.bits(r.bits() | SCR_EN));
+        }
+        self.capturing = index;
+    }
+}
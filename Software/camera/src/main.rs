@@ -2,50 +2,48 @@
 #![no_main]
 #![feature(bench_black_box)]
 
+use core::cell::RefCell;
+use core::fmt::Write;
+use cortex_m::interrupt::{free, Mutex};
 use cortex_m_rt::entry;
-use hal::{
-    gpio::Edge,
-    pac::{dcmi, DCMI},
-    prelude::*,
-};
+use embedded_hal::serial::Read;
+use hal::{pac::interrupt, prelude::*, serial::Serial};
+use ov2640::{Format, Ov2640, Resolution};
 use panic_halt as _;
 use stm32f4xx_hal as hal;
+use xclk::{Command, Sources};
+
+// Not everything the driver offers is used yet
+#[allow(dead_code)]
+mod dcmi;
+mod mco;
+
+use dcmi::{Config, Dcmi, Frame, Pins, FRAME_WORDS};
+use mco::Mco1;
+
+/// The crystal
+const HSE_HZ: u32 = 16_000_000;
+
+/// XCLK at start up, the OV2640's typical input clock. Change it with `xclk <Hz>` on the console
+const XCLK_HZ: u32 = 24_000_000;
+
+/// Shared between the main loop and the DCMI and DMA interrupts
+static CAPTURE: Mutex<RefCell<Option<Dcmi>>> = Mutex::new(RefCell::new(None));
+
+/// Runs `f` on the capture with the interrupts off
+fn with_capture<T>(f: impl FnOnce(&mut Dcmi) -> T) -> T {
+    free(|cs| f(CAPTURE.borrow(cs).borrow_mut().as_mut().unwrap()))
+}
 
 #[entry]
 fn main() -> ! {
     let device = hal::pac::Peripherals::take().unwrap();
     let core = cortex_m::Peripherals::take().unwrap();
 
-    // Enable the clock for the DCMI and GPIOs
-    device.RCC.ahb2enr.write(|w| w.dcmien().set_bit());
-    device.RCC.ahb1enr.write(|w| w.gpioien().set_bit());
-
-    //(#) DCMI pins configuration
-    //  (++) Connect the involved DCMI pins to AF13 using the following function
-    //      GPIO_PinAFConfig(GPIOx, GPIO_PinSourcex, GPIO_AF_DCMI);
-    //  (++) Configure these DCMI pins in alternate function mode by calling
-    //      the function GPIO_Init();
-    //
-    // ???
-
-    device.DCMI.cr.write(|w| w);
-    // DCMI_InitStruct->DCMI_CaptureMode = DCMI_CaptureMode_Continuous;
-    // DCMI_InitStruct->DCMI_SynchroMode = DCMI_SynchroMode_Hardware;
-    // DCMI_InitStruct->DCMI_PCKPolarity = DCMI_PCKPolarity_Falling;
-    // DCMI_InitStruct->DCMI_VSPolarity = DCMI_VSPolarity_Low;
-    // DCMI_InitStruct->DCMI_HSPolarity = DCMI_HSPolarity_Low;
-    // DCMI_InitStruct->DCMI_CaptureRate = DCMI_CaptureRate_All_Frame;
-    // DCMI_InitStruct->DCMI_ExtendedDataMode = DCMI_ExtendedDataMode_8b;
-
-    // # Safety: We got the bits from a read of the register
-    let dcmi = device.DCMI;
-    //Looks like no high level wrapper exists for DCMI from HAL.
-    //We have to use the auto generated building blocks
-
     let rcc = device.RCC.constrain();
     let clocks = rcc
         .cfgr
-        .use_hse(16.mhz())
+        .use_hse(HSE_HZ.hz())
         .require_pll48clk()
         .sysclk(168.mhz())
         .hclk(168.mhz())
@@ -53,18 +51,140 @@ fn main() -> ! {
         .pclk2(82.mhz())
         .freeze();
 
-    let mut syscfg = device.SYSCFG.constrain();
-
     // Initialize the different pins
-    let gpioc = device.GPIOC.split();
-    let gpiod = device.GPIOD.split();
+    let gpioa = device.GPIOA.split();
     let gpiob = device.GPIOB.split();
+    let gpioc = device.GPIOC.split();
+    let gpioe = device.GPIOE.split();
+    let gpiof = device.GPIOF.split();
 
     let mut delay = hal::delay::Delay::new(core.SYST, &clocks);
 
-    //Configure camera with I2C
+    // The console, on the USART2 pins of CameraMCU.ioc
+    let tx = gpioa.pa2.into_alternate::<7>();
+    let rx = gpioa.pa3.into_alternate::<7>();
+    let serial = Serial::new(
+        device.USART2,
+        (tx, rx),
+        hal::serial::config::Config::default().baudrate(115_200.bps()),
+        &clocks,
+    )
+    .unwrap();
+    let mut console = Console::new(serial);
+
+    // The camera only answers on SCCB with a clock on XCLK
+    let sources = Sources {
+        hsi: Sources::HSI_HZ,
+        hse: HSE_HZ,
+        pll: clocks.sysclk().0,
+    };
+    let mut mco = Mco1::new(gpioa.pa8, sources, XCLK_HZ);
+    console.reply(mco.setting());
+
+    // The camera's SCCB control bus is on I2C2, SDA on PF0 and SCL on PF1
+    let scl = gpiof.pf1.into_alternate_open_drain();
+    let sda = gpiof.pf0.into_alternate_open_drain();
+    let i2c = hal::i2c::I2c::new(device.I2C2, (scl, sda), 100.khz(), &clocks);
+    let mut camera = Ov2640::new(i2c);
+    camera
+        .init(&mut delay, Resolution::Qvga, Format::Jpeg)
+        .unwrap();
 
-    let dcmi = device.DCMI;
+    let pins = Pins::new(
+        gpioa.pa4, gpioa.pa6, gpiob.pb7, gpioc.pc6, gpioc.pc7, gpioc.pc8, gpioc.pc9, gpioe.pe4,
+        gpiob.pb6, gpioe.pe5, gpioe.pe6, gpioc.pc10, gpioc.pc12,
+    );
+    let buffers = cortex_m::singleton!(: [[u32; FRAME_WORDS]; 2] = [[0; FRAME_WORDS]; 2]).unwrap();
+    // Sampled on all ten lines like the CubeMX project, with the camera sending JPEG
+    let config = Config {
+        jpeg: true,
+        ..Config::CUBEMX
+    };
+    let mut capture = Dcmi::new(device.DCMI, device.DMA2, pins, config, buffers);
+    capture.start();
+    free(|cs| CAPTURE.borrow(cs).replace(Some(capture)));
+
+    // Never sleeps, the console is polled in between frames
+    loop {
+        if let Some(frame) = with_capture(Dcmi::take_frame) {
+            handle_frame(&frame);
+            with_capture(|capture| capture.release(frame));
+        }
+        match console.poll() {
+            Some(Command::Get) => console.reply(mco.setting()),
+            Some(Command::Set(hz)) => console.reply(mco.set(hz)),
+            None => {}
+        }
+    }
+}
+
+/// Longest line taken from the host
+const LINE: usize = 32;
+
+/// Commands from the host, one per line, see [`xclk::Command`]
+struct Console<S> {
+    serial: S,
+    line: [u8; LINE],
+    len: usize,
+    /// The line did not fit and is ignored up to its end
+    overflow: bool,
+}
+
+impl<S: Read<u8> + Write> Console<S> {
+    fn new(serial: S) -> Self {
+        Self {
+            serial,
+            line: [0; LINE],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Takes in what has arrived, and returns a command once its line is complete. Other lines
+    /// are answered here
+    fn poll(&mut self) -> Option<Command> {
+        while let Ok(byte) = self.serial.read() {
+            if byte != b'\n' {
+                if self.len < LINE {
+                    self.line[self.len] = byte;
+                    self.len += 1;
+                } else {
+                    self.overflow = true;
+                }
+                continue;
+            }
+            let line = core::str::from_utf8(&self.line[..self.len]).ok();
+            let command = line.filter(|_| !self.overflow).and_then(Command::parse);
+            let empty = self.len == 0;
+            self.len = 0;
+            self.overflow = false;
+            match command {
+                Some(command) => return Some(command),
+                None if !empty => {
+                    let _ = writeln!(self.serial, "unknown command");
+                }
+                None => {}
+            }
+        }
+        None
+    }
+
+    fn reply(&mut self, setting: xclk::Setting) {
+        let _ = writeln!(self.serial, "{}", setting);
+    }
+}
+
+/// Where frames go once they are captured. Capture carries on into the other buffer meanwhile
+fn handle_frame(frame: &Frame) {
+    core::hint::black_box(frame.data());
+}
+
+#[interrupt]
+fn DCMI() {
+    with_capture(Dcmi::on_dcmi_interrupt);
+}
 
-    loop {}
+#[interrupt]
+fn DMA2_STREAM1() {
+    with_capture(Dcmi::on_dma_interrupt);
 }
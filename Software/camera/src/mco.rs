@@ -0,0 +1,65 @@
+//! The camera's XCLK, which MCO1 puts out on PA8. [`xclk`] works out the source and prescaler for
+//! a frequency, this writes them to the RCC.
+
+use hal::gpio::{gpioa::PA8, Floating, Input, Speed};
+use hal::pac::RCC;
+use stm32f4xx_hal as hal;
+use xclk::{Setting, Source, Sources};
+
+// RCC_CR
+const CR_HSION: u32 = 1 << 0;
+const CR_HSIRDY: u32 = 1 << 1;
+
+// RCC_CFGR
+const CFGR_MCO1_SHIFT: u32 = 21;
+const CFGR_MCO1_MASK: u32 = 0b11 << CFGR_MCO1_SHIFT;
+const CFGR_MCO1PRE_SHIFT: u32 = 24;
+const CFGR_MCO1PRE_MASK: u32 = 0b111 << CFGR_MCO1PRE_SHIFT;
+
+/// MCO1. Made from PA8, so that nothing else can use the pin
+pub struct Mco1 {
+    sources: Sources,
+    setting: Setting,
+}
+
+impl Mco1 {
+    /// Puts PA8 on MCO1 and starts it as close to `hz` as the `sources` allow
+    pub fn new(pin: PA8<Input<Floating>>, sources: Sources, hz: u32) -> Self {
+        let _ = pin.into_alternate::<0>().set_speed(Speed::VeryHigh);
+        // Any running source gives a setting, and the system clock is always running
+        let setting = sources.choose(hz).unwrap();
+        let mut mco = Self { sources, setting };
+        mco.apply(setting);
+        mco
+    }
+
+    /// What it is putting out now
+    pub fn setting(&self) -> Setting {
+        self.setting
+    }
+
+    /// Moves to the setting closest to `hz`, and returns it. The camera sees a glitch on XCLK
+    /// while it changes, which can spoil the frame being captured
+    pub fn set(&mut self, hz: u32) -> Setting {
+        self.setting = self.sources.choose(hz).unwrap();
+        self.apply(self.setting);
+        self.setting
+    }
+
+    fn apply(&mut self, setting: Setting) {
+        // SAFETY: Only the MCO1 fields of CFGR are changed, and HSI is only ever turned on. No
+        // one else writes either after the clocks are frozen
+        let rcc = unsafe { &*RCC::ptr() };
+        if setting.source == Source::Hsi {
+            rcc.cr.modify(|r, w| unsafe { w.bits(r.bits() | CR_HSION) });
+            while rcc.cr.read().bits() & CR_HSIRDY == 0 {}
+        }
+        rcc.cfgr.modify(|r, w| unsafe {
+            w.bits(
+                (r.bits() & !(CFGR_MCO1_MASK | CFGR_MCO1PRE_MASK))
+                    | (setting.source.bits() << CFGR_MCO1_SHIFT)
+                    | (setting.prescaler_bits() << CFGR_MCO1PRE_SHIFT),
+            )
+        });
+    }
+}
@@ -83,6 +83,89 @@ impl IndexedBlock {
     }
 }
 
+/// The bytes of an [`IndexedBlock`] before its data: the tag
+pub const TAG_SIZE: usize = size_of::<Tag31_1>();
+
+/// An [`IndexedBlock`] sent with the radio's dynamic payload lengths: the tag, then as many
+/// data words as the sender says it needs. The receiver fills the rest with zeros, so a block is
+/// between [`TAG_SIZE`] and 32 bytes on the air, and only the pad words covering data that was
+/// sent ever leave the node. The length is chosen by the sender rather than taken from the data,
+/// so that it does not give away how much of the plaintext is zero
+#[derive(Default)]
+pub struct VarBlock {
+    block: IndexedBlock,
+    /// Data words sent
+    words: usize,
+}
+
+impl VarBlock {
+    /// Wraps a block that has not been encrypted yet, of which only the first `words` data words
+    /// are sent. The rest of its data is lost
+    ///
+    /// # Panics
+    /// If `words` is more than the 7 data words of a block
+    pub fn new(block: IndexedBlock, words: usize) -> Self {
+        assert!(words <= block.data().len());
+        Self { block, words }
+    }
+
+    /// A block as it was received, or `None` if `payload` is not the tag followed by a whole
+    /// number of data words
+    pub fn from_bytes(payload: &[u8]) -> Option<Self> {
+        if !Self::is_block(payload) {
+            return None;
+        }
+        let mut block = IndexedBlock::new();
+        block.as_bytes_mut()[..payload.len()].copy_from_slice(payload);
+        Some(Self {
+            block,
+            words: (payload.len() - TAG_SIZE) / 4,
+        })
+    }
+
+    /// True if `payload` has the length of a block
+    pub fn is_block(payload: &[u8]) -> bool {
+        let len = payload.len();
+        len >= TAG_SIZE && len <= size_of::<IndexedBlock>() && (len - TAG_SIZE) % 4 == 0
+    }
+
+    /// The number of data words sent
+    pub fn words(&self) -> usize {
+        self.words
+    }
+
+    /// The whole block. Data bytes that were not sent are zero once it is decrypted
+    pub fn block(&self) -> &IndexedBlock {
+        &self.block
+    }
+
+    pub fn into_block(self) -> IndexedBlock {
+        self.block
+    }
+
+    pub fn index(&self) -> u32 {
+        Tag::get_index(&self.block.tag)
+    }
+
+    pub fn tag(&self) -> usize {
+        Tag::get_tag(&self.block.tag)
+    }
+
+    /// The bytes to put on the air
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.block.as_bytes()[..TAG_SIZE + 4 * self.words]
+    }
+
+    /// Encrypts or decrypts the bytes that are sent, and clears the rest
+    pub fn do_cipher<Hash, const KEY_SIZE: usize>(&mut self, cipher: &MainCipher<'_, Hash, KEY_SIZE>)
+    where
+        Hash: Fn(u32) -> u32,
+    {
+        self.block.do_cipher(cipher);
+        self.block.as_bytes_mut()[TAG_SIZE + 4 * self.words..].fill(0);
+    }
+}
+
 /// Represents the header bits of a message that contain the index and other user specified data
 pub trait Tag {
     type IndexTy: crate::Index;
@@ -172,8 +255,6 @@ mod tests {
             cipher.cipher_block(index, &mut block);
             assert_eq!(block.as_ref(), original_block.as_ref());
         }
-
-        crate::key::print_freq();
     }
 
     #[test]
@@ -201,8 +282,6 @@ mod tests {
             block.do_cipher(&cipher);
             assert_eq!(&original_block, block.data().as_slice());
         }
-
-        crate::key::print_freq();
     }
 
     #[test]
@@ -230,6 +309,35 @@ mod tests {
         assert_eq!(tag.get_tag(), 1);
     }
 
+    #[test]
+    fn var_block() {
+        let key = Key::new([0x5Au8; 64]);
+        let cipher = MainCipher::new(&key, 0x0102_0304);
+
+        let mut block = IndexedBlock::new();
+        block.tag().set_index(77);
+        block.data_mut()[0] = 0x0000_BEEF;
+        block.data_mut()[1] = 0x0000_0001;
+        let mut sent = VarBlock::new(block, 3);
+        assert_eq!(sent.as_bytes().len(), TAG_SIZE + 12);
+        sent.do_cipher(&cipher);
+
+        let mut received = VarBlock::from_bytes(sent.as_bytes()).unwrap();
+        assert_eq!(received.index(), 77);
+        assert_eq!(received.words(), 3);
+        received.do_cipher(&cipher);
+        assert_eq!(received.block().data(), &[0xBEEF, 1, 0, 0, 0, 0, 0]);
+
+        // Words past the length are not sent even if they are not zero
+        let mut block = IndexedBlock::new();
+        block.data_mut()[6] = 0xFFFF_FFFF;
+        assert_eq!(VarBlock::new(block, 0).as_bytes().len(), TAG_SIZE);
+        assert!(VarBlock::from_bytes(&[0; TAG_SIZE - 1]).is_none());
+        assert!(VarBlock::from_bytes(&[0; TAG_SIZE + 2]).is_none());
+        assert!(VarBlock::from_bytes(&[0; 32]).is_some());
+        assert!(VarBlock::from_bytes(&[0; 36]).is_none());
+    }
+
     #[test]
     fn index_block() {
         use core::mem::{align_of, size_of};
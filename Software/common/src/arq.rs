@@ -0,0 +1,392 @@
+//! Selective-repeat ARQ, so that blocks the receiver missed are sent again without sending the
+//! ones it heard.
+//!
+//! The receiver board answers the blocks it hears with an [`AckBitmap`] of the last
+//! [`ARQ_WINDOW`] sequence numbers, sent to the node as a packet of its own. ACK payloads would
+//! save the turnaround, but the radio driver cannot load them. The node keeps
+//! every block until it is acknowledged, and sends the ones that were not acknowledged in time
+//! again, on the same channel and just before its next new block, until it runs out of tries.
+//!
+//! An ack is not worth a pad from the key. It is encrypted with a SipHash keystream under the
+//! auth key and a nonce, and authenticated like a [`crate::Control`] message. A receiver that
+//! restarts reuses nonces, which only gives away which blocks it heard. Every node has the same
+//! auth key, so the ack also names the node it is for.
+
+use crate::control::siphash24;
+use crate::{PadRegion, VarBlock, AUTH_KEY_SIZE, BLOCK_SIZE};
+
+/// The number of sequence numbers an [`AckBitmap`] covers, and the most blocks a node waits on
+pub const ARQ_WINDOW: usize = 32;
+
+/// The most blocks sent again before each new block
+const RESENDS_PER_BLOCK: usize = 2;
+
+/// Which of the last [`ARQ_WINDOW`] sequence numbers the receiver heard
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AckBitmap {
+    /// The node whose blocks these are
+    pub node: u8,
+    /// The newest sequence number heard
+    pub last: u32,
+    /// Bit `n` is set if `last - n` was heard
+    pub bits: u32,
+}
+
+/// The size of a sealed [`AckBitmap`] on the air
+pub const ACK_SIZE: usize = ACK_MAC_START + 8;
+
+const ACK_MAC_START: usize = 13;
+
+fn ack_mac(packet: &[u8; ACK_SIZE], auth_key: &[u8; AUTH_KEY_SIZE]) -> u64 {
+    // 17 bytes, so it never collides with a control message MAC or a hop hash
+    let mut input = [0u8; 4 + ACK_MAC_START];
+    input[..4].copy_from_slice(b"ack\0");
+    input[4..].copy_from_slice(&packet[..ACK_MAC_START]);
+    siphash24(auth_key, &input)
+}
+
+fn keystream(nonce: u32, auth_key: &[u8; AUTH_KEY_SIZE]) -> [u8; 8] {
+    let mut input = [0u8; 8];
+    input[..4].copy_from_slice(b"arq\0");
+    input[4..].copy_from_slice(&nonce.to_le_bytes());
+    siphash24(auth_key, &input).to_le_bytes()
+}
+
+impl AckBitmap {
+    pub fn contains(&self, sequence: u32) -> bool {
+        let behind = self.last.wrapping_sub(sequence);
+        behind < ARQ_WINDOW as u32 && self.bits & 1 << behind != 0
+    }
+
+    /// Returns the packet to send: `[nonce u32][last, bits encrypted][node][mac u64]`
+    pub fn seal(&self, nonce: u32, auth_key: &[u8; AUTH_KEY_SIZE]) -> [u8; ACK_SIZE] {
+        let mut packet = [0u8; ACK_SIZE];
+        packet[..4].copy_from_slice(&nonce.to_le_bytes());
+        packet[4..8].copy_from_slice(&self.last.to_le_bytes());
+        packet[8..12].copy_from_slice(&self.bits.to_le_bytes());
+        packet[12] = self.node;
+        for (b, k) in packet[4..12].iter_mut().zip(keystream(nonce, auth_key)) {
+            *b ^= k;
+        }
+        let mac = ack_mac(&packet, auth_key);
+        packet[ACK_MAC_START..].copy_from_slice(&mac.to_le_bytes());
+        packet
+    }
+
+    /// Decodes a packet made by [`AckBitmap::seal`]. Anything else, such as a block from the
+    /// host, fails the MAC
+    pub fn open(packet: &[u8], auth_key: &[u8; AUTH_KEY_SIZE]) -> Option<Self> {
+        let packet: &[u8; ACK_SIZE] = packet.try_into().ok()?;
+        let expected = ack_mac(packet, auth_key).to_le_bytes();
+        let diff = expected
+            .iter()
+            .zip(&packet[ACK_MAC_START..])
+            .fold(0, |diff, (a, b)| diff | (a ^ b));
+        if diff != 0 {
+            return None;
+        }
+        let nonce = u32::from_le_bytes(packet[..4].try_into().unwrap());
+        let mut plain = [0u8; 8];
+        for ((p, b), k) in plain
+            .iter_mut()
+            .zip(&packet[4..12])
+            .zip(keystream(nonce, auth_key))
+        {
+            *p = b ^ k;
+        }
+        Some(Self {
+            node: packet[12],
+            last: u32::from_le_bytes(plain[..4].try_into().unwrap()),
+            bits: u32::from_le_bytes(plain[4..].try_into().unwrap()),
+        })
+    }
+}
+
+/// What a node's ARQ has done so far
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ArqStats {
+    /// New blocks sent
+    pub sent: u32,
+    /// Blocks sent again
+    pub resent: u32,
+    pub acked: u32,
+    /// Blocks that ran out of tries, or were pushed out of the window by newer ones
+    pub given_up: u32,
+}
+
+struct Outstanding {
+    sequence: u32,
+    block: [u8; BLOCK_SIZE],
+    len: usize,
+    sent_ms: u64,
+    resends: u8,
+}
+
+/// The node's side: the blocks waiting to be acknowledged
+pub struct ArqSender {
+    window: [Option<Outstanding>; ARQ_WINDOW],
+    /// Blocks sent again since the last new block
+    resends: usize,
+    stats: ArqStats,
+}
+
+impl ArqSender {
+    pub fn new() -> Self {
+        Self {
+            window: core::array::from_fn(|_| None),
+            resends: 0,
+            stats: ArqStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> ArqStats {
+        self.stats
+    }
+
+    /// The number of blocks waiting to be acknowledged
+    pub fn outstanding(&self) -> usize {
+        self.window.iter().flatten().count()
+    }
+
+    /// Forgets every block, for when ARQ is turned off
+    pub fn clear(&mut self) {
+        self.window = core::array::from_fn(|_| None);
+    }
+
+    /// Starts waiting for an ack of a new block. The window never holds up the node, if it is
+    /// full the oldest block is given up on
+    pub fn sent(&mut self, sequence: u32, block: &VarBlock, now_ms: u64) {
+        self.stats.sent = self.stats.sent.wrapping_add(1);
+        self.resends = 0;
+        let slot = match self.window.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None => {
+                self.stats.given_up = self.stats.given_up.wrapping_add(1);
+                self.oldest(|_| true).unwrap()
+            }
+        };
+        let sent = block.as_bytes();
+        let mut bytes = [0u8; BLOCK_SIZE];
+        bytes[..sent.len()].copy_from_slice(sent);
+        self.window[slot] = Some(Outstanding {
+            sequence,
+            block: bytes,
+            len: sent.len(),
+            sent_ms: now_ms,
+            resends: 0,
+        });
+    }
+
+    /// The slot of the block with the lowest sequence number that matches
+    fn oldest(&self, filter: impl Fn(&Outstanding) -> bool) -> Option<usize> {
+        self.window
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, o)| o.as_ref().filter(|o| filter(o)).map(|o| (slot, o)))
+            .min_by_key(|(_, o)| o.sequence)
+            .map(|(slot, _)| slot)
+    }
+
+    /// Returns a block to send again before the next new one: the oldest that has waited
+    /// `timeout_ms` for an ack. Blocks already sent again `retries` times are given up on
+    pub fn resend(&mut self, now_ms: u64, timeout_ms: u32, retries: u8) -> Option<(u32, &[u8])> {
+        if self.resends >= RESENDS_PER_BLOCK {
+            return None;
+        }
+        let due = |o: &Outstanding| now_ms >= o.sent_ms + timeout_ms as u64;
+        while let Some(slot) = self.oldest(due) {
+            let outstanding = self.window[slot].as_mut().unwrap();
+            if outstanding.resends >= retries {
+                self.window[slot] = None;
+                self.stats.given_up = self.stats.given_up.wrapping_add(1);
+                continue;
+            }
+            outstanding.resends += 1;
+            outstanding.sent_ms = now_ms;
+            self.resends += 1;
+            self.stats.resent = self.stats.resent.wrapping_add(1);
+            let outstanding = self.window[slot].as_ref().unwrap();
+            return Some((outstanding.sequence, &outstanding.block[..outstanding.len]));
+        }
+        None
+    }
+
+    /// Stops waiting for every block in `ack`
+    pub fn acked(&mut self, ack: AckBitmap) {
+        for slot in &mut self.window {
+            if matches!(slot, Some(o) if ack.contains(o.sequence)) {
+                *slot = None;
+                self.stats.acked = self.stats.acked.wrapping_add(1);
+            }
+        }
+    }
+}
+
+impl Default for ArqSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The receiver's side: keeps track of what it heard and makes the acks
+pub struct ArqReceiver {
+    node: u8,
+    index_key: u32,
+    downlink: PadRegion,
+    auth_key: [u8; AUTH_KEY_SIZE],
+    heard: Option<AckBitmap>,
+    /// Something was heard since the last ack
+    changed: bool,
+    nonce: u32,
+}
+
+impl ArqReceiver {
+    /// Acks the blocks of node `node`. `offsets` is the number of pads in the key, see
+    /// [`crate::Key::subkey_count`]
+    pub fn new(index_key: u32, offsets: usize, auth_key: [u8; AUTH_KEY_SIZE], node: u8) -> Self {
+        Self {
+            node,
+            index_key,
+            downlink: PadRegion::node_downlink(offsets, node),
+            auth_key,
+            heard: None,
+            changed: false,
+            nonce: 0,
+        }
+    }
+
+    /// Records an encrypted block from the node
+    pub fn heard(&mut self, block: &VarBlock) {
+        let sequence = match self.downlink.sequence_of(block.index(), self.index_key) {
+            Some(sequence) => sequence,
+            None => return,
+        };
+        self.changed = true;
+        let heard = match &mut self.heard {
+            Some(heard) => heard,
+            None => {
+                self.heard = Some(AckBitmap {
+                    node: self.node,
+                    last: sequence,
+                    bits: 1,
+                });
+                return;
+            }
+        };
+        let behind = heard.last.wrapping_sub(sequence);
+        if behind < ARQ_WINDOW as u32 {
+            heard.bits |= 1 << behind;
+        } else if behind > u32::MAX / 2 {
+            // Newer than anything so far
+            let ahead = sequence.wrapping_sub(heard.last);
+            heard.bits = heard.bits.checked_shl(ahead).unwrap_or(0) | 1;
+            heard.last = sequence;
+        }
+    }
+
+    /// Returns the packet acknowledging everything heard, if anything was heard since the last
+    pub fn ack(&mut self) -> Option<[u8; ACK_SIZE]> {
+        let heard = self.heard?;
+        if !core::mem::take(&mut self.changed) {
+            return None;
+        }
+        self.nonce = self.nonce.wrapping_add(1);
+        Some(heard.seal(self.nonce, &self.auth_key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{IndexedBlock, PadPosition, Tag};
+
+    const AUTH_KEY: [u8; AUTH_KEY_SIZE] = *b"arq test key 16b";
+
+    #[test]
+    fn seal_and_open() {
+        let ack = AckBitmap {
+            node: 3,
+            last: 1000,
+            bits: 0b1011,
+        };
+        let packet = ack.seal(7, &AUTH_KEY);
+        assert_eq!(AckBitmap::open(&packet, &AUTH_KEY), Some(ack));
+        assert_ne!(&packet[4..12], &[0xE8, 3, 0, 0, 0b1011, 0, 0, 0]);
+        for bit in 0..ACK_SIZE * 8 {
+            let mut flipped = packet;
+            flipped[bit / 8] ^= 1 << (bit % 8);
+            assert_eq!(AckBitmap::open(&flipped, &AUTH_KEY), None);
+        }
+        assert_eq!(AckBitmap::open(&packet, b"some other key!!"), None);
+        assert!(ack.contains(1000) && ack.contains(999) && ack.contains(997));
+        assert!(!ack.contains(998) && !ack.contains(1001) && !ack.contains(968));
+    }
+
+    #[test]
+    fn sender_resends_what_was_missed() {
+        let mut sender = ArqSender::new();
+        let block = VarBlock::default();
+        for sequence in 10..15 {
+            sender.sent(sequence, &block, 0);
+        }
+        // 11 and 13 were lost
+        sender.acked(AckBitmap {
+            node: 0,
+            last: 14,
+            bits: 0b10101,
+        });
+        assert_eq!(sender.outstanding(), 2);
+        assert_eq!(sender.resend(50, 100, 2), None);
+        assert_eq!(sender.resend(100, 100, 2).map(|(s, _)| s), Some(11));
+        assert_eq!(sender.resend(100, 100, 2).map(|(s, _)| s), Some(13));
+
+        // Two tries each, then they are given up on
+        sender.sent(15, &block, 100);
+        assert_eq!(sender.resend(200, 100, 2).map(|(s, _)| s), Some(11));
+        assert_eq!(sender.resend(200, 100, 2).map(|(s, _)| s), Some(13));
+        sender.sent(16, &block, 200);
+        assert_eq!(sender.resend(300, 100, 2).map(|(s, _)| s), Some(15));
+        assert_eq!(sender.stats().given_up, 2);
+
+        // A full window makes room
+        for sequence in 17..17 + ARQ_WINDOW as u32 {
+            sender.sent(sequence, &block, 300);
+        }
+        assert_eq!(sender.outstanding(), ARQ_WINDOW);
+        assert_eq!(sender.stats().given_up, 4);
+    }
+
+    #[test]
+    fn receiver_acks_what_it_heard() {
+        let downlink = PadRegion::node_downlink(10_000, 1);
+        let index_key = 0x1357_9BDF;
+        let mut receiver = ArqReceiver::new(index_key, 10_000, AUTH_KEY, 1);
+        assert_eq!(receiver.ack(), None);
+        // Blocks from node 2 are not for this receiver to ack
+        let at = |sequence| PadPosition { sequence, word: 0 };
+        let mut other = IndexedBlock::new();
+        other
+            .tag()
+            .set_index(PadRegion::node_downlink(10_000, 2).index_for(at(8), index_key));
+        receiver.heard(&VarBlock::new(other, 0));
+        assert_eq!(receiver.ack(), None);
+        for sequence in [5, 7, 6, 40, 9] {
+            let mut block = IndexedBlock::new();
+            block
+                .tag()
+                .set_index(downlink.index_for(at(sequence), index_key));
+            receiver.heard(&VarBlock::new(block, 0));
+        }
+        let ack = AckBitmap::open(&receiver.ack().unwrap(), &AUTH_KEY).unwrap();
+        // 5 to 7 fell out of the window when 40 came along
+        assert_eq!(
+            ack,
+            AckBitmap {
+                node: 1,
+                last: 40,
+                bits: 1 | 1 << 31
+            }
+        );
+        assert_eq!(receiver.ack(), None);
+    }
+}
@@ -0,0 +1,252 @@
+//! Capture files hold the raw serial stream from a receiver so that it can be decoded again later.
+//!
+//! All integers are little endian:
+//! ```text
+//! header: b"FCAP" | version: u16 | start_unix_us: u64 | channel: u8 | key_id: u64
+//!         | serial_len: u8 | serial_number: [u8; serial_len]
+//! record: timestamp_us: u64 | len: u16 | bytes: [u8; len]
+//! ```
+//! Each record is one frame exactly as it was read from the port, including its zero delimiter.
+//! `timestamp_us` is the number of microseconds between `start_unix_us` and the arrival of the
+//! delimiter. Feeding every record into a [`crate::FrameDecoder`] in order reproduces the original
+//! session.
+
+use std::io::{self, ErrorKind, Read, Write};
+
+const MAGIC: &[u8; 4] = b"FCAP";
+const VERSION: u16 = 1;
+
+/// Bytes without a delimiter are flushed as their own record after this many, so that a noisy
+/// line can't make a record grow forever
+const MAX_RECORD: usize = 1024;
+
+/// Information about where and how a capture was recorded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureHeader {
+    /// Wall clock time when the capture started, in microseconds since the Unix epoch
+    pub start_unix_us: u64,
+    /// The radio channel the receiver was listening on
+    pub channel: u8,
+    /// Fingerprint of the key that was in use, so replays can warn about using the wrong key
+    pub key_id: u64,
+    /// USB serial number of the receiver. Empty if unknown
+    pub serial_number: String,
+}
+
+/// A single frame read from the serial port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureRecord {
+    pub timestamp_us: u64,
+    pub bytes: Vec<u8>,
+}
+
+/// Splits a serial stream into frames and writes them as records
+pub struct CaptureWriter<W: Write> {
+    out: W,
+    pending: Vec<u8>,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Writes `header` to `out` and returns a writer ready for records
+    pub fn new(mut out: W, header: &CaptureHeader) -> io::Result<Self> {
+        let serial = header.serial_number.as_bytes();
+        let serial = &serial[..serial.len().min(u8::MAX as usize)];
+
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        out.write_all(&header.start_unix_us.to_le_bytes())?;
+        out.write_all(&[header.channel])?;
+        out.write_all(&header.key_id.to_le_bytes())?;
+        out.write_all(&[serial.len() as u8])?;
+        out.write_all(serial)?;
+        Ok(Self {
+            out,
+            pending: Vec::new(),
+        })
+    }
+
+    fn write_record(&mut self, timestamp_us: u64) -> io::Result<()> {
+        self.out.write_all(&timestamp_us.to_le_bytes())?;
+        self.out
+            .write_all(&(self.pending.len() as u16).to_le_bytes())?;
+        self.out.write_all(&self.pending)?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Records bytes as they come in from the serial port.
+    /// A record is written every time a frame delimiter is seen
+    pub fn record(&mut self, timestamp_us: u64, bytes: &[u8]) -> io::Result<()> {
+        for &b in bytes {
+            self.pending.push(b);
+            if b == 0 || self.pending.len() == MAX_RECORD {
+                self.write_record(timestamp_us)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flushes the underlying writer. Partial frames stay buffered until their delimiter arrives
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Writes any partial frame that is left over and returns the underlying writer
+    pub fn finish(mut self, timestamp_us: u64) -> io::Result<W> {
+        if !self.pending.is_empty() {
+            self.write_record(timestamp_us)?;
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+fn read_array<const N: usize>(input: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    input.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Reads records back out of a capture file
+pub struct CaptureReader<R: Read> {
+    input: R,
+    header: CaptureHeader,
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(mut input: R) -> io::Result<Self> {
+        if &read_array::<4>(&mut input)? != MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Not a capture file"));
+        }
+        let version = u16::from_le_bytes(read_array(&mut input)?);
+        if version != VERSION {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported capture version {}", version),
+            ));
+        }
+        let start_unix_us = u64::from_le_bytes(read_array(&mut input)?);
+        let [channel] = read_array::<1>(&mut input)?;
+        let key_id = u64::from_le_bytes(read_array(&mut input)?);
+        let [serial_len] = read_array::<1>(&mut input)?;
+        let mut serial = vec![0u8; serial_len as usize];
+        input.read_exact(&mut serial)?;
+
+        Ok(Self {
+            input,
+            header: CaptureHeader {
+                start_unix_us,
+                channel,
+                key_id,
+                serial_number: String::from_utf8_lossy(&serial).into_owned(),
+            },
+        })
+    }
+
+    pub fn header(&self) -> &CaptureHeader {
+        &self.header
+    }
+
+    /// Returns the next record, or `None` at the end of the file.
+    /// A file that ends part way through a record is an error
+    pub fn next_record(&mut self) -> io::Result<Option<CaptureRecord>> {
+        let mut timestamp = [0u8; 8];
+        // Check for a clean end of file before the first byte of a record
+        match self.input.read(&mut timestamp[..1])? {
+            0 => return Ok(None),
+            _ => self.input.read_exact(&mut timestamp[1..])?,
+        }
+        let len = u16::from_le_bytes(read_array(&mut self.input)?);
+        let mut bytes = vec![0u8; len as usize];
+        self.input.read_exact(&mut bytes)?;
+        Ok(Some(CaptureRecord {
+            timestamp_us: u64::from_le_bytes(timestamp),
+            bytes,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = io::Result<CaptureRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> CaptureHeader {
+        CaptureHeader {
+            start_unix_us: 1_650_000_000_000_000,
+            channel: 8,
+            key_id: 0xDEAD_BEEF_1234_5678,
+            serial_number: "TEST".into(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut writer = CaptureWriter::new(Vec::new(), &header()).unwrap();
+        // Frames split across reads and several frames in one read
+        writer.record(10, &[1, 2]).unwrap();
+        writer.record(20, &[3, 0, 4, 0, 5]).unwrap();
+        let file = writer.finish(30).unwrap();
+
+        let mut reader = CaptureReader::new(file.as_slice()).unwrap();
+        assert_eq!(reader.header(), &header());
+        let records: Vec<_> = reader.by_ref().map(Result::unwrap).collect();
+        assert_eq!(
+            records,
+            vec![
+                CaptureRecord {
+                    timestamp_us: 20,
+                    bytes: vec![1, 2, 3, 0]
+                },
+                CaptureRecord {
+                    timestamp_us: 20,
+                    bytes: vec![4, 0]
+                },
+                CaptureRecord {
+                    timestamp_us: 30,
+                    bytes: vec![5]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn long_garbage_is_split() {
+        let mut writer = CaptureWriter::new(Vec::new(), &header()).unwrap();
+        writer.record(1, &vec![0xAA; MAX_RECORD + 1]).unwrap();
+        let file = writer.finish(2).unwrap();
+
+        let records: Vec<_> = CaptureReader::new(file.as_slice())
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].bytes.len(), MAX_RECORD);
+        assert_eq!(records[1].bytes.len(), 1);
+    }
+
+    #[test]
+    fn truncated_record() {
+        let mut writer = CaptureWriter::new(Vec::new(), &header()).unwrap();
+        writer.record(1, &[1, 2, 3, 0]).unwrap();
+        let mut file = writer.finish(1).unwrap();
+        file.pop();
+
+        let mut reader = CaptureReader::new(file.as_slice()).unwrap();
+        let err = reader.next_record().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn bad_magic() {
+        let err = CaptureReader::new(&b"PCAP\x01\x00"[..]).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
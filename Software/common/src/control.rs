@@ -0,0 +1,770 @@
+//! Control messages for reading and changing a node's settings from the host.
+//!
+//! A control message fills the first 20 data bytes of an [`crate::IndexedBlock`] whose tag is
+//! [`TAG_CONTROL`]: `[op][setting][reason][0][value: u32 LE][request: u32 LE][mac: u64 LE]`.
+//! The last 8 are zero, so only the first [`CONTROL_WORDS`] go on the air.
+//! The cipher is a plain XOR, so anyone in range can flip bits of a block without knowing the
+//! key. The MAC is SipHash-2-4 of the index and the first 12 data bytes under a separate 16 byte
+//! auth key, and nothing is applied unless it matches and the last 8 bytes are still zero.
+//! Binding the index means a control block cannot be moved to another sequence number, and the
+//! replay window catches it being sent again.
+
+use crate::message::{to_bytes, to_words};
+use crate::MAX_BLACKLISTED;
+
+/// The tag bit value for blocks that carry a [`Control`] message
+pub const TAG_CONTROL: usize = 1;
+
+/// The data words a block carrying a [`Control`] message sends
+pub const CONTROL_WORDS: usize = MAC_END / 4;
+
+/// The size of the key used to authenticate control messages
+pub const AUTH_KEY_SIZE: usize = 16;
+
+/// The highest channel the nRF24L01 supports
+pub const MAX_CHANNEL: u8 = 125;
+
+/// A node setting that can be read or changed remotely
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Setting {
+    /// Radio channel, 0 to [`MAX_CHANNEL`]
+    Channel = 1,
+    /// Transmit power, see [`PaLevel`]
+    PaLevel = 2,
+    /// Air data rate, see [`DataRate`]
+    DataRate = 3,
+    /// Milliseconds between transmissions
+    TxInterval = 4,
+    /// Index into the camera's resolutions, QQVGA (0) to UXGA (7)
+    CameraResolution = 5,
+    /// JPEG quality scale, lower is better
+    CameraQuality = 6,
+    /// Brightness from -2 to 2, as a two's complement `u32`
+    CameraBrightness = 7,
+    /// 1 to hop between channels picked with the auth key, 0 to stay on [`Setting::Channel`]
+    Hopping = 8,
+    /// Bit `n` set means slot `n` of the hop set is skipped, see [`crate::HopSet`]. At most
+    /// [`MAX_BLACKLISTED`] bits can be set
+    HopBlacklist = 9,
+    /// Times a block the receiver did not acknowledge is sent again, 0 to 15. 0 turns ARQ off
+    ArqRetries = 10,
+    /// Milliseconds to wait for the receiver to acknowledge a block before sending it again
+    ArqTimeout = 11,
+    /// 1 to let the node pick its data rate and PA level from how the link is doing, see
+    /// [`crate::RateController`]. The receiver has to be told too, so that it can follow
+    AutoRate = 12,
+    /// 1 to power the radio down and stop the microcontroller between transmissions. The node
+    /// only hears the host for [`Setting::ListenWindow`] after each one
+    LowPower = 13,
+    /// Milliseconds the node listens after each transmission in low power mode
+    ListenWindow = 14,
+    /// Seconds between battery measurements, see [`crate::Telemetry`]. 0 turns them off
+    TelemetryInterval = 15,
+}
+
+impl Setting {
+    pub const ALL: [Setting; 15] = [
+        Setting::Channel,
+        Setting::PaLevel,
+        Setting::DataRate,
+        Setting::TxInterval,
+        Setting::CameraResolution,
+        Setting::CameraQuality,
+        Setting::CameraBrightness,
+        Setting::Hopping,
+        Setting::HopBlacklist,
+        Setting::ArqRetries,
+        Setting::ArqTimeout,
+        Setting::AutoRate,
+        Setting::LowPower,
+        Setting::ListenWindow,
+        Setting::TelemetryInterval,
+    ];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Setting::Channel => "channel",
+            Setting::PaLevel => "pa-level",
+            Setting::DataRate => "data-rate",
+            Setting::TxInterval => "tx-interval",
+            Setting::CameraResolution => "camera-resolution",
+            Setting::CameraQuality => "camera-quality",
+            Setting::CameraBrightness => "camera-brightness",
+            Setting::Hopping => "hopping",
+            Setting::HopBlacklist => "hop-blacklist",
+            Setting::ArqRetries => "arq-retries",
+            Setting::ArqTimeout => "arq-timeout",
+            Setting::AutoRate => "auto-rate",
+            Setting::LowPower => "low-power",
+            Setting::ListenWindow => "listen-window",
+            Setting::TelemetryInterval => "telemetry-interval",
+        }
+    }
+}
+
+impl TryFrom<u8> for Setting {
+    type Error = u8;
+
+    fn try_from(setting: u8) -> Result<Self, Self::Error> {
+        Setting::ALL
+            .iter()
+            .copied()
+            .find(|&s| s as u8 == setting)
+            .ok_or(setting)
+    }
+}
+
+/// Transmit power, matching the nRF24L01 `RF_PWR` values
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PaLevel {
+    Min = 0,
+    Low = 1,
+    High = 2,
+    Max = 3,
+}
+
+impl TryFrom<u32> for PaLevel {
+    type Error = Rejection;
+
+    fn try_from(level: u32) -> Result<Self, Self::Error> {
+        match level {
+            0 => Ok(PaLevel::Min),
+            1 => Ok(PaLevel::Low),
+            2 => Ok(PaLevel::High),
+            3 => Ok(PaLevel::Max),
+            _ => Err(Rejection::OutOfRange),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DataRate {
+    Kbps250 = 0,
+    Mbps1 = 1,
+    Mbps2 = 2,
+}
+
+impl DataRate {
+    /// Parses the names the desktop tool uses, `250k`, `1m` and `2m`, so that firmware can be
+    /// built for a data rate
+    pub const fn from_name(name: &str) -> Option<Self> {
+        match name.as_bytes() {
+            b"250k" => Some(DataRate::Kbps250),
+            b"1m" => Some(DataRate::Mbps1),
+            b"2m" => Some(DataRate::Mbps2),
+            _ => None,
+        }
+    }
+}
+
+impl TryFrom<u32> for DataRate {
+    type Error = Rejection;
+
+    fn try_from(rate: u32) -> Result<Self, Self::Error> {
+        match rate {
+            0 => Ok(DataRate::Kbps250),
+            1 => Ok(DataRate::Mbps1),
+            2 => Ok(DataRate::Mbps2),
+            _ => Err(Rejection::OutOfRange),
+        }
+    }
+}
+
+/// Why a node refused a request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Rejection {
+    /// The node does not have this setting, for example camera settings on a node without one
+    Unsupported = 1,
+    /// The value is not allowed for this setting
+    OutOfRange = 2,
+}
+
+impl TryFrom<u8> for Rejection {
+    type Error = u8;
+
+    fn try_from(reason: u8) -> Result<Self, Self::Error> {
+        match reason {
+            1 => Ok(Rejection::Unsupported),
+            2 => Ok(Rejection::OutOfRange),
+            other => Err(other),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Control {
+    /// Host to node: report the value of a setting
+    Get(Setting),
+    /// Host to node: change a setting
+    Set(Setting, u32),
+    /// Node to host: the value of `setting` after handling the uplink block with sequence number
+    /// `request`
+    Ack {
+        request: u32,
+        setting: Setting,
+        value: u32,
+    },
+    /// Node to host: the uplink block with sequence number `request` was refused
+    Nack {
+        request: u32,
+        setting: Setting,
+        reason: Rejection,
+    },
+    /// Node to host: a setting the node changed by itself, such as a channel it blacklisted
+    Notify { setting: Setting, value: u32 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlError {
+    /// The MAC did not match, so the block was not sent by someone with the auth key
+    BadMac,
+    /// The MAC matched but the contents make no sense
+    Malformed,
+}
+
+const OP_GET: u8 = 1;
+const OP_SET: u8 = 2;
+const OP_ACK: u8 = 3;
+const OP_NACK: u8 = 4;
+const OP_NOTIFY: u8 = 5;
+
+const MAC_START: usize = 12;
+const MAC_END: usize = MAC_START + 8;
+
+fn mac(index: u32, bytes: &[u8; 28], auth_key: &[u8; AUTH_KEY_SIZE]) -> u64 {
+    let mut input = [0u8; 4 + MAC_START];
+    input[..4].copy_from_slice(&index.to_le_bytes());
+    input[4..].copy_from_slice(&bytes[..MAC_START]);
+    siphash24(auth_key, &input)
+}
+
+impl Control {
+    pub fn setting(&self) -> Setting {
+        match *self {
+            Control::Get(setting)
+            | Control::Set(setting, _)
+            | Control::Ack { setting, .. }
+            | Control::Nack { setting, .. }
+            | Control::Notify { setting, .. } => setting,
+        }
+    }
+
+    /// Returns the data words of a block sent with index `index`
+    pub fn seal(&self, index: u32, auth_key: &[u8; AUTH_KEY_SIZE]) -> [u32; 7] {
+        let mut bytes = [0u8; 28];
+        let (op, reason, value, request) = match *self {
+            Control::Get(_) => (OP_GET, 0, 0, 0),
+            Control::Set(_, value) => (OP_SET, 0, value, 0),
+            Control::Ack { request, value, .. } => (OP_ACK, 0, value, request),
+            Control::Nack {
+                request, reason, ..
+            } => (OP_NACK, reason as u8, 0, request),
+            Control::Notify { value, .. } => (OP_NOTIFY, 0, value, 0),
+        };
+        bytes[0] = op;
+        bytes[1] = self.setting() as u8;
+        bytes[2] = reason;
+        bytes[4..8].copy_from_slice(&value.to_le_bytes());
+        bytes[8..12].copy_from_slice(&request.to_le_bytes());
+        let mac = mac(index, &bytes, auth_key);
+        bytes[MAC_START..MAC_END].copy_from_slice(&mac.to_le_bytes());
+        to_words(&bytes)
+    }
+
+    /// Checks and decodes the decrypted data words of a block received with index `index`
+    pub fn open(
+        index: u32,
+        data: &[u32; 7],
+        auth_key: &[u8; AUTH_KEY_SIZE],
+    ) -> Result<Self, ControlError> {
+        let bytes = to_bytes(data);
+        let expected = mac(index, &bytes, auth_key).to_le_bytes();
+        // Compare every byte so that the time taken does not depend on where they differ
+        let diff = expected
+            .iter()
+            .zip(&bytes[MAC_START..MAC_END])
+            .fold(0, |diff, (a, b)| diff | (a ^ b));
+        let padding = bytes[MAC_END..].iter().fold(0, |diff, b| diff | b);
+        if diff | padding != 0 {
+            return Err(ControlError::BadMac);
+        }
+
+        let setting = Setting::try_from(bytes[1]).map_err(|_| ControlError::Malformed)?;
+        let value = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        let request = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        match bytes[0] {
+            OP_GET => Ok(Control::Get(setting)),
+            OP_SET => Ok(Control::Set(setting, value)),
+            OP_ACK => Ok(Control::Ack {
+                request,
+                setting,
+                value,
+            }),
+            OP_NACK => Ok(Control::Nack {
+                request,
+                setting,
+                reason: Rejection::try_from(bytes[2]).map_err(|_| ControlError::Malformed)?,
+            }),
+            OP_NOTIFY => Ok(Control::Notify { setting, value }),
+            _ => Err(ControlError::Malformed),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CameraConfig {
+    pub resolution: u8,
+    pub quality: u8,
+    pub brightness: i8,
+}
+
+impl CameraConfig {
+    pub const DEFAULT: Self = Self {
+        resolution: 1,
+        quality: 12,
+        brightness: 0,
+    };
+}
+
+/// The settings a node runs with, and the rules for changing them remotely
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NodeConfig {
+    pub channel: u8,
+    pub pa_level: PaLevel,
+    pub data_rate: DataRate,
+    pub tx_interval_ms: u32,
+    /// `None` on nodes without a camera
+    pub camera: Option<CameraConfig>,
+    /// Hop between the channels of the [`crate::HopSet`] instead of staying on `channel`
+    pub hopping: bool,
+    pub hop_blacklist: u32,
+    /// See [`crate::ArqSender`]
+    pub arq_retries: u8,
+    pub arq_timeout_ms: u32,
+    /// See [`crate::RateController`]
+    pub auto_rate: bool,
+    /// See [`Setting::LowPower`]
+    pub low_power: bool,
+    pub listen_ms: u32,
+    /// See [`Setting::TelemetryInterval`]
+    pub telemetry_s: u32,
+}
+
+impl NodeConfig {
+    /// What the firmware starts with
+    pub const DEFAULT: Self = Self {
+        channel: 8,
+        pa_level: PaLevel::Max,
+        data_rate: DataRate::Mbps1,
+        tx_interval_ms: 1000,
+        camera: None,
+        hopping: false,
+        hop_blacklist: 0,
+        arq_retries: 0,
+        arq_timeout_ms: 500,
+        auto_rate: false,
+        low_power: false,
+        listen_ms: 20,
+        telemetry_s: 60,
+    };
+
+    /// The shortest and longest time between transmissions that can be set
+    pub const TX_INTERVAL_MS: (u32, u32) = (50, 60_000);
+
+    /// The shortest and longest ARQ timeout that can be set
+    pub const ARQ_TIMEOUT_MS: (u32, u32) = (10, 60_000);
+
+    /// The shortest and longest listen window that can be set
+    pub const LISTEN_MS: (u32, u32) = (5, 1000);
+
+    /// The longest time between battery measurements that can be set, a day
+    pub const MAX_TELEMETRY_S: u32 = 86_400;
+
+    /// How long the node sleeps after listening in low power mode, or `None` if it stays awake.
+    /// Listening takes up the whole interval if it is longer
+    pub fn sleep_ms(&self) -> Option<u32> {
+        if !self.low_power {
+            return None;
+        }
+        Some(self.tx_interval_ms.saturating_sub(self.listen_ms))
+    }
+
+    pub fn get(&self, setting: Setting) -> Result<u32, Rejection> {
+        let camera = || self.camera.ok_or(Rejection::Unsupported);
+        Ok(match setting {
+            Setting::Channel => self.channel as u32,
+            Setting::PaLevel => self.pa_level as u32,
+            Setting::DataRate => self.data_rate as u32,
+            Setting::TxInterval => self.tx_interval_ms,
+            Setting::CameraResolution => camera()?.resolution as u32,
+            Setting::CameraQuality => camera()?.quality as u32,
+            Setting::CameraBrightness => camera()?.brightness as i32 as u32,
+            Setting::Hopping => self.hopping as u32,
+            Setting::HopBlacklist => self.hop_blacklist,
+            Setting::ArqRetries => self.arq_retries as u32,
+            Setting::ArqTimeout => self.arq_timeout_ms,
+            Setting::AutoRate => self.auto_rate as u32,
+            Setting::LowPower => self.low_power as u32,
+            Setting::ListenWindow => self.listen_ms,
+            Setting::TelemetryInterval => self.telemetry_s,
+        })
+    }
+
+    /// Changes a setting. Nothing is changed if the value is refused
+    pub fn set(&mut self, setting: Setting, value: u32) -> Result<(), Rejection> {
+        let in_range = |ok: bool| {
+            if ok {
+                Ok(())
+            } else {
+                Err(Rejection::OutOfRange)
+            }
+        };
+        match setting {
+            Setting::Channel => {
+                in_range(value <= MAX_CHANNEL as u32)?;
+                self.channel = value as u8;
+            }
+            Setting::PaLevel => self.pa_level = PaLevel::try_from(value)?,
+            Setting::DataRate => self.data_rate = DataRate::try_from(value)?,
+            Setting::TxInterval => {
+                let (min, max) = Self::TX_INTERVAL_MS;
+                in_range((min..=max).contains(&value))?;
+                self.tx_interval_ms = value;
+            }
+            Setting::CameraResolution => {
+                in_range(value <= 7)?;
+                self.camera_mut()?.resolution = value as u8;
+            }
+            Setting::CameraQuality => {
+                in_range(value <= 63)?;
+                self.camera_mut()?.quality = value as u8;
+            }
+            Setting::CameraBrightness => {
+                in_range((-2..=2).contains(&(value as i32)))?;
+                self.camera_mut()?.brightness = value as i32 as i8;
+            }
+            Setting::Hopping => {
+                in_range(value <= 1)?;
+                self.hopping = value == 1;
+            }
+            Setting::HopBlacklist => {
+                in_range(value.count_ones() <= MAX_BLACKLISTED)?;
+                self.hop_blacklist = value;
+            }
+            Setting::ArqRetries => {
+                in_range(value <= 15)?;
+                self.arq_retries = value as u8;
+            }
+            Setting::ArqTimeout => {
+                let (min, max) = Self::ARQ_TIMEOUT_MS;
+                in_range((min..=max).contains(&value))?;
+                self.arq_timeout_ms = value;
+            }
+            Setting::AutoRate => {
+                in_range(value <= 1)?;
+                self.auto_rate = value == 1;
+            }
+            Setting::LowPower => {
+                in_range(value <= 1)?;
+                self.low_power = value == 1;
+            }
+            Setting::ListenWindow => {
+                let (min, max) = Self::LISTEN_MS;
+                in_range((min..=max).contains(&value))?;
+                self.listen_ms = value;
+            }
+            Setting::TelemetryInterval => {
+                in_range(value <= Self::MAX_TELEMETRY_S)?;
+                self.telemetry_s = value;
+            }
+        }
+        Ok(())
+    }
+
+    fn camera_mut(&mut self) -> Result<&mut CameraConfig, Rejection> {
+        self.camera.as_mut().ok_or(Rejection::Unsupported)
+    }
+
+    /// Handles a request from the uplink block with sequence number `request`, returning the reply
+    /// to send back. Replies from other nodes are ignored
+    pub fn handle(&mut self, request: u32, control: Control) -> Option<Control> {
+        let setting = control.setting();
+        let result = match control {
+            Control::Get(_) => self.get(setting),
+            Control::Set(_, value) => self.set(setting, value).and_then(|()| self.get(setting)),
+            Control::Ack { .. } | Control::Nack { .. } | Control::Notify { .. } => return None,
+        };
+        Some(match result {
+            Ok(value) => Control::Ack {
+                request,
+                setting,
+                value,
+            },
+            Err(reason) => Control::Nack {
+                request,
+                setting,
+                reason,
+            },
+        })
+    }
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// A setting the host asks the receiver board itself to change, sent in a
+/// [`crate::FrameKind::Radio`] frame. The receiver echoes it back once applied.
+/// The USB link is trusted so these are not authenticated
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReceiverSetting {
+    pub setting: Setting,
+    pub value: u32,
+}
+
+impl ReceiverSetting {
+    /// The size of a [`crate::FrameKind::Radio`] payload
+    pub const SIZE: usize = 5;
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut out = [0u8; Self::SIZE];
+        out[0] = self.setting as u8;
+        out[1..].copy_from_slice(&self.value.to_le_bytes());
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; Self::SIZE] = bytes.try_into().ok()?;
+        Some(Self {
+            setting: Setting::try_from(bytes[0]).ok()?,
+            value: u32::from_le_bytes(bytes[1..].try_into().unwrap()),
+        })
+    }
+}
+
+/// SipHash-2-4, as described in "SipHash: a fast short-input PRF" by Aumasson and Bernstein
+pub(crate) fn siphash24(key: &[u8; AUTH_KEY_SIZE], data: &[u8]) -> u64 {
+    let mut hasher = SipHasher::new(key);
+    hasher.write(data);
+    hasher.finish()
+}
+
+/// [`siphash24`] of data that arrives in pieces, such as an image read out of flash
+pub(crate) struct SipHasher {
+    v: [u64; 4],
+    /// Bytes that do not make up a whole word yet, little endian
+    tail: u64,
+    len: usize,
+}
+
+impl SipHasher {
+    pub(crate) fn new(key: &[u8; AUTH_KEY_SIZE]) -> Self {
+        let k0 = u64::from_le_bytes(key[..8].try_into().unwrap());
+        let k1 = u64::from_le_bytes(key[8..].try_into().unwrap());
+        Self {
+            v: [
+                k0 ^ 0x736f_6d65_7073_6575,
+                k1 ^ 0x646f_7261_6e64_6f6d,
+                k0 ^ 0x6c79_6765_6e65_7261,
+                k1 ^ 0x7465_6462_7974_6573,
+            ],
+            tail: 0,
+            len: 0,
+        }
+    }
+
+    pub(crate) fn write(&mut self, data: &[u8]) {
+        for &b in data {
+            let at = self.len % 8;
+            self.tail |= (b as u64) << (8 * at);
+            self.len += 1;
+            if at == 7 {
+                compress(&mut self.v, self.tail);
+                self.tail = 0;
+            }
+        }
+    }
+
+    pub(crate) fn finish(mut self) -> u64 {
+        let last = self.tail | (self.len as u64) << 56;
+        compress(&mut self.v, last);
+        let v = &mut self.v;
+        v[2] ^= 0xff;
+        for _ in 0..4 {
+            round(v);
+        }
+        v[0] ^ v[1] ^ v[2] ^ v[3]
+    }
+}
+
+fn round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+fn compress(v: &mut [u64; 4], m: u64) {
+    v[3] ^= m;
+    round(v);
+    round(v);
+    v[0] ^= m;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUTH_KEY: [u8; AUTH_KEY_SIZE] = *b"0123456789abcdef";
+
+    #[test]
+    fn siphash_reference_vectors() {
+        let key: [u8; 16] = core::array::from_fn(|i| i as u8);
+        let message: [u8; 15] = core::array::from_fn(|i| i as u8);
+        assert_eq!(siphash24(&key, &[]), 0x726f_db47_dd0e_0e31);
+        assert_eq!(siphash24(&key, &message), 0xa129_ca61_49be_45e5);
+        // The same in pieces that do not line up with the words
+        let mut hasher = SipHasher::new(&key);
+        for piece in message.chunks(3) {
+            hasher.write(piece);
+        }
+        assert_eq!(hasher.finish(), 0xa129_ca61_49be_45e5);
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let messages = [
+            Control::Get(Setting::PaLevel),
+            Control::Set(Setting::CameraBrightness, -2i32 as u32),
+            Control::Ack {
+                request: 77,
+                setting: Setting::Channel,
+                value: 100,
+            },
+            Control::Nack {
+                request: 78,
+                setting: Setting::CameraQuality,
+                reason: Rejection::Unsupported,
+            },
+            Control::Notify {
+                setting: Setting::HopBlacklist,
+                value: 0x8001,
+            },
+        ];
+        for control in messages {
+            let data = control.seal(0x1234, &AUTH_KEY);
+            assert_eq!(Control::open(0x1234, &data, &AUTH_KEY), Ok(control));
+        }
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let data = Control::Set(Setting::Channel, 10).seal(5, &AUTH_KEY);
+        // A different index, any flipped bit or another key all fail
+        assert_eq!(
+            Control::open(6, &data, &AUTH_KEY),
+            Err(ControlError::BadMac)
+        );
+        for bit in 0..7 * 32 {
+            let mut flipped = data;
+            flipped[bit / 32] ^= 1 << (bit % 32);
+            assert_eq!(
+                Control::open(5, &flipped, &AUTH_KEY),
+                Err(ControlError::BadMac)
+            );
+        }
+        assert_eq!(
+            Control::open(5, &data, b"fedcba9876543210"),
+            Err(ControlError::BadMac)
+        );
+    }
+
+    #[test]
+    fn node_applies_settings() {
+        let mut config = NodeConfig::DEFAULT;
+        assert_eq!(
+            config.handle(1, Control::Set(Setting::Channel, 76)),
+            Some(Control::Ack {
+                request: 1,
+                setting: Setting::Channel,
+                value: 76
+            })
+        );
+        assert_eq!(config.channel, 76);
+
+        let before = config;
+        for (setting, value) in [
+            (Setting::Channel, 126),
+            (Setting::PaLevel, 4),
+            (Setting::DataRate, 3),
+            (Setting::TxInterval, 10),
+            (Setting::ArqRetries, 16),
+            (Setting::ArqTimeout, 5),
+            (Setting::AutoRate, 2),
+            (Setting::LowPower, 2),
+            (Setting::ListenWindow, 2000),
+            (Setting::TelemetryInterval, 86_401),
+        ] {
+            assert_eq!(
+                config.handle(2, Control::Set(setting, value)),
+                Some(Control::Nack {
+                    request: 2,
+                    setting,
+                    reason: Rejection::OutOfRange
+                })
+            );
+        }
+        assert_eq!(
+            config.handle(3, Control::Get(Setting::CameraQuality)),
+            Some(Control::Nack {
+                request: 3,
+                setting: Setting::CameraQuality,
+                reason: Rejection::Unsupported
+            })
+        );
+        assert_eq!(config, before);
+
+        config.camera = Some(CameraConfig::DEFAULT);
+        config.set(Setting::CameraBrightness, -1i32 as u32).unwrap();
+        assert_eq!(config.camera.unwrap().brightness, -1);
+        assert_eq!(config.get(Setting::CameraBrightness), Ok(-1i32 as u32));
+        // Replies are not requests
+        let ack = Control::Ack {
+            request: 0,
+            setting: Setting::Channel,
+            value: 1,
+        };
+        assert_eq!(config.handle(4, ack), None);
+    }
+
+    #[test]
+    fn low_power_duty_cycle() {
+        let mut config = NodeConfig::DEFAULT;
+        assert_eq!(config.sleep_ms(), None);
+        config.set(Setting::LowPower, 1).unwrap();
+        config.set(Setting::ListenWindow, 50).unwrap();
+        assert_eq!(config.sleep_ms(), Some(950));
+        config.set(Setting::TxInterval, 50).unwrap();
+        config.set(Setting::ListenWindow, 100).unwrap();
+        assert_eq!(config.sleep_ms(), Some(0));
+    }
+
+    #[test]
+    fn data_rate_names() {
+        assert_eq!(DataRate::from_name("250k"), Some(DataRate::Kbps250));
+        assert_eq!(DataRate::from_name("2m"), Some(DataRate::Mbps2));
+        assert_eq!(DataRate::from_name("2M"), None);
+    }
+}
@@ -0,0 +1,409 @@
+//! A record of a panic or HardFault that the firmware keeps in RAM across the reset that
+//! follows, so that it can be reported on the next boot.
+//!
+//! The receiver sends it to the host in a [`crate::FrameKind::Fault`] frame and a node sends it
+//! up as a [`crate::MessageKind::Fault`] message. Both carry [`Fault::to_bytes`], a
+//! [`FaultClass`] byte followed by the start of the panic message or the stacked registers.
+//! Until there is someone to tell, the board flashes its LED [`FaultClass::blinks`] times over and
+//! over.
+
+use core::fmt;
+
+use crate::MAX_FRAME_PAYLOAD;
+
+/// The most bytes [`Fault::to_bytes`] writes, so that a fault fits in one frame
+pub const MAX_FAULT_BYTES: usize = MAX_FRAME_PAYLOAD;
+
+/// The bytes of a panic message that are kept
+pub const PANIC_TEXT: usize = MAX_FAULT_BYTES - 1;
+
+/// The words of RAM a record takes, see [`Fault::store`]. The firmware's memory.x keeps at least
+/// this much out of the RAM the program uses
+pub const FAULT_WORDS: usize = 2 + MAX_FAULT_BYTES / 4 + 1;
+
+/// Marks RAM holding a record rather than whatever was there at power on
+const FAULT_MAGIC: u32 = 0xFA17_C0DE;
+
+/// The kind of fault, each with its own blink code
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FaultClass {
+    Panic = 1,
+    /// An access the memory protection unit does not allow, such as executing from the stack
+    MemManage = 2,
+    /// An access that got an error back from the bus, such as to an address with nothing there
+    BusFault = 3,
+    /// An undefined instruction, an unaligned access or a divide by zero
+    UsageFault = 4,
+    /// A HardFault that did not start as one of the others, such as a bad vector table read
+    HardFault = 5,
+}
+
+/// Milliseconds the LED is on or off for each flash
+const BLINK_MS: u32 = 200;
+/// Milliseconds between the groups of flashes
+const PAUSE_MS: u32 = 1400;
+
+impl FaultClass {
+    pub const fn name(self) -> &'static str {
+        match self {
+            FaultClass::Panic => "panic",
+            FaultClass::MemManage => "memory management fault",
+            FaultClass::BusFault => "bus fault",
+            FaultClass::UsageFault => "usage fault",
+            FaultClass::HardFault => "hard fault",
+        }
+    }
+
+    /// How many times the LED flashes before each pause. A single flash is left for boards that
+    /// are fine
+    pub const fn blinks(self) -> u32 {
+        self as u32 + 1
+    }
+
+    /// Whether the LED is on for `step` of the blink code and for how many milliseconds. Steps
+    /// carry on counting up, the pattern repeats
+    pub fn blink(self, step: u32) -> (bool, u32) {
+        let steps = 2 * self.blinks();
+        match step % steps {
+            step if step == steps - 1 => (false, PAUSE_MS),
+            step => (step % 2 == 0, BLINK_MS),
+        }
+    }
+}
+
+/// What the core stacked when the HardFault was taken, and the fault status registers that say
+/// why
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HardFaultRegisters {
+    pub r0: u32,
+    pub r1: u32,
+    pub r2: u32,
+    pub r3: u32,
+    pub r12: u32,
+    pub lr: u32,
+    pub pc: u32,
+    pub xpsr: u32,
+    /// Configurable Fault Status Register: MemManage, bus and usage fault bits
+    pub cfsr: u32,
+    pub hfsr: u32,
+    /// Only valid when the CFSR says so, like `bfar`
+    pub mmfar: u32,
+    pub bfar: u32,
+}
+
+impl HardFaultRegisters {
+    const WORDS: usize = 12;
+
+    pub fn class(&self) -> FaultClass {
+        if self.cfsr & 0xFF != 0 {
+            FaultClass::MemManage
+        } else if self.cfsr & 0xFF00 != 0 {
+            FaultClass::BusFault
+        } else if self.cfsr >> 16 != 0 {
+            FaultClass::UsageFault
+        } else {
+            FaultClass::HardFault
+        }
+    }
+
+    fn to_words(self) -> [u32; Self::WORDS] {
+        [
+            self.r0, self.r1, self.r2, self.r3, self.r12, self.lr, self.pc, self.xpsr, self.cfsr,
+            self.hfsr, self.mmfar, self.bfar,
+        ]
+    }
+
+    fn from_words(w: [u32; Self::WORDS]) -> Self {
+        Self {
+            r0: w[0],
+            r1: w[1],
+            r2: w[2],
+            r3: w[3],
+            r12: w[4],
+            lr: w[5],
+            pc: w[6],
+            xpsr: w[7],
+            cfsr: w[8],
+            hfsr: w[9],
+            mmfar: w[10],
+            bfar: w[11],
+        }
+    }
+}
+
+/// The start of a panic message, written with [`fmt::Write`]. Whatever does not fit is left off,
+/// so writing never fails
+#[derive(Clone, Copy)]
+pub struct PanicText {
+    bytes: [u8; PANIC_TEXT],
+    len: usize,
+    /// A character did not fit, so nothing more is written
+    full: bool,
+}
+
+impl PanicText {
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; PANIC_TEXT],
+            len: 0,
+            full: false,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only whole characters are written
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap()
+    }
+}
+
+impl Default for PanicText {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Write for PanicText {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.full || self.len + c.len_utf8() > PANIC_TEXT {
+                self.full = true;
+                break;
+            }
+            self.len += c.encode_utf8(&mut self.bytes[self.len..]).len();
+        }
+        Ok(())
+    }
+}
+
+impl PartialEq for PanicText {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for PanicText {}
+
+impl fmt::Debug for PanicText {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// Why the firmware last went down
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    Panic(PanicText),
+    HardFault(HardFaultRegisters),
+}
+
+impl Fault {
+    pub fn class(&self) -> FaultClass {
+        match self {
+            Fault::Panic(_) => FaultClass::Panic,
+            Fault::HardFault(registers) => registers.class(),
+        }
+    }
+
+    /// Writes the fault for the host, returning how many bytes it took
+    pub fn to_bytes(&self, out: &mut [u8; MAX_FAULT_BYTES]) -> usize {
+        out[0] = self.class() as u8;
+        match self {
+            Fault::Panic(text) => {
+                let text = text.as_str().as_bytes();
+                out[1..1 + text.len()].copy_from_slice(text);
+                1 + text.len()
+            }
+            Fault::HardFault(registers) => {
+                let words = registers.to_words();
+                for (out, word) in out[1..].chunks_exact_mut(4).zip(words) {
+                    out.copy_from_slice(&word.to_le_bytes());
+                }
+                1 + 4 * words.len()
+            }
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (&class, rest) = bytes.split_first()?;
+        if class == FaultClass::Panic as u8 {
+            let text = core::str::from_utf8(rest).ok()?;
+            if text.len() > PANIC_TEXT {
+                return None;
+            }
+            let mut panic = PanicText::new();
+            fmt::Write::write_str(&mut panic, text).ok()?;
+            return Some(Fault::Panic(panic));
+        }
+        if !(FaultClass::MemManage as u8..=FaultClass::HardFault as u8).contains(&class)
+            || rest.len() != 4 * HardFaultRegisters::WORDS
+        {
+            return None;
+        }
+        let mut words = [0u32; HardFaultRegisters::WORDS];
+        for (word, bytes) in words.iter_mut().zip(rest.chunks_exact(4)) {
+            *word = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+        Some(Fault::HardFault(HardFaultRegisters::from_words(words)))
+    }
+
+    /// Writes the record into the RAM kept across resets: a magic number, the length, the bytes
+    /// from [`Fault::to_bytes`] and a checksum
+    pub fn store(&self, words: &mut [u32; FAULT_WORDS]) {
+        let mut bytes = [0u8; MAX_FAULT_BYTES];
+        let len = self.to_bytes(&mut bytes);
+        words[0] = FAULT_MAGIC;
+        words[1] = len as u32;
+        for (word, bytes) in words[2..].iter_mut().zip(bytes.chunks_exact(4)) {
+            *word = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+        words[FAULT_WORDS - 1] = checksum(&words[..FAULT_WORDS - 1]);
+    }
+
+    /// Reads back what [`Fault::store`] wrote, or `None` if the RAM holds anything else, as it
+    /// does after power on
+    pub fn load(words: &[u32; FAULT_WORDS]) -> Option<Self> {
+        let len = words[1] as usize;
+        if words[0] != FAULT_MAGIC
+            || len > MAX_FAULT_BYTES
+            || words[FAULT_WORDS - 1] != checksum(&words[..FAULT_WORDS - 1])
+        {
+            return None;
+        }
+        let mut bytes = [0u8; MAX_FAULT_BYTES];
+        for (bytes, word) in bytes.chunks_exact_mut(4).zip(&words[2..]) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        Self::from_bytes(&bytes[..len])
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::Panic(text) => write!(f, "{}: {}", self.class().name(), text.as_str()),
+            Fault::HardFault(r) => write!(
+                f,
+                "{} at pc {:#010x} lr {:#010x} xpsr {:#010x} r0 {:#010x} r1 {:#010x} \
+                 r2 {:#010x} r3 {:#010x} r12 {:#010x} cfsr {:#010x} hfsr {:#010x} \
+                 mmfar {:#010x} bfar {:#010x}",
+                self.class().name(),
+                r.pc,
+                r.lr,
+                r.xpsr,
+                r.r0,
+                r.r1,
+                r.r2,
+                r.r3,
+                r.r12,
+                r.cfsr,
+                r.hfsr,
+                r.mmfar,
+                r.bfar
+            ),
+        }
+    }
+}
+
+/// FNV-1a over the words
+pub(crate) fn checksum(words: &[u32]) -> u32 {
+    words.iter().fold(0x811C_9DC5, |hash, &word| {
+        (hash ^ word).wrapping_mul(0x0100_0193)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+
+    #[test]
+    fn panics_round_trip() {
+        let mut text = PanicText::new();
+        write!(text, "panicked at src/main.rs:{}:{}: ", 212, 14).unwrap();
+        assert_eq!(text.as_str(), "panicked at src/main.rs:212:14: ");
+        // Cut short before the character that does not fit, even if a shorter one would
+        write!(
+            text,
+            "{}ü and more",
+            "x".repeat(PANIC_TEXT - text.as_str().len() - 1)
+        )
+        .unwrap();
+        assert_eq!(text.as_str().len(), PANIC_TEXT - 1);
+        assert!(text.as_str().ends_with('x'));
+
+        let fault = Fault::Panic(text);
+        assert_eq!(fault.class(), FaultClass::Panic);
+        let mut bytes = [0u8; MAX_FAULT_BYTES];
+        let len = fault.to_bytes(&mut bytes);
+        assert_eq!(Fault::from_bytes(&bytes[..len]), Some(fault));
+        let mut words = [0u32; FAULT_WORDS];
+        fault.store(&mut words);
+        assert_eq!(Fault::load(&words), Some(fault));
+    }
+
+    #[test]
+    fn hard_faults_round_trip() {
+        let registers = HardFaultRegisters {
+            pc: 0x0800_1234,
+            lr: 0x0800_0F01,
+            // PRECISERR and BFARVALID
+            cfsr: 0x8200,
+            hfsr: 1 << 30,
+            bfar: 0x6000_0000,
+            ..HardFaultRegisters::default()
+        };
+        let fault = Fault::HardFault(registers);
+        assert_eq!(fault.class(), FaultClass::BusFault);
+        let mut bytes = [0u8; MAX_FAULT_BYTES];
+        let len = fault.to_bytes(&mut bytes);
+        assert_eq!(len, 49);
+        assert_eq!(Fault::from_bytes(&bytes[..len]), Some(fault));
+        assert_eq!(Fault::from_bytes(&bytes[..len - 1]), None);
+        let mut words = [0u32; FAULT_WORDS];
+        fault.store(&mut words);
+        assert_eq!(Fault::load(&words), Some(fault));
+
+        for (cfsr, class) in [
+            (0x82, FaultClass::MemManage),
+            (1 << 25, FaultClass::UsageFault),
+            (0, FaultClass::HardFault),
+        ] {
+            let registers = HardFaultRegisters { cfsr, ..registers };
+            assert_eq!(registers.class(), class);
+        }
+        assert!(fault.to_string().starts_with("bus fault at pc 0x08001234"));
+    }
+
+    #[test]
+    fn only_records_are_loaded() {
+        // What RAM might hold at power on
+        let mut state = 0x1234_5678u32;
+        let random = core::array::from_fn(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        });
+        assert_eq!(Fault::load(&random), None);
+        assert_eq!(Fault::load(&[0; FAULT_WORDS]), None);
+
+        let mut words = [0u32; FAULT_WORDS];
+        Fault::HardFault(HardFaultRegisters::default()).store(&mut words);
+        words[5] ^= 1;
+        assert_eq!(Fault::load(&words), None);
+    }
+
+    #[test]
+    fn blink_codes() {
+        let on = |class: FaultClass| {
+            (0..2 * class.blinks())
+                .filter(|&step| class.blink(step).0)
+                .count()
+        };
+        assert_eq!(on(FaultClass::Panic), 2);
+        assert_eq!(on(FaultClass::HardFault), 6);
+        assert_eq!(FaultClass::UsageFault.blink(9), (false, PAUSE_MS));
+        assert_eq!(FaultClass::UsageFault.blink(10), (true, BLINK_MS));
+    }
+}
@@ -0,0 +1,355 @@
+//! Frequency hopping, so that one busy channel or a jammer cannot take the link down and an
+//! observer does not know where to listen.
+//!
+//! Both ends derive the same [`HOP_CHANNELS`] channels from the auth key, and the channel for
+//! each block from its sequence number. The node hops on every transmission and listens for the
+//! host on the channel of the next sequence number. A [`HopFollower`] on the receiver predicts
+//! that channel from the last block it heard, and parks on one channel until it hears the node
+//! again when it loses track. Slots that keep failing are blacklisted by the node, which tells
+//! the host with a [`Control::Notify`] before it stops using them. The follower also keeps track
+//! of the data rate the node announces with auto rate on, see [`crate::RateController`].
+
+use crate::control::siphash24;
+use crate::{
+    Control, DataRate, Key, MainCipher, NodeConfig, PadRegion, Setting, VarBlock, AUTH_KEY_SIZE,
+    MAX_CHANNEL, RATE_LOST_LIMIT, RATE_STEPS, TAG_CONTROL,
+};
+
+/// The number of channels in a hop set. A blacklist is a `u32` with a bit for each
+pub const HOP_CHANNELS: usize = 32;
+
+/// The most slots that can be blacklisted, so that the sequence stays hard to guess
+pub const MAX_BLACKLISTED: u32 = 24;
+
+/// The channels a link hops between and the order it visits them in
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HopSet {
+    channels: [u8; HOP_CHANNELS],
+    key: [u8; AUTH_KEY_SIZE],
+}
+
+impl HopSet {
+    pub fn new(auth_key: &[u8; AUTH_KEY_SIZE]) -> Self {
+        let mut set = Self {
+            channels: [0; HOP_CHANNELS],
+            key: *auth_key,
+        };
+        // The first few steps of a Fisher-Yates shuffle of every channel
+        let mut all: [u8; MAX_CHANNEL as usize + 1] = core::array::from_fn(|c| c as u8);
+        for i in 0..HOP_CHANNELS {
+            let left = (all.len() - i) as u64;
+            let j = i + (set.hash(b"set\0", i as u32) % left) as usize;
+            all.swap(i, j);
+            set.channels[i] = all[i];
+        }
+        set
+    }
+
+    /// Hash inputs are 8 bytes, control message MACs are 16, so the two never collide
+    fn hash(&self, domain: &[u8; 4], n: u32) -> u64 {
+        let mut input = [0u8; 8];
+        input[..4].copy_from_slice(domain);
+        input[4..].copy_from_slice(&n.to_le_bytes());
+        siphash24(&self.key, &input)
+    }
+
+    pub fn channel(&self, slot: usize) -> u8 {
+        self.channels[slot]
+    }
+
+    /// The slot to use for the block with this sequence number
+    pub fn slot(&self, sequence: u32, blacklist: u32) -> usize {
+        let hash = self.hash(b"hop\0", sequence);
+        let slot = (hash % HOP_CHANNELS as u64) as usize;
+        let allowed = !blacklist;
+        if allowed & 1 << slot != 0 || allowed == 0 {
+            return slot;
+        }
+        // Only sequences that landed on a blacklisted slot move, so two ends that disagree about
+        // the blacklist still agree about most of the sequence
+        let n = (hash >> 32) % allowed.count_ones() as u64;
+        (0..HOP_CHANNELS)
+            .filter(|&s| allowed & 1 << s != 0)
+            .nth(n as usize)
+            .unwrap()
+    }
+
+    /// The channel for the block with this sequence number
+    pub fn hop(&self, sequence: u32, blacklist: u32) -> u8 {
+        self.channel(self.slot(sequence, blacklist))
+    }
+}
+
+/// Transmissions in a row that can fail on a slot before it is blacklisted
+const FAIL_LIMIT: u8 = 6;
+
+/// Decides which slots to blacklist from whether transmissions on them were acknowledged
+pub struct ChannelHealth {
+    failures: [u8; HOP_CHANNELS],
+    /// Set by every acknowledgement. A receiver that is switched off makes every slot fail, and
+    /// should not get them all blacklisted
+    armed: bool,
+}
+
+impl ChannelHealth {
+    pub fn new() -> Self {
+        Self {
+            failures: [0; HOP_CHANNELS],
+            armed: false,
+        }
+    }
+
+    /// Records a transmission on `slot`. Returns `blacklist` with the slot added once it has
+    /// failed too many times in a row
+    pub fn record(&mut self, slot: usize, acked: bool, blacklist: u32) -> Option<u32> {
+        if acked {
+            self.failures[slot] = 0;
+            self.armed = true;
+            return None;
+        }
+        self.failures[slot] = self.failures[slot].saturating_add(1);
+        if self.failures[slot] < FAIL_LIMIT
+            || !self.armed
+            || blacklist & 1 << slot != 0
+            || blacklist.count_ones() >= MAX_BLACKLISTED
+        {
+            return None;
+        }
+        self.failures[slot] = 0;
+        self.armed = false;
+        Some(blacklist | 1 << slot)
+    }
+}
+
+impl Default for ChannelHealth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Intervals that can go by without hearing the node before the receiver stops predicting
+const LOCK_MISSES: u64 = 8;
+
+/// How long the receiver stays on one channel while it waits to hear the node again
+const PARK_MS: u64 = 5000;
+
+/// Intervals that can go by without hearing the node before the receiver drops to the bottom
+/// rate step. The node gets there first, it sends again sooner than every interval
+const RATE_MISSES: u64 = RATE_LOST_LIMIT as u64 + 2;
+
+/// Tracks a hopping node from the receiver, deciding which channel to listen on
+pub struct HopFollower<'k, const KEY_BYTES: usize> {
+    cipher: MainCipher<'k, fn(u32) -> u32, KEY_BYTES>,
+    index_key: u32,
+    downlink: PadRegion,
+    auth_key: [u8; AUTH_KEY_SIZE],
+    set: HopSet,
+    blacklist: u32,
+    /// The sequence number of the last block heard and when it was heard
+    last: Option<(u32, u64)>,
+    /// The time between the node's transmissions, measured from the blocks heard
+    interval_ms: u64,
+    park: u32,
+    parked_ms: u64,
+    /// The data rate the node last announced or acknowledged
+    data_rate: Option<DataRate>,
+}
+
+impl<'k, const KEY_BYTES: usize> HopFollower<'k, KEY_BYTES> {
+    /// Follows node `node`. Blocks from other nodes say nothing about where it is
+    pub fn new(
+        key: &'k Key<KEY_BYTES>,
+        index_key: u32,
+        auth_key: [u8; AUTH_KEY_SIZE],
+        node: u8,
+    ) -> Self {
+        Self {
+            cipher: MainCipher::new(key, index_key),
+            index_key,
+            downlink: PadRegion::node_downlink(key.subkey_count::<u32, 7>(), node),
+            auth_key,
+            set: HopSet::new(&auth_key),
+            blacklist: 0,
+            last: None,
+            interval_ms: NodeConfig::DEFAULT.tx_interval_ms as u64,
+            park: 0,
+            parked_ms: 0,
+            data_rate: None,
+        }
+    }
+
+    /// The blacklist the node last announced
+    pub fn blacklist(&self) -> u32 {
+        self.blacklist
+    }
+
+    /// True if the node has been heard recently enough to predict where it is
+    pub fn locked(&self, now_ms: u64) -> bool {
+        matches!(self.missed(now_ms), Some(missed) if missed < LOCK_MISSES)
+    }
+
+    /// Intervals gone by without hearing the node
+    fn missed(&self, now_ms: u64) -> Option<u64> {
+        let (_, heard_ms) = self.last?;
+        let late = now_ms.saturating_sub(heard_ms + self.interval_ms / 2);
+        Some(late / self.interval_ms)
+    }
+
+    /// Handles an encrypted block from the node, heard at `now_ms`
+    pub fn heard(&mut self, block: &VarBlock, now_ms: u64) {
+        let index = block.index();
+        let sequence = match self.downlink.sequence_of(index, self.index_key) {
+            Some(sequence) => sequence,
+            None => return,
+        };
+        match self.last {
+            // The same block again because the acknowledgement was lost, or a block sent again
+            // by the node's ARQ. Sequence numbers only go up, so neither says where the node is
+            Some((last, _)) if last >= sequence => {}
+            Some((last, heard_ms)) => {
+                let (min, max) = NodeConfig::TX_INTERVAL_MS;
+                let sample = now_ms.saturating_sub(heard_ms) / (sequence - last) as u64;
+                let sample = sample.clamp(min as u64, max as u64);
+                self.interval_ms = (self.interval_ms + sample) / 2;
+                self.last = Some((sequence, now_ms));
+            }
+            None => self.last = Some((sequence, now_ms)),
+        }
+
+        if block.tag() != TAG_CONTROL {
+            return;
+        }
+        let mut block = copy(block);
+        block.do_cipher(&self.cipher);
+        match Control::open(index, block.block().data(), &self.auth_key) {
+            Ok(Control::Notify {
+                setting: Setting::HopBlacklist,
+                value,
+            })
+            | Ok(Control::Ack {
+                setting: Setting::HopBlacklist,
+                value,
+                ..
+            }) if value.count_ones() <= MAX_BLACKLISTED => self.blacklist = value,
+            Ok(Control::Notify {
+                setting: Setting::DataRate,
+                value,
+            })
+            | Ok(Control::Ack {
+                setting: Setting::DataRate,
+                value,
+                ..
+            }) => self.data_rate = DataRate::try_from(value).ok(),
+            _ => {}
+        }
+    }
+
+    /// The data rate to listen on at `now_ms` if `config` has auto rate on and the node has moved
+    /// off `config`'s
+    pub fn data_rate(&mut self, config: &NodeConfig, now_ms: u64) -> Option<DataRate> {
+        if !config.auto_rate {
+            return None;
+        }
+        if matches!(self.missed(now_ms), Some(missed) if missed >= RATE_MISSES) {
+            // The node falls back without saying so when it stops getting through
+            self.data_rate = Some(RATE_STEPS[0].0);
+        }
+        match self.data_rate {
+            Some(rate) if rate != config.data_rate => Some(rate),
+            _ => None,
+        }
+    }
+
+    /// The channel to listen on at `now_ms`, or `None` if `config` is not hopping
+    pub fn channel(&mut self, config: &NodeConfig, now_ms: u64) -> Option<u8> {
+        if !config.hopping {
+            return None;
+        }
+        match (self.last, self.missed(now_ms)) {
+            (Some((sequence, _)), Some(missed)) if missed < LOCK_MISSES => {
+                let next = sequence.wrapping_add(1).wrapping_add(missed as u32);
+                Some(self.set.hop(next, self.blacklist))
+            }
+            // Lost. Every channel comes up sooner or later, so wait on one for the node
+            _ => {
+                if now_ms >= self.parked_ms + PARK_MS {
+                    self.parked_ms = now_ms;
+                    self.park = self.park.wrapping_add(1);
+                }
+                Some(self.set.hop(self.park, self.blacklist))
+            }
+        }
+    }
+}
+
+fn copy(block: &VarBlock) -> VarBlock {
+    VarBlock::from_bytes(block.as_bytes()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUTH_KEY: [u8; AUTH_KEY_SIZE] = *b"hopping test key";
+
+    #[test]
+    fn hop_set() {
+        let set = HopSet::new(&AUTH_KEY);
+        let mut channels = set.channels;
+        channels.sort_unstable();
+        assert!(channels.windows(2).all(|w| w[0] < w[1]), "{:?}", channels);
+        assert!(channels.iter().all(|&c| c <= MAX_CHANNEL));
+        assert_ne!(set.channels, HopSet::new(b"some other key!!").channels);
+
+        // Every slot gets used about as often
+        let mut uses = [0u32; HOP_CHANNELS];
+        for sequence in 0..32_000 {
+            uses[set.slot(sequence, 0)] += 1;
+        }
+        assert!(uses.iter().all(|&n| (800..1200).contains(&n)), "{:?}", uses);
+
+        // Blacklisted slots are never used, and nothing else moves
+        let blacklist = 0b1011 << 10;
+        for sequence in 0..10_000 {
+            let slot = set.slot(sequence, blacklist);
+            assert_eq!(blacklist & 1 << slot, 0);
+            let before = set.slot(sequence, 0);
+            if blacklist & 1 << before == 0 {
+                assert_eq!(slot, before);
+            }
+        }
+    }
+
+    #[test]
+    fn channel_health() {
+        let mut health = ChannelHealth::new();
+        // Nothing is blacklisted before the link has worked at all
+        for _ in 0..20 {
+            assert_eq!(health.record(3, false, 0), None);
+        }
+        health.record(5, true, 0);
+        assert_eq!(health.record(3, false, 0), Some(1 << 3));
+        health.record(5, true, 1 << 3);
+        for _ in 1..FAIL_LIMIT {
+            assert_eq!(health.record(4, false, 1 << 3), None);
+        }
+        // A success elsewhere does not reset the count
+        health.record(5, true, 1 << 3);
+        assert_eq!(health.record(4, false, 1 << 3), Some(1 << 3 | 1 << 4));
+        // One slot at a time
+        for _ in 0..20 {
+            assert_eq!(health.record(7, false, 1 << 3 | 1 << 4), None);
+        }
+        health.record(5, true, 1 << 3 | 1 << 4);
+        assert_eq!(
+            health.record(7, false, 1 << 3 | 1 << 4),
+            Some(1 << 3 | 1 << 4 | 1 << 7)
+        );
+
+        let full = (1 << MAX_BLACKLISTED) - 1;
+        health.record(30, true, full);
+        for _ in 0..20 {
+            assert_eq!(health.record(31, false, full), None);
+        }
+    }
+}
@@ -1,8 +1,4 @@
 use core::mem::size_of;
-#[cfg(feature = "std")]
-use lazy_static::lazy_static;
-#[cfg(feature = "std")]
-use std::{collections::HashMap, sync::Mutex};
 
 /// A type that is safe to use as a word in a block or key
 ///
@@ -16,21 +12,11 @@ pub unsafe trait Word: Copy + From<u8> {}
 #[repr(align(4))]
 pub struct Key<const N: usize>([u8; N]);
 
-/// The symmetric key used for both encryption and decryption
-pub const KEY: Key<53280> = Key::new(*include_bytes!("../../private/key.bin"));
-
-#[cfg(feature = "std")]
-lazy_static! {
-    static ref FREQ: Mutex<HashMap<usize, usize>> = Mutex::new(HashMap::new());
-}
+/// The number of bytes in [`KEY`]
+pub const KEY_SIZE: usize = 53280;
 
-#[cfg(test)]
-pub fn print_freq() {
-    let lock = FREQ.lock().unwrap();
-    for (k, v) in lock.iter() {
-        println!("{}: {}", k, v);
-    }
-}
+/// The symmetric key used for both encryption and decryption
+pub const KEY: Key<KEY_SIZE> = Key::new(*include_bytes!("../../private/key.bin"));
 
 impl<const N: usize> Key<N> {
     /// Creates a new key by copying the data from `key` into self
@@ -42,6 +28,17 @@ impl<const N: usize> Key<N> {
         Self(key)
     }
 
+    /// Returns the raw bytes of this key
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Returns the number of distinct subkeys of `L` elements that [`Key::subkey`] can return.
+    /// Word offsets that are equal modulo this value produce the same subkey
+    pub fn subkey_count<T: Word, const L: usize>(&self) -> usize {
+        (N / size_of::<T>() + 1).saturating_sub(L)
+    }
+
     /// Returns a slice len `key_len` of this key based on word offset module the key length
     /// `L` is the number of elements returned
     pub fn subkey<T: Word, const L: usize>(&self, word_offset: usize) -> &[T; L] {
@@ -65,14 +62,6 @@ impl<const N: usize> Key<N> {
         // is aligned to 4 byte bounderies via #[reper(align(4))], the resulting pointer is aligned
         let ptr: *const T = self.0.as_ptr() as *const T;
 
-        #[cfg(feature = "std")]
-        {
-            dbg!(L, key_elements, max_index, offset, max_index);
-            let mut lock = FREQ.lock().unwrap();
-            let count = lock.entry(offset).or_insert_with(|| 0);
-            *count += 1;
-        }
-
         // SAFETY:
         // 1. Offset is in range by the calculation of `max_index` above
         // 2. At least `L` elements are readable by the `max_index` calculation
@@ -91,6 +80,13 @@ impl<const N: usize> Key<N> {
     }
 }
 
+/// SAFETY: u8 has no invalid bit patterns
+unsafe impl Word for u8 {}
+/// SAFETY: u16 has no invalid bit patterns
+unsafe impl Word for u16 {}
+/// SAFETY: u32 has no invalid bit patterns
+unsafe impl Word for u32 {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -110,7 +106,6 @@ mod tests {
     fn subkey() {
         const KEY_LEN: usize = 32;
         let key: [u8; KEY_LEN] = (0u8..KEY_LEN as u8)
-            .into_iter()
             .collect::<Vec<_>>()
             .as_slice()
             .try_into()
@@ -134,15 +129,10 @@ mod tests {
             let expected = [b, b + 1, b + 2, b + 3];
             assert_eq!(subkey[3], u32::from_ne_bytes(expected));
         }
+        assert_eq!(key.subkey_count::<u32, 4>(), 5);
+        assert_eq!(key.subkey_count::<u32, 1>(), 8);
         //Make sure this works for zero sized types
         let zst = key.subkey::<u32, 0>(0);
         assert!(zst.is_empty());
     }
 }
-
-/// SAFETY: u8 has no invalid bit patterns
-unsafe impl Word for u8 {}
-/// SAFETY: u16 has no invalid bit patterns
-unsafe impl Word for u16 {}
-/// SAFETY: u32 has no invalid bit patterns
-unsafe impl Word for u32 {}
@@ -1,5 +1,8 @@
 #![deny(unsafe_op_in_unsafe_fn)]
 #![cfg_attr(not(feature = "std"), no_std)]
+// The firmware is built with a nightly from before Rust 1.66, as it still needs the
+// `bench_black_box` feature. It has neither `div_ceil` nor `is_multiple_of`
+#![allow(clippy::manual_div_ceil, clippy::manual_is_multiple_of)]
 //! aaaa
 //!
 //! ```
@@ -37,10 +40,93 @@
 //! ```
 
 mod key;
-pub use key::{Key, Word, KEY};
+pub use key::{Key, Word, KEY, KEY_SIZE};
 
 mod algorithm;
 pub use algorithm::{GenericCipher, GenericCipherBlock, Index};
 
 mod alg1;
-pub use alg1::{CipherBlock, MainCipher, IndexedBlock, Tag, Tag31_1};
+pub use alg1::{CipherBlock, MainCipher, IndexedBlock, Tag, Tag31_1, VarBlock, TAG_SIZE};
+
+mod link;
+pub use link::{
+    encode_frame, Frame, FrameDecoder, FrameError, FrameKind, FrameQueue, ReceiverCounters,
+    MAX_ENCODED_FRAME, MAX_FRAME_PAYLOAD,
+};
+
+mod pad;
+pub use pad::{PadPosition, PadRegion};
+
+mod nodes;
+pub use nodes::{host_address, node_address, ADDRESS_SIZE, MAX_NODES};
+
+mod message;
+pub use message::{
+    chunk_words, encode_chunks, MessageKind, Reassembler, CHUNK_PAYLOAD, MAX_CHUNKS, MAX_MESSAGE,
+    TAG_DATA,
+};
+
+mod control;
+pub use control::{
+    CameraConfig, Control, ControlError, DataRate, NodeConfig, PaLevel, ReceiverSetting, Rejection,
+    Setting, AUTH_KEY_SIZE, CONTROL_WORDS, MAX_CHANNEL, TAG_CONTROL,
+};
+
+mod replay;
+pub use replay::{ReplayCheck, ReplayWindow, REPLAY_WINDOW_SIZE};
+
+mod arq;
+pub use arq::{AckBitmap, ArqReceiver, ArqSender, ArqStats, ACK_SIZE, ARQ_WINDOW};
+
+mod hop;
+pub use hop::{ChannelHealth, HopFollower, HopSet, HOP_CHANNELS, MAX_BLACKLISTED};
+
+mod rate;
+pub use rate::{RateChange, RateController, RATE_LOST_LIMIT, RATE_STEPS, RATE_WINDOW};
+
+mod node;
+pub use node::{Node, HEARTBEAT};
+
+mod relay;
+pub use relay::{HostRequest, Relay, BLOCK_SIZE};
+
+mod survey;
+pub use survey::{
+    survey_chunk, Survey, MAX_SWEEPS, SAMPLES_PER_DWELL, SURVEY_CHANNELS, SURVEY_CHUNK,
+};
+
+mod fault;
+pub use fault::{
+    Fault, FaultClass, HardFaultRegisters, PanicText, FAULT_WORDS, MAX_FAULT_BYTES, PANIC_TEXT,
+};
+
+mod watchdog;
+pub use watchdog::{
+    CheckIns, ResetCounts, ResetReason, ResetReport, RESET_REASONS, RESET_REPORT_BYTES,
+    RESET_WORDS,
+};
+
+mod telemetry;
+pub use telemetry::{Telemetry, TELEMETRY_BYTES, VREFINT_MV};
+
+mod ota;
+pub use ota::{
+    boot, Boot, ImageHeader, OtaMessage, OtaReceiver, OtaState, OtaStatus, BOOT_LOG_PAGES,
+    MAX_OTA_MESSAGE, OTA_FRAGMENT,
+};
+
+mod persistent;
+pub use persistent::{Flash, PersistentError, PersistentIndex, PowerLoss, RamFlash};
+
+mod radio;
+pub use radio::Radio;
+
+#[cfg(feature = "std")]
+mod capture;
+#[cfg(feature = "std")]
+pub use capture::{CaptureHeader, CaptureReader, CaptureRecord, CaptureWriter};
+
+#[cfg(feature = "std")]
+mod sim;
+#[cfg(feature = "std")]
+pub use sim::{AirStats, ChannelModel, SimAir, SimRadio};
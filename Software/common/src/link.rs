@@ -0,0 +1,457 @@
+//! Framing for the USB-CDC link between a BluePill and the host.
+//!
+//! USB-CDC is a plain byte stream, so every message is wrapped in a frame:
+//! `[kind: u8][payload: 0..=MAX_FRAME_PAYLOAD bytes][crc16: u16 LE]`.
+//! The frame is then COBS encoded and terminated with a zero byte so that the reader can
+//! resynchronize after dropped or corrupted bytes by waiting for the next zero.
+
+/// The largest payload a single frame can carry
+pub const MAX_FRAME_PAYLOAD: usize = 64;
+
+const MAX_RAW_FRAME: usize = 1 + MAX_FRAME_PAYLOAD + 2;
+
+/// The largest number of bytes a frame can occupy on the wire, including the delimiter
+pub const MAX_ENCODED_FRAME: usize = MAX_RAW_FRAME + MAX_RAW_FRAME / 254 + 2;
+
+/// What the payload of a frame contains
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameKind {
+    /// A raw 32 byte [`crate::IndexedBlock`] exactly as it was sent over the air
+    Block = 1,
+    /// Human readable UTF-8 text
+    Text = 2,
+    /// The receiver's [`ReceiverCounters`]
+    Counters = 3,
+    /// A [`crate::ReceiverSetting`] for the receiver board itself. Sent by the host, and echoed
+    /// back by the receiver once it is applied
+    Radio = 4,
+    /// A 32 byte [`crate::IndexedBlock`] that the receiver already decrypted
+    Plain = 5,
+    /// Sent by the host to start a [`crate::Survey`], and by the receiver with the results
+    Survey = 6,
+    /// `[node][payload]`, up to 32 bytes the host wants sent to one node: a block, or a
+    /// [`crate::OtaMessage`]. Plain [`FrameKind::Block`] frames from the host go to node 0
+    Addressed = 7,
+    /// A [`crate::Fault`] the receiver recorded before its last reset, as
+    /// [`crate::Fault::to_bytes`]
+    Fault = 8,
+    /// A [`crate::ResetReport`] the receiver sends at boot, as [`crate::ResetReport::to_bytes`]
+    Reset = 9,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// The frame had more bytes than [`MAX_ENCODED_FRAME`] before its delimiter
+    TooLong,
+    /// The frame was not valid COBS or was too short to contain a kind and crc
+    BadEncoding,
+    /// The crc did not match the contents of the frame
+    BadCrc,
+    /// The kind byte is not one we know about
+    UnknownKind(u8),
+}
+
+/// A decoded frame that borrows its payload from the decoder
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame<'a> {
+    pub kind: FrameKind,
+    pub payload: &'a [u8],
+}
+
+impl TryFrom<u8> for FrameKind {
+    type Error = FrameError;
+
+    fn try_from(kind: u8) -> Result<Self, Self::Error> {
+        match kind {
+            1 => Ok(FrameKind::Block),
+            2 => Ok(FrameKind::Text),
+            3 => Ok(FrameKind::Counters),
+            4 => Ok(FrameKind::Radio),
+            5 => Ok(FrameKind::Plain),
+            6 => Ok(FrameKind::Survey),
+            7 => Ok(FrameKind::Addressed),
+            8 => Ok(FrameKind::Fault),
+            9 => Ok(FrameKind::Reset),
+            other => Err(FrameError::UnknownKind(other)),
+        }
+    }
+}
+
+/// Running totals kept by the receiver firmware and sent to the host every so often.
+/// These count things the host cannot see for itself because the payload never reached it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReceiverCounters {
+    /// Payloads read from the radio
+    pub received: u32,
+    /// Payloads too short or too long to be a block. The radio drops those with a bad CRC
+    /// itself, so they never get this far
+    pub malformed: u32,
+    /// Payloads thrown away because the host was not reading fast enough
+    pub dropped: u32,
+    /// Times the radio did not answer over SPI. The firmware carries on, and sets the radio up
+    /// again if it keeps failing
+    pub radio_errors: u32,
+}
+
+impl ReceiverCounters {
+    /// The size of the counters in a [`FrameKind::Counters`] payload
+    pub const SIZE: usize = 16;
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut out = [0u8; Self::SIZE];
+        out[0..4].copy_from_slice(&self.received.to_le_bytes());
+        out[4..8].copy_from_slice(&self.malformed.to_le_bytes());
+        out[8..12].copy_from_slice(&self.dropped.to_le_bytes());
+        out[12..16].copy_from_slice(&self.radio_errors.to_le_bytes());
+        out
+    }
+
+    /// Reads counters from a frame payload. Returns `None` if the payload is the wrong length
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; Self::SIZE] = bytes.try_into().ok()?;
+        let word = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        Some(Self {
+            received: word(0),
+            malformed: word(4),
+            dropped: word(8),
+            radio_errors: word(12),
+        })
+    }
+}
+
+/// CRC-16/CCITT-FALSE
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for &b in bytes {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn cobs_encode(input: &[u8], out: &mut [u8]) -> usize {
+    let mut code_index = 0;
+    let mut write = 1;
+    let mut code = 1u8;
+    for &b in input {
+        if b == 0 {
+            out[code_index] = code;
+            code_index = write;
+            write += 1;
+            code = 1;
+        } else {
+            out[write] = b;
+            write += 1;
+            code += 1;
+            if code == 0xFF {
+                out[code_index] = code;
+                code_index = write;
+                write += 1;
+                code = 1;
+            }
+        }
+    }
+    out[code_index] = code;
+    write
+}
+
+/// Decodes COBS data in place, returning the decoded length.
+/// The write position never passes the read position so this is safe to do in one buffer
+fn cobs_decode_in_place(buf: &mut [u8]) -> Result<usize, FrameError> {
+    let mut read = 0;
+    let mut write = 0;
+    while read < buf.len() {
+        let code = buf[read];
+        if code == 0 {
+            return Err(FrameError::BadEncoding);
+        }
+        read += 1;
+        for _ in 1..code {
+            if read >= buf.len() {
+                return Err(FrameError::BadEncoding);
+            }
+            buf[write] = buf[read];
+            write += 1;
+            read += 1;
+        }
+        if code != 0xFF && read != buf.len() {
+            buf[write] = 0;
+            write += 1;
+        }
+    }
+    Ok(write)
+}
+
+/// Encodes a frame into `out`, including the trailing zero delimiter.
+/// Returns the number of bytes written.
+///
+/// # Panics
+/// If `payload` is longer than [`MAX_FRAME_PAYLOAD`]
+pub fn encode_frame(kind: FrameKind, payload: &[u8], out: &mut [u8; MAX_ENCODED_FRAME]) -> usize {
+    assert!(
+        payload.len() <= MAX_FRAME_PAYLOAD,
+        "Frame payload of {} bytes is larger than the max of {}",
+        payload.len(),
+        MAX_FRAME_PAYLOAD
+    );
+    let mut raw = [0u8; MAX_RAW_FRAME];
+    raw[0] = kind as u8;
+    raw[1..1 + payload.len()].copy_from_slice(payload);
+    let crc = crc16(&raw[..1 + payload.len()]);
+    let raw_len = 1 + payload.len() + 2;
+    raw[1 + payload.len()..raw_len].copy_from_slice(&crc.to_le_bytes());
+
+    let len = cobs_encode(&raw[..raw_len], out);
+    out[len] = 0;
+    len + 1
+}
+
+fn decode_frame(buf: &mut [u8]) -> Result<Frame<'_>, FrameError> {
+    let len = cobs_decode_in_place(buf)?;
+    if len < 3 {
+        return Err(FrameError::BadEncoding);
+    }
+    let (body, crc) = buf[..len].split_at(len - 2);
+    if crc16(body) != u16::from_le_bytes([crc[0], crc[1]]) {
+        return Err(FrameError::BadCrc);
+    }
+    Ok(Frame {
+        kind: FrameKind::try_from(body[0])?,
+        payload: &body[1..],
+    })
+}
+
+/// Incrementally reassembles frames from a byte stream
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buf: [u8; MAX_ENCODED_FRAME],
+    len: usize,
+    overflow: bool,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self {
+            buf: [0; MAX_ENCODED_FRAME],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Feeds a single byte into the decoder.
+    /// Returns `Some` once `byte` completes a frame, containing either the frame or the reason
+    /// it was dropped
+    pub fn push(&mut self, byte: u8) -> Option<Result<Frame<'_>, FrameError>> {
+        if byte != 0 {
+            if self.len < self.buf.len() {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflow = true;
+            }
+            return None;
+        }
+
+        let len = core::mem::take(&mut self.len);
+        if core::mem::take(&mut self.overflow) {
+            return Some(Err(FrameError::TooLong));
+        }
+        if len == 0 {
+            // Back to back delimiters are allowed and are used by senders to force a resync
+            return None;
+        }
+        Some(decode_frame(&mut self.buf[..len]))
+    }
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A ring buffer of encoded frames waiting for the host to read them.
+/// Frames are added whole or not at all, so a full queue never leaves half a frame on the wire
+pub struct FrameQueue<const N: usize> {
+    buf: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> FrameQueue<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Adds `bytes` to the back of the queue. Returns false without adding anything if there is
+    /// not enough room
+    pub fn push(&mut self, bytes: &[u8]) -> bool {
+        if bytes.len() > N - self.len {
+            return false;
+        }
+        for &b in bytes {
+            self.buf[(self.head + self.len) % N] = b;
+            self.len += 1;
+        }
+        true
+    }
+
+    /// The bytes at the front of the queue that are next to each other in memory. This is all of
+    /// them unless the queue wraps around
+    pub fn front(&self) -> &[u8] {
+        let end = N.min(self.head + self.len);
+        &self.buf[self.head..end]
+    }
+
+    /// Removes `count` bytes from the front of the queue
+    ///
+    /// # Panics
+    /// If `count` is more than [`Self::front`] returned
+    pub fn consume(&mut self, count: usize) {
+        assert!(count <= self.front().len());
+        self.head = (self.head + count) % N;
+        self.len -= count;
+    }
+}
+
+impl<const N: usize> Default for FrameQueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(bytes: &[u8]) -> Vec<Result<(FrameKind, Vec<u8>), FrameError>> {
+        let mut decoder = FrameDecoder::new();
+        let mut frames = Vec::new();
+        for &b in bytes {
+            if let Some(frame) = decoder.push(b) {
+                frames.push(frame.map(|f| (f.kind, f.payload.to_vec())));
+            }
+        }
+        frames
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut out = [0u8; MAX_ENCODED_FRAME];
+        for len in 0..=MAX_FRAME_PAYLOAD {
+            // Include zeros so that COBS has something to do
+            let payload: Vec<u8> = (0..len).map(|i| (i % 7) as u8).collect();
+            let n = encode_frame(FrameKind::Block, &payload, &mut out);
+            assert_eq!(out[n - 1], 0);
+            assert!(!out[..n - 1].contains(&0));
+            assert_eq!(decode_all(&out[..n]), vec![Ok((FrameKind::Block, payload))]);
+        }
+    }
+
+    #[test]
+    fn long_runs_without_zeros() {
+        let mut out = [0u8; MAX_ENCODED_FRAME];
+        let payload = [0xAAu8; MAX_FRAME_PAYLOAD];
+        let n = encode_frame(FrameKind::Text, &payload, &mut out);
+        assert!(n <= MAX_ENCODED_FRAME);
+        assert_eq!(
+            decode_all(&out[..n]),
+            vec![Ok((FrameKind::Text, payload.to_vec()))]
+        );
+    }
+
+    #[test]
+    fn corruption_is_detected() {
+        let mut out = [0u8; MAX_ENCODED_FRAME];
+        let n = encode_frame(FrameKind::Text, b"hello", &mut out);
+        out[3] ^= 0x10;
+        assert_eq!(decode_all(&out[..n]), vec![Err(FrameError::BadCrc)]);
+    }
+
+    #[test]
+    fn resync_after_garbage() {
+        let mut out = [0u8; MAX_ENCODED_FRAME];
+        let mut stream = vec![0x55u8; MAX_ENCODED_FRAME * 2];
+        stream.push(0);
+        let n = encode_frame(FrameKind::Text, b"after", &mut out);
+        stream.extend_from_slice(&out[..n]);
+
+        assert_eq!(
+            decode_all(&stream),
+            vec![
+                Err(FrameError::TooLong),
+                Ok((FrameKind::Text, b"after".to_vec()))
+            ]
+        );
+    }
+
+    #[test]
+    fn counters_round_trip() {
+        let counters = ReceiverCounters {
+            received: 1_000_000,
+            malformed: 7,
+            dropped: 0xFFFF_FFFF,
+            radio_errors: 3,
+        };
+        assert_eq!(
+            ReceiverCounters::from_bytes(&counters.to_bytes()),
+            Some(counters)
+        );
+        assert_eq!(ReceiverCounters::from_bytes(&[0; 15]), None);
+    }
+
+    #[test]
+    fn queue_keeps_frames_whole() {
+        let mut queue = FrameQueue::<10>::new();
+        assert!(queue.push(b"abcd"));
+        assert!(queue.push(b"efgh"));
+        // Only two bytes of room left
+        assert!(!queue.push(b"ijk"));
+        assert_eq!(queue.front(), b"abcdefgh");
+        queue.consume(6);
+
+        // Wraps around the end of the buffer
+        assert!(queue.push(b"ijklmn"));
+        assert_eq!(queue.len(), 8);
+        let mut out = Vec::new();
+        while !queue.is_empty() {
+            let n = queue.front().len().min(3);
+            out.extend_from_slice(&queue.front()[..n]);
+            queue.consume(n);
+        }
+        assert_eq!(out, b"ghijklmn");
+    }
+
+    #[test]
+    fn unknown_kind() {
+        let mut raw = [0xEEu8, 0, 0];
+        let crc = crc16(&raw[..1]).to_le_bytes();
+        raw[1..].copy_from_slice(&crc);
+        let mut out = [0u8; MAX_ENCODED_FRAME];
+        let n = cobs_encode(&raw, &mut out);
+        out[n] = 0;
+        assert_eq!(
+            decode_all(&out[..n + 1]),
+            vec![Err(FrameError::UnknownKind(0xEE))]
+        );
+    }
+}
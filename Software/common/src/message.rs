@@ -0,0 +1,256 @@
+//! Messages longer than one block, split into chunks that each fit in the 28 data bytes of an
+//! [`crate::IndexedBlock`].
+//!
+//! A message is sent as its [`MessageKind`] byte followed by its contents. That stream is cut
+//! into chunks of up to [`CHUNK_PAYLOAD`] bytes, each behind a header byte: bits 0-4 hold the
+//! number of bytes in the chunk, bit 5 marks the first chunk and bit 6 the last one. Chunks of a
+//! message use consecutive sequence numbers so that a lost chunk is noticed. A header of zero is
+//! not a chunk, which is what a heartbeat looks like.
+
+/// The tag bit value for blocks that carry data
+pub const TAG_DATA: usize = 0;
+
+/// The most bytes a single chunk can carry
+pub const CHUNK_PAYLOAD: usize = 27;
+
+/// The longest message nodes are built to receive, not counting the kind byte
+pub const MAX_MESSAGE: usize = 127;
+
+/// The most chunks a message of [`MAX_MESSAGE`] bytes is split into
+pub const MAX_CHUNKS: usize = (MAX_MESSAGE + CHUNK_PAYLOAD) / CHUNK_PAYLOAD;
+
+const LEN_MASK: u8 = 0x1F;
+const FIRST_BIT: u8 = 0x20;
+const LAST_BIT: u8 = 0x40;
+const RESERVED_BIT: u8 = 0x80;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageKind {
+    /// UTF-8 text, as typed in chat mode
+    Text = 1,
+    /// A [`crate::Fault`] the node recorded before its last reset, as [`crate::Fault::to_bytes`]
+    Fault = 2,
+    /// A [`crate::ResetReport`] the node sends at boot, as [`crate::ResetReport::to_bytes`]
+    Reset = 3,
+    /// A [`crate::Telemetry`] reading from the node, as [`crate::Telemetry::to_bytes`]
+    Telemetry = 4,
+    /// A firmware update to a node or its progress, as [`crate::OtaMessage::seal`]
+    Ota = 5,
+}
+
+impl TryFrom<u8> for MessageKind {
+    type Error = u8;
+
+    fn try_from(kind: u8) -> Result<Self, Self::Error> {
+        match kind {
+            1 => Ok(MessageKind::Text),
+            2 => Ok(MessageKind::Fault),
+            3 => Ok(MessageKind::Reset),
+            4 => Ok(MessageKind::Telemetry),
+            5 => Ok(MessageKind::Ota),
+            other => Err(other),
+        }
+    }
+}
+
+pub(crate) fn to_words(bytes: &[u8; 28]) -> [u32; 7] {
+    let mut words = [0u32; 7];
+    for (word, bytes) in words.iter_mut().zip(bytes.chunks_exact(4)) {
+        *word = u32::from_le_bytes(bytes.try_into().unwrap());
+    }
+    words
+}
+
+pub(crate) fn to_bytes(words: &[u32; 7]) -> [u8; 28] {
+    let mut bytes = [0u8; 28];
+    for (bytes, word) in bytes.chunks_exact_mut(4).zip(words) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    bytes
+}
+
+/// Splits a message into the data words of consecutive blocks
+pub fn encode_chunks(kind: MessageKind, message: &[u8]) -> impl Iterator<Item = [u32; 7]> + '_ {
+    let total = 1 + message.len();
+    let count = (total + CHUNK_PAYLOAD - 1) / CHUNK_PAYLOAD;
+    (0..count).map(move |i| {
+        let start = i * CHUNK_PAYLOAD;
+        let end = total.min(start + CHUNK_PAYLOAD);
+        let mut bytes = [0u8; 28];
+        bytes[0] = (end - start) as u8;
+        if i == 0 {
+            bytes[0] |= FIRST_BIT;
+        }
+        if i + 1 == count {
+            bytes[0] |= LAST_BIT;
+        }
+        for (dst, pos) in bytes[1..].iter_mut().zip(start..end) {
+            *dst = match pos {
+                0 => kind as u8,
+                pos => message[pos - 1],
+            };
+        }
+        to_words(&bytes)
+    })
+}
+
+/// The data words a block has to send for the chunk in `data`: its header and bytes. None for a
+/// heartbeat
+pub fn chunk_words(data: &[u32; 7]) -> usize {
+    let header = data[0].to_le_bytes()[0];
+    if header == 0 {
+        return 0;
+    }
+    (1 + (header & LEN_MASK) as usize + 3) / 4
+}
+
+/// Collects chunks back into messages of up to `N` bytes, counting the kind byte
+pub struct Reassembler<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    /// The sequence number the next chunk of the current message should have, if a message is
+    /// in progress
+    next: Option<u32>,
+    overflow: bool,
+}
+
+impl<const N: usize> Reassembler<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            next: None,
+            overflow: false,
+        }
+    }
+
+    /// Adds the data of the block with sequence number `sequence`.
+    /// Returns the message once its last chunk arrives. A message with a missing chunk, an
+    /// unknown kind, or more than `N` bytes is dropped
+    pub fn push(&mut self, sequence: u32, data: &[u32; 7]) -> Option<(MessageKind, &[u8])> {
+        let bytes = to_bytes(data);
+        let header = bytes[0];
+        let len = (header & LEN_MASK) as usize;
+        if len == 0 || len > CHUNK_PAYLOAD || header & RESERVED_BIT != 0 {
+            return None;
+        }
+
+        if header & FIRST_BIT != 0 {
+            self.len = 0;
+            self.overflow = false;
+        } else if self.next != Some(sequence) {
+            // The start of this message, or a chunk in the middle of it, was lost
+            self.next = None;
+            return None;
+        }
+        self.next = Some(sequence.wrapping_add(1));
+
+        match self.buf.get_mut(self.len..self.len + len) {
+            Some(dst) => {
+                dst.copy_from_slice(&bytes[1..1 + len]);
+                self.len += len;
+            }
+            None => self.overflow = true,
+        }
+
+        if header & LAST_BIT == 0 {
+            return None;
+        }
+        self.next = None;
+        let len = core::mem::take(&mut self.len);
+        if core::mem::take(&mut self.overflow) {
+            return None;
+        }
+        let kind = MessageKind::try_from(self.buf[0]).ok()?;
+        Some((kind, &self.buf[1..len]))
+    }
+}
+
+impl<const N: usize> Default for Reassembler<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(reassembler: &mut Reassembler<128>, first: u32, message: &[u8]) -> Vec<Vec<u8>> {
+        encode_chunks(MessageKind::Text, message)
+            .enumerate()
+            .filter_map(|(i, mut data)| {
+                // Only the words a block sends get through
+                let words = chunk_words(&data);
+                data[words..].fill(0);
+                reassembler
+                    .push(first + i as u32, &data)
+                    .map(|(_, m)| m.to_vec())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut reassembler = Reassembler::<128>::new();
+        for len in [0, 1, 26, 27, 28, 54, 55, 100] {
+            let message: Vec<u8> = (0..len as u8).collect();
+            assert_eq!(send(&mut reassembler, len * 10, &message), vec![message]);
+        }
+    }
+
+    #[test]
+    fn words_sent() {
+        let words = |len| -> Vec<_> {
+            let message = vec![1; len];
+            encode_chunks(MessageKind::Text, &message)
+                .map(|chunk| chunk_words(&chunk))
+                .collect()
+        };
+        // The header and kind bytes, then the text
+        assert_eq!(words(2), [1]);
+        assert_eq!(words(3), [2]);
+        assert_eq!(words(30), [7, 2]);
+        assert_eq!(chunk_words(&[0; 7]), 0);
+    }
+
+    #[test]
+    fn test_pattern_is_ignored() {
+        let mut reassembler = Reassembler::<128>::new();
+        assert_eq!(reassembler.push(0, &[0, 1, 2, 3, 4, 5, 6]), None);
+    }
+
+    #[test]
+    fn missing_chunk_drops_message() {
+        let mut reassembler = Reassembler::<128>::new();
+        let chunks: Vec<_> = encode_chunks(MessageKind::Text, &[b'a'; 60]).collect();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(reassembler.push(0, &chunks[0]), None);
+        assert_eq!(reassembler.push(2, &chunks[2]), None);
+        // Or the first chunk
+        assert_eq!(reassembler.push(11, &chunks[1]), None);
+        assert_eq!(reassembler.push(12, &chunks[2]), None);
+        // The next message is not affected
+        assert_eq!(send(&mut reassembler, 3, b"ok"), vec![b"ok".to_vec()]);
+    }
+
+    #[test]
+    fn max_message_fits() {
+        let message = [b'm'; MAX_MESSAGE];
+        assert_eq!(encode_chunks(MessageKind::Text, &message).count(), MAX_CHUNKS);
+        let mut reassembler = Reassembler::<{ MAX_MESSAGE + 1 }>::new();
+        let mut received = None;
+        for (i, data) in encode_chunks(MessageKind::Text, &message).enumerate() {
+            received = reassembler.push(i as u32, &data).map(|(_, m)| m.to_vec());
+        }
+        assert_eq!(received.as_deref(), Some(&message[..]));
+    }
+
+    #[test]
+    fn too_long_is_dropped() {
+        let mut reassembler = Reassembler::<128>::new();
+        assert!(send(&mut reassembler, 0, &[b'x'; 128]).is_empty());
+        assert_eq!(send(&mut reassembler, 10, b"fits"), vec![b"fits".to_vec()]);
+    }
+}
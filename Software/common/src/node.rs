@@ -0,0 +1,786 @@
+//! What a node does with the blocks it sends and receives, kept apart from the radio so that it
+//! can be tested on the host.
+
+use crate::{
+    chunk_words, encode_chunks, AckBitmap, ArqSender, ArqStats, ChannelHealth, Control, Fault,
+    HopSet, IndexedBlock, Key, MainCipher, MessageKind, NodeConfig, OtaMessage, OtaStatus,
+    PadPosition, PadRegion, PersistentError, Radio, RateChange, RateController, Reassembler,
+    ReplayCheck, ReplayWindow, ResetReport, Setting, Tag, Telemetry, VarBlock, AUTH_KEY_SIZE,
+    BLOCK_SIZE, CONTROL_WORDS, MAX_CHUNKS, MAX_FAULT_BYTES, MAX_MESSAGE, MAX_OTA_MESSAGE,
+    TAG_CONTROL, TAG_DATA,
+};
+
+/// The data a node sends when it has nothing else to say. It is not a chunk, so it takes no data
+/// words and only the tag goes on the air
+pub const HEARTBEAT: [u32; 7] = [0; 7];
+
+/// While hopping the blacklist is sent once every this many blocks, for receivers that missed it
+/// or restarted
+const ANNOUNCE_EVERY: u32 = 64;
+
+/// A block waiting to be sent again
+struct Unsent {
+    block: VarBlock,
+    sequence: u32,
+    /// It carries a control message, so the settings change once it gets through
+    control: bool,
+}
+
+pub struct Node<'k, const KEY_BYTES: usize> {
+    id: u8,
+    cipher: MainCipher<'k, fn(u32) -> u32, KEY_BYTES>,
+    index_key: u32,
+    auth_key: [u8; AUTH_KEY_SIZE],
+    /// We send with pads from our share of the downlink region, the host sends with pads from our
+    /// share of the uplink region
+    downlink: PadRegion,
+    uplink: PadRegion,
+    /// Data blocks are not authenticated, so anyone can move this one
+    replay: ReplayWindow,
+    /// Only moved by control blocks that passed their MAC, so that forged data blocks with a
+    /// far off index cannot make the host's settings look old
+    control_replay: ReplayWindow,
+    messages: Reassembler<{ MAX_MESSAGE + 1 }>,
+    /// The reply to the last message from the host. Its chunks are sent instead of heartbeats
+    /// until they run out
+    outbox: [[u32; 7]; MAX_CHUNKS],
+    outbox_len: usize,
+    outbox_pos: usize,
+    /// The answer to the last control message, sent before anything else
+    reply: Option<Control>,
+    /// New blocks made so far, for repeating the blacklist now and then
+    blocks: u32,
+    /// Our pads have run out, see [`Node::exhausted`]
+    exhausted: bool,
+    config: NodeConfig,
+    /// The radio settings in use, which lag `config` by a transmission
+    applied: NodeConfig,
+    /// What the radio was last configured with. Differs from `applied` in the channel while
+    /// hopping
+    tuned: NodeConfig,
+    /// A block the host did not acknowledge, sent again before anything new
+    unsent: Option<Unsent>,
+    hops: HopSet,
+    health: ChannelHealth,
+    arq: ArqSender,
+    rate: RateController,
+    /// Milliseconds since the last battery measurement
+    since_telemetry_ms: u32,
+    /// The last firmware update message from the host, until the firmware takes it
+    ota: [u8; MAX_MESSAGE],
+    ota_len: Option<usize>,
+}
+
+impl<'k, const KEY_BYTES: usize> Node<'k, KEY_BYTES> {
+    /// `node` is the node's id, which picks its share of the pads. It must be below
+    /// [`crate::MAX_NODES`] and different from every other node's
+    pub fn new(
+        key: &'k Key<KEY_BYTES>,
+        index_key: u32,
+        auth_key: [u8; AUTH_KEY_SIZE],
+        node: u8,
+    ) -> Self {
+        let offsets = key.subkey_count::<u32, 7>();
+        Self {
+            id: node,
+            cipher: MainCipher::new(key, index_key),
+            index_key,
+            auth_key,
+            downlink: PadRegion::node_downlink(offsets, node),
+            uplink: PadRegion::node_uplink(offsets, node),
+            replay: ReplayWindow::new(),
+            control_replay: ReplayWindow::new(),
+            messages: Reassembler::new(),
+            outbox: [[0; 7]; MAX_CHUNKS],
+            outbox_len: 0,
+            outbox_pos: 0,
+            reply: None,
+            blocks: 0,
+            exhausted: false,
+            config: NodeConfig::DEFAULT,
+            applied: NodeConfig::DEFAULT,
+            tuned: NodeConfig::DEFAULT,
+            unsent: None,
+            hops: HopSet::new(&auth_key),
+            health: ChannelHealth::new(),
+            arq: ArqSender::new(),
+            rate: RateController::new(),
+            since_telemetry_ms: 0,
+            ota: [0; MAX_MESSAGE],
+            ota_len: None,
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// The settings the host asked for. Radio settings should be applied after the next
+    /// transmission, which carries the acknowledgement on the old ones
+    pub fn config(&self) -> &NodeConfig {
+        &self.config
+    }
+
+    /// For setting what the node starts with, such as the camera on nodes that have one
+    pub fn config_mut(&mut self) -> &mut NodeConfig {
+        &mut self.config
+    }
+
+    /// Starts on the radio settings in `config` rather than the defaults, such as the data rate
+    /// the firmware was built for. The receiver has to start on the same ones
+    pub fn start_on(&mut self, config: NodeConfig) {
+        self.config = config;
+        self.applied = config;
+    }
+
+    pub fn arq_stats(&self) -> ArqStats {
+        self.arq.stats()
+    }
+
+    /// Sends `fault` up in place of the heartbeats that follow, so the host hears why the node
+    /// last went down. Call it at boot with what the firmware found
+    pub fn report_fault(&mut self, fault: &Fault) {
+        let mut bytes = [0u8; MAX_FAULT_BYTES];
+        let len = fault.to_bytes(&mut bytes);
+        self.report(MessageKind::Fault, &bytes[..len]);
+    }
+
+    /// Sends `report` up in place of the heartbeats that follow. Call it at boot
+    pub fn report_reset(&mut self, report: &ResetReport) {
+        self.report(MessageKind::Reset, &report.to_bytes());
+    }
+
+    /// Sends `telemetry` up in place of the heartbeats that follow
+    pub fn report_telemetry(&mut self, telemetry: &Telemetry) {
+        self.report(MessageKind::Telemetry, &telemetry.to_bytes());
+    }
+
+    /// Counts `elapsed_ms` towards the next battery measurement, returning true when it is time
+    /// for one. Call it after every transmission with the time until the next
+    pub fn telemetry_due(&mut self, elapsed_ms: u32) -> bool {
+        let interval_ms = self.config.telemetry_s.saturating_mul(1000);
+        if interval_ms == 0 {
+            return false;
+        }
+        self.since_telemetry_ms = self.since_telemetry_ms.saturating_add(elapsed_ms);
+        if self.since_telemetry_ms < interval_ms {
+            return false;
+        }
+        self.since_telemetry_ms = 0;
+        true
+    }
+
+    /// Copies the firmware update message waiting for the firmware into `buf`, see
+    /// [`crate::OtaReceiver::receive`], and returns its length
+    pub fn take_ota(&mut self, buf: &mut [u8; MAX_MESSAGE]) -> Option<usize> {
+        let len = self.ota_len.take()?;
+        buf[..len].copy_from_slice(&self.ota[..len]);
+        Some(len)
+    }
+
+    /// Sends how far a firmware update got up to the host
+    pub fn report_ota(&mut self, status: &OtaStatus) {
+        let mut bytes = [0u8; MAX_OTA_MESSAGE];
+        let len = OtaMessage::Status(*status).seal(status.version, &self.auth_key, &mut bytes);
+        self.report(MessageKind::Ota, &bytes[..len]);
+    }
+
+    /// Queues a message behind the ones still waiting, or in place of them if it does not fit
+    fn report(&mut self, kind: MessageKind, message: &[u8]) {
+        let chunks = encode_chunks(kind, message).count();
+        if self.outbox_pos == self.outbox_len || self.outbox_len + chunks > MAX_CHUNKS {
+            self.outbox_len = 0;
+            self.outbox_pos = 0;
+        }
+        for data in encode_chunks(kind, message) {
+            self.outbox[self.outbox_len] = data;
+            self.outbox_len += 1;
+        }
+    }
+
+    /// True if there is something other than a heartbeat to send, or a block to send again
+    pub fn has_pending(&self) -> bool {
+        self.reply.is_some() || self.outbox_pos < self.outbox_len || self.unsent.is_some()
+    }
+
+    /// True once [`Node::transmit`] has run out of pads. The node sends nothing more, as that
+    /// would use a pad again, until it is given a new key
+    pub fn exhausted(&self) -> bool {
+        self.exhausted
+    }
+
+    /// Returns the next block to transmit, already encrypted. `position` is given the number of
+    /// data words the block sends, and returns where in our pads to send it. It must never have
+    /// been handed out before, even before a reboot. On a node it comes from a
+    /// [`crate::PersistentIndex`]
+    ///
+    /// # Panics
+    ///
+    /// If the position is past the end of our pads
+    pub fn next_block(&mut self, position: impl FnOnce(usize) -> PadPosition) -> VarBlock {
+        let block = self.block(|words| Ok::<_, PersistentError<()>>(position(words)));
+        block.expect("the pads have run out").0
+    }
+
+    /// Returns the block, its sequence number and whether it carries a control message. `None`,
+    /// and exhausted from then on, if `position` has none left or gives one past our pads. A
+    /// flash error from `position` only gives `None`: the same data goes out on the next call
+    fn block<E>(
+        &mut self,
+        position: impl FnOnce(usize) -> Result<PadPosition, PersistentError<E>>,
+    ) -> Option<(VarBlock, u32, bool)> {
+        let reply = self.reply.or_else(|| self.announcement());
+        let from_outbox = reply.is_none() && self.outbox_pos < self.outbox_len;
+        let data = if from_outbox {
+            self.outbox[self.outbox_pos]
+        } else {
+            HEARTBEAT
+        };
+        let words = match reply {
+            Some(_) => CONTROL_WORDS,
+            None => chunk_words(&data),
+        };
+
+        let position = match position(words) {
+            Ok(position) if self.downlink.fits(position, words) => position,
+            Err(PersistentError::Flash(_)) => return None,
+            _ => {
+                self.exhausted = true;
+                return None;
+            }
+        };
+        self.reply = None;
+        self.outbox_pos += from_outbox as usize;
+        self.blocks = self.blocks.wrapping_add(1);
+        let index = self.downlink.index_for(position, self.index_key);
+        let mut block = IndexedBlock::new();
+        block.tag().set_index(index);
+        if let Some(reply) = reply {
+            *block.data_mut() = reply.seal(index, &self.auth_key);
+            block.tag().set_tag(TAG_CONTROL);
+        } else {
+            *block.data_mut() = data;
+            block.tag().set_tag(TAG_DATA);
+        }
+        let mut block = VarBlock::new(block, words);
+        block.do_cipher(&self.cipher);
+        Some((block, position.sequence, reply.is_some()))
+    }
+
+    /// Tells the receiver about a data rate to move to, or the host about a blacklist it has not
+    /// heard yet. The blacklist is repeated now and then
+    fn announcement(&self) -> Option<Control> {
+        if self.config.data_rate != self.applied.data_rate && self.applied.auto_rate {
+            return Some(Control::Notify {
+                setting: Setting::DataRate,
+                value: self.config.data_rate as u32,
+            });
+        }
+        let blacklist = self.config.hop_blacklist;
+        let new = blacklist != self.applied.hop_blacklist;
+        let repeat = blacklist != 0 && self.blocks % ANNOUNCE_EVERY == 0;
+        if !self.config.hopping || !(new || repeat) {
+            return None;
+        }
+        Some(Control::Notify {
+            setting: Setting::HopBlacklist,
+            value: blacklist,
+        })
+    }
+
+    /// Handles a payload received from the host: an encrypted block, an ack from the receiver or
+    /// a firmware update message
+    pub fn receive(&mut self, payload: &[u8]) {
+        if let Some(ack) = AckBitmap::open(payload, &self.auth_key) {
+            if ack.node == self.id {
+                self.arq.acked(ack);
+            }
+            return;
+        }
+        let mut block = match VarBlock::from_bytes(payload) {
+            Some(block) => block,
+            None => return self.keep_ota(payload),
+        };
+        let index = block.index();
+        let sequence = match self.uplink.sequence_of(index, self.index_key) {
+            Some(sequence) => sequence,
+            // Not encrypted by the host
+            None => return self.keep_ota(payload),
+        };
+        block.do_cipher(&self.cipher);
+        let (tag, block) = (block.tag(), block.into_block());
+
+        if tag == TAG_CONTROL {
+            let control = match Control::open(index, block.data(), &self.auth_key) {
+                Ok(control) => control,
+                Err(_) => return,
+            };
+            if self.control_replay.check(sequence) == ReplayCheck::Fresh {
+                self.reply = self.config.handle(sequence, control);
+            }
+            return;
+        }
+
+        if self.replay.check(sequence) != ReplayCheck::Fresh {
+            return;
+        }
+        if let Some((MessageKind::Text, text)) = self.messages.push(sequence, block.data()) {
+            // Echo the message back so the host can see the round trip
+            self.outbox_len = 0;
+            self.outbox_pos = 0;
+            for data in encode_chunks(MessageKind::Text, text) {
+                self.outbox[self.outbox_len] = data;
+                self.outbox_len += 1;
+            }
+        }
+    }
+
+    /// Keeps a payload that is not one of the host's blocks for the firmware, as it may be a
+    /// firmware update message. [`crate::OtaReceiver::receive`] checks its MAC
+    fn keep_ota(&mut self, payload: &[u8]) {
+        if payload.len() <= MAX_OTA_MESSAGE {
+            self.ota[..payload.len()].copy_from_slice(payload);
+            self.ota_len = Some(payload.len());
+        }
+    }
+
+    /// Sends the next block over `radio`, or the last one again if it was not acknowledged.
+    /// `position` is only called when a new block is needed, see [`Node::next_block`]. Returns
+    /// false if the block has to be sent again. While hopping it is dropped instead, since the
+    /// receiver has moved on to the next channel by the time it could be.
+    ///
+    /// If `position` runs out, or gives a position past the end of our pads, nothing is sent then
+    /// or ever again, and [`Node::exhausted`] turns true. If it fails to write the position to
+    /// flash nothing is sent and it returns false, so the block is tried again later.
+    ///
+    /// With ARQ on, blocks the receiver did not acknowledge in time go out first, on the same
+    /// channel, and a block is never held back for the radio's own acknowledgement
+    pub fn transmit<R: Radio, E>(
+        &mut self,
+        radio: &mut R,
+        now_ms: u64,
+        position: impl FnOnce(usize) -> Result<PadPosition, PersistentError<E>>,
+    ) -> Result<bool, R::Error> {
+        if self.exhausted {
+            return Ok(false);
+        }
+        let unsent = match self.unsent.take() {
+            Some(unsent) => unsent,
+            None => {
+                let (block, sequence, control) = match self.block(position) {
+                    Some(block) => block,
+                    None => return Ok(false),
+                };
+                Unsent {
+                    block,
+                    sequence,
+                    control,
+                }
+            }
+        };
+        let hopping = self.applied.hopping;
+        let slot = self.hops.slot(unsent.sequence, self.applied.hop_blacklist);
+        // A radio error keeps the block to be sent again, like a lost one
+        if let Err(err) = self.tune(radio, hopping.then(|| self.hops.channel(slot))) {
+            self.unsent = Some(unsent);
+            return Err(err);
+        }
+        let (retries, timeout_ms) = (self.applied.arq_retries, self.applied.arq_timeout_ms);
+        if retries == 0 && self.arq.outstanding() > 0 {
+            self.arq.clear();
+        }
+        while let Some((_, block)) = self.arq.resend(now_ms, timeout_ms, retries) {
+            if let Err(err) = radio.send(block) {
+                self.unsent = Some(unsent);
+                return Err(err);
+            }
+        }
+        let acked = match radio.send(unsent.block.as_bytes()) {
+            Ok(acked) => acked,
+            Err(err) => {
+                self.unsent = Some(unsent);
+                return Err(err);
+            }
+        };
+        if retries > 0 {
+            self.arq.sent(unsent.sequence, &unsent.block, now_ms);
+        }
+
+        if hopping {
+            let blacklist = self.config.hop_blacklist;
+            if let Some(blacklist) = self.health.record(slot, acked, blacklist) {
+                // Used once the announcement gets through, see `announcement`
+                self.config.hop_blacklist = blacklist;
+            }
+        }
+        if !acked && !hopping && retries == 0 {
+            self.unsent = Some(unsent);
+            self.adapt_rate(radio, false)?;
+            return Ok(false);
+        }
+        // Settings change only after the reply has gone out on the old ones, the host moves the
+        // receiver once it sees the reply
+        if acked && unsent.control {
+            self.applied = self.config;
+        }
+        self.adapt_rate(radio, acked)?;
+        // Listen where the host will send
+        let next = unsent.sequence.wrapping_add(1);
+        let blacklist = self.applied.hop_blacklist;
+        let listen = self.applied.hopping.then(|| self.hops.hop(next, blacklist));
+        self.tune(radio, listen)?;
+        Ok(true)
+    }
+
+    /// With auto rate on, moves along [`crate::RATE_STEPS`] when the rate controller says to. A
+    /// new data rate waits for the receiver to be told, see `announcement`, unless the link is
+    /// gone
+    fn adapt_rate<R: Radio>(&mut self, radio: &mut R, acked: bool) -> Result<(), R::Error> {
+        if !self.applied.auto_rate {
+            return Ok(());
+        }
+        let retries = radio.retries()?;
+        match self.rate.record(&self.applied, acked, retries) {
+            Some(RateChange::Step(data_rate, pa_level)) => {
+                self.config.data_rate = data_rate;
+                self.config.pa_level = pa_level;
+                self.applied.pa_level = pa_level;
+            }
+            Some(RateChange::Fallback(data_rate, pa_level)) => {
+                self.config.data_rate = data_rate;
+                self.config.pa_level = pa_level;
+                self.applied.data_rate = data_rate;
+                self.applied.pa_level = pa_level;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Configures `radio` with the applied settings, on `channel` if given
+    fn tune<R: Radio>(&mut self, radio: &mut R, channel: Option<u8>) -> Result<(), R::Error> {
+        let tuned = NodeConfig {
+            channel: channel.unwrap_or(self.applied.channel),
+            ..self.applied
+        };
+        if tuned != self.tuned {
+            radio.configure(&tuned)?;
+            self.tuned = tuned;
+        }
+        Ok(())
+    }
+
+    /// Handles every block waiting in `radio`, or those up to a firmware update message so that
+    /// the firmware can take it with [`Node::take_ota`] before the next one arrives
+    pub fn poll<R: Radio>(&mut self, radio: &mut R) -> Result<(), R::Error> {
+        while self.ota_len.is_none() && radio.data_available()? {
+            let mut payload = [0u8; BLOCK_SIZE];
+            let len = radio.receive(&mut payload)?;
+            self.receive(&payload[..len]);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ResetCounts, ResetReason, Setting};
+
+    const KEY_BYTES: usize = 4096;
+    const INDEX_KEY: u32 = 0x8BAD_F00D;
+    const AUTH_KEY: [u8; AUTH_KEY_SIZE] = *b"node test key 16";
+    const NODE: u8 = 2;
+
+    fn key() -> Key<KEY_BYTES> {
+        let mut state = 0x9E37_79B9u32;
+        Key::new(core::array::from_fn(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }))
+    }
+
+    /// Plays the host: encrypts blocks for the node and decrypts its replies
+    struct Host<'k> {
+        cipher: MainCipher<'k, fn(u32) -> u32, KEY_BYTES>,
+        uplink: PadRegion,
+        position: PadPosition,
+    }
+
+    impl<'k> Host<'k> {
+        fn new(key: &'k Key<KEY_BYTES>) -> Self {
+            Self {
+                cipher: MainCipher::new(key, INDEX_KEY),
+                uplink: PadRegion::node_uplink(key.subkey_count::<u32, 7>(), NODE),
+                position: PadPosition::default(),
+            }
+        }
+
+        fn block(&mut self, tag: usize, data: impl FnOnce(u32) -> [u32; 7]) -> VarBlock {
+            let words = if tag == TAG_CONTROL { CONTROL_WORDS } else { 7 };
+            let index = self.uplink.index_for(self.position.take(words), INDEX_KEY);
+            let mut block = IndexedBlock::new();
+            block.tag().set_index(index);
+            block.tag().set_tag(tag);
+            *block.data_mut() = data(index);
+            let mut block = VarBlock::new(block, words);
+            block.do_cipher(&self.cipher);
+            block
+        }
+
+        fn decrypt(&self, mut block: VarBlock) -> (u32, usize, [u32; 7]) {
+            block.do_cipher(&self.cipher);
+            (block.index(), block.tag(), *block.block().data())
+        }
+    }
+
+    #[test]
+    fn echoes_messages() {
+        let key = key();
+        let mut node = Node::new(&key, INDEX_KEY, AUTH_KEY, NODE);
+        let mut host = Host::new(&key);
+        let mut pads = PadPosition::default();
+        let heartbeat = node.next_block(|words| pads.take(words));
+        assert_eq!(heartbeat.as_bytes().len(), crate::TAG_SIZE);
+        // It takes no key words
+        assert_eq!(
+            pads,
+            PadPosition {
+                sequence: 1,
+                word: 0
+            }
+        );
+        assert_eq!(host.decrypt(heartbeat).2, HEARTBEAT);
+
+        let message = b"a message that takes two blocks to send";
+        for data in encode_chunks(MessageKind::Text, message) {
+            let block = host.block(TAG_DATA, |_| data);
+            node.receive(block.as_bytes());
+        }
+        let mut messages = Reassembler::<128>::new();
+        let mut echoed = None;
+        while node.has_pending() {
+            let sequence = pads.sequence;
+            let (_, tag, data) = host.decrypt(node.next_block(|words| pads.take(words)));
+            assert_eq!(tag, TAG_DATA);
+            if let Some((_, text)) = messages.push(sequence, &data) {
+                echoed = Some(text.to_vec());
+            }
+        }
+        assert_eq!(echoed.as_deref(), Some(&message[..]));
+        // A whole chunk, then the header and 14 bytes of the last one
+        assert_eq!(pads.word, 7 + 4);
+        assert_eq!(
+            host.decrypt(node.next_block(|words| pads.take(words))).2,
+            HEARTBEAT
+        );
+    }
+
+    #[test]
+    fn reports_faults() {
+        let key = key();
+        let mut node = Node::new(&key, INDEX_KEY, AUTH_KEY, NODE);
+        let host = Host::new(&key);
+        let mut text = crate::PanicText::new();
+        core::fmt::Write::write_str(&mut text, "panicked at src/main.rs:212:14").unwrap();
+        let fault = Fault::Panic(text);
+        let reset = ResetReport {
+            reason: ResetReason::Software,
+            counts: ResetCounts([0, 0, 1, 0, 0, 0]),
+        };
+        // Both go out, one after the other
+        node.report_reset(&reset);
+        node.report_fault(&fault);
+
+        let mut messages = Reassembler::<128>::new();
+        let mut reported = Vec::new();
+        let mut pads = PadPosition::default();
+        while node.has_pending() {
+            let sequence = pads.sequence;
+            let (_, _, data) = host.decrypt(node.next_block(|words| pads.take(words)));
+            if let Some((kind, bytes)) = messages.push(sequence, &data) {
+                reported.push((kind, bytes.to_vec()));
+            }
+        }
+        assert_eq!(reported.len(), 2);
+        assert_eq!(reported[0].0, MessageKind::Reset);
+        assert_eq!(ResetReport::from_bytes(&reported[0].1), Some(reset));
+        assert_eq!(reported[1].0, MessageKind::Fault);
+        assert_eq!(Fault::from_bytes(&reported[1].1), Some(fault));
+    }
+
+    #[test]
+    fn reports_telemetry() {
+        let key = key();
+        let mut node = Node::new(&key, INDEX_KEY, AUTH_KEY, NODE);
+        let host = Host::new(&key);
+        node.config_mut().telemetry_s = 2;
+        let due: Vec<_> = (0..8).map(|_| node.telemetry_due(500)).collect();
+        assert_eq!(due, [false, false, false, true, false, false, false, true]);
+        node.config_mut().telemetry_s = 0;
+        assert!(!(0..100).any(|_| node.telemetry_due(60_000)));
+
+        let telemetry = Telemetry {
+            battery_mv: 3700,
+            supply_mv: 3300,
+            temperature_c: 21,
+        };
+        node.report_telemetry(&telemetry);
+        let (_, _, data) = host.decrypt(node.next_block(|_| PadPosition::default()));
+        let mut messages = Reassembler::<128>::new();
+        let (kind, bytes) = messages.push(0, &data).unwrap();
+        assert_eq!(kind, MessageKind::Telemetry);
+        assert_eq!(Telemetry::from_bytes(bytes), Some(telemetry));
+        assert!(!node.has_pending());
+    }
+
+    #[test]
+    fn passes_on_ota_messages() {
+        use crate::{ImageHeader, OtaState};
+
+        let key = key();
+        let mut node = Node::new(&key, INDEX_KEY, AUTH_KEY, NODE);
+        let host = Host::new(&key);
+        let header = ImageHeader::new(b"firmware", 2, &AUTH_KEY);
+        let mut sealed = [0u8; MAX_OTA_MESSAGE];
+        let len = OtaMessage::Begin(header).seal(2, &AUTH_KEY, &mut sealed);
+        // Sent as it is, outside the pads
+        node.receive(&sealed[..len]);
+        let mut message = [0u8; MAX_MESSAGE];
+        assert_eq!(node.take_ota(&mut message), Some(len));
+        assert_eq!(message[..len], sealed[..len]);
+        assert_eq!(node.take_ota(&mut message), None);
+
+        let status = OtaStatus {
+            state: OtaState::Receiving,
+            version: 2,
+            received: 0,
+        };
+        node.report_ota(&status);
+        let (_, _, data) = host.decrypt(node.next_block(|_| PadPosition::default()));
+        let mut messages = Reassembler::<128>::new();
+        let (kind, bytes) = messages.push(0, &data).unwrap();
+        assert_eq!(kind, MessageKind::Ota);
+        assert_eq!(
+            OtaMessage::open(bytes, 2, &AUTH_KEY),
+            Ok(OtaMessage::Status(status))
+        );
+    }
+
+    #[test]
+    fn answers_control_messages() {
+        let key = key();
+        let mut node = Node::new(&key, INDEX_KEY, AUTH_KEY, NODE);
+        let mut host = Host::new(&key);
+
+        let set = Control::Set(Setting::TxInterval, 250);
+        let block = host.block(TAG_CONTROL, |index| set.seal(index, &AUTH_KEY));
+        // A copy sent again is a replay and is not answered twice
+        let copy = VarBlock::from_bytes(block.as_bytes()).unwrap();
+        node.receive(block.as_bytes());
+        assert_eq!(node.config().tx_interval_ms, 250);
+
+        let reply = node.next_block(|words| {
+            assert_eq!(words, CONTROL_WORDS);
+            PadPosition::default()
+        });
+        // The last 8 bytes of a control message are zero and left off
+        assert_eq!(reply.as_bytes().len(), BLOCK_SIZE - 8);
+        let (index, tag, data) = host.decrypt(reply);
+        assert_eq!(tag, TAG_CONTROL);
+        assert_eq!(
+            Control::open(index, &data, &AUTH_KEY),
+            Ok(Control::Ack {
+                request: 0,
+                setting: Setting::TxInterval,
+                value: 250
+            })
+        );
+        node.receive(copy.as_bytes());
+        assert!(!node.has_pending());
+
+        // Signed with the wrong key
+        let forged = Control::Set(Setting::Channel, 1);
+        let block = host.block(TAG_CONTROL, |index| forged.seal(index, b"not the auth key"));
+        node.receive(block.as_bytes());
+        assert!(!node.has_pending());
+        assert_eq!(node.config().channel, NodeConfig::DEFAULT.channel);
+
+        // Data blocks are not authenticated, one far ahead must not hold control messages back
+        let next = host.position;
+        host.position.sequence = 1000;
+        let block = host.block(TAG_DATA, |_| [1; 7]);
+        node.receive(block.as_bytes());
+        host.position = next;
+        let set = Control::Set(Setting::TxInterval, 500);
+        let block = host.block(TAG_CONTROL, |index| set.seal(index, &AUTH_KEY));
+        node.receive(block.as_bytes());
+        assert_eq!(node.config().tx_interval_ms, 500);
+    }
+
+    #[test]
+    fn stops_when_the_pads_run_out() {
+        use crate::{ChannelModel, SimAir};
+
+        let key = key();
+        let last = PadRegion::node_downlink(key.subkey_count::<u32, 7>(), NODE).len() - 1;
+        let air = SimAir::new(ChannelModel::PERFECT, 1);
+        let mut radio = air.radio();
+        let _receiver = air.radio();
+        let mut node = Node::new(&key, INDEX_KEY, AUTH_KEY, NODE);
+        // A heartbeat takes no words and fits at the last one, a data block does not
+        let at = |word| Ok::<_, PersistentError<()>>(PadPosition { sequence: 0, word });
+        assert!(node.transmit(&mut radio, 0, |_| at(last)).unwrap());
+        assert!(!node.exhausted());
+        node.report_telemetry(&Telemetry {
+            battery_mv: 3700,
+            supply_mv: 3300,
+            temperature_c: 21,
+        });
+        assert!(!node.transmit(&mut radio, 0, |_| at(last)).unwrap());
+        assert!(node.exhausted());
+        // Nothing more goes out, even with pads to spare
+        assert!(!node.transmit(&mut radio, 0, |_| at(0)).unwrap());
+        air.advance(0);
+        assert_eq!(air.stats().sent, 1);
+
+        let mut node = Node::new(&key, INDEX_KEY, AUTH_KEY, NODE);
+        assert!(!node
+            .transmit(&mut radio, 0, |_| Err(PersistentError::<()>::Exhausted))
+            .unwrap());
+        assert!(node.exhausted());
+    }
+
+    #[test]
+    fn retries_a_block_the_index_log_failed_on() {
+        use crate::{ChannelModel, PowerLoss, SimAir};
+
+        let key = key();
+        let air = SimAir::new(ChannelModel::PERFECT, 1);
+        let mut radio = air.radio();
+        let _receiver = air.radio();
+        let mut node = Node::new(&key, INDEX_KEY, AUTH_KEY, NODE);
+        node.report_telemetry(&Telemetry {
+            battery_mv: 3700,
+            supply_mv: 3300,
+            temperature_c: 21,
+        });
+        let failed = |_| Err(PersistentError::Flash(PowerLoss));
+        assert!(!node.transmit(&mut radio, 0, failed).unwrap());
+        // Nothing went out and the telemetry still waits
+        assert!(!node.exhausted());
+        assert!(node.has_pending());
+        air.advance(0);
+        assert_eq!(air.stats().sent, 0);
+
+        let mut pads = PadPosition::default();
+        let sent = node.transmit(&mut radio, 0, |words| {
+            Ok::<_, PersistentError<()>>(pads.take(words))
+        });
+        assert!(sent.unwrap());
+        assert!(!node.has_pending());
+        assert!(pads.word > 0);
+    }
+}
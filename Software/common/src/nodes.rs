@@ -0,0 +1,24 @@
+//! Several nodes sending to one receiver.
+//!
+//! The receiver listens for node `n` on pipe `n + 1` at [`node_address`]`(n)`, so that the radio
+//! acknowledges every node on a pipe of its own. Pipe 0 is left for the acknowledgements of the
+//! receiver's own transmissions, which go to [`host_address`]`(n)`. Each node also gets its own
+//! share of the pads (see [`crate::PadRegion::node_downlink`]), which is how the host tells who
+//! sent a block and what keeps two nodes from reusing each other's pads.
+
+/// The most nodes one receiver can listen to, one for each of the radio's pipes 1 to 5
+pub const MAX_NODES: usize = 5;
+
+/// The length of a radio address
+pub const ADDRESS_SIZE: usize = 5;
+
+/// Where node `node` sends, and the receiver listens for it. Pipes 2 to 5 share all but the first
+/// byte with pipe 1, so only the first byte differs
+pub const fn node_address(node: u8) -> [u8; ADDRESS_SIZE] {
+    [b'0' + node, b'n', b'o', b'd', b'e']
+}
+
+/// Where node `node` listens for blocks from the host and acks from the receiver
+pub const fn host_address(node: u8) -> [u8; ADDRESS_SIZE] {
+    [b'0' + node, b'h', b'o', b's', b't']
+}
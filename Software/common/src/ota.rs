@@ -0,0 +1,923 @@
+//! Firmware updates over the radio.
+//!
+//! The host sends a node a new image a radio packet per message: an [`OtaMessage::Begin`] with the
+//! image's [`ImageHeader`], then the image [`OTA_FRAGMENT`] bytes at a time. They go out in the
+//! clear rather than in encrypted blocks, as an image of tens of K would use up the node's uplink
+//! pads many times over, and the image holds nothing secret: the key is left out of it. Every
+//! message ends with a SipHash-2-4 MAC under the auth key that also covers the image version, so
+//! fragments can neither be flipped on the air nor mixed between images. The node writes them in
+//! order into its staging slot. It answers every Begin, and the fragment that finishes the image,
+//! with an [`OtaStatus`] saying how much it has, which is where the host carries on after a loss.
+//! The answers go up in [`crate::MessageKind::Ota`] messages like any other.
+//!
+//! Once the last byte is in, the node checks the MAC in the header, which covers the version and
+//! size as well as the image, and asks the bootloader to install it. [`boot`] swaps the staging
+//! and active slots a page at a time through a scratch page, logging every step so that a power
+//! loss part way through carries on where it stopped. The new image then gets one start to call
+//! [`OtaReceiver::confirm`]. If the board resets before it does, the old image is swapped back.
+//!
+//! The [`Flash`] both work on holds, in pages: the active slot, the staging slot, the scratch
+//! page and the [`BOOT_LOG_PAGES`] of the log. Each slot ends with the header of its image.
+
+use crate::control::SipHasher;
+use crate::{ControlError, Flash, AUTH_KEY_SIZE, BLOCK_SIZE};
+
+/// The image bytes in each [`OtaMessage::Data`], as many as fit in a radio packet
+pub const OTA_FRAGMENT: usize = 16;
+
+/// The longest [`OtaMessage::seal`] writes
+pub const MAX_OTA_MESSAGE: usize = 1 + 4 + OTA_FRAGMENT + MAC_SIZE;
+const _: () = assert!(MAX_OTA_MESSAGE <= BLOCK_SIZE);
+
+/// The pages at the end of the flash that log the bootloader's progress
+pub const BOOT_LOG_PAGES: usize = 2;
+
+const MAC_SIZE: usize = 8;
+const HEADER_WORDS: usize = 4;
+
+const OP_BEGIN: u8 = 1;
+const OP_DATA: u8 = 2;
+const OP_STATUS: u8 = 3;
+
+/// Log records. A step record carries how many steps of the swap are done
+const LOG_REQUEST: u16 = 1;
+const LOG_SWAP: u16 = 2;
+const LOG_SWAPPED: u16 = 3;
+const LOG_TRIAL: u16 = 4;
+const LOG_CONFIRM: u16 = 5;
+const LOG_STEP: u16 = 0x8000;
+
+/// Swapping a page takes a step for each of its three copies
+const STEPS_PER_PAGE: usize = 3;
+
+/// Describes an image and authenticates it. It is stored after the image in its slot
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageHeader {
+    /// Only images newer than the running one are installed
+    pub version: u32,
+    pub size: u32,
+    pub mac: u64,
+}
+
+impl ImageHeader {
+    /// The header the host sends along with `image`
+    pub fn new(image: &[u8], version: u32, auth_key: &[u8; AUTH_KEY_SIZE]) -> Self {
+        let size = image.len() as u32;
+        let mut mac = Self::hasher(version, size, auth_key);
+        mac.write(image);
+        Self {
+            version,
+            size,
+            mac: mac.finish(),
+        }
+    }
+
+    fn hasher(version: u32, size: u32, auth_key: &[u8; AUTH_KEY_SIZE]) -> SipHasher {
+        let mut hasher = SipHasher::new(auth_key);
+        hasher.write(&version.to_le_bytes());
+        hasher.write(&size.to_le_bytes());
+        hasher
+    }
+
+    fn to_words(self) -> [u32; HEADER_WORDS] {
+        [
+            self.version,
+            self.size,
+            self.mac as u32,
+            (self.mac >> 32) as u32,
+        ]
+    }
+
+    /// Reads the header at the end of the slot starting at `first`. An image flashed without
+    /// one has none
+    fn read<F: Flash>(flash: &F, layout: &Layout, first: usize) -> Option<Self> {
+        let page = first + layout.slot_pages - 1;
+        let words: [u32; HEADER_WORDS] =
+            core::array::from_fn(|i| flash.read(page, layout.header_word() + i));
+        if words == [u32::MAX; HEADER_WORDS] {
+            return None;
+        }
+        Some(Self {
+            version: words[0],
+            size: words[1],
+            mac: words[2] as u64 | (words[3] as u64) << 32,
+        })
+    }
+
+    /// True if the slot starting at `first` holds the image this header describes
+    fn verify<F: Flash>(
+        &self,
+        flash: &F,
+        layout: &Layout,
+        first: usize,
+        auth_key: &[u8; AUTH_KEY_SIZE],
+    ) -> bool {
+        if self.size > layout.capacity() {
+            return false;
+        }
+        let mut mac = Self::hasher(self.version, self.size, auth_key);
+        let size = self.size as usize;
+        for start in (0..size).step_by(4) {
+            let word = start / 4;
+            let page = first + word / layout.page_words;
+            let bytes = flash.read(page, word % layout.page_words).to_le_bytes();
+            mac.write(&bytes[..(size - start).min(4)]);
+        }
+        mac.finish() == self.mac
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OtaState {
+    /// Nothing has been sent yet
+    Idle = 0,
+    Receiving = 1,
+    /// The image is in and checked. The node restarts into it
+    Ready = 2,
+    /// The image did not match the MAC in its header
+    BadImage = 3,
+    /// The image does not fit in the staging slot
+    TooBig = 4,
+    /// The node is running an image it has not confirmed yet, and takes no other until it does
+    Busy = 5,
+    /// The image is not newer than the running one
+    Stale = 6,
+}
+
+impl TryFrom<u8> for OtaState {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(OtaState::Idle),
+            1 => Ok(OtaState::Receiving),
+            2 => Ok(OtaState::Ready),
+            3 => Ok(OtaState::BadImage),
+            4 => Ok(OtaState::TooBig),
+            5 => Ok(OtaState::Busy),
+            6 => Ok(OtaState::Stale),
+            other => Err(other),
+        }
+    }
+}
+
+/// How far a node has got with an image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OtaStatus {
+    pub state: OtaState,
+    pub version: u32,
+    /// The bytes written so far, which is where the host carries on from
+    pub received: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OtaMessage<'a> {
+    /// Starts sending an image, or asks how far the node got if it is the one being received
+    Begin(ImageHeader),
+    /// The image from `offset`, a multiple of four. Only the last one may be shorter than
+    /// [`OTA_FRAGMENT`]
+    Data { offset: u32, data: &'a [u8] },
+    /// The node's answer to each of the others
+    Status(OtaStatus),
+}
+
+impl<'a> OtaMessage<'a> {
+    /// The version the MAC covers. Data messages belong to the image being sent
+    fn version(&self, image: u32) -> u32 {
+        match self {
+            OtaMessage::Begin(header) => header.version,
+            OtaMessage::Data { .. } => image,
+            OtaMessage::Status(status) => status.version,
+        }
+    }
+
+    /// Writes the message and its MAC to `out` and returns the length. `version` is that of the
+    /// image being sent, which data messages do not carry themselves
+    ///
+    /// # Panics
+    /// If a data message holds more than [`OTA_FRAGMENT`] bytes
+    pub fn seal(
+        &self,
+        version: u32,
+        auth_key: &[u8; AUTH_KEY_SIZE],
+        out: &mut [u8; MAX_OTA_MESSAGE],
+    ) -> usize {
+        let len = match *self {
+            OtaMessage::Begin(header) => {
+                out[0] = OP_BEGIN;
+                out[1..5].copy_from_slice(&header.version.to_le_bytes());
+                out[5..9].copy_from_slice(&header.size.to_le_bytes());
+                out[9..17].copy_from_slice(&header.mac.to_le_bytes());
+                17
+            }
+            OtaMessage::Data { offset, data } => {
+                assert!(data.len() <= OTA_FRAGMENT);
+                out[0] = OP_DATA;
+                out[1..5].copy_from_slice(&offset.to_le_bytes());
+                out[5..5 + data.len()].copy_from_slice(data);
+                5 + data.len()
+            }
+            OtaMessage::Status(status) => {
+                out[0] = OP_STATUS;
+                out[1] = status.state as u8;
+                out[2..6].copy_from_slice(&status.version.to_le_bytes());
+                out[6..10].copy_from_slice(&status.received.to_le_bytes());
+                10
+            }
+        };
+        let mac = mac(self.version(version), &out[..len], auth_key);
+        out[len..len + MAC_SIZE].copy_from_slice(&mac);
+        len + MAC_SIZE
+    }
+
+    /// Checks and decodes a message sealed for the image with version `version`
+    pub fn open(
+        bytes: &'a [u8],
+        version: u32,
+        auth_key: &[u8; AUTH_KEY_SIZE],
+    ) -> Result<Self, ControlError> {
+        if bytes.len() < 1 + MAC_SIZE {
+            return Err(ControlError::Malformed);
+        }
+        let (body, tag) = bytes.split_at(bytes.len() - MAC_SIZE);
+        let word = |at: usize| -> Result<u32, ControlError> {
+            let bytes = body.get(at..at + 4).ok_or(ControlError::Malformed)?;
+            Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+        };
+        let message = match body[0] {
+            OP_BEGIN if body.len() == 17 => OtaMessage::Begin(ImageHeader {
+                version: word(1)?,
+                size: word(5)?,
+                mac: u64::from_le_bytes(body[9..17].try_into().unwrap()),
+            }),
+            OP_DATA if body.len() >= 5 && body.len() <= 5 + OTA_FRAGMENT => OtaMessage::Data {
+                offset: word(1)?,
+                data: &body[5..],
+            },
+            OP_STATUS if body.len() == 10 => OtaMessage::Status(OtaStatus {
+                state: OtaState::try_from(body[1]).map_err(|_| ControlError::Malformed)?,
+                version: word(2)?,
+                received: word(6)?,
+            }),
+            _ => return Err(ControlError::Malformed),
+        };
+        let expected = mac(message.version(version), body, auth_key);
+        // Compare every byte so that the time taken does not depend on where they differ
+        let diff = expected
+            .iter()
+            .zip(tag)
+            .fold(0, |diff, (a, b)| diff | (a ^ b));
+        if diff != 0 {
+            return Err(ControlError::BadMac);
+        }
+        Ok(message)
+    }
+}
+
+fn mac(version: u32, body: &[u8], auth_key: &[u8; AUTH_KEY_SIZE]) -> [u8; MAC_SIZE] {
+    let mut hasher = SipHasher::new(auth_key);
+    hasher.write(&version.to_le_bytes());
+    hasher.write(body);
+    hasher.finish().to_le_bytes()
+}
+
+/// Where everything is in the flash, see the module docs
+struct Layout {
+    slot_pages: usize,
+    page_words: usize,
+}
+
+impl Layout {
+    fn of<F: Flash>(flash: &F) -> Self {
+        let slot_pages = flash.pages().saturating_sub(1 + BOOT_LOG_PAGES) / 2;
+        let layout = Self {
+            slot_pages,
+            page_words: flash.page_words(),
+        };
+        assert!(slot_pages > 0, "the flash has no room for the slots");
+        // An update and a rollback, with a request and a confirmation each
+        assert!(
+            BOOT_LOG_PAGES * layout.page_words >= 2 * (layout.steps() + 4),
+            "the log is too small for the slots"
+        );
+        layout
+    }
+
+    fn active(&self) -> usize {
+        0
+    }
+
+    fn staging(&self) -> usize {
+        self.slot_pages
+    }
+
+    fn scratch(&self) -> usize {
+        2 * self.slot_pages
+    }
+
+    fn log(&self) -> usize {
+        self.scratch() + 1
+    }
+
+    fn steps(&self) -> usize {
+        self.slot_pages * STEPS_PER_PAGE
+    }
+
+    fn header_word(&self) -> usize {
+        self.page_words - HEADER_WORDS
+    }
+
+    /// The largest image a slot holds, leaving room for the header
+    fn capacity(&self) -> u32 {
+        ((self.slot_pages * self.page_words - HEADER_WORDS) * 4) as u32
+    }
+}
+
+/// What the log says the bootloader should do next
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    /// The staging slot holds a checked image to install
+    Requested,
+    /// Swapping the slots, `done` steps in. Swapping the old image back if `revert`
+    Swapping {
+        revert: bool,
+        done: usize,
+    },
+    Swapped {
+        revert: bool,
+    },
+    /// The new image has been started once
+    Trial,
+    Confirmed,
+}
+
+impl Phase {
+    /// Records that make no sense where they are, such as ones a power loss cut short or that
+    /// an interrupted erase left behind, change nothing
+    fn apply(self, record: u16, steps: usize) -> Self {
+        match (self, record) {
+            (Phase::Idle | Phase::Requested | Phase::Confirmed, LOG_REQUEST) => Phase::Requested,
+            (Phase::Requested, LOG_SWAP) => Phase::Swapping {
+                revert: false,
+                done: 0,
+            },
+            (Phase::Trial, LOG_SWAP) => Phase::Swapping {
+                revert: true,
+                done: 0,
+            },
+            (Phase::Swapping { revert, done }, record)
+                if record == LOG_STEP | (done + 1) as u16 =>
+            {
+                Phase::Swapping {
+                    revert,
+                    done: done + 1,
+                }
+            }
+            (Phase::Swapping { revert, done }, LOG_SWAPPED) if done == steps => {
+                Phase::Swapped { revert }
+            }
+            (Phase::Swapped { revert: false }, LOG_TRIAL) => Phase::Trial,
+            (Phase::Trial, LOG_CONFIRM) => Phase::Confirmed,
+            (phase, _) => phase,
+        }
+    }
+}
+
+/// The bootloader's progress, a word per record: the record in the low half and its complement
+/// in the high half, so that one cut short by a power loss is ignored
+struct Log {
+    phase: Phase,
+    /// The word the next record goes in, counting from the start of the log
+    next: usize,
+}
+
+impl Log {
+    fn read<F: Flash>(flash: &F, layout: &Layout) -> Self {
+        let mut log = Self {
+            phase: Phase::Idle,
+            next: 0,
+        };
+        for i in 0..BOOT_LOG_PAGES * layout.page_words {
+            let word = flash.read(layout.log() + i / layout.page_words, i % layout.page_words);
+            if word == u32::MAX {
+                continue;
+            }
+            log.next = i + 1;
+            let record = word as u16;
+            if (word >> 16) as u16 == !record {
+                log.phase = log.phase.apply(record, layout.steps());
+            }
+        }
+        log
+    }
+
+    fn append<F: Flash>(
+        &mut self,
+        flash: &mut F,
+        layout: &Layout,
+        record: u16,
+    ) -> Result<(), F::Error> {
+        let (page, word) = (self.next / layout.page_words, self.next % layout.page_words);
+        // Move past the word first so a failed write is never written over
+        self.next += 1;
+        flash.write(
+            layout.log() + page,
+            word,
+            record as u32 | ((!record) as u32) << 16,
+        )?;
+        self.phase = self.phase.apply(record, layout.steps());
+        Ok(())
+    }
+
+    /// Erases the log, last page first so that what is left of an interrupted erase still
+    /// starts with the records it had
+    fn clear<F: Flash>(&mut self, flash: &mut F, layout: &Layout) -> Result<(), F::Error> {
+        for page in (0..BOOT_LOG_PAGES).rev() {
+            flash.erase(layout.log() + page)?;
+        }
+        self.phase = Phase::Idle;
+        self.next = 0;
+        Ok(())
+    }
+}
+
+/// Receives an image on the node and writes it to the staging slot of `flash`
+pub struct OtaReceiver<F: Flash> {
+    flash: F,
+    layout: Layout,
+    auth_key: [u8; AUTH_KEY_SIZE],
+    /// The image being received
+    header: Option<ImageHeader>,
+    received: u32,
+    state: OtaState,
+    /// The running image was just installed and has not confirmed itself yet
+    trial: bool,
+}
+
+impl<F: Flash> OtaReceiver<F> {
+    /// # Panics
+    /// If `flash` is too small for the slots, the scratch page and the log
+    pub fn new(flash: F, auth_key: [u8; AUTH_KEY_SIZE]) -> Self {
+        let layout = Layout::of(&flash);
+        let trial = Log::read(&flash, &layout).phase == Phase::Trial;
+        Self {
+            flash,
+            layout,
+            auth_key,
+            header: None,
+            received: 0,
+            state: OtaState::Idle,
+            trial,
+        }
+    }
+
+    /// The version of the running image. One flashed without a header counts as 0
+    pub fn version(&self) -> u32 {
+        ImageHeader::read(&self.flash, &self.layout, self.layout.active()).map_or(0, |h| h.version)
+    }
+
+    pub fn status(&self) -> OtaStatus {
+        OtaStatus {
+            state: self.state,
+            version: self.header.map_or(0, |h| h.version),
+            received: self.received,
+        }
+    }
+
+    /// Handles a message from the host. Returns the status to send back after a Begin or the
+    /// fragment that finishes the image, or `None` for other fragments and for messages that
+    /// failed authentication
+    pub fn receive(&mut self, message: &[u8]) -> Result<Option<OtaStatus>, F::Error> {
+        let version = self.header.map_or(0, |h| h.version);
+        match OtaMessage::open(message, version, &self.auth_key) {
+            Ok(OtaMessage::Begin(header)) => self.begin(header),
+            Ok(OtaMessage::Data { offset, data }) => {
+                let receiving = self.state == OtaState::Receiving;
+                self.data(offset, data)?;
+                // The host asks with a Begin after each window, so only the end of the image
+                // needs telling unasked
+                if !receiving || self.state == OtaState::Receiving {
+                    return Ok(None);
+                }
+            }
+            Ok(OtaMessage::Status(_)) | Err(_) => return Ok(None),
+        }
+        Ok(Some(self.status()))
+    }
+
+    fn begin(&mut self, header: ImageHeader) {
+        // Asking again carries on with the image
+        if self.header == Some(header) {
+            return;
+        }
+        self.header = Some(header);
+        self.received = 0;
+        self.state = if self.trial {
+            OtaState::Busy
+        } else if header.version <= self.version() {
+            // The bootloader would refuse it anyway, so do not wear the staging slot out on it
+            OtaState::Stale
+        } else if header.size > self.layout.capacity() {
+            OtaState::TooBig
+        } else {
+            OtaState::Receiving
+        };
+    }
+
+    fn data(&mut self, offset: u32, data: &[u8]) -> Result<(), F::Error> {
+        let header = match self.header {
+            Some(header) if self.state == OtaState::Receiving => header,
+            _ => return Ok(()),
+        };
+        // Anything but the next bytes is dropped. The status tells the host where to carry on
+        let end = offset as u64 + data.len() as u64;
+        let last = end == header.size as u64;
+        if offset != self.received || end > header.size as u64 || (!last && data.len() % 4 != 0) {
+            return Ok(());
+        }
+        let first = offset as usize / 4;
+        for (i, chunk) in data.chunks(4).enumerate() {
+            let mut bytes = [0xFF; 4];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            self.write_staged(first + i, u32::from_le_bytes(bytes))?;
+        }
+        self.received = end as u32;
+        if last {
+            self.finish(header)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a word of the staging slot, erasing each page when its first word is written
+    fn write_staged(&mut self, word: usize, value: u32) -> Result<(), F::Error> {
+        let page_words = self.layout.page_words;
+        let (page, word) = (self.layout.staging() + word / page_words, word % page_words);
+        if word == 0 {
+            self.flash.erase(page)?;
+        }
+        self.flash.write(page, word, value)
+    }
+
+    fn finish(&mut self, header: ImageHeader) -> Result<(), F::Error> {
+        let layout = &self.layout;
+        if !header.verify(&self.flash, layout, layout.staging(), &self.auth_key) {
+            self.state = OtaState::BadImage;
+            return Ok(());
+        }
+        // The header goes at the end of the last page, which the image may not have reached
+        let last = layout.staging() + layout.slot_pages - 1;
+        let words = (header.size as usize + 3) / 4;
+        if words <= (layout.slot_pages - 1) * layout.page_words {
+            self.flash.erase(last)?;
+        }
+        for (i, word) in header.to_words().into_iter().enumerate() {
+            self.flash.write(last, layout.header_word() + i, word)?;
+        }
+        // Nothing is in progress while this image runs, or it would be on trial
+        let mut log = Log::read(&self.flash, layout);
+        log.clear(&mut self.flash, layout)?;
+        log.append(&mut self.flash, layout, LOG_REQUEST)?;
+        self.state = OtaState::Ready;
+        Ok(())
+    }
+
+    /// Keeps the running image if the bootloader just installed it, otherwise it is swapped back
+    /// at the next reset. Call it once the image has shown that it works, such as after its
+    /// first acknowledged transmission
+    pub fn confirm(&mut self) -> Result<(), F::Error> {
+        if !self.trial {
+            return Ok(());
+        }
+        let mut log = Log::read(&self.flash, &self.layout);
+        log.append(&mut self.flash, &self.layout, LOG_CONFIRM)?;
+        self.trial = false;
+        Ok(())
+    }
+
+    /// For the bootloader to run on in tests
+    pub fn flash_mut(&mut self) -> &mut F {
+        &mut self.flash
+    }
+}
+
+/// What [`boot`] did before starting the active image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Boot {
+    /// There was nothing to do
+    Active,
+    /// Installed a new image, which now has to confirm itself
+    Installed,
+    /// The new image reset before confirming itself and the old one is back
+    RolledBack,
+    /// The staged image was not newer than the active one, or did not match its header
+    Rejected,
+}
+
+/// The bootloader's side of an update, run at every reset before the active image is started.
+/// Carries on from wherever a power loss stopped the last boot
+///
+/// # Panics
+/// If `flash` is too small for the slots, the scratch page and the log
+pub fn boot<F: Flash>(flash: &mut F, auth_key: &[u8; AUTH_KEY_SIZE]) -> Result<Boot, F::Error> {
+    let layout = Layout::of(flash);
+    let mut log = Log::read(flash, &layout);
+    loop {
+        match log.phase {
+            Phase::Idle => return Ok(Boot::Active),
+            Phase::Confirmed => {
+                log.clear(flash, &layout)?;
+                return Ok(Boot::Active);
+            }
+            Phase::Requested => {
+                let active = ImageHeader::read(flash, &layout, layout.active());
+                let active = active.map_or(0, |h| h.version);
+                match ImageHeader::read(flash, &layout, layout.staging()) {
+                    Some(staged)
+                        if staged.version > active
+                            && staged.verify(flash, &layout, layout.staging(), auth_key) =>
+                    {
+                        log.append(flash, &layout, LOG_SWAP)?
+                    }
+                    _ => {
+                        log.clear(flash, &layout)?;
+                        return Ok(Boot::Rejected);
+                    }
+                }
+            }
+            Phase::Swapping { done, .. } if done == layout.steps() => {
+                log.append(flash, &layout, LOG_SWAPPED)?
+            }
+            Phase::Swapping { done, .. } => {
+                swap_step(flash, &layout, done)?;
+                log.append(flash, &layout, LOG_STEP | (done + 1) as u16)?;
+            }
+            Phase::Swapped { revert: false } => {
+                log.append(flash, &layout, LOG_TRIAL)?;
+                return Ok(Boot::Installed);
+            }
+            // The new image reset before confirming itself
+            Phase::Trial => log.append(flash, &layout, LOG_SWAP)?,
+            Phase::Swapped { revert: true } => {
+                log.clear(flash, &layout)?;
+                return Ok(Boot::RolledBack);
+            }
+        }
+    }
+}
+
+/// Does step `step` of swapping the slots. Each step can be done again after a power loss,
+/// since the page it copies from is only erased by a later one
+fn swap_step<F: Flash>(flash: &mut F, layout: &Layout, step: usize) -> Result<(), F::Error> {
+    let page = step / STEPS_PER_PAGE;
+    let (active, staging) = (layout.active() + page, layout.staging() + page);
+    let (from, to) = match step % STEPS_PER_PAGE {
+        0 => (active, layout.scratch()),
+        1 => (staging, active),
+        _ => (layout.scratch(), staging),
+    };
+    flash.erase(to)?;
+    for word in 0..layout.page_words {
+        let value = flash.read(from, word);
+        if value != u32::MAX {
+            flash.write(to, word, value)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PowerLoss, RamFlash};
+
+    const AUTH_KEY: [u8; AUTH_KEY_SIZE] = *b"ota test key 16b";
+
+    /// Slots of 4 pages of 256 bytes
+    type TestFlash = RamFlash<11, 64>;
+
+    fn image(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+            .collect()
+    }
+
+    fn seal(message: OtaMessage, version: u32) -> Vec<u8> {
+        let mut out = [0u8; MAX_OTA_MESSAGE];
+        let len = message.seal(version, &AUTH_KEY, &mut out);
+        out[..len].to_vec()
+    }
+
+    /// Sends `image` to `receiver` the way the host does, returning the last status
+    fn send(receiver: &mut OtaReceiver<TestFlash>, image: &[u8], version: u32) -> OtaStatus {
+        let header = ImageHeader::new(image, version, &AUTH_KEY);
+        receiver
+            .receive(&seal(OtaMessage::Begin(header), version))
+            .unwrap()
+            .unwrap();
+        for (i, data) in image.chunks(OTA_FRAGMENT).enumerate() {
+            let offset = (i * OTA_FRAGMENT) as u32;
+            receiver
+                .receive(&seal(OtaMessage::Data { offset, data }, version))
+                .unwrap();
+        }
+        receiver.status()
+    }
+
+    /// The image in the slot starting at `first`
+    fn slot(flash: &TestFlash, first: usize, len: usize) -> Vec<u8> {
+        (0..len.div_ceil(4))
+            .flat_map(|word| flash.read(first + word / 64, word % 64).to_le_bytes())
+            .take(len)
+            .collect()
+    }
+
+    #[test]
+    fn messages() {
+        let header = ImageHeader::new(&image(500, 0), 7, &AUTH_KEY);
+        let data = image(OTA_FRAGMENT, 1);
+        let status = OtaStatus {
+            state: OtaState::Receiving,
+            version: 7,
+            received: 32,
+        };
+        for message in [
+            OtaMessage::Begin(header),
+            OtaMessage::Data {
+                offset: 16,
+                data: &data,
+            },
+            OtaMessage::Status(status),
+        ] {
+            let mut bytes = seal(message, 7);
+            assert_eq!(OtaMessage::open(&bytes, 7, &AUTH_KEY), Ok(message));
+            bytes[1] ^= 1;
+            assert_eq!(
+                OtaMessage::open(&bytes, 7, &AUTH_KEY),
+                Err(ControlError::BadMac)
+            );
+        }
+        // Data for another image
+        let bytes = seal(
+            OtaMessage::Data {
+                offset: 0,
+                data: &data,
+            },
+            7,
+        );
+        assert_eq!(
+            OtaMessage::open(&bytes, 8, &AUTH_KEY),
+            Err(ControlError::BadMac)
+        );
+        assert_eq!(
+            OtaMessage::open(&[OP_DATA], 7, &AUTH_KEY),
+            Err(ControlError::Malformed)
+        );
+    }
+
+    #[test]
+    fn receives_an_image() {
+        let mut receiver = OtaReceiver::new(TestFlash::new(), AUTH_KEY);
+        let image = image(700, 2);
+        let header = ImageHeader::new(&image, 3, &AUTH_KEY);
+        let begin = seal(OtaMessage::Begin(header), 3);
+        let fragment = |i: usize| {
+            let data = &image[i * OTA_FRAGMENT..((i + 1) * OTA_FRAGMENT).min(image.len())];
+            let offset = (i * OTA_FRAGMENT) as u32;
+            seal(OtaMessage::Data { offset, data }, 3)
+        };
+        // Fragments are only answered once the image is done
+        let received = |receiver: &mut OtaReceiver<TestFlash>, message: &[u8]| {
+            assert_eq!(receiver.receive(message), Ok(None));
+            receiver.status().received
+        };
+
+        let status = receiver.receive(&begin).unwrap().unwrap();
+        assert_eq!(status.state, OtaState::Receiving);
+        assert_eq!(received(&mut receiver, &fragment(0)), 16);
+        // A lost fragment stops the rest until the host goes back to it
+        assert_eq!(received(&mut receiver, &fragment(2)), 16);
+        assert_eq!(received(&mut receiver, &fragment(0)), 16);
+        // Asking again changes nothing
+        assert_eq!(receiver.receive(&begin).unwrap().unwrap().received, 16);
+        let mut tampered = fragment(1);
+        tampered[10] ^= 1;
+        assert_eq!(received(&mut receiver, &tampered), 16);
+        for i in 1..43 {
+            received(&mut receiver, &fragment(i));
+        }
+        let done = receiver.receive(&fragment(43)).unwrap().unwrap();
+        assert_eq!(done.state, OtaState::Ready);
+        assert_eq!(done.received, 700);
+        let flash = receiver.flash_mut();
+        assert_eq!(slot(flash, 4, 700), image);
+        assert_eq!(
+            ImageHeader::read(flash, &Layout::of(flash), 4),
+            Some(header)
+        );
+        assert_eq!(Log::read(flash, &Layout::of(flash)).phase, Phase::Requested);
+
+        // Too big for the slot, which holds 1008 bytes
+        let mut receiver = OtaReceiver::new(TestFlash::new(), AUTH_KEY);
+        assert_eq!(send(&mut receiver, &[0; 1009], 1).state, OtaState::TooBig);
+        assert_eq!(send(&mut receiver, &[0; 1008], 2).state, OtaState::Ready);
+
+        // Signed with another key
+        let mut receiver = OtaReceiver::new(TestFlash::new(), *b"not the auth key");
+        assert_eq!(receiver.receive(&begin), Ok(None));
+    }
+
+    #[test]
+    fn installs_and_rolls_back() {
+        let old = image(300, 3);
+        let new = image(900, 4);
+        let mut flash = TestFlash::new();
+        // The first image was flashed without a header
+        for (word, bytes) in old.chunks(4).enumerate() {
+            let mut padded = [0xFF; 4];
+            padded[..bytes.len()].copy_from_slice(bytes);
+            flash
+                .write(word / 64, word % 64, u32::from_le_bytes(padded))
+                .unwrap();
+        }
+        assert_eq!(boot(&mut flash, &AUTH_KEY), Ok(Boot::Active));
+
+        let mut receiver = OtaReceiver::new(flash, AUTH_KEY);
+        assert_eq!(receiver.version(), 0);
+        assert_eq!(send(&mut receiver, &new, 5).state, OtaState::Ready);
+        let flash = receiver.flash_mut();
+        assert_eq!(boot(flash, &AUTH_KEY), Ok(Boot::Installed));
+        assert_eq!(slot(flash, 0, 900), new);
+        assert_eq!(slot(flash, 4, 300), old);
+
+        // The new image resets before confirming itself
+        let mut flash = std::mem::take(flash);
+        let mut receiver = OtaReceiver::new(flash, AUTH_KEY);
+        assert_eq!(receiver.version(), 5);
+        assert_eq!(send(&mut receiver, &new, 6).state, OtaState::Busy);
+        flash = std::mem::take(receiver.flash_mut());
+        assert_eq!(boot(&mut flash, &AUTH_KEY), Ok(Boot::RolledBack));
+        assert_eq!(slot(&flash, 0, 300), old);
+        assert_eq!(boot(&mut flash, &AUTH_KEY), Ok(Boot::Active));
+
+        // This time it confirms itself and stays
+        let mut receiver = OtaReceiver::new(flash, AUTH_KEY);
+        assert_eq!(send(&mut receiver, &new, 5).state, OtaState::Ready);
+        flash = std::mem::take(receiver.flash_mut());
+        assert_eq!(boot(&mut flash, &AUTH_KEY), Ok(Boot::Installed));
+        let mut receiver = OtaReceiver::new(flash, AUTH_KEY);
+        receiver.confirm().unwrap();
+        flash = std::mem::take(receiver.flash_mut());
+        assert_eq!(boot(&mut flash, &AUTH_KEY), Ok(Boot::Active));
+        assert_eq!(boot(&mut flash, &AUTH_KEY), Ok(Boot::Active));
+        assert_eq!(slot(&flash, 0, 900), new);
+
+        // An image that is not newer is refused, and nothing is staged
+        let mut receiver = OtaReceiver::new(flash, AUTH_KEY);
+        assert_eq!(send(&mut receiver, &old, 4).state, OtaState::Stale);
+        assert_eq!(send(&mut receiver, &new, 5).state, OtaState::Stale);
+        assert_eq!(receiver.status().received, 0);
+        flash = std::mem::take(receiver.flash_mut());
+        assert_eq!(boot(&mut flash, &AUTH_KEY), Ok(Boot::Active));
+
+        // The bootloader still refuses one if it gets staged, such as by older firmware
+        let mut receiver = OtaReceiver::new(flash, AUTH_KEY);
+        let header = ImageHeader::new(&old, 4, &AUTH_KEY);
+        receiver.header = Some(header);
+        receiver.state = OtaState::Receiving;
+        for (i, data) in old.chunks(OTA_FRAGMENT).enumerate() {
+            receiver.data((i * OTA_FRAGMENT) as u32, data).unwrap();
+        }
+        assert_eq!(receiver.status().state, OtaState::Ready);
+        flash = std::mem::take(receiver.flash_mut());
+        assert_eq!(boot(&mut flash, &AUTH_KEY), Ok(Boot::Rejected));
+        assert_eq!(slot(&flash, 0, 900), new);
+    }
+
+    #[test]
+    fn survives_power_loss() {
+        let new = image(1000, 5);
+        let mut cut = 1;
+        loop {
+            let mut receiver = OtaReceiver::new(TestFlash::new(), AUTH_KEY);
+            assert_eq!(send(&mut receiver, &new, 1).state, OtaState::Ready);
+            let mut flash = std::mem::take(receiver.flash_mut());
+            flash.cut_power_after(cut);
+            let first = boot(&mut flash, &AUTH_KEY);
+            flash.restore_power();
+            let result = match first {
+                Ok(result) => result,
+                Err(PowerLoss) => boot(&mut flash, &AUTH_KEY).unwrap(),
+            };
+            assert_eq!(result, Boot::Installed, "power cut after {}", cut);
+            assert_eq!(slot(&flash, 0, 1000), new, "power cut after {}", cut);
+            if first.is_ok() {
+                break;
+            }
+            cut += 1;
+        }
+        // Every erase and write of the swap was cut once
+        assert!(cut > 4 * STEPS_PER_PAGE * 2);
+    }
+}
@@ -0,0 +1,291 @@
+use crate::MAX_NODES;
+
+/// The key words one block's pad takes up at most, one for each of its data words
+const PAD_WORDS: u32 = 7;
+
+/// How far a sender has got through its [`PadRegion`]: the sequence number of its next block,
+/// and how many of the region's key words the blocks before it took up
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PadPosition {
+    pub sequence: u32,
+    pub word: u32,
+}
+
+impl PadPosition {
+    /// Returns this position for a block of `words` data words, and moves past it
+    pub fn take(&mut self, words: usize) -> PadPosition {
+        let position = *self;
+        self.sequence += 1;
+        self.word += words as u32;
+        position
+    }
+}
+
+/// A range of key words reserved for one direction of traffic.
+///
+/// The pad for a block is the key words from offset `(index ^ index_key) % offsets`, where
+/// `offsets` is the number of distinct pads the key has (see [`crate::Key::subkey_count`]).
+/// If both directions picked indices freely they would end up using the same key words, so each
+/// direction gets its own range and turns a [`PadPosition`] into an index whose pad falls inside
+/// it. A block only takes as many key words as it sends data words, and the next block's pad
+/// starts right after them, so no key word is ever used twice. Once a direction has used up
+/// its words it has to stop, as a repeated pad gives away the XOR of two plaintexts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PadRegion {
+    /// The first key word
+    start: u32,
+    /// The number of key words
+    len: u32,
+    offsets: u32,
+}
+
+impl PadRegion {
+    /// # Panics
+    /// If the region has no room for a whole block or does not start its pads inside the key
+    pub const fn new(start: u32, len: u32, offsets: u32) -> Self {
+        assert!(len >= PAD_WORDS);
+        assert!(start as u64 + len as u64 <= offsets as u64);
+        Self {
+            start,
+            len,
+            offsets,
+        }
+    }
+
+    /// The region used by nodes sending to the host: the first three quarters of the key
+    pub const fn downlink(offsets: usize) -> Self {
+        let offsets = offsets as u32;
+        Self::new(0, offsets - offsets / 4, offsets)
+    }
+
+    /// The region used by the host sending to nodes: the last quarter of the key
+    pub const fn uplink(offsets: usize) -> Self {
+        let offsets = offsets as u32;
+        Self::new(offsets - offsets / 4, offsets / 4, offsets)
+    }
+
+    /// Node `node`'s share of [`Self::downlink`]
+    ///
+    /// # Panics
+    /// If `node` is not below [`MAX_NODES`]
+    pub const fn node_downlink(offsets: usize, node: u8) -> Self {
+        Self::downlink(offsets).share(node)
+    }
+
+    /// The pads the host uses for node `node`, its share of [`Self::uplink`]
+    ///
+    /// # Panics
+    /// If `node` is not below [`MAX_NODES`]
+    pub const fn node_uplink(offsets: usize, node: u8) -> Self {
+        Self::uplink(offsets).share(node)
+    }
+
+    /// One of [`MAX_NODES`] equal parts of this region. Words left over at the end go unused
+    const fn share(self, node: u8) -> Self {
+        assert!((node as usize) < MAX_NODES);
+        let len = self.len / MAX_NODES as u32;
+        Self::new(self.start + node as u32 * len, len, self.offsets)
+    }
+
+    /// The number of key words in this region
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of sequence numbers that fit in the 31 bits of an index. Each one moves the
+    /// index up by a whole key, see [`Self::index_for`]
+    pub fn sequences(&self) -> u32 {
+        0x8000_0000 / self.offsets - 1
+    }
+
+    /// True if a block of `words` data words can be sent at `position` without going past the
+    /// end of the region
+    pub fn fits(&self, position: PadPosition, words: usize) -> bool {
+        position.sequence < self.sequences()
+            && position.word < self.len
+            && position.word as u64 + words as u64 <= self.len as u64
+    }
+
+    /// Indices are 31 bits, so `index ^ index_key` always has the same top bit as the index key.
+    /// This is the lowest multiple of `offsets` with that top bit
+    fn base(&self, index_key: u32) -> u64 {
+        if index_key & 0x8000_0000 == 0 {
+            0
+        } else {
+            let offsets = self.offsets as u64;
+            (0x8000_0000 + offsets - 1) / offsets * offsets
+        }
+    }
+
+    /// Returns the index to send a block at `position` with. Its pad starts `position.word` key
+    /// words into the region, and the sequence number picks which multiple of the key length is
+    /// added so that [`Self::sequence_of`] can recover it
+    ///
+    /// # Panics
+    /// If `position` is past the end of the region, see [`Self::fits`]
+    pub fn index_for(&self, position: PadPosition, index_key: u32) -> u32 {
+        assert!(self.fits(position, 0));
+        let offset = (self.start + position.word) as u64;
+        let x = self.base(index_key) + position.sequence as u64 * self.offsets as u64 + offset;
+        (x as u32 ^ index_key) & 0x7FFF_FFFF
+    }
+
+    /// Returns the sequence number of a received index, or `None` if its pad does not start in
+    /// this region
+    pub fn sequence_of(&self, index: u32, index_key: u32) -> Option<u32> {
+        let x = ((index ^ index_key) as u64).checked_sub(self.base(index_key))?;
+        let offset = (x % self.offsets as u64) as u32;
+        offset.checked_sub(self.start).filter(|&o| o < self.len)?;
+        u32::try_from(x / self.offsets as u64).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFSETS: usize = 13314;
+
+    /// The key words the cipher uses for a block of `words` data words sent with `index`
+    fn key_words(index: u32, index_key: u32, words: usize) -> std::ops::Range<usize> {
+        let offset = (index ^ index_key) as usize % OFFSETS;
+        offset..offset + words
+    }
+
+    fn regions() -> Vec<PadRegion> {
+        let nodes = 0..MAX_NODES as u8;
+        nodes
+            .clone()
+            .map(|n| PadRegion::node_downlink(OFFSETS, n))
+            .chain(nodes.map(|n| PadRegion::node_uplink(OFFSETS, n)))
+            .collect()
+    }
+
+    #[test]
+    fn pads_never_share_key_words() {
+        let words = OFFSETS + PAD_WORDS as usize - 1;
+        let regions = regions();
+        assert_eq!(regions[0].len(), 1997);
+        assert_eq!(regions[MAX_NODES].len(), 665);
+
+        for index_key in [0, 0x1234_5678, 0xDEAD_BEEF, 0xFFFF_FFFF] {
+            // Which region's pad each key word went to
+            let mut owner = vec![None; words];
+            for (r, region) in regions.iter().enumerate() {
+                let mut position = PadPosition::default();
+                // Blocks of every size, skipping those that no longer fit, until every word
+                // is used
+                for size in (0..=PAD_WORDS as usize).cycle() {
+                    if !region.fits(position, size) {
+                        if position.word == region.len() {
+                            break;
+                        }
+                        continue;
+                    }
+                    let sequence = position.sequence;
+                    let index = region.index_for(position.take(size), index_key);
+                    assert!(index <= 0x7FFF_FFFF);
+                    assert_eq!(region.sequence_of(index, index_key), Some(sequence));
+                    for word in key_words(index, index_key, size) {
+                        assert!(word < words);
+                        assert_eq!(owner[word], None, "key word {} used twice", word);
+                        owner[word] = Some(r);
+                    }
+                }
+            }
+            // Only the words left over when splitting between nodes, and those past the last
+            // offset, go unused
+            let unused = owner.iter().filter(|o| o.is_none()).count();
+            assert!(unused < 2 * MAX_NODES + PAD_WORDS as usize, "{}", unused);
+        }
+    }
+
+    #[test]
+    fn regions_split_the_key() {
+        let down = PadRegion::downlink(OFFSETS);
+        let up = PadRegion::uplink(OFFSETS);
+        assert_eq!(down.len() + up.len(), OFFSETS as u32);
+        for index_key in [0, 0x1234_5678, 0xDEAD_BEEF, 0xFFFF_FFFF] {
+            for sequence in (0..down.sequences())
+                .step_by(997)
+                .chain([down.sequences() - 1])
+            {
+                for (region, other) in [(down, up), (up, down)] {
+                    let word = sequence % (region.len() - PAD_WORDS + 1);
+                    let index = region.index_for(PadPosition { sequence, word }, index_key);
+                    assert!(index <= 0x7FFF_FFFF);
+                    let words = key_words(index, index_key, PAD_WORDS as usize);
+                    assert!(words.start >= region.start as usize);
+                    assert!(words.end <= (region.start + region.len) as usize);
+
+                    assert_eq!(region.sequence_of(index, index_key), Some(sequence));
+                    assert_eq!(other.sequence_of(index, index_key), None);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_node_has_its_own_pads() {
+        let index_key = 0x1234_5678;
+        let regions = regions();
+        assert_eq!(
+            regions[0].len(),
+            PadRegion::downlink(OFFSETS).len() / MAX_NODES as u32
+        );
+        for sequence in (0..20_000).step_by(13) {
+            for (node, region) in regions.iter().enumerate() {
+                for word in [0, 1, region.len() - 1] {
+                    let index = region.index_for(PadPosition { sequence, word }, index_key);
+                    // Only the region the block was sent with claims it
+                    for (other, other_region) in regions.iter().enumerate() {
+                        let expected = if other == node { Some(sequence) } else { None };
+                        assert_eq!(other_region.sequence_of(index, index_key), expected);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn blocks_only_take_the_words_they_send() {
+        let up = PadRegion::uplink(OFFSETS);
+        let index_key = 0xCAFE_F00D;
+        let offset = |position| (up.index_for(position, index_key) ^ index_key) as usize % OFFSETS;
+        let mut position = PadPosition::default();
+        for size in [3, 0, 7, 1, 0, 0, 5] {
+            let before = offset(position);
+            position.take(size);
+            assert_eq!(offset(position), before + size);
+        }
+        assert_eq!(
+            position,
+            PadPosition {
+                sequence: 7,
+                word: 16
+            }
+        );
+
+        // The last word fits one more word, but not a whole block
+        let last = PadPosition {
+            sequence: 0,
+            word: up.len() - 1,
+        };
+        assert!(up.fits(last, 1));
+        assert!(!up.fits(last, 2));
+        let end = PadPosition {
+            sequence: 0,
+            word: up.len(),
+        };
+        assert!(!up.fits(end, 0));
+        let sequences = PadPosition {
+            sequence: up.sequences(),
+            word: 0,
+        };
+        assert!(!up.fits(sequences, 0));
+    }
+}
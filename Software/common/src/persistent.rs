@@ -0,0 +1,335 @@
+//! A [`PadPosition`] that keeps counting up across reboots, so that a node never hands out the
+//! same position twice. Nothing here knows how many pads there are: positions carry on past the
+//! end of the node's [`crate::PadRegion`], and the node stops sending once they do, see
+//! [`crate::Node::exhausted`].
+//!
+//! Sequence numbers are reserved in blocks. The end of each block is appended to a log in flash
+//! before the first sequence number in it is handed out, so after a crash the node starts at the
+//! end of the last block it reserved. Key words are few, so they are not reserved ahead: the
+//! words a block takes are logged before it is sent, and none are skipped by a reboot. Records
+//! are written round robin over the pages to spread the wear, and each value is stored next to
+//! its complement so that a write cut short by a power loss is ignored.
+
+use crate::PadPosition;
+
+/// Flash that is erased a page at a time and written a word at a time. Erased words read as
+/// `u32::MAX` and writing can only clear bits
+pub trait Flash {
+    type Error;
+
+    /// The number of pages set aside for the log. Must be at least two
+    fn pages(&self) -> usize;
+
+    /// The number of words in each page
+    fn page_words(&self) -> usize;
+
+    fn read(&self, page: usize, word: usize) -> u32;
+
+    fn write(&mut self, page: usize, word: usize, value: u32) -> Result<(), Self::Error>;
+
+    fn erase(&mut self, page: usize) -> Result<(), Self::Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PersistentError<E> {
+    Flash(E),
+    /// The sequence number or key word count would overflow a `u32`. The node's pads run out
+    /// long before that
+    Exhausted,
+}
+
+impl<E> From<E> for PersistentError<E> {
+    fn from(err: E) -> Self {
+        Self::Flash(err)
+    }
+}
+
+/// Each record is the sequence number and key word, followed by their complements
+const RECORD_WORDS: usize = 4;
+
+pub struct PersistentIndex<F: Flash> {
+    flash: F,
+    /// How many sequence numbers to reserve at once. Larger blocks mean fewer writes but skip
+    /// more sequence numbers on each reboot
+    reserve: u32,
+    next: PadPosition,
+    /// The end of the current block, exclusive
+    reserved: PadPosition,
+    page: usize,
+    /// The next record to write in `page`
+    slot: usize,
+}
+
+impl<F: Flash> PersistentIndex<F> {
+    /// Recovers the position from the log in `flash`, or starts at zero if it is empty
+    pub fn open(flash: F, reserve: u32) -> Self {
+        assert!(flash.pages() >= 2, "the log needs at least two pages");
+        assert!(reserve > 0);
+        let slots = flash.page_words() / RECORD_WORDS;
+
+        // Positions only go up, so the largest record is the newest
+        let mut latest: Option<(PadPosition, usize, usize)> = None;
+        for page in 0..flash.pages() {
+            for slot in 0..slots {
+                let record = |i| flash.read(page, slot * RECORD_WORDS + i);
+                let position = PadPosition {
+                    sequence: record(0),
+                    word: record(1),
+                };
+                let valid = record(2) == !position.sequence && record(3) == !position.word;
+                if valid && latest.map(|(max, _, _)| max) < Some(position) {
+                    latest = Some((position, page, slot));
+                }
+            }
+        }
+
+        let (next, page, slot) = match latest {
+            Some((position, page, slot)) => (position, page, slot + 1),
+            // Anything already written is garbage from an interrupted first write. Start again
+            // from the first page, which the next record erases
+            None => (PadPosition::default(), flash.pages() - 1, slots),
+        };
+        let mut index = Self {
+            flash,
+            reserve,
+            next,
+            reserved: next,
+            page,
+            slot,
+        };
+        // Skip records that were cut short after the newest one
+        while index.slot < slots && !index.is_erased(index.page, index.slot) {
+            index.slot += 1;
+        }
+        index
+    }
+
+    fn is_erased(&self, page: usize, slot: usize) -> bool {
+        (0..RECORD_WORDS).all(|i| self.flash.read(page, slot * RECORD_WORDS + i) == u32::MAX)
+    }
+
+    /// Returns the position to send a block of `words` data words at. Neither its sequence
+    /// number nor its key words have been handed out before, even by an earlier boot
+    pub fn next(&mut self, words: usize) -> Result<PadPosition, PersistentError<F::Error>> {
+        let mut end = self.reserved;
+        if self.next.sequence == self.reserved.sequence {
+            end.sequence = self
+                .next
+                .sequence
+                .checked_add(self.reserve)
+                .ok_or(PersistentError::Exhausted)?;
+        }
+        let word = u32::try_from(words)
+            .ok()
+            .and_then(|words| self.next.word.checked_add(words))
+            .ok_or(PersistentError::Exhausted)?;
+        end.word = end.word.max(word);
+        if end != self.reserved {
+            self.append(end)?;
+            self.reserved = end;
+        }
+        Ok(self.next.take(words))
+    }
+
+    fn append(&mut self, position: PadPosition) -> Result<(), F::Error> {
+        if self.slot == self.flash.page_words() / RECORD_WORDS {
+            self.page = (self.page + 1) % self.flash.pages();
+            self.slot = 0;
+            self.flash.erase(self.page)?;
+        }
+        // Move past the slot first so a failed write is never written over
+        let word = self.slot * RECORD_WORDS;
+        self.slot += 1;
+        let values = [
+            position.sequence,
+            position.word,
+            !position.sequence,
+            !position.word,
+        ];
+        for (i, value) in values.into_iter().enumerate() {
+            self.flash.write(self.page, word + i, value)?;
+        }
+        Ok(())
+    }
+
+    pub fn into_flash(self) -> F {
+        self.flash
+    }
+}
+
+/// The error from [`RamFlash`] once its power has been cut
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerLoss;
+
+/// Flash in RAM for testing. It can lose power part way through an operation
+pub struct RamFlash<const PAGES: usize, const WORDS: usize> {
+    pages: [[u32; WORDS]; PAGES],
+    /// Operations left before the power is cut
+    power: Option<usize>,
+    /// How many times each page has been erased
+    pub erases: [usize; PAGES],
+}
+
+impl<const PAGES: usize, const WORDS: usize> RamFlash<PAGES, WORDS> {
+    pub fn new() -> Self {
+        Self {
+            pages: [[u32::MAX; WORDS]; PAGES],
+            power: None,
+            erases: [0; PAGES],
+        }
+    }
+
+    /// Cuts the power during the `operations`th operation from now. That operation only gets
+    /// half done, and everything after it fails until [`RamFlash::restore_power`]
+    pub fn cut_power_after(&mut self, operations: usize) {
+        self.power = Some(operations);
+    }
+
+    pub fn restore_power(&mut self) {
+        self.power = None;
+    }
+
+    /// Returns true if this operation should only get half done
+    fn use_power(&mut self) -> Result<bool, PowerLoss> {
+        match &mut self.power {
+            None => Ok(false),
+            Some(0) => Err(PowerLoss),
+            Some(left) => {
+                *left -= 1;
+                Ok(*left == 0)
+            }
+        }
+    }
+}
+
+impl<const PAGES: usize, const WORDS: usize> Default for RamFlash<PAGES, WORDS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const PAGES: usize, const WORDS: usize> Flash for RamFlash<PAGES, WORDS> {
+    type Error = PowerLoss;
+
+    fn pages(&self) -> usize {
+        PAGES
+    }
+
+    fn page_words(&self) -> usize {
+        WORDS
+    }
+
+    fn read(&self, page: usize, word: usize) -> u32 {
+        self.pages[page][word]
+    }
+
+    fn write(&mut self, page: usize, word: usize, value: u32) -> Result<(), PowerLoss> {
+        // The STM32F1 writes half a word at a time
+        let value = if self.use_power()? {
+            value | 0xFFFF_0000
+        } else {
+            value
+        };
+        self.pages[page][word] &= value;
+        if self.power == Some(0) {
+            return Err(PowerLoss);
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, page: usize) -> Result<(), PowerLoss> {
+        let half = self.use_power()?;
+        let words = if half { WORDS / 2 } else { WORDS };
+        self.pages[page][..words].fill(u32::MAX);
+        self.erases[page] += 1;
+        if self.power == Some(0) {
+            return Err(PowerLoss);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type TestFlash = RamFlash<4, 16>;
+
+    /// Block sizes to ask for, a heartbeat now and then
+    fn words(i: usize) -> usize {
+        [3, 0, 7, 1, 0][i % 5]
+    }
+
+    #[test]
+    fn counts_across_reboots() {
+        let mut flash = TestFlash::new();
+        let mut last: Option<PadPosition> = None;
+        let mut used = 0;
+        for _ in 0..200 {
+            let mut index = PersistentIndex::open(flash, 8);
+            for i in 0..5 {
+                let next = index.next(words(i)).unwrap();
+                if let Some(last) = last {
+                    assert!(last.sequence < next.sequence);
+                }
+                // Key words carry on from where the last block left them
+                assert_eq!(next.word, used);
+                used += words(i) as u32;
+                last = Some(next);
+            }
+            flash = index.into_flash();
+        }
+        // The log went round the pages several times, wearing them evenly
+        let erases = flash.erases;
+        assert!(erases.iter().all(|&e| e >= 5), "{:?}", erases);
+        assert!(erases.iter().max().unwrap() - erases.iter().min().unwrap() <= 1);
+    }
+
+    #[test]
+    fn survives_power_loss() {
+        // Cut the power at every point in a run that fills the log a few times
+        for cut in 1..600 {
+            let mut flash = TestFlash::new();
+            let mut issued: Option<PadPosition> = None;
+            // The key words the blocks handed out so far take up
+            let mut used = 0;
+            for boot in 0..3 {
+                if boot == 1 {
+                    flash.cut_power_after(cut);
+                }
+                let mut index = PersistentIndex::open(flash, 3);
+                for i in 0..100 {
+                    match index.next(words(i)) {
+                        Ok(next) => {
+                            assert!(issued.map(|p| p.sequence) < Some(next.sequence));
+                            assert!(next.word >= used, "cut at {}", cut);
+                            issued = Some(next);
+                            used = next.word + words(i) as u32;
+                        }
+                        Err(err) => {
+                            assert_eq!(err, PersistentError::Flash(PowerLoss));
+                            break;
+                        }
+                    }
+                }
+                flash = index.into_flash();
+                flash.restore_power();
+            }
+        }
+    }
+
+    #[test]
+    fn exhausted() {
+        let mut flash = TestFlash::new();
+        for (i, value) in [u32::MAX - 2, 5, 2, !5].into_iter().enumerate() {
+            flash.write(0, i, value).unwrap();
+        }
+        let mut index = PersistentIndex::open(flash, 4);
+        assert_eq!(index.next(0), Err(PersistentError::Exhausted));
+
+        let mut index = PersistentIndex::<TestFlash>::open(TestFlash::new(), 4);
+        index.next(0).unwrap();
+        index.next(u32::MAX as usize).unwrap();
+        assert_eq!(index.next(1), Err(PersistentError::Exhausted));
+    }
+}
@@ -0,0 +1,30 @@
+//! The few things the node and receiver need from a radio, so that the same logic runs on the
+//! nRF24L01+ and on the host simulator.
+
+use crate::{NodeConfig, ADDRESS_SIZE};
+
+pub trait Radio {
+    type Error;
+
+    /// Switches to the channel, PA level and data rate in `config`
+    fn configure(&mut self, config: &NodeConfig) -> Result<(), Self::Error>;
+
+    /// Sends to `address` from now on, see [`crate::host_address`]
+    fn set_destination(&mut self, address: &[u8; ADDRESS_SIZE]) -> Result<(), Self::Error>;
+
+    /// Sends a payload and waits for it to be acknowledged. Returns false if it never was
+    fn send(&mut self, payload: &[u8]) -> Result<bool, Self::Error>;
+
+    /// The retries the last [`Radio::send`] needed, or the most the radio makes if it was never
+    /// acknowledged
+    fn retries(&mut self) -> Result<u8, Self::Error>;
+
+    fn data_available(&mut self) -> Result<bool, Self::Error>;
+
+    /// Reads the oldest payload received into `buf`, returning its length
+    fn receive(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error>;
+
+    /// True if something stronger than -64 dBm was on the channel while listening, as reported by
+    /// the nRF24L01's received power detector
+    fn carrier(&mut self) -> Result<bool, Self::Error>;
+}
@@ -0,0 +1,305 @@
+//! Picks the data rate and PA level from how the link is doing, so that a node next to the
+//! receiver sends at 2 Mbps and one far away still gets through at 250 kbps.
+//!
+//! The settings form a ladder, [`RATE_STEPS`], from the most robust to the fastest and then the
+//! quietest. The [`RateController`] looks at the last [`RATE_WINDOW`] transmissions, how many were
+//! lost and how many retries the radio needed for the rest. A window steps down as soon as it
+//! goes bad. Stepping up takes several good windows in a row, and the thresholds for good and bad
+//! leave a gap so that a middling link stays where it is. A step up that goes bad straight away
+//! doubles the good windows needed before the next try.
+//!
+//! The receiver has to follow data rate changes. The node announces them with a
+//! [`crate::Control::Notify`] on the old data rate and only switches once that is acknowledged,
+//! see [`crate::HopFollower::data_rate`]. After [`RATE_LOST_LIMIT`] transmissions in a row go
+//! unacknowledged the link is taken to be gone, and both ends drop to the bottom step without
+//! telling each other.
+
+use crate::{DataRate, NodeConfig, PaLevel};
+
+/// The settings the controller moves between, from the most robust to the fastest and quietest
+pub const RATE_STEPS: [(DataRate, PaLevel); 6] = [
+    (DataRate::Kbps250, PaLevel::Max),
+    (DataRate::Mbps1, PaLevel::Max),
+    (DataRate::Mbps2, PaLevel::Max),
+    (DataRate::Mbps2, PaLevel::High),
+    (DataRate::Mbps2, PaLevel::Low),
+    (DataRate::Mbps2, PaLevel::Min),
+];
+
+/// The transmissions each decision to step up is made on
+pub const RATE_WINDOW: u16 = 16;
+
+/// Transmissions in a row that go unacknowledged before the link is taken to be gone
+pub const RATE_LOST_LIMIT: u8 = 8;
+
+/// More lost than this in a window is bad
+const BAD_LOST: u16 = RATE_WINDOW / 8;
+/// More retries than this in a window is bad, one for each transmission
+const BAD_RETRIES: u16 = RATE_WINDOW;
+/// A good window loses nothing and needs at most this many retries
+const GOOD_RETRIES: u16 = RATE_WINDOW / 4;
+
+/// Good windows in a row needed to step up, until a step up fails
+const UP_WINDOWS: u8 = 2;
+const MAX_UP_WINDOWS: u8 = 64;
+
+/// What the controller wants the node to do
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateChange {
+    /// Move to these settings. A new PA level can be used straight away, a new data rate once the
+    /// receiver has been told
+    Step(DataRate, PaLevel),
+    /// The link is gone, so move to these without telling the receiver. It falls back by itself
+    Fallback(DataRate, PaLevel),
+}
+
+/// The node's side: decides when to change step from the outcome of every transmission
+pub struct RateController {
+    sent: u16,
+    lost: u16,
+    retries: u16,
+    /// Unacknowledged transmissions in a row
+    lost_in_row: u8,
+    /// Good windows in a row
+    good: u8,
+    up_windows: u8,
+    /// The current window is the first after a step up
+    probing: bool,
+}
+
+impl RateController {
+    pub fn new() -> Self {
+        Self {
+            sent: 0,
+            lost: 0,
+            retries: 0,
+            lost_in_row: 0,
+            good: 0,
+            up_windows: UP_WINDOWS,
+            probing: false,
+        }
+    }
+
+    /// The step `config` is on, or the first with its data rate if its PA level is not on the
+    /// ladder
+    pub fn step(config: &NodeConfig) -> usize {
+        let exact = (config.data_rate, config.pa_level);
+        RATE_STEPS
+            .iter()
+            .position(|&step| step == exact)
+            .or_else(|| {
+                RATE_STEPS
+                    .iter()
+                    .position(|&(rate, _)| rate == config.data_rate)
+            })
+            .unwrap_or(0)
+    }
+
+    fn next_window(&mut self) {
+        self.sent = 0;
+        self.lost = 0;
+        self.retries = 0;
+    }
+
+    /// Records a transmission made with `config`, and the retries the radio needed if it was
+    /// acknowledged. Returns the settings to move to when it is time to
+    pub fn record(&mut self, config: &NodeConfig, acked: bool, retries: u8) -> Option<RateChange> {
+        let step = Self::step(config);
+        self.sent += 1;
+        if acked {
+            self.lost_in_row = 0;
+            self.retries += retries as u16;
+        } else {
+            self.lost += 1;
+            self.lost_in_row = self.lost_in_row.saturating_add(1);
+        }
+
+        if self.lost_in_row >= RATE_LOST_LIMIT {
+            self.next_window();
+            self.lost_in_row = 0;
+            self.good = 0;
+            self.probing = false;
+            let (rate, pa_level) = RATE_STEPS[0];
+            return if step > 0 {
+                Some(RateChange::Fallback(rate, pa_level))
+            } else {
+                None
+            };
+        }
+        if self.lost > BAD_LOST || self.retries > BAD_RETRIES {
+            self.next_window();
+            self.good = 0;
+            if core::mem::take(&mut self.probing) {
+                self.up_windows = (self.up_windows * 2).min(MAX_UP_WINDOWS);
+            }
+            if step == 0 {
+                return None;
+            }
+            let (rate, pa_level) = RATE_STEPS[step - 1];
+            return Some(RateChange::Step(rate, pa_level));
+        }
+        if self.sent < RATE_WINDOW {
+            return None;
+        }
+
+        let good = self.lost == 0 && self.retries <= GOOD_RETRIES;
+        self.next_window();
+        if core::mem::take(&mut self.probing) {
+            // The step up held
+            self.up_windows = (self.up_windows / 2).max(UP_WINDOWS);
+        }
+        if !good {
+            self.good = 0;
+            return None;
+        }
+        self.good += 1;
+        if self.good < self.up_windows || step + 1 == RATE_STEPS.len() {
+            return None;
+        }
+        self.good = 0;
+        self.probing = true;
+        let (rate, pa_level) = RATE_STEPS[step + 1];
+        Some(RateChange::Step(rate, pa_level))
+    }
+}
+
+impl Default for RateController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A link with `path_loss_db` between the node and receiver. Each try gets through less often
+    /// the closer the signal gets to the receiver's sensitivity at the data rate, and the radio
+    /// makes up to 15 retries
+    struct Link {
+        path_loss_db: f64,
+        rng: u64,
+    }
+
+    impl Link {
+        fn new(path_loss_db: f64) -> Self {
+            Self {
+                path_loss_db,
+                rng: 0x2545_F491,
+            }
+        }
+
+        fn chance(&mut self, probability: f64) -> bool {
+            self.rng ^= self.rng << 13;
+            self.rng ^= self.rng >> 7;
+            self.rng ^= self.rng << 17;
+            ((self.rng >> 11) as f64 / (1u64 << 53) as f64) < probability
+        }
+
+        /// Returns whether it was acknowledged and the retries it took
+        fn send(&mut self, config: &NodeConfig) -> (bool, u8) {
+            let power_dbm = [-18.0, -12.0, -6.0, 0.0][config.pa_level as usize];
+            let sensitivity_dbm = [-94.0, -85.0, -82.0][config.data_rate as usize];
+            let margin = power_dbm - self.path_loss_db - sensitivity_dbm;
+            let fail = (0.5 - margin / 4.0).clamp(0.0, 1.0);
+            for retries in 0..=15 {
+                if !self.chance(fail) {
+                    return (true, retries);
+                }
+            }
+            (false, 15)
+        }
+    }
+
+    /// Runs `count` transmissions, with the receiver following straight away. Returns how many
+    /// were lost and how many times the step changed
+    fn run(
+        link: &mut Link,
+        controller: &mut RateController,
+        config: &mut NodeConfig,
+        count: usize,
+    ) -> (usize, usize) {
+        let (mut lost, mut changes) = (0, 0);
+        for _ in 0..count {
+            let (acked, retries) = link.send(config);
+            lost += !acked as usize;
+            match controller.record(config, acked, retries) {
+                Some(RateChange::Step(rate, pa_level) | RateChange::Fallback(rate, pa_level)) => {
+                    config.data_rate = rate;
+                    config.pa_level = pa_level;
+                    changes += 1;
+                }
+                None => {}
+            }
+        }
+        (lost, changes)
+    }
+
+    #[test]
+    fn climbs_on_a_clean_link() {
+        let mut link = Link::new(50.0);
+        let mut controller = RateController::new();
+        let mut config = NodeConfig::DEFAULT;
+        let (lost, changes) = run(&mut link, &mut controller, &mut config, 200);
+        assert_eq!((lost, changes), (0, RATE_STEPS.len() - 2));
+        assert_eq!(RateController::step(&config), RATE_STEPS.len() - 1);
+    }
+
+    #[test]
+    fn steps_down_as_the_node_moves_away() {
+        let mut link = Link::new(50.0);
+        let mut controller = RateController::new();
+        let mut config = NodeConfig::DEFAULT;
+        run(&mut link, &mut controller, &mut config, 200);
+
+        // Too far for 2 Mbps, but 1 Mbps still gets through first time
+        link.path_loss_db = 83.0;
+        run(&mut link, &mut controller, &mut config, 200);
+        assert_eq!((config.data_rate, config.pa_level), RATE_STEPS[1]);
+        let (lost, _) = run(&mut link, &mut controller, &mut config, 2000);
+        assert!(lost < 20, "{}", lost);
+
+        // Only 250 kbps reaches this far
+        link.path_loss_db = 90.0;
+        run(&mut link, &mut controller, &mut config, 200);
+        assert_eq!((config.data_rate, config.pa_level), RATE_STEPS[0]);
+    }
+
+    #[test]
+    fn failed_step_ups_are_tried_less_often() {
+        // 2 Mbps needs a lot of retries, 1 Mbps is clean
+        let mut link = Link::new(83.0);
+        let mut controller = RateController::new();
+        let mut config = NodeConfig::DEFAULT;
+        run(&mut link, &mut controller, &mut config, 500);
+        let (_, early) = run(&mut link, &mut controller, &mut config, 2000);
+        let (_, late) = run(&mut link, &mut controller, &mut config, 2000);
+        assert!(late < early, "{} then {}", early, late);
+        assert!(late <= 4, "{}", late);
+    }
+
+    #[test]
+    fn falls_back_when_the_link_is_gone() {
+        let mut controller = RateController::new();
+        let config = NodeConfig {
+            data_rate: DataRate::Mbps2,
+            pa_level: PaLevel::Low,
+            ..NodeConfig::DEFAULT
+        };
+        let mut changes = Vec::new();
+        for _ in 0..RATE_LOST_LIMIT {
+            // The receiver never acknowledges the new data rate, so the node stays where it was
+            changes.extend(controller.record(&config, false, 15));
+        }
+        let (rate, pa_level) = RATE_STEPS[0];
+        assert_eq!(changes.last(), Some(&RateChange::Fallback(rate, pa_level)));
+        let bottom = NodeConfig {
+            data_rate: rate,
+            pa_level,
+            ..config
+        };
+        for _ in 0..RATE_LOST_LIMIT * 2 {
+            assert_eq!(controller.record(&bottom, false, 15), None);
+        }
+    }
+}
@@ -0,0 +1,490 @@
+//! What the receiver board does between the radio and USB, kept apart from both so that it can
+//! be tested on the host.
+
+use crate::{
+    encode_frame, host_address, Fault, FrameDecoder, FrameKind, FrameQueue, NodeConfig, Radio,
+    ReceiverCounters, ReceiverSetting, Rejection, ResetReport, Survey, VarBlock, MAX_ENCODED_FRAME,
+    MAX_FAULT_BYTES, MAX_NODES, MAX_SWEEPS,
+};
+
+/// The size of a whole [`crate::IndexedBlock`], the most a block takes on the air
+pub const BLOCK_SIZE: usize = 32;
+
+/// Changes a setting in `config` and tells the host it was applied
+fn set<const N: usize>(
+    config: &mut NodeConfig,
+    to_host: &mut FrameQueue<N>,
+    radio: ReceiverSetting,
+) -> Result<(), Rejection> {
+    config.set(radio.setting, radio.value)?;
+    let mut echo = [0u8; MAX_ENCODED_FRAME];
+    let len = encode_frame(FrameKind::Radio, &radio.to_bytes(), &mut echo);
+    to_host.push(&echo[..len]);
+    Ok(())
+}
+
+/// Something the host asked the receiver to do with its radio
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostRequest<'a> {
+    /// Send this payload to node `node` as it is. It is a whole [`VarBlock`], between
+    /// [`crate::TAG_SIZE`] and [`BLOCK_SIZE`] bytes long, or for an addressed frame anything up
+    /// to [`BLOCK_SIZE`] bytes, such as a firmware update message
+    Transmit { node: u8, block: &'a [u8] },
+    /// Change the radio to match [`Relay::config`]. The host has already been told
+    Apply(ReceiverSetting),
+    /// Survey every channel this many times, see [`Relay::survey_sweep`]
+    Survey(u16),
+}
+
+/// Frames blocks from the radio for the host and decodes what the host sends back.
+/// `N` is the number of bytes that can wait for the host to read them
+pub struct Relay<const N: usize> {
+    frames: FrameDecoder,
+    to_host: FrameQueue<N>,
+    counters: ReceiverCounters,
+    config: NodeConfig,
+    /// The channel picked by a [`crate::HopFollower`], used instead of the configured one
+    hop_channel: Option<u8>,
+    /// What the radio was last configured with
+    tuned: NodeConfig,
+    survey: Survey,
+    sweeps_left: u16,
+    /// The node the radio last sent to
+    destination: Option<u8>,
+}
+
+impl<const N: usize> Relay<N> {
+    pub fn new() -> Self {
+        Self {
+            frames: FrameDecoder::new(),
+            to_host: FrameQueue::new(),
+            counters: ReceiverCounters::default(),
+            config: NodeConfig::DEFAULT,
+            hop_channel: None,
+            tuned: NodeConfig::DEFAULT,
+            survey: Survey::new(),
+            sweeps_left: 0,
+            destination: None,
+        }
+    }
+
+    /// The radio settings the host asked for
+    pub fn config(&self) -> &NodeConfig {
+        &self.config
+    }
+
+    /// Starts on `config` rather than the default settings, such as the data rate the firmware
+    /// was built for. Call it before the radio is set up with [`Relay::config`]
+    pub fn start_on(&mut self, config: NodeConfig) {
+        self.config = config;
+    }
+
+    pub fn counters(&self) -> ReceiverCounters {
+        self.counters
+    }
+
+    /// Queues `fault` for the host. Call it at boot with what the firmware found
+    pub fn report_fault(&mut self, fault: &Fault) {
+        let mut bytes = [0u8; MAX_FAULT_BYTES];
+        let len = fault.to_bytes(&mut bytes);
+        self.queue(FrameKind::Fault, &bytes[..len]);
+    }
+
+    /// Queues `report` for the host. Call it at boot
+    pub fn report_reset(&mut self, report: &ResetReport) {
+        self.queue(FrameKind::Reset, &report.to_bytes());
+    }
+
+    /// Frames waiting for the host. Write out what fits from [`FrameQueue::front`] whenever USB
+    /// has room
+    pub fn to_host(&mut self) -> &mut FrameQueue<N> {
+        &mut self.to_host
+    }
+
+    fn queue(&mut self, kind: FrameKind, payload: &[u8]) -> bool {
+        let mut frame = [0u8; MAX_ENCODED_FRAME];
+        let len = encode_frame(kind, payload, &mut frame);
+        self.to_host.push(&frame[..len])
+    }
+
+    /// Handles a byte read from the host, returning what to do once it completes a request
+    pub fn from_host(&mut self, byte: u8) -> Option<HostRequest<'_>> {
+        let frame = match self.frames.push(byte) {
+            Some(Ok(frame)) => frame,
+            _ => return None,
+        };
+        match frame.kind {
+            // The host encrypts blocks itself, so they go out exactly as they came in
+            FrameKind::Block if VarBlock::is_block(frame.payload) => Some(HostRequest::Transmit {
+                node: 0,
+                block: frame.payload,
+            }),
+            FrameKind::Addressed => {
+                let (&node, block) = frame.payload.split_first()?;
+                if node as usize >= MAX_NODES || block.is_empty() || block.len() > BLOCK_SIZE {
+                    return None;
+                }
+                Some(HostRequest::Transmit { node, block })
+            }
+            FrameKind::Radio => {
+                let radio = ReceiverSetting::from_bytes(frame.payload)?;
+                set(&mut self.config, &mut self.to_host, radio).ok()?;
+                Some(HostRequest::Apply(radio))
+            }
+            FrameKind::Survey => {
+                let sweeps = u16::from_le_bytes(frame.payload.try_into().ok()?);
+                if !(1..=MAX_SWEEPS).contains(&sweeps) {
+                    return None;
+                }
+                self.survey = Survey::new();
+                self.sweeps_left = sweeps;
+                Some(HostRequest::Survey(sweeps))
+            }
+            _ => None,
+        }
+    }
+
+    /// Changes a setting on `radio` without the host asking, such as the data rate a
+    /// [`crate::HopFollower`] saw the node move to. The host is told the same way as when it asks
+    pub fn apply<R: Radio>(
+        &mut self,
+        radio: &mut R,
+        setting: ReceiverSetting,
+    ) -> Result<(), R::Error> {
+        if set(&mut self.config, &mut self.to_host, setting).is_ok() {
+            self.tune(radio)?;
+        }
+        Ok(())
+    }
+
+    /// Queues a payload read from the radio for the host as a `kind` frame. Payloads too short to
+    /// be a block, or that do not fit in the queue, are counted and dropped so that a slow host
+    /// never holds up the radio
+    pub fn from_radio(&mut self, kind: FrameKind, payload: &[u8]) {
+        self.counters.received = self.counters.received.wrapping_add(1);
+        if !VarBlock::is_block(payload) {
+            self.counters.malformed = self.counters.malformed.wrapping_add(1);
+        } else if !self.queue(kind, payload) {
+            self.counters.dropped = self.counters.dropped.wrapping_add(1);
+        }
+    }
+
+    /// Counts a radio operation that failed, for the host to see
+    pub fn radio_error(&mut self) {
+        self.counters.radio_errors = self.counters.radio_errors.wrapping_add(1);
+    }
+
+    /// Handles bytes read from the host, sending blocks and changing settings on `radio`
+    pub fn host_bytes<R: Radio>(&mut self, bytes: &[u8], radio: &mut R) -> Result<(), R::Error> {
+        for &b in bytes {
+            match self.from_host(b) {
+                // If the node does not ack there is nothing more we can do, the host notices when
+                // no reply comes back
+                Some(HostRequest::Transmit { node, block }) => {
+                    let mut buf = [0u8; BLOCK_SIZE];
+                    let buf = &mut buf[..block.len()];
+                    buf.copy_from_slice(block);
+                    self.send_to(radio, node, buf)?;
+                }
+                Some(HostRequest::Apply(_)) => self.tune(radio)?,
+                // The sweeps take too long to run here
+                Some(HostRequest::Survey(_)) | None => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends `payload` to node `node`, pointing the radio at it first if it was sending elsewhere.
+    /// Returns whether it was acknowledged
+    pub fn send_to<R: Radio>(
+        &mut self,
+        radio: &mut R,
+        node: u8,
+        payload: &[u8],
+    ) -> Result<bool, R::Error> {
+        if self.destination != Some(node) {
+            radio.set_destination(&host_address(node))?;
+            self.destination = Some(node);
+        }
+        radio.send(payload)
+    }
+
+    /// Moves `radio` to `channel` while hopping, or back to the configured channel for `None`
+    pub fn hop_to<R: Radio>(&mut self, radio: &mut R, channel: Option<u8>) -> Result<(), R::Error> {
+        self.hop_channel = channel;
+        self.tune(radio)
+    }
+
+    fn tune<R: Radio>(&mut self, radio: &mut R) -> Result<(), R::Error> {
+        let tuned = NodeConfig {
+            channel: self.hop_channel.unwrap_or(self.config.channel),
+            ..self.config
+        };
+        if tuned != self.tuned {
+            radio.configure(&tuned)?;
+            self.tuned = tuned;
+        }
+        Ok(())
+    }
+
+    /// True while a survey the host asked for has sweeps left to run
+    pub fn surveying(&self) -> bool {
+        self.sweeps_left > 0
+    }
+
+    /// Runs the next sweep of the survey, and queues the results for the host after the last one.
+    /// The radio goes back to where it was after each sweep, so blocks can still be relayed
+    /// between them
+    pub fn survey_sweep<R: Radio>(
+        &mut self,
+        radio: &mut R,
+        dwell: impl FnMut(),
+    ) -> Result<(), R::Error> {
+        if self.sweeps_left == 0 {
+            return Ok(());
+        }
+        self.survey.sweep(radio, &self.tuned, dwell)?;
+        radio.configure(&self.tuned)?;
+        self.sweeps_left -= 1;
+        if self.sweeps_left == 0 {
+            for (payload, len) in self.survey.chunks() {
+                let mut frame = [0u8; MAX_ENCODED_FRAME];
+                let len = encode_frame(FrameKind::Survey, &payload[..len], &mut frame);
+                self.to_host.push(&frame[..len]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues every payload waiting in `radio` for the host. `open` gets each block before it is
+    /// queued, and returns the kind of frame to send it as
+    pub fn poll_radio<R: Radio>(
+        &mut self,
+        radio: &mut R,
+        mut open: impl FnMut(&mut VarBlock) -> FrameKind,
+    ) -> Result<(), R::Error> {
+        while radio.data_available()? {
+            let mut payload = [0u8; BLOCK_SIZE];
+            let len = radio.receive(&mut payload)?;
+            match VarBlock::from_bytes(&payload[..len]) {
+                Some(mut block) => {
+                    let kind = open(&mut block);
+                    self.from_radio(kind, block.as_bytes());
+                }
+                None => self.from_radio(FrameKind::Block, &payload[..len]),
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues the counters for the host. Call this about once a second. Nothing is counted if
+    /// there is no room, the next one has the same totals
+    pub fn send_counters(&mut self) {
+        let counters = self.counters.to_bytes();
+        self.queue(FrameKind::Counters, &counters);
+    }
+}
+
+impl<const N: usize> Default for Relay<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Setting, TAG_SIZE};
+
+    fn drain<const N: usize>(relay: &mut Relay<N>) -> Vec<(FrameKind, Vec<u8>)> {
+        let mut decoder = FrameDecoder::new();
+        let mut frames = Vec::new();
+        let queue = relay.to_host();
+        while !queue.is_empty() {
+            for &b in queue.front() {
+                if let Some(Ok(frame)) = decoder.push(b) {
+                    frames.push((frame.kind, frame.payload.to_vec()));
+                }
+            }
+            let n = queue.front().len();
+            queue.consume(n);
+        }
+        frames
+    }
+
+    fn host_frame(kind: FrameKind, payload: &[u8]) -> Vec<u8> {
+        let mut out = [0u8; MAX_ENCODED_FRAME];
+        let len = encode_frame(kind, payload, &mut out);
+        out[..len].to_vec()
+    }
+
+    #[test]
+    fn slow_host_drops_blocks() {
+        // Room for two block frames
+        let mut relay = Relay::<80>::new();
+        for i in 0..3 {
+            relay.from_radio(FrameKind::Block, &[i; BLOCK_SIZE]);
+        }
+        relay.from_radio(FrameKind::Block, &[0; TAG_SIZE - 1]);
+        assert_eq!(
+            relay.counters(),
+            ReceiverCounters {
+                received: 4,
+                malformed: 1,
+                dropped: 1,
+                radio_errors: 0,
+            }
+        );
+        assert_eq!(
+            drain(&mut relay),
+            [
+                (FrameKind::Block, vec![0; BLOCK_SIZE]),
+                (FrameKind::Block, vec![1; BLOCK_SIZE])
+            ]
+        );
+
+        relay.send_counters();
+        assert_eq!(
+            drain(&mut relay),
+            [(FrameKind::Counters, relay.counters().to_bytes().to_vec())]
+        );
+    }
+
+    #[test]
+    fn host_requests() {
+        let mut relay = Relay::<256>::new();
+        let mut requests = Vec::new();
+        let radio = ReceiverSetting {
+            setting: Setting::Channel,
+            value: 100,
+        };
+        let bad_radio = ReceiverSetting {
+            setting: Setting::Channel,
+            value: 200,
+        };
+        let stream = [
+            host_frame(FrameKind::Block, &[7; BLOCK_SIZE]),
+            host_frame(FrameKind::Block, &[7; 3]),
+            host_frame(FrameKind::Block, &[6; TAG_SIZE]),
+            host_frame(FrameKind::Addressed, &[&[3][..], &[8; BLOCK_SIZE]].concat()),
+            host_frame(FrameKind::Addressed, &[&[5][..], &[9; BLOCK_SIZE]].concat()),
+            host_frame(FrameKind::Addressed, &[&[2][..], &[10; 29]].concat()),
+            host_frame(FrameKind::Addressed, &[2]),
+            host_frame(
+                FrameKind::Addressed,
+                &[&[2][..], &[11; BLOCK_SIZE + 1]].concat(),
+            ),
+            host_frame(FrameKind::Radio, &radio.to_bytes()),
+            host_frame(FrameKind::Radio, &bad_radio.to_bytes()),
+            host_frame(FrameKind::Survey, &3u16.to_le_bytes()),
+            host_frame(FrameKind::Survey, &0u16.to_le_bytes()),
+        ]
+        .concat();
+        for b in stream {
+            match relay.from_host(b) {
+                Some(HostRequest::Transmit { node, block }) => {
+                    requests.push(format!("tx {} to {}", block[0], node))
+                }
+                Some(HostRequest::Apply(r)) => requests.push(format!("apply {}", r.value)),
+                Some(HostRequest::Survey(sweeps)) => requests.push(format!("survey {}", sweeps)),
+                None => {}
+            }
+        }
+        assert_eq!(
+            requests,
+            [
+                "tx 7 to 0",
+                "tx 6 to 0",
+                "tx 8 to 3",
+                "tx 10 to 2",
+                "apply 100",
+                "survey 3"
+            ]
+        );
+        assert_eq!(relay.config().channel, 100);
+        assert_eq!(
+            drain(&mut relay),
+            [(FrameKind::Radio, radio.to_bytes().to_vec())]
+        );
+    }
+
+    #[test]
+    fn addressed_blocks() {
+        use crate::{node_address, ChannelModel, SimAir};
+
+        let air = SimAir::new(ChannelModel::PERFECT, 1);
+        let mut radio = air.radio_on(&[node_address(0), node_address(1)]);
+        let mut nodes = [
+            air.radio_on(&[host_address(0)]),
+            air.radio_on(&[host_address(1)]),
+        ];
+        let mut relay = Relay::<256>::new();
+        let stream = [
+            host_frame(FrameKind::Addressed, &[&[1][..], &[1; BLOCK_SIZE]].concat()),
+            host_frame(FrameKind::Block, &[2; 12]),
+        ]
+        .concat();
+        relay.host_bytes(&stream, &mut radio).unwrap();
+        air.advance(0);
+        let mut buf = [0u8; BLOCK_SIZE];
+        for (node, (expected, len)) in nodes.iter_mut().zip([(2, 12), (1, BLOCK_SIZE)]) {
+            assert_eq!(node.receive(&mut buf), Ok(len));
+            assert_eq!(buf[0], expected);
+            assert!(!node.data_available().unwrap());
+        }
+    }
+
+    #[test]
+    fn reports_faults() {
+        let mut relay = Relay::<256>::new();
+        let fault = Fault::HardFault(crate::HardFaultRegisters {
+            pc: 0x0800_1234,
+            ..Default::default()
+        });
+        let reset = ResetReport {
+            reason: crate::ResetReason::Software,
+            counts: crate::ResetCounts([1, 0, 1, 0, 0, 0]),
+        };
+        relay.report_reset(&reset);
+        relay.report_fault(&fault);
+        let frames = drain(&mut relay);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].0, FrameKind::Reset);
+        assert_eq!(ResetReport::from_bytes(&frames[0].1), Some(reset));
+        assert_eq!(frames[1].0, FrameKind::Fault);
+        assert_eq!(Fault::from_bytes(&frames[1].1), Some(fault));
+    }
+
+    #[test]
+    fn survey() {
+        use crate::{survey_chunk, SimAir, SURVEY_CHANNELS};
+
+        let air = SimAir::new(crate::ChannelModel::PERFECT, 1);
+        let mut radio = air.radio();
+        let mut node = air.radio();
+        let mut relay = Relay::<256>::new();
+        let request = host_frame(FrameKind::Survey, &2u16.to_le_bytes());
+        relay.host_bytes(&request, &mut radio).unwrap();
+        assert!(relay.surveying());
+
+        air.jam(&[50]);
+        relay.survey_sweep(&mut radio, || {}).unwrap();
+        assert!(drain(&mut relay).is_empty());
+        relay.survey_sweep(&mut radio, || {}).unwrap();
+        assert!(!relay.surveying());
+        let mut occupancy = Vec::new();
+        for (kind, payload) in drain(&mut relay) {
+            assert_eq!(kind, FrameKind::Survey);
+            occupancy.extend_from_slice(survey_chunk(&payload).unwrap().1);
+        }
+        assert_eq!(occupancy.len(), SURVEY_CHANNELS);
+        assert!(occupancy
+            .iter()
+            .enumerate()
+            .all(|(c, &o)| (o == 255) == (c == 50)));
+
+        // Back on the channel it was on
+        air.jam(&[]);
+        assert!(node.send(&[1]).unwrap());
+    }
+}
@@ -0,0 +1,110 @@
+/// The number of indices behind the newest index that [`ReplayWindow`] remembers
+pub const REPLAY_WINDOW_SIZE: u32 = 64;
+
+/// The result of checking an index against a [`ReplayWindow`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplayCheck {
+    /// This index has not been seen before
+    Fresh,
+    /// This index was already seen inside the window
+    Duplicate,
+    /// This index is too far behind the newest index to tell if it was seen before
+    TooOld,
+}
+
+/// Sliding window of recently seen indices, used to spot blocks that arrive more than once.
+/// Because blocks that share an index also share a pad, a duplicate is either a retransmission
+/// or a replay
+#[derive(Clone, Debug, Default)]
+pub struct ReplayWindow {
+    newest: Option<u32>,
+    /// Bit `n` is set if `newest - n` has been seen
+    seen: u64,
+}
+
+impl ReplayWindow {
+    pub const fn new() -> Self {
+        Self {
+            newest: None,
+            seen: 0,
+        }
+    }
+
+    /// Returns the newest index seen so far
+    pub fn newest(&self) -> Option<u32> {
+        self.newest
+    }
+
+    /// Checks `index` and records it as seen
+    pub fn check(&mut self, index: u32) -> ReplayCheck {
+        let newest = match self.newest {
+            None => {
+                self.newest = Some(index);
+                self.seen = 1;
+                return ReplayCheck::Fresh;
+            }
+            Some(newest) => newest,
+        };
+
+        if index > newest {
+            let shift = index - newest;
+            self.seen = if shift >= REPLAY_WINDOW_SIZE {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.newest = Some(index);
+            return ReplayCheck::Fresh;
+        }
+
+        let age = newest - index;
+        if age >= REPLAY_WINDOW_SIZE {
+            return ReplayCheck::TooOld;
+        }
+        let bit = 1u64 << age;
+        if self.seen & bit != 0 {
+            ReplayCheck::Duplicate
+        } else {
+            self.seen |= bit;
+            ReplayCheck::Fresh
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_order() {
+        let mut window = ReplayWindow::new();
+        for i in 0..200 {
+            assert_eq!(window.check(i), ReplayCheck::Fresh);
+        }
+        assert_eq!(window.newest(), Some(199));
+        assert_eq!(window.check(199), ReplayCheck::Duplicate);
+        assert_eq!(window.check(150), ReplayCheck::Duplicate);
+        assert_eq!(window.check(100), ReplayCheck::TooOld);
+    }
+
+    #[test]
+    fn reordering_inside_window() {
+        let mut window = ReplayWindow::new();
+        assert_eq!(window.check(10), ReplayCheck::Fresh);
+        assert_eq!(window.check(12), ReplayCheck::Fresh);
+        assert_eq!(window.check(11), ReplayCheck::Fresh);
+        assert_eq!(window.check(11), ReplayCheck::Duplicate);
+        assert_eq!(window.check(9), ReplayCheck::Fresh);
+        assert_eq!(window.check(10), ReplayCheck::Duplicate);
+    }
+
+    #[test]
+    fn large_jump_clears_window() {
+        let mut window = ReplayWindow::new();
+        window.check(5);
+        assert_eq!(window.check(5 + REPLAY_WINDOW_SIZE), ReplayCheck::Fresh);
+        assert_eq!(window.check(6), ReplayCheck::Fresh);
+        assert_eq!(window.check(5), ReplayCheck::TooOld);
+    }
+}
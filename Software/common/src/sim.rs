@@ -0,0 +1,482 @@
+//! A simulated radio link for running the node and receiver on the host.
+//!
+//! Every [`SimRadio`] made from the same [`SimAir`] hears the others when they are on the same
+//! channel and data rate, and listening on the address they send to. Time only moves when
+//! [`SimAir::advance`] is called, so tests are repeatable for a given seed.
+
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+
+use crate::{DataRate, NodeConfig, PaLevel, Radio, ADDRESS_SIZE};
+
+/// What happens to packets on the way. Probabilities are per packet
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelModel {
+    /// Chance that a packet is lost even after the radio's retries
+    pub loss: f64,
+    /// Chance that a fade starts. Everything sent during a fade is lost
+    pub burst_start: f64,
+    /// Chance that a fade ends
+    pub burst_end: f64,
+    /// Chance that a packet arrives with a bit flipped, as if the CRC missed it
+    pub corruption: f64,
+    pub latency_ms: u64,
+    /// Up to this many more milliseconds of latency, picked for each packet. Packets sent closer
+    /// together than this can arrive out of order
+    pub jitter_ms: u64,
+    /// Between every pair of radios. The closer what arrives gets to the receiver's sensitivity
+    /// at the data rate, the more retries the sender needs, see [`SimRadio::retries`]
+    pub path_loss_db: f64,
+}
+
+impl ChannelModel {
+    pub const PERFECT: Self = Self {
+        loss: 0.0,
+        burst_start: 0.0,
+        burst_end: 1.0,
+        corruption: 0.0,
+        latency_ms: 0,
+        jitter_ms: 0,
+        path_loss_db: 0.0,
+    };
+}
+
+/// Packet totals for the whole air
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AirStats {
+    pub sent: u64,
+    pub lost: u64,
+    pub corrupted: u64,
+    pub delivered: u64,
+    /// Arrived while the receiving radio's FIFO was full
+    pub overflowed: u64,
+}
+
+/// Payloads an nRF24L01+ holds before it starts dropping them
+const RX_FIFO: usize = 3;
+/// Retries an nRF24L01+ makes before giving up on a payload, as `nrf24_radio` sets it up
+const RETRIES: u8 = 15;
+
+/// The nRF24L01+'s output power at each PA level
+fn power_dbm(pa_level: PaLevel) -> f64 {
+    match pa_level {
+        PaLevel::Min => -18.0,
+        PaLevel::Low => -12.0,
+        PaLevel::High => -6.0,
+        PaLevel::Max => 0.0,
+    }
+}
+
+/// The nRF24L01+'s receiver sensitivity at each data rate
+fn sensitivity_dbm(data_rate: DataRate) -> f64 {
+    match data_rate {
+        DataRate::Kbps250 => -94.0,
+        DataRate::Mbps1 => -85.0,
+        DataRate::Mbps2 => -82.0,
+    }
+}
+
+struct Station {
+    channel: u8,
+    data_rate: DataRate,
+    pa_level: PaLevel,
+    /// Retries the last send needed
+    retries: u8,
+    /// Where it sends. `None` until it is set, which every station hears
+    to: Option<[u8; ADDRESS_SIZE]>,
+    /// The addresses it listens on. Empty hears every address
+    pipes: Vec<[u8; ADDRESS_SIZE]>,
+    fifo: VecDeque<Vec<u8>>,
+}
+
+impl Station {
+    fn hears(&self, channel: u8, data_rate: DataRate, to: Option<[u8; ADDRESS_SIZE]>) -> bool {
+        let addressed = match to {
+            Some(to) => self.pipes.is_empty() || self.pipes.contains(&to),
+            None => true,
+        };
+        self.channel == channel && self.data_rate == data_rate && addressed
+    }
+}
+
+struct InFlight {
+    arrives_ms: u64,
+    from: usize,
+    channel: u8,
+    data_rate: DataRate,
+    to: Option<[u8; ADDRESS_SIZE]>,
+    payload: Vec<u8>,
+}
+
+struct Air {
+    model: ChannelModel,
+    rng: u64,
+    now_ms: u64,
+    fading: bool,
+    /// Bit `n` set means everything sent on channel `n` is lost
+    jammed: u128,
+    /// Bit `n` set means something was sent on channel `n` in the current millisecond
+    keyed: u128,
+    stations: Vec<Station>,
+    in_flight: Vec<InFlight>,
+    stats: AirStats,
+}
+
+impl Air {
+    /// xorshift64*
+    fn next_u64(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+/// The medium that simulated radios share
+#[derive(Clone)]
+pub struct SimAir(Arc<Mutex<Air>>);
+
+impl SimAir {
+    pub fn new(model: ChannelModel, seed: u64) -> Self {
+        Self(Arc::new(Mutex::new(Air {
+            model,
+            // xorshift gets stuck at zero
+            rng: seed | 1,
+            now_ms: 0,
+            fading: false,
+            jammed: 0,
+            keyed: 0,
+            stations: Vec::new(),
+            in_flight: Vec::new(),
+            stats: AirStats::default(),
+        })))
+    }
+
+    /// Adds a radio that hears every address, starting on the default settings
+    pub fn radio(&self) -> SimRadio {
+        self.radio_on(&[])
+    }
+
+    /// Adds a radio that only hears `pipes`, like an nRF24L01+ with those reading pipes open
+    pub fn radio_on(&self, pipes: &[[u8; ADDRESS_SIZE]]) -> SimRadio {
+        let mut air = self.0.lock().unwrap();
+        air.stations.push(Station {
+            channel: NodeConfig::DEFAULT.channel,
+            data_rate: NodeConfig::DEFAULT.data_rate,
+            pa_level: NodeConfig::DEFAULT.pa_level,
+            retries: 0,
+            to: None,
+            pipes: pipes.to_vec(),
+            fifo: VecDeque::new(),
+        });
+        SimRadio {
+            air: self.clone(),
+            id: air.stations.len() - 1,
+        }
+    }
+
+    pub fn now_ms(&self) -> u64 {
+        self.0.lock().unwrap().now_ms
+    }
+
+    pub fn stats(&self) -> AirStats {
+        self.0.lock().unwrap().stats
+    }
+
+    pub fn set_model(&self, model: ChannelModel) {
+        self.0.lock().unwrap().model = model;
+    }
+
+    /// Loses everything sent on `channels` from now on, as if something louder was there
+    pub fn jam(&self, channels: &[u8]) {
+        self.0.lock().unwrap().jammed = channels.iter().fold(0, |jammed, &c| jammed | 1 << c);
+    }
+
+    /// Moves time forward, delivering packets that arrive by then
+    pub fn advance(&self, ms: u64) {
+        let mut guard = self.0.lock().unwrap();
+        let air = &mut *guard;
+        air.now_ms += ms;
+        if ms > 0 {
+            air.keyed = 0;
+        }
+        let now = air.now_ms;
+        let (mut arrived, waiting) = air
+            .in_flight
+            .drain(..)
+            .partition::<Vec<_>, _>(|p| p.arrives_ms <= now);
+        air.in_flight = waiting;
+        // The sort is stable, so packets with the same arrival time keep the order they were sent
+        arrived.sort_by_key(|p| p.arrives_ms);
+        for packet in arrived {
+            let mut heard = false;
+            for id in 0..air.stations.len() {
+                let station = &mut air.stations[id];
+                if id == packet.from || !station.hears(packet.channel, packet.data_rate, packet.to)
+                {
+                    continue;
+                }
+                heard = true;
+                if station.fifo.len() < RX_FIFO {
+                    station.fifo.push_back(packet.payload.clone());
+                } else {
+                    air.stats.overflowed += 1;
+                }
+            }
+            if heard {
+                air.stats.delivered += 1;
+            }
+        }
+    }
+}
+
+/// A radio on a [`SimAir`]
+pub struct SimRadio {
+    air: SimAir,
+    id: usize,
+}
+
+impl Radio for SimRadio {
+    type Error = Infallible;
+
+    fn configure(&mut self, config: &NodeConfig) -> Result<(), Infallible> {
+        let mut air = self.air.0.lock().unwrap();
+        let station = &mut air.stations[self.id];
+        station.channel = config.channel;
+        station.data_rate = config.data_rate;
+        station.pa_level = config.pa_level;
+        Ok(())
+    }
+
+    fn set_destination(&mut self, address: &[u8; ADDRESS_SIZE]) -> Result<(), Infallible> {
+        let mut air = self.air.0.lock().unwrap();
+        air.stations[self.id].to = Some(*address);
+        Ok(())
+    }
+
+    fn send(&mut self, payload: &[u8]) -> Result<bool, Infallible> {
+        let mut air = self.air.0.lock().unwrap();
+        air.stats.sent += 1;
+        let model = air.model;
+        // Gilbert-Elliott: the channel flips between good and fading
+        let fading = air.fading;
+        air.fading = if fading {
+            !air.chance(model.burst_end)
+        } else {
+            air.chance(model.burst_start)
+        };
+        let Station {
+            channel,
+            data_rate,
+            pa_level,
+            to,
+            ..
+        } = air.stations[self.id];
+        // Nobody else listening means nobody to send the acknowledgement
+        let listening = air
+            .stations
+            .iter()
+            .enumerate()
+            .any(|(id, station)| id != self.id && station.hears(channel, data_rate, to));
+        air.keyed |= 1 << channel;
+        let jammed = air.jammed & 1 << channel != 0;
+        // Each try fails more often the closer the signal gets to the sensitivity, with only the
+        // last one counting towards the model's loss
+        let margin = power_dbm(pa_level) - model.path_loss_db - sensitivity_dbm(data_rate);
+        let fail = (0.5 - margin / 4.0).clamp(0.0, 1.0);
+        let mut retries = 0;
+        while fail > 0.0 && retries <= RETRIES && air.chance(fail) {
+            retries += 1;
+        }
+        let lost =
+            retries > RETRIES || air.fading || jammed || air.chance(model.loss) || !listening;
+        air.stations[self.id].retries = if lost { RETRIES } else { retries };
+        if lost {
+            air.stats.lost += 1;
+            return Ok(false);
+        }
+
+        let mut payload = payload.to_vec();
+        if !payload.is_empty() && air.chance(model.corruption) {
+            let bit = air.next_u64() as usize % (payload.len() * 8);
+            payload[bit / 8] ^= 1 << (bit % 8);
+            air.stats.corrupted += 1;
+        }
+        let jitter = match model.jitter_ms {
+            0 => 0,
+            jitter => air.next_u64() % (jitter + 1),
+        };
+        let arrives_ms = air.now_ms + model.latency_ms + jitter;
+        air.in_flight.push(InFlight {
+            arrives_ms,
+            from: self.id,
+            channel,
+            data_rate,
+            to,
+            payload,
+        });
+        Ok(true)
+    }
+
+    /// Only the path loss costs retries. Everything else the model loses is lost outright
+    fn retries(&mut self) -> Result<u8, Infallible> {
+        let air = self.air.0.lock().unwrap();
+        Ok(air.stations[self.id].retries)
+    }
+
+    fn data_available(&mut self) -> Result<bool, Infallible> {
+        let air = self.air.0.lock().unwrap();
+        Ok(!air.stations[self.id].fifo.is_empty())
+    }
+
+    fn receive(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+        let mut air = self.air.0.lock().unwrap();
+        match air.stations[self.id].fifo.pop_front() {
+            Some(payload) => {
+                let len = payload.len().min(buf.len());
+                buf[..len].copy_from_slice(&payload[..len]);
+                Ok(len)
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Jammed channels, and channels something was sent on this millisecond, are busy
+    fn carrier(&mut self) -> Result<bool, Infallible> {
+        let air = self.air.0.lock().unwrap();
+        let channel = air.stations[self.id].channel;
+        Ok((air.jammed | air.keyed) & 1 << channel != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_model() {
+        let air = SimAir::new(ChannelModel::PERFECT, 1);
+        let mut a = air.radio();
+        let mut b = air.radio();
+        assert!(a.send(&[1]).unwrap());
+        assert!(!b.data_available().unwrap());
+        air.advance(0);
+        let mut buf = [0u8; 32];
+        assert_eq!(b.receive(&mut buf), Ok(1));
+        assert_eq!(buf[0], 1);
+
+        // A radio on another channel hears nothing and sends nothing
+        let mut config = NodeConfig::DEFAULT;
+        config.channel = 90;
+        b.configure(&config).unwrap();
+        assert!(!a.send(&[2]).unwrap());
+        air.advance(1);
+        assert!(!b.data_available().unwrap());
+        a.configure(&config).unwrap();
+
+        // Nothing gets through a jammed channel, and the jammer is heard
+        air.jam(&[90]);
+        assert!(!a.send(&[3]).unwrap());
+        assert!(b.carrier().unwrap());
+        air.jam(&[]);
+        air.advance(1);
+        assert!(!b.carrier().unwrap());
+
+        // Only radios listening on the address hear it
+        let mut c = air.radio_on(&[*b"1node"]);
+        c.configure(&config).unwrap();
+        a.set_destination(b"2node").unwrap();
+        assert!(a.send(&[4]).unwrap());
+        air.advance(1);
+        assert!(!c.data_available().unwrap());
+        assert_eq!(b.receive(&mut buf), Ok(1));
+        a.set_destination(b"1node").unwrap();
+        assert!(a.send(&[5]).unwrap());
+        air.advance(1);
+        assert!(c.data_available().unwrap());
+        assert_eq!(b.receive(&mut buf), Ok(1));
+        // Out of the way of the rest
+        c.configure(&NodeConfig::DEFAULT).unwrap();
+
+        // Only three fit in the FIFO
+        for i in 0..5 {
+            assert!(a.send(&[i]).unwrap());
+        }
+        air.advance(1);
+        assert_eq!(air.stats().overflowed, 2);
+        while b.data_available().unwrap() {
+            b.receive(&mut buf).unwrap();
+        }
+
+        air.set_model(ChannelModel {
+            loss: 0.2,
+            burst_start: 0.05,
+            burst_end: 0.2,
+            corruption: 0.1,
+            latency_ms: 5,
+            jitter_ms: 20,
+            path_loss_db: 0.0,
+        });
+        let mut received = Vec::new();
+        for i in 0..1000u16 {
+            a.send(&i.to_le_bytes()).unwrap();
+            air.advance(1);
+            while b.data_available().unwrap() {
+                let len = b.receive(&mut buf).unwrap();
+                received.push(u16::from_le_bytes([buf[0], buf[1]]));
+                assert_eq!(len, 2);
+            }
+        }
+        let stats = air.stats();
+        // Random loss plus fades lasting five packets a twentieth of the time
+        let lost = stats.lost as f64 / 1000.0;
+        assert!((0.3..0.55).contains(&lost), "{:?}", stats);
+        assert!(stats.corrupted > 30, "{:?}", stats);
+        assert!(
+            received.windows(2).any(|w| w[0] > w[1]),
+            "nothing was reordered"
+        );
+    }
+
+    #[test]
+    fn path_loss() {
+        let air = SimAir::new(
+            ChannelModel {
+                path_loss_db: 84.0,
+                ..ChannelModel::PERFECT
+            },
+            5,
+        );
+        let mut a = air.radio();
+        let mut b = air.radio();
+        let mut config = NodeConfig {
+            data_rate: DataRate::Kbps250,
+            pa_level: PaLevel::Max,
+            ..NodeConfig::DEFAULT
+        };
+        // Returns how many of 100 were acknowledged and the retries they took
+        let mut send = |config: &NodeConfig| {
+            a.configure(config).unwrap();
+            b.configure(config).unwrap();
+            let (mut acked, mut retries) = (0, 0);
+            for _ in 0..100 {
+                acked += a.send(&[1]).unwrap() as u32;
+                retries += a.retries().unwrap() as u32;
+            }
+            (acked, retries)
+        };
+        // Plenty of margin at 250 kbps, a few retries at 1 Mbps and nothing at 2 Mbps
+        assert_eq!(send(&config), (100, 0));
+        config.data_rate = DataRate::Mbps1;
+        let (acked, retries) = send(&config);
+        assert_eq!(acked, 100);
+        assert!((10..100).contains(&retries), "{}", retries);
+        config.data_rate = DataRate::Mbps2;
+        assert_eq!(send(&config), (0, 1500));
+    }
+}
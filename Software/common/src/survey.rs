@@ -0,0 +1,190 @@
+//! Spectrum surveys, so that the channel can be picked by measuring instead of by guesswork.
+//!
+//! The receiver visits every channel in turn and samples the nRF24L01's received power detector
+//! (RPD), which is set by anything above -64 dBm, [`SAMPLES_PER_DWELL`] times. Each sweep adds
+//! to a count of busy samples for every channel. The host starts a survey with a
+//! [`crate::FrameKind::Survey`] frame holding the number of sweeps as a `u16`, and gets the
+//! results back in frames of `[first channel][occupancy; up to SURVEY_CHUNK]`, where occupancy
+//! is the fraction of samples that were busy scaled to 0..=255.
+
+use crate::{NodeConfig, Radio, MAX_CHANNEL, MAX_FRAME_PAYLOAD};
+
+/// The number of channels a survey covers
+pub const SURVEY_CHANNELS: usize = MAX_CHANNEL as usize + 1;
+
+/// Times the detector is read on each channel in a sweep
+pub const SAMPLES_PER_DWELL: u16 = 4;
+
+/// The most sweeps a survey can be asked for, so that the counts cannot overflow
+pub const MAX_SWEEPS: u16 = u16::MAX / SAMPLES_PER_DWELL;
+
+/// The most channels in one results frame
+pub const SURVEY_CHUNK: usize = MAX_FRAME_PAYLOAD - 1;
+
+/// Busy samples counted on every channel
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Survey {
+    busy: [u16; SURVEY_CHANNELS],
+    sweeps: u16,
+}
+
+impl Survey {
+    pub const fn new() -> Self {
+        Self {
+            busy: [0; SURVEY_CHANNELS],
+            sweeps: 0,
+        }
+    }
+
+    pub fn sweeps(&self) -> u16 {
+        self.sweeps
+    }
+
+    /// Samples every channel once with `radio`, using the rest of `config` as it is. `dwell` is
+    /// called before each sample and should wait long enough for the detector to settle, at
+    /// least 170us on the nRF24L01. The radio is left on the last channel
+    pub fn sweep<R: Radio>(
+        &mut self,
+        radio: &mut R,
+        config: &NodeConfig,
+        mut dwell: impl FnMut(),
+    ) -> Result<(), R::Error> {
+        for (channel, busy) in (0..=MAX_CHANNEL).zip(&mut self.busy) {
+            radio.configure(&NodeConfig { channel, ..*config })?;
+            for _ in 0..SAMPLES_PER_DWELL {
+                dwell();
+                if radio.carrier()? {
+                    *busy = busy.saturating_add(1);
+                }
+            }
+        }
+        self.sweeps = self.sweeps.saturating_add(1);
+        Ok(())
+    }
+
+    /// The fraction of samples on `channel` that were busy, 0 to 255. Anything heard at all is
+    /// at least 1
+    pub fn occupancy(&self, channel: u8) -> u8 {
+        let samples = self.sweeps as u32 * SAMPLES_PER_DWELL as u32;
+        let busy = self.busy[channel as usize] as u32;
+        match samples {
+            0 => 0,
+            samples => (busy * 255 / samples).max(busy.min(1)) as u8,
+        }
+    }
+
+    /// The payloads of the results frames, each with its length
+    pub fn chunks(&self) -> impl Iterator<Item = ([u8; MAX_FRAME_PAYLOAD], usize)> + '_ {
+        (0..SURVEY_CHANNELS)
+            .step_by(SURVEY_CHUNK)
+            .map(move |first| {
+                let count = SURVEY_CHUNK.min(SURVEY_CHANNELS - first);
+                let mut payload = [0u8; MAX_FRAME_PAYLOAD];
+                payload[0] = first as u8;
+                for (i, occupancy) in payload[1..=count].iter_mut().enumerate() {
+                    *occupancy = self.occupancy((first + i) as u8);
+                }
+                (payload, 1 + count)
+            })
+    }
+}
+
+impl Default for Survey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads a results frame, returning the first channel and the occupancy of each channel from it
+pub fn survey_chunk(payload: &[u8]) -> Option<(u8, &[u8])> {
+    let (&first, occupancy) = payload.split_first()?;
+    if occupancy.is_empty() || first as usize + occupancy.len() > SURVEY_CHANNELS {
+        return None;
+    }
+    Some((first, occupancy))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Busy on the channels in `busy`, and on every other sample of `half`
+    struct Detector {
+        channel: u8,
+        busy: &'static [u8],
+        half: u8,
+        samples: u32,
+    }
+
+    impl Radio for Detector {
+        type Error = ();
+
+        fn configure(&mut self, config: &NodeConfig) -> Result<(), ()> {
+            self.channel = config.channel;
+            Ok(())
+        }
+
+        fn set_destination(&mut self, _: &[u8; crate::ADDRESS_SIZE]) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn send(&mut self, _: &[u8]) -> Result<bool, ()> {
+            Ok(false)
+        }
+
+        fn retries(&mut self) -> Result<u8, ()> {
+            Ok(15)
+        }
+
+        fn data_available(&mut self) -> Result<bool, ()> {
+            Ok(false)
+        }
+
+        fn receive(&mut self, _: &mut [u8]) -> Result<usize, ()> {
+            Ok(0)
+        }
+
+        fn carrier(&mut self) -> Result<bool, ()> {
+            self.samples += 1;
+            let half = self.channel == self.half && self.samples & 1 == 0;
+            Ok(half || self.busy.contains(&self.channel))
+        }
+    }
+
+    #[test]
+    fn sweeps_and_chunks() {
+        let mut radio = Detector {
+            channel: 0,
+            busy: &[3, 125],
+            half: 40,
+            samples: 0,
+        };
+        let mut survey = Survey::new();
+        assert_eq!(survey.occupancy(3), 0);
+        let mut dwells = 0;
+        for _ in 0..3 {
+            survey
+                .sweep(&mut radio, &NodeConfig::DEFAULT, || dwells += 1)
+                .unwrap();
+        }
+        assert_eq!(survey.sweeps(), 3);
+        assert_eq!(dwells, 3 * SURVEY_CHANNELS * SAMPLES_PER_DWELL as usize);
+        assert_eq!(survey.occupancy(3), 255);
+        assert_eq!(survey.occupancy(125), 255);
+        assert_eq!(survey.occupancy(40), 127);
+        assert_eq!(survey.occupancy(41), 0);
+
+        let mut occupancy = Vec::new();
+        for (payload, len) in survey.chunks() {
+            let (first, chunk) = survey_chunk(&payload[..len]).unwrap();
+            assert_eq!(first as usize, occupancy.len());
+            occupancy.extend_from_slice(chunk);
+        }
+        assert_eq!(occupancy.len(), SURVEY_CHANNELS);
+        assert_eq!((occupancy[3], occupancy[40], occupancy[41]), (255, 127, 0));
+
+        assert_eq!(survey_chunk(&[]), None);
+        assert_eq!(survey_chunk(&[5]), None);
+        assert_eq!(survey_chunk(&[125, 1, 2]), None);
+    }
+}
@@ -0,0 +1,98 @@
+//! Battery telemetry from a node, sent up encrypted as a [`crate::MessageKind::Telemetry`]
+//! message every [`crate::Setting::TelemetryInterval`] seconds.
+//!
+//! The node reads its battery on an ADC pin behind a divider. The ADC measures against the
+//! supply, which sags along with the battery, so the internal reference is read too and the
+//! supply worked out from it.
+
+use core::fmt;
+
+/// The bytes [`Telemetry::to_bytes`] writes
+pub const TELEMETRY_BYTES: usize = 5;
+
+/// The STM32F103's internal reference, 1.16V to 1.24V
+pub const VREFINT_MV: u32 = 1200;
+
+/// Full scale of the 12 bit ADC
+const ADC_MAX: u32 = 4095;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Telemetry {
+    pub battery_mv: u16,
+    /// What the microcontroller runs from, after the regulator
+    pub supply_mv: u16,
+    /// From the microcontroller's own sensor, which is only good to a few degrees
+    pub temperature_c: i8,
+}
+
+impl Telemetry {
+    /// From ADC readings of the battery pin and of the internal reference. The battery is divided
+    /// down by `divider` before the pin
+    pub fn from_adc(battery: u16, vrefint: u16, divider: u32, temperature_c: i8) -> Self {
+        let supply_mv = VREFINT_MV * ADC_MAX / (vrefint as u32).max(1);
+        let battery_mv = battery as u32 * supply_mv / ADC_MAX * divider;
+        Self {
+            battery_mv: battery_mv.min(u16::MAX as u32) as u16,
+            supply_mv: supply_mv.min(u16::MAX as u32) as u16,
+            temperature_c,
+        }
+    }
+
+    /// `[battery_mv: u16 LE][supply_mv: u16 LE][temperature_c: i8]`
+    pub fn to_bytes(&self) -> [u8; TELEMETRY_BYTES] {
+        let mut bytes = [0u8; TELEMETRY_BYTES];
+        bytes[..2].copy_from_slice(&self.battery_mv.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.supply_mv.to_le_bytes());
+        bytes[4] = self.temperature_c as u8;
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes: [u8; TELEMETRY_BYTES] = bytes.try_into().ok()?;
+        Some(Self {
+            battery_mv: u16::from_le_bytes([bytes[0], bytes[1]]),
+            supply_mv: u16::from_le_bytes([bytes[2], bytes[3]]),
+            temperature_c: bytes[4] as i8,
+        })
+    }
+}
+
+impl fmt::Display for Telemetry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "battery {}.{:03} V, supply {}.{:03} V, {} C",
+            self.battery_mv / 1000,
+            self.battery_mv % 1000,
+            self.supply_mv / 1000,
+            self.supply_mv % 1000,
+            self.temperature_c
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_adc() {
+        // A 3.3V supply reads the reference as 1489, and half of a 3.7V battery as 2296
+        let telemetry = Telemetry::from_adc(2296, 1489, 2, 24);
+        assert_eq!(telemetry.supply_mv, 3300);
+        assert!((3695..=3705).contains(&telemetry.battery_mv));
+        assert_eq!(
+            Telemetry::from_bytes(&telemetry.to_bytes()),
+            Some(telemetry)
+        );
+        assert_eq!(Telemetry::from_bytes(&[0; 4]), None);
+
+        let cold = Telemetry {
+            battery_mv: 3050,
+            supply_mv: 3300,
+            temperature_c: -5,
+        };
+        assert_eq!(Telemetry::from_bytes(&cold.to_bytes()), Some(cold));
+        assert_eq!(cold.to_string(), "battery 3.050 V, supply 3.300 V, -5 C");
+    }
+}
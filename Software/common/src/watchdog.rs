@@ -0,0 +1,279 @@
+//! Keeps the firmware from hanging for good. The independent watchdog resets the board unless it
+//! is fed, and it is only fed while every task keeps checking in, see [`CheckIns`]. A task stuck
+//! on the radio's SPI bus stops checking in, and so do the tasks that never get to run behind it.
+//!
+//! On the next boot the firmware decodes why it was reset with [`ResetReason::from_csr`] and counts
+//! the resets since power on in the RAM it keeps across them, see [`ResetCounts::record`]. The
+//! receiver sends a [`ResetReport`] to the host in a [`crate::FrameKind::Reset`] frame and a node
+//! sends it up as a [`crate::MessageKind::Reset`] message.
+
+use core::fmt;
+
+use crate::fault::checksum;
+
+/// Which tasks have checked in lately, so the watchdog is only fed while all of them run
+pub struct CheckIns<const N: usize> {
+    /// The longest each task may go without checking in, in ticks
+    timeouts: [u64; N],
+    last: [u64; N],
+}
+
+impl<const N: usize> CheckIns<N> {
+    /// Every task counts as having checked in at `now`
+    pub fn new(timeouts: [u64; N], now: u64) -> Self {
+        Self {
+            timeouts,
+            last: [now; N],
+        }
+    }
+
+    pub fn check_in(&mut self, task: usize, now: u64) {
+        self.last[task] = now;
+    }
+
+    /// For tasks that run at an interval the host can change
+    pub fn set_timeout(&mut self, task: usize, timeout: u64) {
+        self.timeouts[task] = timeout;
+    }
+
+    /// The first task that has gone longer than its timeout without checking in
+    pub fn late(&self, now: u64) -> Option<usize> {
+        (0..N).find(|&task| now.saturating_sub(self.last[task]) > self.timeouts[task])
+    }
+
+    /// True if the watchdog should be fed
+    pub fn healthy(&self, now: u64) -> bool {
+        self.late(now).is_none()
+    }
+}
+
+/// How many reasons there are, see [`ResetCounts`]
+pub const RESET_REASONS: usize = 6;
+
+/// The bytes [`ResetReport::to_bytes`] writes
+pub const RESET_REPORT_BYTES: usize = 1 + 2 * RESET_REASONS;
+
+/// The words of RAM [`ResetCounts::record`] keeps, after the [`crate::Fault`] record
+pub const RESET_WORDS: usize = 1 + RESET_REASONS / 2 + 1;
+
+/// Marks RAM holding counts rather than whatever was there at power on
+const RESET_MAGIC: u32 = 0x5E5E_7C47;
+
+/// Why the board last came out of reset
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ResetReason {
+    PowerOn = 0,
+    /// The reset button, or a debugger
+    Pin = 1,
+    /// The firmware asked for it, as it does after a panic or HardFault
+    Software = 2,
+    /// The independent watchdog was not fed in time
+    Watchdog = 3,
+    WindowWatchdog = 4,
+    /// Entering standby or stop while the option bytes forbid it
+    LowPower = 5,
+}
+
+impl ResetReason {
+    pub const ALL: [Self; RESET_REASONS] = [
+        ResetReason::PowerOn,
+        ResetReason::Pin,
+        ResetReason::Software,
+        ResetReason::Watchdog,
+        ResetReason::WindowWatchdog,
+        ResetReason::LowPower,
+    ];
+
+    /// Decodes the flags in the STM32F1's RCC_CSR. The firmware clears them after reading them, so
+    /// they only say what happened since the last boot. Every internal reset also pulls NRST low
+    /// and sets the pin flag, which is why it comes last
+    pub fn from_csr(csr: u32) -> Self {
+        const FLAGS: [(u32, ResetReason); 5] = [
+            (1 << 31, ResetReason::LowPower),
+            (1 << 30, ResetReason::WindowWatchdog),
+            (1 << 29, ResetReason::Watchdog),
+            (1 << 28, ResetReason::Software),
+            (1 << 27, ResetReason::PowerOn),
+        ];
+        FLAGS
+            .iter()
+            .find(|&&(flag, _)| csr & flag != 0)
+            .map_or(ResetReason::Pin, |&(_, reason)| reason)
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            ResetReason::PowerOn => "power on",
+            ResetReason::Pin => "pin",
+            ResetReason::Software => "software",
+            ResetReason::Watchdog => "watchdog",
+            ResetReason::WindowWatchdog => "window watchdog",
+            ResetReason::LowPower => "low power",
+        }
+    }
+}
+
+impl TryFrom<u8> for ResetReason {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Self::ALL.get(value as usize).copied().ok_or(value)
+    }
+}
+
+/// The resets since power on for each [`ResetReason`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResetCounts(pub [u16; RESET_REASONS]);
+
+impl ResetCounts {
+    pub fn get(&self, reason: ResetReason) -> u16 {
+        self.0[reason as usize]
+    }
+
+    /// Counts a reset for `reason` in the RAM kept across resets and returns the counts. A power
+    /// on starts them again, as does RAM that does not hold them
+    pub fn record(words: &mut [u32; RESET_WORDS], reason: ResetReason) -> Self {
+        let mut counts = match Self::load(words) {
+            Some(counts) if reason != ResetReason::PowerOn => counts,
+            _ => Self::default(),
+        };
+        let count = &mut counts.0[reason as usize];
+        *count = count.saturating_add(1);
+        counts.store(words);
+        counts
+    }
+
+    fn store(&self, words: &mut [u32; RESET_WORDS]) {
+        words[0] = RESET_MAGIC;
+        for (word, pair) in words[1..].iter_mut().zip(self.0.chunks_exact(2)) {
+            *word = pair[0] as u32 | (pair[1] as u32) << 16;
+        }
+        words[RESET_WORDS - 1] = checksum(&words[..RESET_WORDS - 1]);
+    }
+
+    fn load(words: &[u32; RESET_WORDS]) -> Option<Self> {
+        if words[0] != RESET_MAGIC || words[RESET_WORDS - 1] != checksum(&words[..RESET_WORDS - 1])
+        {
+            return None;
+        }
+        let mut counts = Self::default();
+        for (pair, word) in counts.0.chunks_exact_mut(2).zip(&words[1..]) {
+            pair[0] = *word as u16;
+            pair[1] = (*word >> 16) as u16;
+        }
+        Some(counts)
+    }
+}
+
+/// What a board sends at boot: why it was reset and how often it has been since power on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResetReport {
+    pub reason: ResetReason,
+    pub counts: ResetCounts,
+}
+
+impl ResetReport {
+    /// The reason followed by the counts, little endian
+    pub fn to_bytes(&self) -> [u8; RESET_REPORT_BYTES] {
+        let mut bytes = [0u8; RESET_REPORT_BYTES];
+        bytes[0] = self.reason as u8;
+        for (out, count) in bytes[1..].chunks_exact_mut(2).zip(self.counts.0) {
+            out.copy_from_slice(&count.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != RESET_REPORT_BYTES {
+            return None;
+        }
+        let reason = ResetReason::try_from(bytes[0]).ok()?;
+        let mut counts = ResetCounts::default();
+        for (count, bytes) in counts.0.iter_mut().zip(bytes[1..].chunks_exact(2)) {
+            *count = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        Some(Self { reason, counts })
+    }
+}
+
+impl fmt::Display for ResetReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} reset, since power on:", self.reason.name())?;
+        let mut first = true;
+        for reason in ResetReason::ALL {
+            let count = self.counts.get(reason);
+            if count == 0 || reason == ResetReason::PowerOn {
+                continue;
+            }
+            let sep = if first { " " } else { ", " };
+            write!(f, "{}{} {}", sep, count, reason.name())?;
+            first = false;
+        }
+        if first {
+            f.write_str(" none")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_ins() {
+        let mut check_ins = CheckIns::new([100, 1000], 0);
+        assert!(check_ins.healthy(100));
+        assert_eq!(check_ins.late(101), Some(0));
+
+        check_ins.check_in(0, 90);
+        assert!(check_ins.healthy(190));
+        // The slow task has a longer timeout
+        assert_eq!(check_ins.late(1001), Some(0));
+        check_ins.check_in(0, 1000);
+        assert_eq!(check_ins.late(1001), Some(1));
+        check_ins.set_timeout(1, 5000);
+        assert!(check_ins.healthy(1001));
+    }
+
+    #[test]
+    fn reset_reasons() {
+        assert_eq!(ResetReason::from_csr(0x0C00_0000), ResetReason::PowerOn);
+        assert_eq!(ResetReason::from_csr(0x0400_0000), ResetReason::Pin);
+        assert_eq!(ResetReason::from_csr(0x1400_0000), ResetReason::Software);
+        assert_eq!(ResetReason::from_csr(0x2400_0000), ResetReason::Watchdog);
+        assert_eq!(ResetReason::from_csr(0), ResetReason::Pin);
+        for reason in ResetReason::ALL {
+            assert_eq!(ResetReason::try_from(reason as u8), Ok(reason));
+        }
+        assert_eq!(ResetReason::try_from(6), Err(6));
+    }
+
+    #[test]
+    fn reset_counts() {
+        // What RAM might hold at power on
+        let mut words = [0xA5A5_A5A5; RESET_WORDS];
+        let counts = ResetCounts::record(&mut words, ResetReason::Watchdog);
+        assert_eq!(counts.get(ResetReason::Watchdog), 1);
+
+        ResetCounts::record(&mut words, ResetReason::Watchdog);
+        let counts = ResetCounts::record(&mut words, ResetReason::Software);
+        assert_eq!(counts, ResetCounts([0, 0, 1, 2, 0, 0]));
+        let report = ResetReport {
+            reason: ResetReason::Software,
+            counts,
+        };
+        assert_eq!(ResetReport::from_bytes(&report.to_bytes()), Some(report));
+        assert_eq!(
+            report.to_string(),
+            "software reset, since power on: 1 software, 2 watchdog"
+        );
+
+        let counts = ResetCounts::record(&mut words, ResetReason::PowerOn);
+        assert_eq!(counts, ResetCounts([1, 0, 0, 0, 0, 0]));
+        words[2] ^= 1;
+        let counts = ResetCounts::record(&mut words, ResetReason::Pin);
+        assert_eq!(counts, ResetCounts([0, 1, 0, 0, 0, 0]));
+    }
+}
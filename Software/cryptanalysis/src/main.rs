@@ -1,35 +1,92 @@
-use rand::{RngCore, Rng};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+
+use common::{
+    CaptureReader, CipherBlock, FrameDecoder, FrameKind, Key, MainCipher, VarBlock, TAG_SIZE,
+};
+use rand::{Rng, RngCore};
 
 fn main() {
+    match std::env::args().nth(1) {
+        Some(path) => analyze_capture(&path),
+        None => demo(),
+    }
+}
+
+fn demo() {
     let mut rng = rand::rngs::OsRng;
     //Assert cryptographically secure
     let _: &dyn rand::CryptoRng = &rng;
 
-    let mut plaintext = [0u8; 32];
+    let mut plaintext = [0u8; 28];
     let mut key = [0u8; 1024];
     rng.fill_bytes(&mut plaintext);
     rng.fill_bytes(&mut key);
 
-    let cipher = common::a::Cipher::new(key, rng.gen());
-    let mut block = common::a::Block::new(plaintext);
-    cipher.cipher_block(0, &mut block).unwrap();
+    let key = Key::new(key);
+    let cipher = MainCipher::new(&key, rng.gen());
+    let mut block = CipherBlock::new(plaintext);
+    cipher.cipher_block(0, &mut block);
     println!("{:b}", V(block.0.to_vec()));
 }
 
+/// Reads a capture recorded by `desktop record` and looks at the ciphertext it contains
+fn analyze_capture(path: &str) {
+    let file = File::open(path).expect("Failed to open capture");
+    let reader = CaptureReader::new(BufReader::new(file)).expect("Failed to read capture header");
+    let header = reader.header().clone();
+    println!(
+        "Capture from device {:?} on channel {} with key {:016x}",
+        header.serial_number, header.channel, header.key_id
+    );
+
+    let mut decoder = FrameDecoder::new();
+    let mut ciphertext = Vec::new();
+    let mut index_uses: HashMap<u32, usize> = HashMap::new();
+    let mut bad_frames = 0;
+    for record in reader {
+        let record = record.expect("Capture is corrupt");
+        for &b in &record.bytes {
+            match decoder.push(b) {
+                Some(Ok(frame)) if frame.kind == FrameKind::Block => {
+                    let block = match VarBlock::from_bytes(frame.payload) {
+                        Some(block) => block,
+                        None => {
+                            bad_frames += 1;
+                            continue;
+                        }
+                    };
+                    *index_uses.entry(block.index()).or_insert(0) += 1;
+                    // Only the data words that were sent, the rest never left the node
+                    ciphertext.extend_from_slice(&block.as_bytes()[TAG_SIZE..]);
+                }
+                Some(Err(_)) => bad_frames += 1,
+                _ => {}
+            }
+        }
+    }
+
+    let blocks: usize = index_uses.values().sum();
+    println!("{} blocks, {} bad frames", blocks, bad_frames);
+    // Blocks with the same index were encrypted with the same part of the pad
+    let reused = index_uses.values().filter(|&&uses| uses > 1).count();
+    println!("{} indices were used more than once", reused);
+    if !ciphertext.is_empty() {
+        run_tests(&ciphertext, "Ciphertext bytes");
+    }
+}
+
 fn run_tests(buf: &[u8], msg: impl AsRef<str>) {
-    use rand_distr::{StudentT, Distribution, Normal, Exp};
     use statest::ks::*;
-    
-    let t = StudentT::new(1.0).unwrap();
-    let t_vec = (0..1000).map(|_| t.sample(&mut rand::thread_rng()))
-                         .collect::<Vec<f64>>();
-
-    let tdist = StudentT::new(1.0f64).unwrap();
-    let ndist = Normal::new(0.0f64, 1.0f64).unwrap();
-    let edist = Exp::new(1.0f64).unwrap();
-    println!("StudentT? {}", t_vec.ks1(&tdist, 0.05)); // true
-    println!("Normal? {}", t_vec.ks1(&ndist, 0.05)); // false
-    println!("Exponential? {}", t_vec.ks1(&edist, 0.05)); // false
+    use statrs::distribution::Uniform;
+
+    // Spread each byte uniformly over its bucket so that it can be compared against a
+    // continuous distribution
+    let mut rng = rand::thread_rng();
+    let samples: Vec<f64> = buf.iter().map(|&b| b as f64 + rng.gen::<f64>()).collect();
+    let uniform = Uniform::new(0.0, 256.0).unwrap();
+    println!("{} uniform? {}", msg.as_ref(), samples.ks1(&uniform, 0.05));
 }
 
 struct V(Vec<u8>);
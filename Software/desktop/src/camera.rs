@@ -0,0 +1,114 @@
+//! The camera board's serial console, which takes one command per line. So far it only has the
+//! one for XCLK, see [`xclk::Command`].
+
+use std::io::{self, ErrorKind, Write};
+use std::time::{Duration, Instant};
+
+use xclk::Command;
+
+use crate::device::DeviceArgs;
+use crate::transport::{PortProvider, Transport};
+
+/// How long the board gets to answer
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Moves XCLK as close to `hz` as the board can get, or asks for it if `None`, and prints what
+/// it is now. The board is not found by VID/PID like the receiver, its port has to be given
+pub fn xclk(
+    ports: &dyn PortProvider,
+    device: &DeviceArgs,
+    hz: Option<u32>,
+    mut out: impl Write,
+) -> io::Result<()> {
+    let command = match hz {
+        Some(hz) => Command::Set(hz),
+        None => Command::Get,
+    };
+    let path = device.port.as_ref().ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            "Give the camera board's serial port with --port",
+        )
+    })?;
+    let mut port = ports.open(path, device.baud)?;
+    let reply = exchange(&mut *port, command)?;
+    if !reply.starts_with("xclk ") {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("The camera answered `{}`", reply),
+        ));
+    }
+    writeln!(out, "{}", reply)
+}
+
+/// Sends `command` and returns the line that comes back, without its line ending
+fn exchange(port: &mut dyn Transport, command: Command) -> io::Result<String> {
+    port.write_all(format!("{}\n", command).as_bytes())?;
+    port.flush()?;
+    let start = Instant::now();
+    let mut line = Vec::new();
+    let mut byte = [0];
+    loop {
+        match port.read(&mut byte) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) => line.push(byte[0]),
+            Err(err) if err.kind() == ErrorKind::TimedOut => {
+                if start.elapsed() > TIMEOUT {
+                    return Err(io::Error::new(
+                        ErrorKind::TimedOut,
+                        "The camera did not answer",
+                    ));
+                }
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{device_args, MockPorts, PID, VID};
+    #[test]
+    fn xclk_command() {
+        use xclk::Sources;
+
+        let mut ports = MockPorts::default();
+        let receiver = ports.add("/dev/ttyACM0", VID, PID, "A");
+        let camera = ports.add("/dev/ttyUSB0", 0x0403, 0x6001, "C");
+        // Answers like the camera firmware, with its 16 MHz crystal and the PLL at 168 MHz
+        let sources = Sources {
+            hsi: Sources::HSI_HZ,
+            hse: 16_000_000,
+            pll: 168_000_000,
+        };
+        let mut current = sources.choose(24_000_000).unwrap();
+        camera.respond_with(move |bytes| {
+            match Command::parse(std::str::from_utf8(bytes).unwrap()) {
+                Some(Command::Set(hz)) => current = sources.choose(hz).unwrap(),
+                Some(Command::Get) => {}
+                None => return b"unknown command\r\n".to_vec(),
+            }
+            format!("{}\r\n", current).into_bytes()
+        });
+
+        let mut args = device_args(None);
+        let mut printed = Vec::new();
+        // The receiver matches the VID/PID, so the port is never guessed
+        let err = xclk(&ports, &args, None, &mut printed).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        args.port = Some("/dev/ttyUSB0".to_owned());
+        xclk(&ports, &args, None, &mut printed).unwrap();
+        xclk(&ports, &args, Some(42_000_000), &mut printed).unwrap();
+        assert_eq!(
+            String::from_utf8(printed).unwrap(),
+            "xclk 16000000 Hz from HSE/1\nxclk 42000000 Hz from PLL/4\n"
+        );
+        assert_eq!(camera.written(), b"xclk\nxclk 42000000\n");
+        // Nothing was sent to the receiver
+        assert_eq!(receiver.opens(), 0);
+    }
+}
@@ -0,0 +1,93 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use common::{CaptureHeader, CaptureReader, CaptureWriter};
+
+use crate::decode::Pipeline;
+use crate::device::DeviceArgs;
+use crate::keys::Keys;
+use crate::output::Output;
+use crate::supervisor;
+use crate::transport::PortProvider;
+
+pub fn unix_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+/// Listens to the device, printing decoded messages and saving every frame to `path`
+pub fn record(
+    ports: &dyn PortProvider,
+    device: &DeviceArgs,
+    keys: &Keys,
+    output: &mut Output,
+    path: &Path,
+    channel: u8,
+) -> io::Result<()> {
+    let port = device.resolve(ports)?;
+    let header = CaptureHeader {
+        start_unix_us: unix_us(),
+        channel,
+        key_id: keys.fingerprint(),
+        serial_number: port.serial_number.clone().unwrap_or_default(),
+    };
+    let mut writer = CaptureWriter::new(BufWriter::new(File::create(path)?), &header)?;
+    let mut pipeline = Pipeline::new(keys);
+
+    let start = Instant::now();
+    let result = supervisor::supervise(
+        ports,
+        device,
+        port,
+        supervisor::POLL_INTERVAL,
+        |bytes: &[u8]| {
+            let elapsed_us = start.elapsed().as_micros() as u64;
+            writer.record(elapsed_us, bytes)?;
+            writer.flush()?;
+            pipeline.feed(bytes, |event| {
+                output.event(header.start_unix_us + elapsed_us, &event)
+            })?;
+            output.flush()
+        },
+    );
+    writer.finish(start.elapsed().as_micros() as u64)?;
+    result
+}
+
+/// Feeds a capture back through the decode pipeline.
+/// `speed` scales the delay between frames: 1.0 is real time, 2.0 is twice as fast and 0.0 (or
+/// less) decodes everything as fast as possible
+pub fn replay(keys: &Keys, output: &mut Output, path: &Path, speed: f64) -> io::Result<()> {
+    let reader = CaptureReader::new(BufReader::new(File::open(path)?))?;
+    let header = reader.header().clone();
+    eprintln!(
+        "Capture from device {:?} on channel {}, started at {} us",
+        header.serial_number, header.channel, header.start_unix_us
+    );
+    if header.key_id != keys.fingerprint() {
+        eprintln!(
+            "Warning: capture was recorded with key {:016x} but key {:016x} is in use",
+            header.key_id,
+            keys.fingerprint()
+        );
+    }
+
+    let mut pipeline = Pipeline::new(keys);
+    let start = Instant::now();
+    for record in reader {
+        let record = record?;
+        if speed > 0.0 {
+            let due = Duration::from_secs_f64(record.timestamp_us as f64 / 1e6 / speed);
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                std::thread::sleep(wait);
+            }
+        }
+        let timestamp_us = header.start_unix_us + record.timestamp_us;
+        pipeline.feed(&record.bytes, |event| output.event(timestamp_us, &event))?;
+    }
+    output.flush()
+}
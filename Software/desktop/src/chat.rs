@@ -0,0 +1,219 @@
+//! Interactive chat with a node: lines typed on stdin are sent up, messages from every node are
+//! printed as they arrive with the name of the node that sent them.
+
+use std::io::{self, BufRead, ErrorKind, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+
+use common::{
+    Fault, MessageKind, Reassembler, ResetReport, Telemetry, MAX_MESSAGE, MAX_NODES, TAG_DATA,
+};
+
+use crate::decode::{Event, Pipeline};
+use crate::nodes::Registry;
+use crate::supervisor::Session;
+use crate::uplink::Uplink;
+
+/// Reads lines from stdin on another thread so that the serial port can be read at the same time
+pub fn stdin_lines() -> Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+pub struct Chat<'k, W: Write> {
+    pipeline: Pipeline<'k>,
+    /// One for each node, since each has its own sequence numbers
+    messages: [Reassembler<{ MAX_MESSAGE + 1 }>; MAX_NODES],
+    nodes: Registry,
+    uplink: Uplink<'k>,
+    lines: Receiver<String>,
+    out: W,
+}
+
+impl<'k, W: Write> Chat<'k, W> {
+    pub fn new(
+        pipeline: Pipeline<'k>,
+        uplink: Uplink<'k>,
+        nodes: Registry,
+        lines: Receiver<String>,
+        out: W,
+    ) -> Self {
+        Self {
+            pipeline,
+            messages: std::array::from_fn(|_| Reassembler::new()),
+            nodes,
+            uplink,
+            lines,
+            out,
+        }
+    }
+}
+
+impl<W: Write> Session for Chat<'_, W> {
+    fn on_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let Self {
+            pipeline,
+            messages,
+            nodes,
+            out,
+            ..
+        } = self;
+        pipeline.feed(bytes, |event| match event {
+            Event::Block(block) => match (block.node, block.sequence) {
+                (Some(node), Some(sequence)) if block.tag == TAG_DATA => {
+                    match messages[node as usize].push(sequence, &block.data) {
+                        Some((MessageKind::Text, text)) => writeln!(
+                            out,
+                            "{}: {}",
+                            nodes.name(node),
+                            String::from_utf8_lossy(text)
+                        ),
+                        Some((MessageKind::Fault, bytes)) => match Fault::from_bytes(bytes) {
+                            Some(fault) => {
+                                writeln!(out, "{}: reset after a {}", nodes.name(node), fault)
+                            }
+                            None => Ok(()),
+                        },
+                        Some((MessageKind::Reset, bytes)) => match ResetReport::from_bytes(bytes) {
+                            Some(report) => writeln!(out, "{}: {}", nodes.name(node), report),
+                            None => Ok(()),
+                        },
+                        Some((MessageKind::Telemetry, bytes)) => match Telemetry::from_bytes(bytes)
+                        {
+                            Some(telemetry) => writeln!(out, "{}: {}", nodes.name(node), telemetry),
+                            None => Ok(()),
+                        },
+                        // Only the update sending it cares about its progress
+                        Some((MessageKind::Ota, _)) | None => Ok(()),
+                    }
+                }
+                _ => Ok(()),
+            },
+            Event::Text(text) => writeln!(out, "receiver: {}", text),
+            Event::Fault(fault) => writeln!(out, "receiver: reset after a {}", fault),
+            Event::Reset(report) => writeln!(out, "receiver: {}", report),
+            Event::Counters(_) | Event::Radio(_) | Event::Survey { .. } => Ok(()),
+            Event::Error(err) => {
+                eprintln!("error: {}", err);
+                Ok(())
+            }
+        })?;
+        out.flush()
+    }
+
+    fn poll(&mut self, device: &mut dyn Write) -> io::Result<()> {
+        loop {
+            let line = match self.lines.try_recv() {
+                Ok(line) => line,
+                // Keep listening after stdin closes, there may still be replies on the way
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => return Ok(()),
+            };
+            match self.uplink.encode(MessageKind::Text, line.as_bytes()) {
+                Ok(frames) => {
+                    device.write_all(&frames)?;
+                    device.flush()?;
+                }
+                Err(e) if e.kind() == ErrorKind::InvalidInput => eprintln!("{}", e),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{
+        device_args, frame, sequence_file, test_keys, MockPorts, SimulatedDevice, PID, VID,
+    };
+    use crate::supervisor::supervise;
+    use common::{
+        FrameKind, HardFaultRegisters, PanicText, ResetCounts, ResetReason, MAX_FAULT_BYTES,
+    };
+    use std::time::Duration;
+    #[test]
+    fn chat_round_trip() {
+        use crate::uplink::UplinkArgs;
+
+        let keys = test_keys();
+        let mut ports = MockPorts::default();
+        let mut sim = SimulatedDevice::new(ports.add("/dev/ttyACM0", VID, PID, "A"), &keys);
+        sim.send_message("hello from the node, this takes more than one block");
+        sim.send_text("radio ok");
+        sim.send_message("second");
+        let mut text = PanicText::new();
+        std::fmt::Write::write_str(&mut text, "panicked at src/main.rs:212:14").unwrap();
+        let node_reset = ResetReport {
+            reason: ResetReason::Software,
+            counts: ResetCounts([0, 0, 1, 0, 0, 0]),
+        };
+        sim.send_reset(&node_reset);
+        sim.send_fault(&Fault::Panic(text));
+        let receiver_fault = Fault::HardFault(HardFaultRegisters {
+            pc: 0x0800_0400,
+            // DIVBYZERO
+            cfsr: 1 << 25,
+            ..Default::default()
+        });
+        let mut bytes = [0u8; MAX_FAULT_BYTES];
+        let len = receiver_fault.to_bytes(&mut bytes);
+        sim.device
+            .push_bytes(&frame(FrameKind::Fault, &bytes[..len]));
+        let receiver_reset = ResetReport {
+            reason: ResetReason::Watchdog,
+            counts: ResetCounts([0, 0, 2, 1, 0, 0]),
+        };
+        sim.device
+            .push_bytes(&frame(FrameKind::Reset, &receiver_reset.to_bytes()));
+
+        let (tx, rx) = mpsc::channel();
+        tx.send("hello node".to_owned()).unwrap();
+        tx.send("x".repeat(MAX_MESSAGE + 1)).unwrap();
+        tx.send("bye".to_owned()).unwrap();
+        drop(tx);
+
+        let args = UplinkArgs {
+            sequence_file: sequence_file("facilitador-chat").into(),
+            node: 0,
+        };
+        let mut printed = Vec::new();
+        let chat = Chat::new(
+            Pipeline::new(&keys),
+            Uplink::new(&keys, &args),
+            crate::nodes::Registry::default(),
+            rx,
+            &mut printed,
+        );
+        supervise(
+            &ports,
+            &device_args(None),
+            device_args(None).resolve(&ports).unwrap(),
+            Duration::ZERO,
+            chat,
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(printed).unwrap(),
+            format!(
+                "node 0: hello from the node, this takes more than one block\n\
+                 receiver: radio ok\n\
+                 node 0: second\n\
+                 node 0: software reset, since power on: 1 software\n\
+                 node 0: reset after a panic: panicked at src/main.rs:212:14\n\
+                 receiver: reset after a {}\n\
+                 receiver: watchdog reset, since power on: 2 software, 1 watchdog\n",
+                receiver_fault
+            )
+        );
+        // The line that was too long is skipped
+        assert_eq!(sim.uplink_messages(), ["hello node", "bye"]);
+        std::fs::remove_file(args.sequence_file).unwrap();
+    }
+}
@@ -0,0 +1,751 @@
+//! Reading and changing node settings over the radio with authenticated control messages.
+//!
+//! The node answers a request in its next transmission. Changing the channel, data rate or
+//! hopping also moves the receiver board, after the node has acknowledged on the old settings.
+//! The receiver learns the hop blacklist from the node's replies and needs no help. Changing the
+//! ARQ retries tells the receiver whether to send acks, and turning on auto rate tells it to
+//! follow the data rates the node announces.
+
+use std::io::{self, ErrorKind, Write};
+use std::time::{Duration, Instant};
+
+use clap::{Args, Subcommand};
+use common::{
+    encode_frame, Control, ControlError, FrameKind, ReceiverSetting, Setting, AUTH_KEY_SIZE,
+    MAX_ENCODED_FRAME, TAG_CONTROL,
+};
+
+use crate::decode::{Event, Pipeline};
+use crate::device::DeviceArgs;
+use crate::keys::Keys;
+use crate::nodes::Registry;
+use crate::supervisor::{self, Session};
+use crate::transport::PortProvider;
+use crate::uplink::{Uplink, UplinkArgs};
+
+#[derive(Args, Debug, Clone)]
+pub struct ConfigArgs {
+    /// Seconds to wait for each reply
+    #[clap(long, default_value_t = 10)]
+    pub timeout: u64,
+    #[clap(flatten)]
+    pub uplink: UplinkArgs,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Print the value of a node setting
+    Get {
+        #[clap(value_parser = parse_setting)]
+        setting: Setting,
+        #[clap(flatten)]
+        args: ConfigArgs,
+    },
+    /// Change a node setting. PA level takes min, low, high or max, data rate 250k, 1m or 2m,
+    /// hopping and auto rate off or on and the hop blacklist a mask of hop slots such as 0x30
+    Set {
+        #[clap(value_parser = parse_setting)]
+        setting: Setting,
+        #[clap(allow_hyphen_values = true)]
+        value: String,
+        #[clap(flatten)]
+        args: ConfigArgs,
+    },
+}
+
+fn parse_setting(name: &str) -> Result<Setting, String> {
+    Setting::ALL
+        .iter()
+        .copied()
+        .find(|s| s.name() == name)
+        .ok_or_else(|| {
+            let names: Vec<_> = Setting::ALL.iter().map(|s| s.name()).collect();
+            format!("expected one of {}", names.join(", "))
+        })
+}
+
+const PA_LEVELS: [&str; 4] = ["min", "low", "high", "max"];
+const DATA_RATES: [&str; 3] = ["250k", "1m", "2m"];
+const OFF_ON: [&str; 2] = ["off", "on"];
+
+/// Parses a value as [`format_value`] prints it. The node checks the range
+pub fn parse_value(setting: Setting, text: &str) -> io::Result<u32> {
+    let names: &[&str] = match setting {
+        Setting::PaLevel => &PA_LEVELS,
+        Setting::DataRate => &DATA_RATES,
+        Setting::Hopping | Setting::AutoRate | Setting::LowPower => &OFF_ON,
+        _ => &[],
+    };
+    if let Some(i) = names.iter().position(|&name| name == text) {
+        return Ok(i as u32);
+    }
+    let parsed = match setting {
+        Setting::CameraBrightness => text.parse::<i32>().map(|v| v as u32),
+        Setting::HopBlacklist => match text.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => text.parse::<u32>(),
+        },
+        _ => text.parse::<u32>(),
+    };
+    parsed.map_err(|e| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("Bad value {:?} for {}: {}", text, setting.name(), e),
+        )
+    })
+}
+
+pub fn format_value(setting: Setting, value: u32) -> String {
+    let name = match setting {
+        Setting::PaLevel => PA_LEVELS.get(value as usize),
+        Setting::DataRate => DATA_RATES.get(value as usize),
+        Setting::Hopping | Setting::AutoRate | Setting::LowPower => OFF_ON.get(value as usize),
+        Setting::CameraBrightness => return (value as i32).to_string(),
+        Setting::HopBlacklist => return format!("{:#010x}", value),
+        _ => None,
+    };
+    match name {
+        Some(name) => name.to_string(),
+        None => value.to_string(),
+    }
+}
+
+enum State {
+    Unsent,
+    /// Waiting for the node to answer the uplink block with this sequence number
+    Node {
+        sequence: u32,
+        since: Instant,
+    },
+    /// The node changed a setting the receiver has to follow
+    MoveReceiver(ReceiverSetting),
+    Receiver {
+        setting: ReceiverSetting,
+        since: Instant,
+    },
+    Done,
+}
+
+/// Sends one control request and waits for the replies, printing the resulting value
+pub struct ConfigSession<'k, W: Write> {
+    pipeline: Pipeline<'k>,
+    uplink: Uplink<'k>,
+    auth_key: [u8; AUTH_KEY_SIZE],
+    request: Control,
+    timeout: Duration,
+    state: State,
+    out: W,
+}
+
+impl<'k, W: Write> ConfigSession<'k, W> {
+    pub fn new(
+        keys: &'k Keys,
+        uplink: Uplink<'k>,
+        request: Control,
+        timeout: Duration,
+        out: W,
+    ) -> Self {
+        Self {
+            pipeline: Pipeline::new(keys),
+            uplink,
+            auth_key: keys.auth_key,
+            request,
+            timeout,
+            state: State::Unsent,
+            out,
+        }
+    }
+
+    fn on_reply(&mut self, reply: Control) -> io::Result<()> {
+        let sequence = match self.state {
+            State::Node { sequence, .. } => sequence,
+            _ => return Ok(()),
+        };
+        match reply {
+            Control::Ack {
+                request,
+                setting,
+                value,
+            } if request == sequence => {
+                writeln!(
+                    self.out,
+                    "{} = {}",
+                    setting.name(),
+                    format_value(setting, value)
+                )?;
+                let moves_receiver = matches!(
+                    setting,
+                    Setting::Channel
+                        | Setting::DataRate
+                        | Setting::Hopping
+                        | Setting::ArqRetries
+                        | Setting::AutoRate
+                );
+                self.state = match self.request {
+                    Control::Set(..) if moves_receiver => {
+                        State::MoveReceiver(ReceiverSetting { setting, value })
+                    }
+                    _ => State::Done,
+                };
+                Ok(())
+            }
+            Control::Nack {
+                request,
+                setting,
+                reason,
+            } if request == sequence => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("The node refused {}: {:?}", setting.name(), reason),
+            )),
+            // An answer to an earlier request, or a setting the node changed by itself
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Session for ConfigSession<'_, W> {
+    fn on_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let mut events = Vec::new();
+        self.pipeline.feed(bytes, |event| {
+            events.push(event);
+            Ok::<_, io::Error>(())
+        })?;
+        for event in events {
+            match event {
+                // Other nodes number their replies from their own sequence numbers
+                Event::Block(block)
+                    if block.tag == TAG_CONTROL && block.node == Some(self.uplink.node()) =>
+                {
+                    match Control::open(block.index, &block.data, &self.auth_key) {
+                        Ok(reply) => self.on_reply(reply)?,
+                        Err(ControlError::BadMac) => {
+                            eprintln!("Ignoring a control block that failed authentication")
+                        }
+                        Err(ControlError::Malformed) => {
+                            eprintln!("Ignoring a malformed control block")
+                        }
+                    }
+                }
+                Event::Radio(applied) => {
+                    if let State::Receiver { setting, .. } = self.state {
+                        if applied == setting {
+                            writeln!(
+                                self.out,
+                                "receiver: {} = {}",
+                                setting.setting.name(),
+                                format_value(setting.setting, setting.value)
+                            )?;
+                            self.state = State::Done;
+                        }
+                    }
+                }
+                Event::Text(text) => eprintln!("receiver: {}", text),
+                Event::Fault(fault) => eprintln!("receiver: reset after a {}", fault),
+                Event::Reset(report) => eprintln!("receiver: {}", report),
+                Event::Error(err) => eprintln!("error: {}", err),
+                Event::Block(_) | Event::Counters(_) | Event::Survey { .. } => {}
+            }
+        }
+        self.out.flush()
+    }
+
+    fn poll(&mut self, device: &mut dyn Write) -> io::Result<()> {
+        match self.state {
+            State::Unsent => {
+                let (sequence, frames) = self.uplink.encode_control(&self.request)?;
+                device.write_all(&frames)?;
+                device.flush()?;
+                self.state = State::Node {
+                    sequence,
+                    since: Instant::now(),
+                };
+            }
+            State::MoveReceiver(setting) => {
+                let mut frame = [0u8; MAX_ENCODED_FRAME];
+                let len = encode_frame(FrameKind::Radio, &setting.to_bytes(), &mut frame);
+                device.write_all(&frame[..len])?;
+                device.flush()?;
+                self.state = State::Receiver {
+                    setting,
+                    since: Instant::now(),
+                };
+            }
+            State::Node { since, .. } | State::Receiver { since, .. }
+                if since.elapsed() >= self.timeout =>
+            {
+                let who = match self.state {
+                    State::Node { .. } => "node",
+                    _ => "receiver",
+                };
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!("No reply from the {} after {:?}", who, self.timeout),
+                ));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn done(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+}
+
+pub fn run(
+    ports: &dyn PortProvider,
+    device: &DeviceArgs,
+    keys: &Keys,
+    nodes: &Registry,
+    command: &ConfigCommand,
+    out: impl Write,
+) -> io::Result<()> {
+    let (request, args) = match command {
+        ConfigCommand::Get { setting, args } => (Control::Get(*setting), args),
+        ConfigCommand::Set {
+            setting,
+            value,
+            args,
+        } => (Control::Set(*setting, parse_value(*setting, value)?), args),
+    };
+    nodes.check(args.uplink.node)?;
+    let session = ConfigSession::new(
+        keys,
+        Uplink::new(keys, &args.uplink),
+        request,
+        Duration::from_secs(args.timeout),
+        out,
+    );
+    supervisor::run(ports, device, session)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{
+        auth_key_file, device_args, frame, load_keys, sequence_file, test_keys, MockPorts,
+        RadioState, SimulatedNode, Step, PID, VID,
+    };
+    use crate::supervisor::supervise;
+    use common::{ChannelModel, SimAir};
+
+    #[test]
+    fn values() {
+        for setting in Setting::ALL {
+            assert_eq!(parse_setting(setting.name()), Ok(setting));
+        }
+        for (setting, text, value) in [
+            (Setting::Channel, "76", 76),
+            (Setting::PaLevel, "high", 2),
+            (Setting::PaLevel, "3", 3),
+            (Setting::DataRate, "250k", 0),
+            (Setting::CameraBrightness, "-2", -2i32 as u32),
+            (Setting::Hopping, "on", 1),
+            (Setting::AutoRate, "off", 0),
+            (Setting::LowPower, "on", 1),
+            (Setting::HopBlacklist, "0x00000030", 0x30),
+        ] {
+            assert_eq!(parse_value(setting, text).unwrap(), value);
+            if text != "3" {
+                assert_eq!(format_value(setting, value), text);
+            }
+        }
+        assert_eq!(format_value(Setting::PaLevel, 9), "9");
+        assert!(parse_value(Setting::Channel, "-1").is_err());
+    }
+    fn config(
+        ports: &MockPorts,
+        keys: &Keys,
+        request: Control,
+        sequence_file: &str,
+    ) -> (io::Result<()>, String) {
+        let args = UplinkArgs {
+            sequence_file: sequence_file.into(),
+            node: 0,
+        };
+        let mut printed = Vec::new();
+        let session = ConfigSession::new(
+            keys,
+            Uplink::new(keys, &args),
+            request,
+            Duration::ZERO,
+            &mut printed,
+        );
+        let device = device_args(None);
+        let result = supervise(
+            ports,
+            &device,
+            device.resolve(ports).unwrap(),
+            Duration::ZERO,
+            session,
+        );
+        (result, String::from_utf8(printed).unwrap())
+    }
+
+    #[test]
+    fn config_round_trip() {
+        let keys = test_keys();
+        let mut ports = MockPorts::default();
+        let device = ports.add("/dev/ttyACM0", VID, PID, "A");
+        let state = SimulatedNode::attach(&device, test_keys());
+        // The node would take a reused sequence number for a replay
+        let seq = sequence_file("facilitador-config");
+
+        // The session sends its request after the first read
+        device.push(Step::Timeout);
+        let (result, printed) = config(&ports, &keys, Control::Set(Setting::Channel, 76), &seq);
+        result.unwrap();
+        assert_eq!(printed, "channel = 76\nreceiver: channel = 76\n");
+        assert_eq!(state.lock().unwrap().node.config().channel, 76);
+        assert_eq!(state.lock().unwrap().relay.config().channel, 76);
+
+        device.push(Step::Timeout);
+        let (result, printed) = config(&ports, &keys, Control::Get(Setting::PaLevel), &seq);
+        result.unwrap();
+        assert_eq!(printed, "pa-level = max\n");
+
+        device.push(Step::Timeout);
+        let (result, printed) = config(&ports, &keys, Control::Set(Setting::TxInterval, 1), &seq);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(printed, "");
+        assert_eq!(state.lock().unwrap().node.config().tx_interval_ms, 1000);
+        std::fs::remove_file(seq).unwrap();
+    }
+
+    #[test]
+    fn config_needs_auth_key() {
+        let keys = test_keys();
+        let mut node_keys = test_keys();
+        node_keys.auth_key[0] ^= 1;
+        let mut ports = MockPorts::default();
+        let device = ports.add("/dev/ttyACM0", VID, PID, "A");
+        let state = SimulatedNode::attach(&device, node_keys);
+        let seq = sequence_file("facilitador-config-auth");
+
+        device.push(Step::Timeout);
+        device.push(Step::Timeout);
+        let (result, _) = config(&ports, &keys, Control::Set(Setting::Channel, 20), &seq);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+        assert_eq!(state.lock().unwrap().node.config().channel, 8);
+        std::fs::remove_file(seq).unwrap();
+    }
+
+    #[test]
+    fn config_command() {
+        use clap::Parser;
+        use common::CameraConfig;
+
+        let mut ports = MockPorts::default();
+        let device = ports.add("/dev/ttyACM0", VID, PID, "A");
+        let auth = auth_key_file("facilitador-config-command-auth");
+        let state = SimulatedNode::attach(&device, load_keys(&auth));
+        state.lock().unwrap().node.config_mut().camera = Some(CameraConfig::DEFAULT);
+        device.push(Step::Timeout);
+
+        let seq = sequence_file("facilitador-config-command");
+        let cli = crate::Cli::parse_from([
+            "desktop",
+            "config",
+            "set",
+            "camera-brightness",
+            "-1",
+            "--sequence-file",
+            &seq,
+            "--auth-key",
+            &auth,
+        ]);
+        crate::run(&cli, &ports).unwrap();
+        assert_eq!(
+            state
+                .lock()
+                .unwrap()
+                .node
+                .config()
+                .camera
+                .unwrap()
+                .brightness,
+            -1
+        );
+        std::fs::remove_file(seq).unwrap();
+        std::fs::remove_file(auth).unwrap();
+    }
+    #[test]
+    fn hopping_link() {
+        use common::HopSet;
+
+        let keys: &'static Keys = Box::leak(Box::new(test_keys()));
+        let model = ChannelModel {
+            loss: 0.05,
+            ..ChannelModel::PERFECT
+        };
+        let mut radio = RadioState::new(keys, SimAir::new(model, 3));
+        let mut pipeline = Pipeline::new(keys);
+        let args = UplinkArgs {
+            sequence_file: sequence_file("facilitador-hopping").into(),
+            node: 0,
+        };
+        let mut uplink = Uplink::new(keys, &args);
+        let hops = HopSet::new(&keys.auth_key);
+        // Blocks heard in each second
+        let mut heard = vec![0; 40];
+
+        for ms in 0..40_000u64 {
+            // Turned on like `config set hopping on` does, asking again until the node answers
+            if ms % 500 == 0 && !radio.relay.config().hopping {
+                let (_, request) = uplink
+                    .encode_control(&Control::Set(Setting::Hopping, 1))
+                    .unwrap();
+                radio.host_writes(&request);
+            }
+            match ms {
+                // Two of the hop channels stop working
+                5_000 => radio.air.jam(&[hops.channel(4), hops.channel(9)]),
+                // Everything stops working long enough for the receiver to lose track
+                20_000 => radio.air.set_model(ChannelModel { loss: 1.0, ..model }),
+                22_000 => radio.air.set_model(model),
+                _ => {}
+            }
+            if ms % 50 == 0 {
+                radio.transmit();
+            }
+            let bytes = radio.advance(1);
+            let mut events = Vec::new();
+            pipeline
+                .feed(&bytes, |event| {
+                    events.push(event);
+                    Ok::<_, io::Error>(())
+                })
+                .unwrap();
+            for event in events {
+                let block = match event {
+                    Event::Block(block) => block,
+                    _ => continue,
+                };
+                heard[ms as usize / 1000] += 1;
+                if block.tag != TAG_CONTROL {
+                    continue;
+                }
+                if let Ok(Control::Ack {
+                    setting: Setting::Hopping,
+                    value,
+                    ..
+                }) = Control::open(block.index, &block.data, &keys.auth_key)
+                {
+                    let setting = ReceiverSetting {
+                        setting: Setting::Hopping,
+                        value,
+                    };
+                    radio.host_writes(&frame(FrameKind::Radio, &setting.to_bytes()));
+                }
+            }
+        }
+
+        assert!(radio.relay.config().hopping);
+        // The jammed slots were blacklisted and the receiver was told. Losing the receiver now and
+        // then may blacklist one or two more
+        let blacklist = radio.node.config().hop_blacklist;
+        assert_eq!(
+            blacklist & (1 << 4 | 1 << 9),
+            1 << 4 | 1 << 9,
+            "{:#x}",
+            blacklist
+        );
+        assert!(blacklist.count_ones() <= 4, "{:#x}", blacklist);
+        assert_eq!(radio.follower.blacklist(), blacklist);
+        // Most of the twenty blocks a second get through once the blacklist is in place, and
+        // again after the receiver found the node
+        assert!(heard[15..20].iter().all(|&n| n >= 15), "{:?}", heard);
+        assert_eq!(heard[21], 0, "{:?}", heard);
+        assert!(heard[30..].iter().all(|&n| n >= 15), "{:?}", heard);
+        std::fs::remove_file(args.sequence_file).unwrap();
+    }
+    /// Runs a hopping link over a lossy channel with `arq_retries`, returning the blocks a second
+    /// the host heard at least once and the fraction of the node's blocks that made it
+    fn goodput(arq_retries: u32, name: &str) -> (f64, f64) {
+        use std::collections::HashSet;
+
+        let keys: &'static Keys = Box::leak(Box::new(test_keys()));
+        let model = ChannelModel {
+            loss: 0.2,
+            ..ChannelModel::PERFECT
+        };
+        let mut radio = RadioState::new(keys, SimAir::new(model, 11));
+        let mut pipeline = Pipeline::new(keys);
+        let args = UplinkArgs {
+            sequence_file: sequence_file(name).into(),
+            node: 0,
+        };
+        let mut uplink = Uplink::new(keys, &args);
+        // Measured over 30 seconds once the settings are in place, with two more for the last
+        // blocks to be sent again
+        let mut start = None;
+        let mut sent = 0..0;
+        let mut heard = HashSet::new();
+
+        for ms in 0.. {
+            let config = *radio.relay.config();
+            let wanted = [(Setting::Hopping, 1), (Setting::ArqRetries, arq_retries)];
+            let missing = wanted
+                .into_iter()
+                .find(|&(setting, value)| config.get(setting) != Ok(value));
+            match (missing, start) {
+                (Some((setting, value)), None) if ms % 500 == 0 => {
+                    let (_, request) = uplink
+                        .encode_control(&Control::Set(setting, value))
+                        .unwrap();
+                    radio.host_writes(&request);
+                }
+                (None, None) => start = Some(ms),
+                _ => {}
+            }
+            let measuring = matches!(start, Some(start) if ms < start + 30_000);
+            if matches!(start, Some(start) if ms >= start + 32_000) {
+                break;
+            }
+            if ms % 50 == 0 {
+                let index = &mut radio.index;
+                let mut sequence = 0;
+                radio
+                    .node
+                    .transmit(&mut radio.node_radio, ms, |words| {
+                        index
+                            .next(words)
+                            .inspect(|position| sequence = position.sequence)
+                    })
+                    .unwrap();
+                if measuring {
+                    if sent.is_empty() {
+                        sent.start = sequence;
+                    }
+                    sent.end = sequence + 1;
+                }
+            }
+            let bytes = radio.advance(1);
+            let mut events = Vec::new();
+            pipeline
+                .feed(&bytes, |event| {
+                    events.push(event);
+                    Ok::<_, io::Error>(())
+                })
+                .unwrap();
+            for event in events {
+                let block = match event {
+                    Event::Block(block) => block,
+                    _ => continue,
+                };
+                heard.extend(block.sequence);
+                if block.tag != TAG_CONTROL {
+                    continue;
+                }
+                if let Ok(Control::Ack { setting, value, .. }) =
+                    Control::open(block.index, &block.data, &keys.auth_key)
+                {
+                    let setting = ReceiverSetting { setting, value };
+                    radio.host_writes(&frame(FrameKind::Radio, &setting.to_bytes()));
+                }
+            }
+        }
+
+        std::fs::remove_file(args.sequence_file).unwrap();
+        let delivered = sent.clone().filter(|s| heard.contains(s)).count();
+        (
+            delivered as f64 / 30.0,
+            delivered as f64 / sent.len() as f64,
+        )
+    }
+
+    #[test]
+    fn arq_goodput() {
+        let (without, without_ratio) = goodput(0, "facilitador-goodput-off");
+        let (with, with_ratio) = goodput(3, "facilitador-goodput-on");
+        println!(
+            "ARQ off {:.1} blocks/s ({:.1}%), on {:.1} blocks/s ({:.1}%)",
+            without,
+            without_ratio * 100.0,
+            with,
+            with_ratio * 100.0
+        );
+        assert!(without_ratio < 0.9, "{}", without_ratio);
+        assert!(with_ratio > 0.98, "{}", with_ratio);
+        assert!(with > without * 1.1);
+    }
+    #[test]
+    fn auto_rate_follows_the_link() {
+        use common::DataRate;
+
+        let keys: &'static Keys = Box::leak(Box::new(test_keys()));
+        let model = ChannelModel {
+            path_loss_db: 50.0,
+            ..ChannelModel::PERFECT
+        };
+        let mut radio = RadioState::new(keys, SimAir::new(model, 5));
+        let mut pipeline = Pipeline::new(keys);
+        let args = UplinkArgs {
+            sequence_file: sequence_file("facilitador-auto-rate").into(),
+            node: 0,
+        };
+        let mut uplink = Uplink::new(keys, &args);
+        // The receiver's data rate and the blocks the host heard, each second
+        let mut rates = Vec::new();
+        let mut heard = vec![0; 60];
+
+        for ms in 0..60_000 {
+            if !radio.relay.config().auto_rate && ms % 500 == 0 {
+                let (_, request) = uplink
+                    .encode_control(&Control::Set(Setting::AutoRate, 1))
+                    .unwrap();
+                radio.host_writes(&request);
+            }
+            // The node moves out of range of 1 Mbps
+            if ms == 20_000 {
+                radio.air.set_model(ChannelModel {
+                    path_loss_db: 86.0,
+                    ..model
+                });
+            }
+            if ms % 50 == 0 {
+                radio.transmit();
+            }
+            if ms % 1000 == 0 {
+                rates.push(radio.relay.config().data_rate);
+            }
+            let bytes = radio.advance(1);
+            let mut events = Vec::new();
+            pipeline
+                .feed(&bytes, |event| {
+                    events.push(event);
+                    Ok::<_, io::Error>(())
+                })
+                .unwrap();
+            for event in events {
+                let block = match event {
+                    Event::Block(block) => block,
+                    _ => continue,
+                };
+                heard[ms as usize / 1000] += 1;
+                if block.tag != TAG_CONTROL {
+                    continue;
+                }
+                if let Ok(Control::Ack { setting, value, .. }) =
+                    Control::open(block.index, &block.data, &keys.auth_key)
+                {
+                    let setting = ReceiverSetting { setting, value };
+                    radio.host_writes(&frame(FrameKind::Radio, &setting.to_bytes()));
+                }
+            }
+        }
+
+        // Up to 2 Mbps next to the receiver, then down to 250 kbps when only that gets through,
+        // with the receiver following each time
+        assert_eq!(rates[19], DataRate::Mbps2, "{:?}", rates);
+        assert!(
+            rates[50..].iter().all(|&rate| rate == DataRate::Kbps250),
+            "{:?}",
+            rates
+        );
+        assert_eq!(radio.node.config().data_rate, DataRate::Kbps250);
+        assert!(heard[10..20].iter().all(|&n| n >= 19), "{:?}", heard);
+        assert!(heard[30..].iter().all(|&n| n >= 15), "{:?}", heard);
+        std::fs::remove_file(args.sequence_file).unwrap();
+    }
+}
@@ -0,0 +1,399 @@
+use std::fmt;
+
+use common::{
+    survey_chunk, Fault, FrameDecoder, FrameError, FrameKind, IndexedBlock, PadRegion,
+    ReceiverCounters, ReceiverSetting, ReplayCheck, ReplayWindow, ResetReport, Tag, VarBlock,
+    MAX_NODES,
+};
+
+use crate::keys::{Cipher, Keys};
+
+/// An [`IndexedBlock`] after decryption
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedBlock {
+    /// The index exactly as it was sent
+    pub index: u32,
+    /// The node that sent this block, worked out from which node's downlink region its pad is in.
+    /// `None` if it is in none of them, so it was not sent by a node
+    pub node: Option<u8>,
+    /// The sender's sequence number, worked out from the index. `None` along with `node`
+    pub sequence: Option<u32>,
+    pub tag: usize,
+    pub data: [u32; 7],
+    /// The block exactly as it was received. Blocks only carry the data words their sender
+    /// needed, so this is between [`common::TAG_SIZE`] and 32 bytes
+    pub ciphertext: Vec<u8>,
+    /// Whether this sequence number was seen before. Blocks that are not fresh are still decoded so that
+    /// retransmissions and replays can be inspected
+    pub replay: ReplayCheck,
+}
+
+impl DecodedBlock {
+    /// Returns the bytes of the block after decryption, laid out like an [`IndexedBlock`] and as
+    /// long as the ciphertext
+    pub fn plaintext(&self) -> Vec<u8> {
+        let mut block = IndexedBlock::new();
+        block.tag().set_index(self.index);
+        block.tag().set_tag(self.tag);
+        *block.data_mut() = self.data;
+        block.as_bytes()[..self.ciphertext.len()].to_vec()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    Frame(FrameError),
+    /// A block frame did not have the length of a [`VarBlock`]. Holds the frame payload
+    BlockLength(Vec<u8>),
+    /// A counters frame was not [`ReceiverCounters::SIZE`] bytes. Holds the payload length
+    CountersLength(usize),
+    /// A radio frame did not hold a valid [`ReceiverSetting`]. Holds the frame payload
+    BadRadio(Vec<u8>),
+    /// A survey frame did not fit the channels. Holds the frame payload
+    BadSurvey(Vec<u8>),
+    /// A fault frame did not hold a valid [`Fault`]. Holds the frame payload
+    BadFault(Vec<u8>),
+    /// A reset frame did not hold a valid [`ResetReport`]. Holds the frame payload
+    BadReset(Vec<u8>),
+    /// A frame only the host sends
+    Unexpected(FrameKind),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Frame(e) => write!(f, "bad frame: {:?}", e),
+            DecodeError::BlockLength(raw) => write!(f, "block frame has {} bytes", raw.len()),
+            DecodeError::CountersLength(len) => write!(f, "counters frame has {} bytes", len),
+            DecodeError::BadRadio(raw) => write!(f, "bad radio frame {:02x?}", raw),
+            DecodeError::BadSurvey(raw) => write!(f, "bad survey frame {:02x?}", raw),
+            DecodeError::BadFault(raw) => write!(f, "bad fault frame {:02x?}", raw),
+            DecodeError::BadReset(raw) => write!(f, "bad reset frame {:02x?}", raw),
+            DecodeError::Unexpected(kind) => write!(f, "unexpected {:?} frame", kind),
+        }
+    }
+}
+
+/// Something the receiver told us
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Block(DecodedBlock),
+    Text(String),
+    /// The receiver reported its counters
+    Counters(ReceiverCounters),
+    /// The receiver applied a setting the host sent it
+    Radio(ReceiverSetting),
+    /// Survey results for the channels from `first` on, see [`common::Survey`]
+    Survey {
+        first: u8,
+        occupancy: Vec<u8>,
+    },
+    /// Why the receiver went down before its last reset
+    Fault(Fault),
+    /// Why the receiver was last reset, sent at boot
+    Reset(ResetReport),
+    Error(DecodeError),
+}
+
+/// Decrypts a raw block exactly as it was received over the air.
+/// The node and sequence number are left for the caller to fill in
+pub fn decrypt_block(cipher: &Cipher<'_>, raw: &[u8]) -> Result<DecodedBlock, DecodeError> {
+    let mut block =
+        VarBlock::from_bytes(raw).ok_or_else(|| DecodeError::BlockLength(raw.to_vec()))?;
+    block.do_cipher(cipher);
+    Ok(DecodedBlock {
+        index: block.index(),
+        node: None,
+        sequence: None,
+        tag: block.tag(),
+        data: *block.block().data(),
+        ciphertext: raw.to_vec(),
+        replay: ReplayCheck::Fresh,
+    })
+}
+
+/// Builds a [`DecodedBlock`] from a block the receiver already decrypted. The ciphertext is
+/// worked out again so that it looks the same as a block the host decrypted
+pub fn plain_block(cipher: &Cipher<'_>, raw: &[u8]) -> Result<DecodedBlock, DecodeError> {
+    // The cipher is its own inverse, so decrypting the plaintext gives the ciphertext
+    let encrypted = decrypt_block(cipher, raw)?;
+    let block = VarBlock::from_bytes(raw).unwrap();
+    Ok(DecodedBlock {
+        data: *block.block().data(),
+        ciphertext: encrypted.plaintext(),
+        ..encrypted
+    })
+}
+
+/// Turns bytes read from the receiver into [`Event`]s.
+/// Keep one pipeline for a whole session, even across reconnects, so that partial frames and the
+/// replay windows carry over
+pub struct Pipeline<'k> {
+    frames: FrameDecoder,
+    /// One for each node, since each has its own sequence numbers
+    windows: [ReplayWindow; MAX_NODES],
+    cipher: Cipher<'k>,
+    index_key: u32,
+    downlinks: [PadRegion; MAX_NODES],
+}
+
+impl<'k> Pipeline<'k> {
+    pub fn new(keys: &'k Keys) -> Self {
+        Self {
+            frames: FrameDecoder::new(),
+            windows: std::array::from_fn(|_| ReplayWindow::new()),
+            cipher: keys.cipher(),
+            index_key: keys.index_key,
+            downlinks: std::array::from_fn(|node| keys.downlink(node as u8)),
+        }
+    }
+
+    /// Works out which node sent `block` and whether it was seen before
+    fn identify(&mut self, block: &mut DecodedBlock) {
+        let index_key = self.index_key;
+        let found = self
+            .downlinks
+            .iter()
+            .enumerate()
+            .find_map(|(node, region)| {
+                let sequence = region.sequence_of(block.index, index_key)?;
+                Some((node, sequence))
+            });
+        if let Some((node, sequence)) = found {
+            block.node = Some(node as u8);
+            block.sequence = Some(sequence);
+            block.replay = self.windows[node].check(sequence);
+        }
+    }
+
+    /// Feeds bytes from the serial port through the pipeline, calling `on_event` for every
+    /// complete frame. Stops at the first error returned by `on_event`
+    pub fn feed<E>(
+        &mut self,
+        bytes: &[u8],
+        mut on_event: impl FnMut(Event) -> Result<(), E>,
+    ) -> Result<(), E> {
+        for &b in bytes {
+            let event = match self.frames.push(b) {
+                None => continue,
+                Some(Err(e)) => Event::Error(DecodeError::Frame(e)),
+                Some(Ok(frame)) => match frame.kind {
+                    FrameKind::Block | FrameKind::Plain => {
+                        let decoded = if frame.kind == FrameKind::Block {
+                            decrypt_block(&self.cipher, frame.payload)
+                        } else {
+                            plain_block(&self.cipher, frame.payload)
+                        };
+                        match decoded {
+                            Ok(mut block) => {
+                                self.identify(&mut block);
+                                Event::Block(block)
+                            }
+                            Err(e) => Event::Error(e),
+                        }
+                    }
+                    FrameKind::Text => {
+                        Event::Text(String::from_utf8_lossy(frame.payload).into_owned())
+                    }
+                    FrameKind::Counters => match ReceiverCounters::from_bytes(frame.payload) {
+                        Some(counters) => Event::Counters(counters),
+                        None => Event::Error(DecodeError::CountersLength(frame.payload.len())),
+                    },
+                    FrameKind::Radio => match ReceiverSetting::from_bytes(frame.payload) {
+                        Some(setting) => Event::Radio(setting),
+                        None => Event::Error(DecodeError::BadRadio(frame.payload.to_vec())),
+                    },
+                    FrameKind::Survey => match survey_chunk(frame.payload) {
+                        Some((first, occupancy)) => Event::Survey {
+                            first,
+                            occupancy: occupancy.to_vec(),
+                        },
+                        None => Event::Error(DecodeError::BadSurvey(frame.payload.to_vec())),
+                    },
+                    FrameKind::Fault => match Fault::from_bytes(frame.payload) {
+                        Some(fault) => Event::Fault(fault),
+                        None => Event::Error(DecodeError::BadFault(frame.payload.to_vec())),
+                    },
+                    FrameKind::Reset => match ResetReport::from_bytes(frame.payload) {
+                        Some(report) => Event::Reset(report),
+                        None => Event::Error(DecodeError::BadReset(frame.payload.to_vec())),
+                    },
+                    FrameKind::Addressed => Event::Error(DecodeError::Unexpected(frame.kind)),
+                },
+            };
+            on_event(event)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{frame, listen, test_keys, MockPorts, SimulatedDevice, Step, PID, VID};
+    use common::{PadPosition, BLOCK_SIZE, TAG_SIZE};
+    use std::io::ErrorKind;
+    #[test]
+    fn blocks_are_decrypted() {
+        let keys = test_keys();
+        let mut ports = MockPorts::default();
+        let mut sim = SimulatedDevice::new(ports.add("/dev/ttyACM0", VID, PID, "A"), &keys);
+
+        // Split a frame across two reads like USB packets can
+        let split = sim.block_frame(10, [7; 7]);
+        sim.device.push_bytes(&split[..10]);
+        sim.device.push(Step::Timeout);
+        sim.device.push_bytes(&split[10..]);
+        for _ in 0..3 {
+            sim.send_block();
+        }
+        sim.send_text("hello");
+        let counters = ReceiverCounters {
+            received: 5,
+            malformed: 2,
+            dropped: 1,
+            radio_errors: 0,
+        };
+        sim.device
+            .push_bytes(&frame(FrameKind::Counters, &counters.to_bytes()));
+
+        let (events, result) = listen(&ports, &keys);
+        result.unwrap();
+        let block = |sequence, data| {
+            let mut decoded = decrypt_block(&keys.cipher(), &sim.encrypt(sequence, data)).unwrap();
+            assert_eq!(decoded.data, data);
+            decoded.node = Some(0);
+            decoded.sequence = Some(sequence);
+            Event::Block(decoded)
+        };
+        assert_eq!(
+            events,
+            vec![
+                block(10, [7; 7]),
+                block(0, [0, 1, 2, 3, 4, 5, 6]),
+                block(1, [0, 1, 2, 3, 4, 5, 6]),
+                block(2, [0, 1, 2, 3, 4, 5, 6]),
+                Event::Text("hello".into()),
+                Event::Counters(counters),
+            ]
+        );
+    }
+
+    #[test]
+    fn wrong_key_does_not_decrypt() {
+        let keys = test_keys();
+        let mut other = test_keys();
+        other.index_key ^= 1;
+        let mut ports = MockPorts::default();
+        let mut sim = SimulatedDevice::new(ports.add("/dev/ttyACM0", VID, PID, "A"), &other);
+        sim.send_block();
+
+        let (events, _) = listen(&ports, &keys);
+        match &events[..] {
+            [Event::Block(DecodedBlock { data, .. })] => {
+                assert_ne!(data, &[0, 1, 2, 3, 4, 5, 6])
+            }
+            other => panic!("Unexpected events {:?}", other),
+        }
+    }
+
+    #[test]
+    fn error_paths() {
+        let keys = test_keys();
+        let mut ports = MockPorts::default();
+        let mut sim = SimulatedDevice::new(ports.add("/dev/ttyACM0", VID, PID, "A"), &keys);
+
+        let mut corrupt = sim.block_frame(5, [0; 7]);
+        corrupt[3] ^= 0x40;
+        sim.device.push_bytes(&corrupt);
+        sim.device.push_bytes(&frame(FrameKind::Block, &[1, 2, 3]));
+        sim.send_block();
+        sim.device.push(Step::Error(ErrorKind::BrokenPipe));
+        sim.send_block();
+
+        let (events, result) = listen(&ports, &keys);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::BrokenPipe);
+        assert_eq!(events.len(), 3);
+        // Depending on which byte was hit this fails the COBS decode or the crc
+        assert!(matches!(
+            events[0],
+            Event::Error(DecodeError::Frame(
+                FrameError::BadCrc | FrameError::BadEncoding
+            ))
+        ));
+        assert_eq!(
+            events[1],
+            Event::Error(DecodeError::BlockLength(vec![1, 2, 3]))
+        );
+        assert!(matches!(
+            events[2],
+            Event::Block(DecodedBlock {
+                sequence: Some(0),
+                ..
+            })
+        ));
+    }
+    #[test]
+    fn receiver_can_decrypt() {
+        let keys = test_keys();
+        let mut ports = MockPorts::default();
+        let sim = SimulatedDevice::new(ports.add("/dev/ttyACM0", VID, PID, "A"), &keys);
+
+        let data = [9, 8, 7, 6, 5, 4, 3];
+        sim.device.push_bytes(&sim.block_frame(4, data));
+        let mut plaintext = IndexedBlock::new();
+        plaintext.tag().set_index(keys.downlink(0).index_for(
+            PadPosition {
+                sequence: 5,
+                word: 5 * 7,
+            },
+            keys.index_key,
+        ));
+        *plaintext.data_mut() = data;
+        let plaintext = VarBlock::new(plaintext, 7);
+        sim.device
+            .push_bytes(&frame(FrameKind::Plain, plaintext.as_bytes()));
+
+        let (events, result) = listen(&ports, &keys);
+        result.unwrap();
+        let decrypted = |sequence| {
+            let mut block = decrypt_block(&keys.cipher(), &sim.encrypt(sequence, data)).unwrap();
+            block.node = Some(0);
+            block.sequence = Some(sequence);
+            Event::Block(block)
+        };
+        // Exactly what the host would have decrypted itself, ciphertext included
+        assert_eq!(events, [decrypted(4), decrypted(5)]);
+    }
+    #[test]
+    fn blocks_of_every_size() {
+        let keys = test_keys();
+        let mut ports = MockPorts::default();
+        let mut sim = SimulatedDevice::new(ports.add("/dev/ttyACM0", VID, PID, "A"), &keys);
+
+        // A heartbeat is only the tag, and a short message only sends the words it needs
+        let blocks = [
+            ([0; 7], 0, TAG_SIZE),
+            ([0x0102, 0, 0, 0, 0, 0, 0], 1, TAG_SIZE + 4),
+            ([u32::MAX; 7], 7, BLOCK_SIZE),
+        ];
+        for (data, words, _) in blocks {
+            sim.send_data(data, words);
+        }
+
+        let (events, result) = listen(&ports, &keys);
+        result.unwrap();
+        assert_eq!(events.len(), blocks.len());
+        for (event, (data, _, len)) in events.iter().zip(blocks) {
+            match event {
+                Event::Block(block) => {
+                    assert_eq!(block.data, data);
+                    assert_eq!(block.ciphertext.len(), len);
+                    assert_eq!(block.plaintext().len(), len);
+                    assert_eq!(block.replay, ReplayCheck::Fresh);
+                }
+                other => panic!("Unexpected event {:?}", other),
+            }
+        }
+    }
+}
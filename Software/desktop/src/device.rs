@@ -0,0 +1,142 @@
+use std::io::{self, ErrorKind};
+
+use clap::Args;
+use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+
+use crate::transport::{PortProvider, Transport};
+
+/// Options that control which serial device is opened
+#[derive(Args, Debug, Clone)]
+pub struct DeviceArgs {
+    /// Serial port to open directly instead of searching by VID/PID (ex. /dev/ttyACM0)
+    #[clap(long, global = true)]
+    pub port: Option<String>,
+
+    /// USB vendor ID of the receiver, in hex
+    #[clap(long, default_value = "16c0", value_parser = parse_hex_u16, global = true)]
+    pub vid: u16,
+
+    /// USB product ID of the receiver, in hex
+    #[clap(long, default_value = "27dd", value_parser = parse_hex_u16, global = true)]
+    pub pid: u16,
+
+    /// Only use the device with this USB serial number
+    #[clap(long, global = true)]
+    pub serial: Option<String>,
+
+    /// Baud rate to open the port with. USB-CDC devices ignore this
+    #[clap(long, default_value_t = 115_200, global = true)]
+    pub baud: u32,
+
+    /// Exit when the device is unplugged instead of waiting for it to come back
+    #[clap(long, global = true)]
+    pub no_reconnect: bool,
+}
+
+fn parse_hex_u16(s: &str) -> Result<u16, String> {
+    let digits = s.trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(digits, 16).map_err(|e| format!("`{}` is not a hex u16: {}", s, e))
+}
+
+/// A USB serial port that matched the VID/PID and serial number filters
+#[derive(Debug, Clone)]
+pub struct FoundDevice {
+    pub port: SerialPortInfo,
+    pub usb: UsbPortInfo,
+}
+
+impl DeviceArgs {
+    fn matches(&self, usb: &UsbPortInfo) -> bool {
+        usb.vid == self.vid
+            && usb.pid == self.pid
+            && match &self.serial {
+                Some(serial) => usb.serial_number.as_deref() == Some(serial.as_str()),
+                None => true,
+            }
+    }
+
+    /// Returns every connected device that matches these arguments
+    pub fn find_all(&self, ports: &dyn PortProvider) -> io::Result<Vec<FoundDevice>> {
+        let ports = ports.available_ports()?;
+        Ok(ports
+            .into_iter()
+            .filter_map(|port| match &port.port_type {
+                SerialPortType::UsbPort(usb) if self.matches(usb) => Some(FoundDevice {
+                    usb: usb.clone(),
+                    port,
+                }),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Works out which port should be opened.
+    /// `--port` always wins, otherwise the first matching device is used
+    pub fn resolve(&self, ports: &dyn PortProvider) -> io::Result<ResolvedPort> {
+        if let Some(port) = &self.port {
+            return Ok(ResolvedPort {
+                path: port.clone(),
+                serial_number: self.serial.clone(),
+            });
+        }
+        let device = self.find_all(ports)?.into_iter().next().ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "Failed to find device {:04x}:{:04x}. Is it connected?",
+                    self.vid, self.pid
+                ),
+            )
+        })?;
+        eprintln!(
+            "Found device {:?} {:?} {:?}",
+            device.usb.product, device.usb.manufacturer, device.usb.serial_number
+        );
+        Ok(ResolvedPort {
+            path: device.port.port_name,
+            serial_number: device.usb.serial_number,
+        })
+    }
+
+    pub fn open(&self, ports: &dyn PortProvider) -> io::Result<Box<dyn Transport>> {
+        self.resolve(ports)?.open(ports, self.baud)
+    }
+}
+
+/// A port that has been chosen but not opened yet
+#[derive(Debug, Clone)]
+pub struct ResolvedPort {
+    pub path: String,
+    pub serial_number: Option<String>,
+}
+
+impl ResolvedPort {
+    pub fn open(&self, ports: &dyn PortProvider, baud: u32) -> io::Result<Box<dyn Transport>> {
+        eprintln!("Opening {}", self.path);
+        ports.open(&self.path, baud)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{device_args, MockPorts, PID, VID};
+    #[test]
+    fn discovery() {
+        let mut ports = MockPorts::default();
+        ports.add("/dev/ttyUSB0", 0x0403, 0x6001, "FTDI");
+        ports.add("/dev/ttyACM0", VID, PID, "A");
+        ports.add("/dev/ttyACM1", VID, PID, "B");
+
+        let found = device_args(None).find_all(&ports).unwrap();
+        let names: Vec<_> = found.iter().map(|f| f.port.port_name.as_str()).collect();
+        assert_eq!(names, ["/dev/ttyACM0", "/dev/ttyACM1"]);
+
+        let resolved = device_args(Some("B")).resolve(&ports).unwrap();
+        assert_eq!(resolved.path, "/dev/ttyACM1");
+        assert_eq!(resolved.serial_number.as_deref(), Some("B"));
+
+        let err = device_args(Some("C")).resolve(&ports).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+}
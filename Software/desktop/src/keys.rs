@@ -0,0 +1,108 @@
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+
+use clap::Args;
+use common::{Key, MainCipher, PadRegion, AUTH_KEY_SIZE, KEY, KEY_SIZE};
+
+pub type Cipher<'k> = MainCipher<'k, fn(u32) -> u32, KEY_SIZE>;
+
+/// Where the auth key is read from without `--auth-key`. Made by `Software/make-keys.sh`
+const AUTH_KEY_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../private/auth-key.bin");
+
+/// Options for choosing the key material. The key and index key compiled in from `private/` are
+/// used by default, and the auth key is read from there when the program starts
+#[derive(Args, Debug, Clone, Default)]
+pub struct KeyArgs {
+    /// Key file to use instead of the compiled in `private/key.bin`
+    #[clap(long, global = true)]
+    pub key: Option<PathBuf>,
+
+    /// Index key file to use instead of the compiled in `private/index-key.bin`
+    #[clap(long, global = true)]
+    pub index_key: Option<PathBuf>,
+
+    /// Control message auth key file to use instead of `private/auth-key.bin`
+    #[clap(long, global = true)]
+    pub auth_key: Option<PathBuf>,
+}
+
+pub struct Keys {
+    pub key: Box<Key<KEY_SIZE>>,
+    pub index_key: u32,
+    /// Authenticates control messages, see [`common::Control`]
+    pub auth_key: [u8; AUTH_KEY_SIZE],
+}
+
+fn read_exact_file<const N: usize>(path: &PathBuf) -> io::Result<[u8; N]> {
+    let bytes = std::fs::read(path)?;
+    let len = bytes.len();
+    bytes.try_into().map_err(|_| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{} is {} bytes, expected {} bytes", path.display(), len, N),
+        )
+    })
+}
+
+impl Keys {
+    pub fn load(args: &KeyArgs) -> io::Result<Self> {
+        let key = match &args.key {
+            Some(path) => Key::new(read_exact_file::<KEY_SIZE>(path)?),
+            None => KEY,
+        };
+        let index_key = match &args.index_key {
+            Some(path) => read_exact_file::<4>(path)?,
+            None => *include_bytes!("../../private/index-key.bin"),
+        };
+        let auth_key = match &args.auth_key {
+            Some(path) => read_exact_file::<AUTH_KEY_SIZE>(path)?,
+            None => read_exact_file::<AUTH_KEY_SIZE>(&AUTH_KEY_FILE.into()).map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!(
+                        "Reading {}: {}. Make it with Software/make-keys.sh, or give one with \
+                         --auth-key",
+                        AUTH_KEY_FILE, err
+                    ),
+                )
+            })?,
+        };
+        Ok(Self {
+            key: Box::new(key),
+            index_key: u32::from_ne_bytes(index_key),
+            auth_key,
+        })
+    }
+
+    pub fn cipher(&self) -> Cipher<'_> {
+        MainCipher::new(&self.key, self.index_key)
+    }
+
+    /// The number of distinct pad offsets the key has. A block uses up to 7 words of key from its
+    /// offset
+    pub fn offsets(&self) -> usize {
+        self.key.subkey_count::<u32, 7>()
+    }
+
+    /// The pads used by node `node` sending to the host
+    pub fn downlink(&self, node: u8) -> PadRegion {
+        PadRegion::node_downlink(self.offsets(), node)
+    }
+
+    /// The pads used by the host sending to node `node`
+    pub fn uplink(&self, node: u8) -> PadRegion {
+        PadRegion::node_uplink(self.offsets(), node)
+    }
+
+    /// A short FNV-1a hash of the key so that two machines can check that they use the same key
+    /// without printing it
+    pub fn fingerprint(&self) -> u64 {
+        self.key
+            .as_bytes()
+            .iter()
+            .chain(self.index_key.to_ne_bytes().iter())
+            .fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+                (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+            })
+    }
+}
@@ -1,36 +1,346 @@
-use serialport::{SerialPortType, UsbPortInfo, SerialPort};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use clap::{Parser, Subcommand};
+use common::MessageKind;
+use serde_json::json;
+
+mod camera;
+mod capture;
+mod chat;
+mod config;
+mod decode;
+mod device;
+mod keys;
+#[cfg(test)]
+mod mock;
+mod nodes;
+mod ota;
+mod output;
+mod pcapng;
+mod stats;
+mod supervisor;
+mod survey;
+mod telemetry;
+mod transport;
+mod uplink;
+
+use chat::Chat;
+use config::ConfigCommand;
+use decode::Pipeline;
+use device::DeviceArgs;
+use keys::{KeyArgs, Keys};
+use nodes::{NodeArgs, Registry};
+use ota::OtaArgs;
+use output::{Output, OutputFormat};
+use pcapng::PcapngArgs;
+use stats::{ExportFormat, LinkStats, StatsExport};
+use survey::SurveyArgs;
+use telemetry::{TelemetryArgs, TelemetryLog};
+use transport::{PortProvider, SystemPorts};
+use uplink::{Uplink, UplinkArgs};
+
+/// Host tool for the facilitador radio boards
+#[derive(Parser, Debug)]
+#[clap(version)]
+struct Cli {
+    #[clap(flatten)]
+    device: DeviceArgs,
+
+    #[clap(flatten)]
+    keys: KeyArgs,
+
+    #[clap(flatten)]
+    nodes: NodeArgs,
+
+    /// How results are printed
+    #[clap(long, value_enum, default_value = "text", global = true)]
+    format: OutputFormat,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List connected devices that match the VID/PID
+    List,
+    /// Print every message the receiver forwards
+    Listen {
+        #[clap(flatten)]
+        pcapng: PcapngArgs,
+    },
+    /// Encrypt a text message and send it to a node through the receiver
+    Send {
+        message: String,
+        #[clap(flatten)]
+        uplink: UplinkArgs,
+    },
+    /// Send each line typed to a node and print what every node sends
+    Chat {
+        #[clap(flatten)]
+        uplink: UplinkArgs,
+    },
+    /// Read or change a node setting over the radio
+    #[clap(subcommand)]
+    Config(ConfigCommand),
+    /// Listen and also save every frame with timestamps to a capture file
+    Record {
+        file: PathBuf,
+        /// Radio channel the receiver is on, stored in the capture
+        #[clap(long, default_value_t = 8)]
+        channel: u8,
+        #[clap(flatten)]
+        pcapng: PcapngArgs,
+    },
+    /// Decode a capture file as if it came from the device
+    Replay {
+        file: PathBuf,
+        /// Playback speed relative to the original timing. 0 decodes as fast as possible
+        #[clap(long, default_value_t = 1.0)]
+        speed: f64,
+        #[clap(flatten)]
+        pcapng: PcapngArgs,
+    },
+    /// Print link quality (loss, duplicates, reordering, throughput) while listening
+    Stats {
+        /// Seconds between summaries
+        #[clap(long, default_value_t = 1)]
+        interval: u64,
+        /// Seconds covered by the sliding window
+        #[clap(long, default_value_t = 10)]
+        window: u64,
+        /// Also write every summary to this file
+        #[clap(long)]
+        export: Option<PathBuf>,
+        /// Format of the export file
+        #[clap(long, value_enum, default_value = "csv")]
+        export_format: ExportFormat,
+    },
+    /// Measure how busy every channel is from the receiver and recommend the quietest
+    Survey {
+        #[clap(flatten)]
+        survey: SurveyArgs,
+    },
+    /// Print the battery telemetry every node sends, with a graph of recent readings
+    Telemetry {
+        #[clap(flatten)]
+        telemetry: TelemetryArgs,
+    },
+    /// Send a node new firmware over the radio
+    Ota {
+        #[clap(flatten)]
+        ota: OtaArgs,
+    },
+    /// Read or change the clock the camera board gives its camera, over the board's serial
+    /// console. Needs --port
+    Xclk {
+        /// Frequency to move to in Hz, the closest the board can make is used
+        hz: Option<u32>,
+    },
+    /// Print information about the key in use
+    Keyinfo,
+}
 
 fn main() {
-    let ports = serialport::available_ports().expect("Failed to enumerate serial ports");
-    let mut device = None;
-    for port in ports {
-        let info = if let SerialPortType::UsbPort(info) = &port.port_type {
-            if info.vid == 0x16c0 && info.pid == 0x27dd {
-                info.clone()
-            } else {
-                continue;
-            }
-        } else {
-            continue;
-        };
-        device = Some((port, info));
+    let cli = Cli::parse();
+    if let Err(err) = run(&cli, &SystemPorts) {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run(cli: &Cli, ports: &dyn PortProvider) -> io::Result<()> {
+    match &cli.command {
+        Command::List => list(cli, ports),
+        Command::Listen { pcapng } => {
+            let keys = Keys::load(&cli.keys)?;
+            let mut output = Output::new(cli.format, Registry::load(&cli.nodes)?, pcapng)?;
+            let mut pipeline = Pipeline::new(&keys);
+            supervisor::run(ports, &cli.device, |bytes: &[u8]| {
+                let now = capture::unix_us();
+                pipeline.feed(bytes, |event| output.event(now, &event))?;
+                output.flush()
+            })
+        }
+        Command::Send { message, uplink } => {
+            let keys = Keys::load(&cli.keys)?;
+            Registry::load(&cli.nodes)?.check(uplink.node)?;
+            let frames =
+                Uplink::new(&keys, uplink).encode(MessageKind::Text, message.as_bytes())?;
+            let mut device = cli.device.open(ports)?;
+            device.write_all(&frames)?;
+            device.flush()
+        }
+        Command::Chat { uplink } => {
+            let keys = Keys::load(&cli.keys)?;
+            let nodes = Registry::load(&cli.nodes)?;
+            nodes.check(uplink.node)?;
+            let chat = Chat::new(
+                Pipeline::new(&keys),
+                Uplink::new(&keys, uplink),
+                nodes,
+                chat::stdin_lines(),
+                io::stdout(),
+            );
+            supervisor::run(ports, &cli.device, chat)
+        }
+        Command::Config(command) => {
+            let keys = Keys::load(&cli.keys)?;
+            let nodes = Registry::load(&cli.nodes)?;
+            config::run(ports, &cli.device, &keys, &nodes, command, io::stdout())
+        }
+        Command::Record {
+            file,
+            channel,
+            pcapng,
+        } => {
+            let keys = Keys::load(&cli.keys)?;
+            let mut output = Output::new(cli.format, Registry::load(&cli.nodes)?, pcapng)?;
+            capture::record(ports, &cli.device, &keys, &mut output, file, *channel)
+        }
+        Command::Replay {
+            file,
+            speed,
+            pcapng,
+        } => {
+            let keys = Keys::load(&cli.keys)?;
+            let mut output = Output::new(cli.format, Registry::load(&cli.nodes)?, pcapng)?;
+            capture::replay(&keys, &mut output, file, *speed)
+        }
+        Command::Stats {
+            interval,
+            window,
+            export,
+            export_format,
+        } => stats(
+            cli,
+            ports,
+            Duration::from_secs(*interval),
+            Duration::from_secs(*window),
+            export.as_deref(),
+            *export_format,
+        ),
+        Command::Survey { survey } => {
+            survey::run(ports, &cli.device, survey, cli.format, io::stdout())
+        }
+        Command::Telemetry { telemetry } => {
+            let keys = Keys::load(&cli.keys)?;
+            let nodes = Registry::load(&cli.nodes)?;
+            let log = TelemetryLog::new(
+                Pipeline::new(&keys),
+                nodes,
+                telemetry,
+                cli.format,
+                io::stdout(),
+            )?;
+            supervisor::run(ports, &cli.device, log)
+        }
+        Command::Ota { ota } => {
+            let keys = Keys::load(&cli.keys)?;
+            let nodes = Registry::load(&cli.nodes)?;
+            ota::run(ports, &cli.device, &keys, &nodes, ota, io::stdout())
+        }
+        Command::Xclk { hz } => camera::xclk(ports, &cli.device, *hz, io::stdout()),
+        Command::Keyinfo => keyinfo(cli),
     }
-    let (port, info) = device.expect("Failed to find device. Is it connected?");
-    println!("Found device {:?} {:?} {:?}", info.product, info.manufacturer, info.serial_number);
-    println!("Opening {}", port.port_name);
-    let port = serialport::new(&port.port_name, 115_200).open().expect("Failed to open serial port!");
-    match handle_loop(port) {
-        Ok(_) => println!("Serial port done"),
-        Err(err) => println!("Serial closed with error {:?}", err),
+}
+
+fn list(cli: &Cli, ports: &dyn PortProvider) -> io::Result<()> {
+    for found in cli.device.find_all(ports)? {
+        let usb = &found.usb;
+        match cli.format {
+            OutputFormat::Text => println!(
+                "{} {:04x}:{:04x} serial {} ({} {})",
+                found.port.port_name,
+                usb.vid,
+                usb.pid,
+                usb.serial_number.as_deref().unwrap_or("?"),
+                usb.manufacturer.as_deref().unwrap_or("?"),
+                usb.product.as_deref().unwrap_or("?"),
+            ),
+            OutputFormat::Json => println!(
+                "{}",
+                json!({
+                    "port": found.port.port_name,
+                    "vid": usb.vid,
+                    "pid": usb.pid,
+                    "serial_number": usb.serial_number,
+                    "manufacturer": usb.manufacturer,
+                    "product": usb.product,
+                })
+            ),
+        }
     }
+    Ok(())
+}
+
+fn stats(
+    cli: &Cli,
+    ports: &dyn PortProvider,
+    interval: Duration,
+    window: Duration,
+    export: Option<&Path>,
+    export_format: ExportFormat,
+) -> io::Result<()> {
+    let keys = Keys::load(&cli.keys)?;
+    let nodes = Registry::load(&cli.nodes)?;
+    let mut pipeline = Pipeline::new(&keys);
+    let mut export = export
+        .map(|path| StatsExport::create(path, export_format))
+        .transpose()?;
+
+    let mut link = LinkStats::new(window);
+    let mut last_print = Instant::now();
+    supervisor::run(ports, &cli.device, |bytes: &[u8]| {
+        let now = capture::unix_us();
+        pipeline.feed(bytes, |event| {
+            link.record(now, &event);
+            Ok::<_, io::Error>(())
+        })?;
+        if last_print.elapsed() >= interval {
+            last_print = Instant::now();
+            link.advance(now);
+            match cli.format {
+                OutputFormat::Text => println!("{}\n", stats::stats_text(&link, &nodes)),
+                OutputFormat::Json => println!("{}", stats::stats_json(now, &link, &nodes)),
+            }
+            if let Some(export) = &mut export {
+                export.write(now, &link, &nodes)?;
+            }
+        }
+        Ok(())
+    })
 }
 
-fn handle_loop(mut device: Box<dyn SerialPort>) -> Result<(), std::io::Error> {
-    let mut data = [0u8; 1024]; 
-    loop {
-        let msg = "HeLlO";
-        device.write_all(msg.as_bytes())?;
-        let bytes = device.read(&mut data)?;
-        println!("Got bytes {:?}", bytes);
+fn keyinfo(cli: &Cli) -> io::Result<()> {
+    let keys = Keys::load(&cli.keys)?;
+    let key_bytes = keys.key.as_bytes().len();
+    let offsets = keys.offsets();
+    // How many data words each node and the host sending to it can send before the pads run out
+    let (downlink, uplink) = (keys.downlink(0).len(), keys.uplink(0).len());
+    let fingerprint = keys.fingerprint();
+    match cli.format {
+        OutputFormat::Text => {
+            println!("key bytes: {}", key_bytes);
+            println!("distinct pad offsets: {}", offsets);
+            println!(
+                "pad words per node: {} downlink, {} uplink",
+                downlink, uplink
+            );
+            println!("fingerprint: {:016x}", fingerprint);
+        }
+        OutputFormat::Json => println!(
+            "{}",
+            json!({
+                "key_bytes": key_bytes,
+                "pad_offsets": offsets,
+                "node_downlink_words": downlink,
+                "node_uplink_words": uplink,
+                "fingerprint": format!("{:016x}", fingerprint),
+            })
+        ),
     }
+    Ok(())
 }
@@ -0,0 +1,538 @@
+//! An in-process stand-in for a receiver board so that the host side can be tested without
+//! hardware. Each module's tests drive it from that module, this only holds what they share.
+
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::{
+    chunk_words, encode_chunks, encode_frame, host_address, node_address, ArqReceiver,
+    ChannelModel, Fault, FrameDecoder, FrameKind, HopFollower, IndexedBlock, Key, MessageKind,
+    Node, OtaReceiver, PadPosition, PersistentIndex, Radio, RamFlash, Reassembler, ReceiverSetting,
+    Relay, ResetReport, Setting, SimAir, SimRadio, Tag, Telemetry, VarBlock, BOOT_LOG_PAGES,
+    KEY_SIZE, MAX_ENCODED_FRAME, MAX_FAULT_BYTES, MAX_MESSAGE, MAX_NODES,
+};
+use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+
+use crate::decode::{decrypt_block, Event, Pipeline};
+use crate::device::DeviceArgs;
+use crate::keys::{Cipher, KeyArgs, Keys};
+use crate::supervisor::supervise;
+use crate::transport::{PortProvider, Transport};
+
+/// One thing that a read from the mock port will return
+#[derive(Debug)]
+pub enum Step {
+    Bytes(Vec<u8>),
+    Timeout,
+    Error(ErrorKind),
+    /// The read fails and the device disappears for this many port scans or open attempts
+    Unplug {
+        polls: usize,
+    },
+}
+
+type Responder = Box<dyn FnMut(&[u8]) -> Vec<u8> + Send>;
+
+#[derive(Default)]
+struct State {
+    to_host: VecDeque<Step>,
+    from_host: Vec<u8>,
+    opens: usize,
+    /// Port scans and opens left until an unplugged device shows up again
+    unplugged: usize,
+    /// Called with everything the host writes, returns bytes to send back
+    responder: Option<Responder>,
+}
+
+impl State {
+    /// Returns true if the device is currently plugged in, counting this as one poll if not
+    fn poll_present(&mut self) -> bool {
+        if self.unplugged == 0 {
+            true
+        } else {
+            self.unplugged -= 1;
+            false
+        }
+    }
+}
+
+/// The device side of a mock port. Cloning gives another handle to the same device
+#[derive(Clone, Default)]
+pub struct MockDevice(Arc<Mutex<State>>);
+
+impl MockDevice {
+    pub fn push(&self, step: Step) {
+        self.0.lock().unwrap().to_host.push_back(step);
+    }
+
+    pub fn push_bytes(&self, bytes: &[u8]) {
+        self.push(Step::Bytes(bytes.to_vec()));
+    }
+
+    /// Answers every write from the host with the bytes `responder` returns for it
+    pub fn respond_with(&self, responder: impl FnMut(&[u8]) -> Vec<u8> + Send + 'static) {
+        self.0.lock().unwrap().responder = Some(Box::new(responder));
+    }
+
+    /// Everything the host has written so far
+    pub fn written(&self) -> Vec<u8> {
+        self.0.lock().unwrap().from_host.clone()
+    }
+
+    pub fn opens(&self) -> usize {
+        self.0.lock().unwrap().opens
+    }
+}
+
+/// The host side of a mock port. Once every queued step has been read the stream ends
+struct MockTransport(MockDevice);
+
+impl Read for MockTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = (self.0).0.lock().unwrap();
+        match state.to_host.pop_front() {
+            None => Ok(0),
+            Some(Step::Timeout) => Err(ErrorKind::TimedOut.into()),
+            Some(Step::Error(kind)) => Err(kind.into()),
+            Some(Step::Unplug { polls }) => {
+                state.unplugged = polls;
+                Err(ErrorKind::BrokenPipe.into())
+            }
+            Some(Step::Bytes(mut bytes)) => {
+                let n = bytes.len().min(buf.len());
+                buf[..n].copy_from_slice(&bytes[..n]);
+                if n < bytes.len() {
+                    state.to_host.push_front(Step::Bytes(bytes.split_off(n)));
+                }
+                Ok(n)
+            }
+        }
+    }
+}
+
+impl Write for MockTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = (self.0).0.lock().unwrap();
+        state.from_host.extend_from_slice(buf);
+        if let Some(responder) = &mut state.responder {
+            let reply = responder(buf);
+            if !reply.is_empty() {
+                state.to_host.push_back(Step::Bytes(reply));
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A set of mock USB serial ports
+#[derive(Default)]
+pub struct MockPorts {
+    ports: Vec<(SerialPortInfo, MockDevice)>,
+}
+
+impl MockPorts {
+    /// Adds a USB device and returns a handle to it
+    pub fn add(&mut self, path: &str, vid: u16, pid: u16, serial: &str) -> MockDevice {
+        let info = SerialPortInfo {
+            port_name: path.to_owned(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid,
+                pid,
+                serial_number: Some(serial.to_owned()),
+                manufacturer: Some("Fake Company".to_owned()),
+                product: Some("Serial Port".to_owned()),
+            }),
+        };
+        let device = MockDevice::default();
+        self.ports.push((info, device.clone()));
+        device
+    }
+}
+
+impl PortProvider for MockPorts {
+    fn available_ports(&self) -> io::Result<Vec<SerialPortInfo>> {
+        Ok(self
+            .ports
+            .iter()
+            .filter(|(_, device)| device.0.lock().unwrap().poll_present())
+            .map(|(info, _)| info.clone())
+            .collect())
+    }
+
+    fn open(&self, path: &str, _baud: u32) -> io::Result<Box<dyn Transport>> {
+        let (_, device) = self
+            .ports
+            .iter()
+            .find(|(info, _)| info.port_name == path)
+            .ok_or_else(|| io::Error::from(ErrorKind::NotFound))?;
+        let mut state = device.0.lock().unwrap();
+        if !state.poll_present() {
+            return Err(ErrorKind::NotFound.into());
+        }
+        state.opens += 1;
+        Ok(Box::new(MockTransport(device.clone())))
+    }
+}
+
+/// Keys filled with a fixed pseudo random pattern so tests don't depend on `private/`
+pub fn test_keys() -> Keys {
+    let mut state = 0x2545_F491_4F6C_DD1Du64;
+    let bytes: Vec<u8> = (0..KEY_SIZE)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    Keys {
+        key: Box::new(Key::new(bytes.try_into().unwrap())),
+        index_key: 0x1234_5678,
+        auth_key: *b"test auth key 16",
+    }
+}
+
+/// Produces the byte stream a receiver board sends, encrypting blocks the same way the node
+/// firmware does, and decodes what the host sends up
+pub struct SimulatedDevice<'k> {
+    pub device: MockDevice,
+    keys: &'k Keys,
+    cipher: Cipher<'k>,
+    next: PadPosition,
+}
+
+impl<'k> SimulatedDevice<'k> {
+    pub fn new(device: MockDevice, keys: &'k Keys) -> Self {
+        Self {
+            device,
+            keys,
+            cipher: keys.cipher(),
+            next: PadPosition::default(),
+        }
+    }
+
+    /// Returns a block sent at `position` as it would be sent over the air, with only the first
+    /// `words` of its data words
+    pub fn encrypt_words(&self, position: PadPosition, data: [u32; 7], words: usize) -> Vec<u8> {
+        let mut block = IndexedBlock::new();
+        *block.data_mut() = data;
+        let index = self
+            .keys
+            .downlink(0)
+            .index_for(position, self.keys.index_key);
+        block.tag().set_index(index);
+        let mut block = VarBlock::new(block, words);
+        block.do_cipher(&self.cipher);
+        block.as_bytes().to_vec()
+    }
+
+    /// Returns a whole block as it would be sent over the air. Its pad is the 7 key words after
+    /// those of the whole blocks before `sequence`
+    pub fn encrypt(&self, sequence: u32, data: [u32; 7]) -> Vec<u8> {
+        let position = PadPosition {
+            sequence,
+            word: sequence * 7,
+        };
+        self.encrypt_words(position, data, 7)
+    }
+
+    /// Returns the encoded frame the receiver forwards for a whole block from node 0
+    pub fn block_frame(&self, sequence: u32, data: [u32; 7]) -> Vec<u8> {
+        frame(FrameKind::Block, &self.encrypt(sequence, data))
+    }
+
+    /// Sends the first `words` of `data` with the next sequence number, and returns it
+    pub fn send_data(&mut self, data: [u32; 7], words: usize) -> u32 {
+        let position = self.next.take(words);
+        let block = self.encrypt_words(position, data, words);
+        self.device.push_bytes(&frame(FrameKind::Block, &block));
+        position.sequence
+    }
+
+    /// Sends a whole block of data with the next sequence number
+    pub fn send_block(&mut self) -> u32 {
+        self.send_data([0, 1, 2, 3, 4, 5, 6], 7)
+    }
+
+    /// Sends the chunks of a message from the node
+    fn send_chunks(&mut self, kind: MessageKind, message: &[u8]) {
+        for data in encode_chunks(kind, message) {
+            self.send_data(data, chunk_words(&data));
+        }
+    }
+
+    /// Sends a text message from the node
+    pub fn send_message(&mut self, text: &str) {
+        self.send_chunks(MessageKind::Text, text.as_bytes());
+    }
+
+    /// Why the node last went down, reported once it is back
+    pub fn send_fault(&mut self, fault: &Fault) {
+        let mut bytes = [0u8; MAX_FAULT_BYTES];
+        let len = fault.to_bytes(&mut bytes);
+        self.send_chunks(MessageKind::Fault, &bytes[..len]);
+    }
+
+    /// Why the node was last reset, reported at boot
+    pub fn send_reset(&mut self, report: &ResetReport) {
+        self.send_chunks(MessageKind::Reset, &report.to_bytes());
+    }
+
+    /// A battery reading from the node
+    pub fn send_telemetry(&mut self, telemetry: &Telemetry) {
+        self.send_chunks(MessageKind::Telemetry, &telemetry.to_bytes());
+    }
+
+    /// Text sent by the receiver itself
+    pub fn send_text(&self, text: &str) {
+        self.device
+            .push_bytes(&frame(FrameKind::Text, text.as_bytes()));
+    }
+
+    /// Decrypts the blocks the host wrote as the nodes would, returning the messages in them
+    pub fn uplink_messages(&self) -> Vec<String> {
+        let mut frames = FrameDecoder::new();
+        let mut messages: [Reassembler<{ MAX_MESSAGE + 1 }>; MAX_NODES] =
+            std::array::from_fn(|_| Reassembler::new());
+        let mut out = Vec::new();
+        for b in self.device.written() {
+            let (node, raw) = match frames.push(b) {
+                Some(Ok(frame)) if frame.kind == FrameKind::Addressed => {
+                    let (&node, raw) = frame.payload.split_first().unwrap();
+                    (node, raw.to_vec())
+                }
+                Some(other) => panic!("Host wrote an unexpected frame {:?}", other),
+                None => continue,
+            };
+            let block = decrypt_block(&self.cipher, &raw).unwrap();
+            let sequence = self
+                .keys
+                .uplink(node)
+                .sequence_of(block.index, self.keys.index_key)
+                .expect("Host used a pad outside the node's uplink region");
+            if let Some((MessageKind::Text, text)) =
+                messages[node as usize].push(sequence, &block.data)
+            {
+                out.push(String::from_utf8(text.to_vec()).unwrap());
+            }
+        }
+        out
+    }
+}
+
+/// A radio for node `node`, sending to the receiver and listening for the host like the node
+/// firmware's
+pub fn node_radio(air: &SimAir, node: u8) -> SimRadio {
+    let mut radio = air.radio_on(&[host_address(node)]);
+    radio.set_destination(&node_address(node)).unwrap();
+    radio
+}
+
+/// Node 0 and a receiver running the firmware's logic, talking over a simulated radio link
+pub struct RadioState {
+    pub air: SimAir,
+    pub node: Node<'static, KEY_SIZE>,
+    pub node_radio: SimRadio,
+    pub index: PersistentIndex<RamFlash<2, 64>>,
+    pub relay: Relay<1024>,
+    pub receiver_radio: SimRadio,
+    pub follower: HopFollower<'static, KEY_SIZE>,
+    pub arq: [ArqReceiver; MAX_NODES],
+    /// Where the node writes firmware updates, laid out like the node's flash with 32K slots
+    pub ota: OtaReceiver<RamFlash<{ 2 * 32 + 1 + BOOT_LOG_PAGES }, 256>>,
+}
+
+impl RadioState {
+    pub fn new(keys: &'static Keys, air: SimAir) -> Self {
+        Self {
+            node: Node::new(&keys.key, keys.index_key, keys.auth_key, 0),
+            node_radio: node_radio(&air, 0),
+            index: PersistentIndex::open(RamFlash::new(), 16),
+            relay: Relay::new(),
+            receiver_radio: air.radio_on(&std::array::from_fn::<_, MAX_NODES, _>(|node| {
+                node_address(node as u8)
+            })),
+            follower: HopFollower::new(&keys.key, keys.index_key, keys.auth_key, 0),
+            arq: std::array::from_fn(|node| {
+                ArqReceiver::new(keys.index_key, keys.offsets(), keys.auth_key, node as u8)
+            }),
+            ota: OtaReceiver::new(RamFlash::new(), keys.auth_key),
+            air,
+        }
+    }
+
+    /// Sends the node's next block, returning false if it has to be sent again
+    pub fn transmit(&mut self) -> bool {
+        let index = &mut self.index;
+        let now = self.air.now_ms();
+        self.node
+            .transmit(&mut self.node_radio, now, |words| index.next(words))
+            .unwrap()
+    }
+
+    /// Handles bytes from the host like the receiver's USB interrupt
+    pub fn host_writes(&mut self, bytes: &[u8]) {
+        self.relay
+            .host_bytes(bytes, &mut self.receiver_radio)
+            .unwrap();
+    }
+
+    /// Lets `ms` pass on the air and has both ends handle what arrived. Returns what the
+    /// receiver sends the host
+    pub fn advance(&mut self, ms: u64) -> Vec<u8> {
+        self.air.advance(ms);
+        let now = self.air.now_ms();
+        self.node.poll(&mut self.node_radio).unwrap();
+        let mut message = [0u8; MAX_MESSAGE];
+        while let Some(len) = self.node.take_ota(&mut message) {
+            if let Some(status) = self.ota.receive(&message[..len]).unwrap() {
+                self.node.report_ota(&status);
+            }
+            self.node.poll(&mut self.node_radio).unwrap();
+        }
+        let (follower, arq) = (&mut self.follower, &mut self.arq);
+        self.relay
+            .poll_radio(&mut self.receiver_radio, |block| {
+                follower.heard(block, now);
+                arq.iter_mut().for_each(|arq| arq.heard(block));
+                FrameKind::Block
+            })
+            .unwrap();
+        if let Some(rate) = self.follower.data_rate(self.relay.config(), now) {
+            let setting = ReceiverSetting {
+                setting: Setting::DataRate,
+                value: rate as u32,
+            };
+            self.relay.apply(&mut self.receiver_radio, setting).unwrap();
+        }
+        let channel = self.follower.channel(self.relay.config(), now);
+        self.relay
+            .hop_to(&mut self.receiver_radio, channel)
+            .unwrap();
+        // Sent on the channel the node listens on after its transmission
+        if self.relay.config().arq_retries > 0 {
+            for (node, arq) in self.arq.iter_mut().enumerate() {
+                if let Some(ack) = arq.ack() {
+                    self.relay
+                        .send_to(&mut self.receiver_radio, node as u8, &ack)
+                        .unwrap();
+                }
+            }
+        }
+        // All at once, where the receiver's survey task does a sweep at a time between others
+        while self.relay.surveying() {
+            self.relay
+                .survey_sweep(&mut self.receiver_radio, || {})
+                .unwrap();
+        }
+        let mut out = Vec::new();
+        let queue = self.relay.to_host();
+        while !queue.is_empty() {
+            out.extend_from_slice(queue.front());
+            queue.consume(queue.front().len());
+        }
+        out
+    }
+}
+
+/// A node and receiver that answer the host like the firmware does, over a perfect link. The
+/// node replies as soon as it has something to say instead of waiting for its next transmission
+pub struct SimulatedNode;
+
+impl SimulatedNode {
+    /// Makes `device` answer the host with a node using `keys`
+    pub fn attach(device: &MockDevice, keys: Keys) -> Arc<Mutex<RadioState>> {
+        // The node borrows the key for as long as the device lives
+        let keys: &'static Keys = Box::leak(Box::new(keys));
+        let air = SimAir::new(ChannelModel::PERFECT, 0);
+        let state = Arc::new(Mutex::new(RadioState::new(keys, air)));
+        let shared = state.clone();
+        device.respond_with(move |bytes| {
+            let mut state = shared.lock().unwrap();
+            // A frame at a time, since the node's radio only holds three blocks
+            let mut out = Vec::new();
+            for frame in bytes.split_inclusive(|&b| b == 0) {
+                state.host_writes(frame);
+                out.extend(state.advance(0));
+            }
+            while state.node.has_pending() && state.transmit() {
+                out.extend(state.advance(0));
+            }
+            out
+        });
+        state
+    }
+}
+
+pub fn frame(kind: FrameKind, payload: &[u8]) -> Vec<u8> {
+    let mut out = [0u8; MAX_ENCODED_FRAME];
+    let len = encode_frame(kind, payload, &mut out);
+    out[..len].to_vec()
+}
+
+/// The receiver's USB IDs
+pub const VID: u16 = 0x16c0;
+pub const PID: u16 = 0x27dd;
+
+/// Finds the receiver by its IDs, and gives up when it is unplugged
+pub fn device_args(serial: Option<&str>) -> DeviceArgs {
+    DeviceArgs {
+        port: None,
+        vid: VID,
+        pid: PID,
+        serial: serial.map(str::to_owned),
+        baud: 115_200,
+        no_reconnect: true,
+    }
+}
+
+/// Opens the matching mock device and decodes everything it sends
+pub fn listen(ports: &MockPorts, keys: &Keys) -> (Vec<Event>, io::Result<()>) {
+    listen_with(ports, keys, &device_args(None))
+}
+
+pub fn listen_with(
+    ports: &MockPorts,
+    keys: &Keys,
+    args: &DeviceArgs,
+) -> (Vec<Event>, io::Result<()>) {
+    let mut pipeline = Pipeline::new(keys);
+    let mut events = Vec::new();
+    let port = args.resolve(ports).unwrap();
+    let result = supervise(ports, args, port, Duration::ZERO, |bytes: &[u8]| {
+        pipeline.feed(bytes, |event| {
+            events.push(event);
+            Ok(())
+        })
+    });
+    (events, result)
+}
+
+/// A path in the temp directory for a test to write to, with nothing there yet
+pub fn sequence_file(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path.to_str().unwrap().to_owned()
+}
+
+/// The test auth key in a file for `--auth-key`, so that commands run here do not need
+/// `private/auth-key.bin`
+pub fn auth_key_file(name: &str) -> String {
+    let path = sequence_file(name);
+    std::fs::write(&path, test_keys().auth_key).unwrap();
+    path
+}
+
+pub fn load_keys(auth_key: &str) -> Keys {
+    Keys::load(&KeyArgs {
+        auth_key: Some(auth_key.into()),
+        ..Default::default()
+    })
+    .unwrap()
+}
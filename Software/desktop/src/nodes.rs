@@ -0,0 +1,207 @@
+//! The nodes sending to the receiver, and the names to show them by.
+//!
+//! A registry file has one node per line, its id and then its name: `2 weather station`.
+//! Blank lines and lines starting with `#` are skipped.
+
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use common::MAX_NODES;
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct NodeArgs {
+    /// Node registry file. Without one every node id is known by its number
+    #[clap(long, global = true)]
+    pub nodes: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Registry {
+    names: [Option<String>; MAX_NODES],
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self {
+            names: std::array::from_fn(|node| Some(format!("node {}", node))),
+        }
+    }
+}
+
+impl Registry {
+    pub fn load(args: &NodeArgs) -> io::Result<Self> {
+        match &args.nodes {
+            Some(path) => Self::read(path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn read(path: &Path) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut names: [Option<String>; MAX_NODES] = Default::default();
+        for (number, line) in text
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.trim()))
+        {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |message: String| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("line {}: {}", number, message),
+                )
+            };
+            let (id, name) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let node = id
+                .parse::<u8>()
+                .ok()
+                .filter(|&node| (node as usize) < MAX_NODES)
+                .ok_or_else(|| invalid(format!("`{}` is not a node id below {}", id, MAX_NODES)))?;
+            let name = name.trim();
+            if name.is_empty() {
+                return Err(invalid(format!("node {} has no name", node)));
+            }
+            let slot = &mut names[node as usize];
+            if slot.is_some() {
+                return Err(invalid(format!("node {} is listed twice", node)));
+            }
+            *slot = Some(name.to_owned());
+        }
+        Ok(Self { names })
+    }
+
+    pub fn contains(&self, node: u8) -> bool {
+        matches!(self.names.get(node as usize), Some(Some(_)))
+    }
+
+    /// Fails unless `node` is registered, so that nothing is sent to a node that isn't there
+    pub fn check(&self, node: u8) -> io::Result<()> {
+        if self.contains(node) {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Node {} is not in the node registry", node),
+            ))
+        }
+    }
+
+    /// The registered name of `node`, or its number if it is not registered
+    pub fn name(&self, node: u8) -> String {
+        match self.names.get(node as usize) {
+            Some(Some(name)) => name.clone(),
+            _ => format!("unknown node {}", node),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::{Event, Pipeline};
+    use crate::keys::Keys;
+    use crate::mock::{node_radio, sequence_file, test_keys, RadioState};
+    use common::{
+        ChannelModel, MessageKind, Node, PersistentIndex, RamFlash, Reassembler, ReplayCheck,
+        SimAir, MAX_MESSAGE,
+    };
+
+    #[test]
+    fn parse() {
+        let registry = Registry::parse("# id name\n\n0 roof\n3   garden shed  \n").unwrap();
+        assert!(registry.contains(0) && registry.contains(3));
+        assert_eq!(
+            registry.check(1).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        assert_eq!(registry.name(3), "garden shed");
+        assert_eq!(registry.name(1), "unknown node 1");
+        assert!(!registry.contains(200));
+
+        for (text, line) in [("0 a\n0 b", 2), ("9 far", 1), ("x y", 1), ("\n2", 2)] {
+            let err = Registry::parse(text).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert!(
+                err.to_string().starts_with(&format!("line {}:", line)),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn every_node_by_default() {
+        let registry = Registry::load(&NodeArgs::default()).unwrap();
+        assert!((0..MAX_NODES as u8).all(|node| registry.contains(node)));
+        assert_eq!(registry.name(2), "node 2");
+    }
+    #[test]
+    fn multiple_nodes() {
+        use crate::uplink::{Uplink, UplinkArgs};
+
+        // Node 2 shares the air with the receiver and node 0 in `RadioState`
+        let keys: &'static Keys = Box::leak(Box::new(test_keys()));
+        let mut radio = RadioState::new(keys, SimAir::new(ChannelModel::PERFECT, 5));
+        let mut other = Node::new(&keys.key, keys.index_key, keys.auth_key, 2);
+        let mut other_radio = node_radio(&radio.air, 2);
+        let mut other_index = PersistentIndex::<RamFlash<2, 64>>::open(RamFlash::new(), 16);
+        let mut pipeline = Pipeline::new(keys);
+        let args = UplinkArgs {
+            sequence_file: sequence_file("facilitador-nodes").into(),
+            node: 2,
+        };
+        let mut uplink = Uplink::new(keys, &args);
+        let mut messages: [Reassembler<{ MAX_MESSAGE + 1 }>; MAX_NODES] =
+            std::array::from_fn(|_| Reassembler::new());
+        let mut fresh = [0; MAX_NODES];
+        let mut echoed = Vec::new();
+
+        for ms in 0..1_000u64 {
+            if ms % 50 == 0 {
+                radio.transmit();
+                let index = &mut other_index;
+                other
+                    .transmit(&mut other_radio, ms, |words| index.next(words))
+                    .unwrap();
+            }
+            if ms == 100 {
+                let blocks = uplink.encode(MessageKind::Text, b"only for two").unwrap();
+                radio.host_writes(&blocks);
+            }
+            let bytes = radio.advance(1);
+            other.poll(&mut other_radio).unwrap();
+            pipeline
+                .feed(&bytes, |event| {
+                    let block = match event {
+                        Event::Block(block) => block,
+                        other => panic!("Unexpected event {:?}", other),
+                    };
+                    // Both nodes start at sequence 0, and neither looks like a replay of the other
+                    let node = block.node.unwrap() as usize;
+                    assert_eq!(block.replay, ReplayCheck::Fresh);
+                    assert_eq!(block.sequence, Some(fresh[node]));
+                    fresh[node] += 1;
+                    if let Some((MessageKind::Text, text)) =
+                        messages[node].push(block.sequence.unwrap(), &block.data)
+                    {
+                        echoed.push((node, text.to_vec()));
+                    }
+                    Ok::<_, io::Error>(())
+                })
+                .unwrap();
+        }
+
+        assert!(fresh[0] >= 20 && fresh[2] >= 20, "{:?}", fresh);
+        assert_eq!(fresh.iter().sum::<u32>(), fresh[0] + fresh[2]);
+        // Only the node it was addressed to got the message
+        assert_eq!(echoed, [(2, b"only for two".to_vec())]);
+        std::fs::remove_file(args.node_sequence_file()).unwrap();
+    }
+}
@@ -0,0 +1,326 @@
+//! Sending a node new firmware over the radio, see [`common::OtaReceiver`].
+//!
+//! The image goes up a window of fragments at a time, followed by an [`OtaMessage::Begin`] that
+//! asks the node how much of the image it has. The next window starts from there, so lost
+//! fragments are sent again. Every message is a radio packet of its own, sent in the clear rather
+//! than through the uplink pads, which a whole image would use up, and each carries its own MAC.
+//! Once the node has the whole image and the image's MAC checks out, it restarts into it.
+
+use std::io::{self, ErrorKind, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use clap::Args;
+use common::{
+    ImageHeader, MessageKind, OtaMessage, OtaState, OtaStatus, Reassembler, AUTH_KEY_SIZE,
+    MAX_MESSAGE, MAX_OTA_MESSAGE, OTA_FRAGMENT, TAG_DATA,
+};
+
+use crate::decode::{Event, Pipeline};
+use crate::device::DeviceArgs;
+use crate::keys::Keys;
+use crate::nodes::Registry;
+use crate::supervisor::{self, Session};
+use crate::transport::PortProvider;
+use crate::uplink::{Uplink, UplinkArgs};
+
+#[derive(Args, Debug, Clone)]
+pub struct OtaArgs {
+    /// The image, a raw binary of the node firmware without its key section
+    pub image: PathBuf,
+    /// Version of the image. The node's bootloader only installs images newer than the one it runs
+    #[clap(long)]
+    pub version: u32,
+    /// Fragments sent before waiting for the node to answer
+    #[clap(long, default_value_t = 64)]
+    pub window: usize,
+    /// Seconds to wait for the node to answer
+    #[clap(long, default_value_t = 10)]
+    pub timeout: u64,
+    #[clap(flatten)]
+    pub uplink: UplinkArgs,
+}
+
+enum State {
+    /// The next window goes out at the next poll
+    Send,
+    /// Waiting for the node to answer the Begin sent after the window
+    Window {
+        since: Instant,
+        /// Asked again after the window went unanswered
+        query: bool,
+    },
+    Done,
+}
+
+/// Sends one image and waits for the node to have it
+pub struct OtaSession<'k, W: Write> {
+    pipeline: Pipeline<'k>,
+    uplink: Uplink<'k>,
+    auth_key: [u8; AUTH_KEY_SIZE],
+    messages: Reassembler<{ MAX_MESSAGE + 1 }>,
+    name: String,
+    image: Vec<u8>,
+    header: ImageHeader,
+    window: usize,
+    timeout: Duration,
+    /// Set once the node has answered the first [`OtaMessage::Begin`]
+    begun: bool,
+    /// What the node last said it has
+    received: u32,
+    state: State,
+    out: W,
+}
+
+impl<'k, W: Write> OtaSession<'k, W> {
+    pub fn new(
+        keys: &'k Keys,
+        uplink: Uplink<'k>,
+        name: String,
+        image: Vec<u8>,
+        args: &OtaArgs,
+        out: W,
+    ) -> Self {
+        Self {
+            pipeline: Pipeline::new(keys),
+            uplink,
+            auth_key: keys.auth_key,
+            messages: Reassembler::new(),
+            name,
+            header: ImageHeader::new(&image, args.version, &keys.auth_key),
+            image,
+            window: args.window.max(1),
+            timeout: Duration::from_secs(args.timeout),
+            begun: false,
+            received: 0,
+            state: State::Send,
+            out,
+        }
+    }
+
+    fn encode(&self, message: OtaMessage) -> Vec<u8> {
+        let mut sealed = [0u8; MAX_OTA_MESSAGE];
+        let len = message.seal(self.header.version, &self.auth_key, &mut sealed);
+        self.uplink.encode_packet(&sealed[..len])
+    }
+
+    fn on_status(&mut self, status: OtaStatus) -> io::Result<()> {
+        // The answer to the Begin after the last window can follow the one to the last fragment
+        if status.version != self.header.version || matches!(self.state, State::Done) {
+            return Ok(());
+        }
+        let refused = |reason: &str| Err(io::Error::new(ErrorKind::InvalidInput, reason));
+        match status.state {
+            OtaState::Ready => {
+                writeln!(
+                    self.out,
+                    "{}: has version {}, restarting into it",
+                    self.name, self.header.version
+                )?;
+                self.state = State::Done;
+                return Ok(());
+            }
+            OtaState::BadImage => return refused("The image did not match its MAC on the node"),
+            OtaState::TooBig => {
+                return refused("The image does not fit in the node's staging slot")
+            }
+            OtaState::Busy => {
+                return refused("The node is running an image it has not confirmed yet")
+            }
+            OtaState::Stale => return refused("The node already runs this version or a newer one"),
+            // It restarted and lost track of the image
+            OtaState::Idle => self.begun = false,
+            OtaState::Receiving => self.begun = true,
+        }
+        let size = self.header.size;
+        if status.received * 10 / size.max(1) != self.received * 10 / size.max(1) {
+            writeln!(
+                self.out,
+                "{}: {} of {} bytes",
+                self.name, status.received, size
+            )?;
+        }
+        self.received = status.received;
+        // The node has handled the window, so anything it does not have was lost
+        if let State::Window { .. } = self.state {
+            self.state = State::Send;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Session for OtaSession<'_, W> {
+    fn on_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let mut events = Vec::new();
+        self.pipeline.feed(bytes, |event| {
+            events.push(event);
+            Ok::<_, io::Error>(())
+        })?;
+        for event in events {
+            match event {
+                Event::Block(block) if block.node == Some(self.uplink.node()) => {
+                    let sequence = match block.sequence {
+                        Some(sequence) if block.tag == TAG_DATA => sequence,
+                        _ => continue,
+                    };
+                    let status = match self.messages.push(sequence, &block.data) {
+                        Some((MessageKind::Ota, bytes)) => {
+                            OtaMessage::open(bytes, self.header.version, &self.auth_key)
+                        }
+                        _ => continue,
+                    };
+                    match status {
+                        Ok(OtaMessage::Status(status)) => self.on_status(status)?,
+                        Ok(_) => {}
+                        Err(_) => eprintln!("Ignoring an update status that failed authentication"),
+                    }
+                }
+                Event::Text(text) => eprintln!("receiver: {}", text),
+                Event::Error(err) => eprintln!("error: {}", err),
+                _ => {}
+            }
+        }
+        self.out.flush()
+    }
+
+    fn poll(&mut self, device: &mut dyn Write) -> io::Result<()> {
+        let mut frames = Vec::new();
+        match self.state {
+            State::Send if !self.begun => {
+                frames = self.encode(OtaMessage::Begin(self.header));
+                self.state = State::Window {
+                    since: Instant::now(),
+                    query: true,
+                };
+            }
+            State::Send => {
+                let start = self.received as usize;
+                let end = self.image.len().min(start + self.window * OTA_FRAGMENT);
+                for offset in (start..end).step_by(OTA_FRAGMENT) {
+                    frames.extend(self.encode(OtaMessage::Data {
+                        offset: offset as u32,
+                        data: &self.image[offset..end.min(offset + OTA_FRAGMENT)],
+                    }));
+                }
+                // The node only answers fragments once it has the whole image
+                frames.extend(self.encode(OtaMessage::Begin(self.header)));
+                self.state = State::Window {
+                    since: Instant::now(),
+                    query: false,
+                };
+            }
+            State::Window { since, query, .. } if since.elapsed() >= self.timeout => {
+                if query {
+                    return Err(io::Error::new(
+                        ErrorKind::TimedOut,
+                        format!("No reply from the node after {:?}", self.timeout),
+                    ));
+                }
+                // Ask how far it got
+                frames = self.encode(OtaMessage::Begin(self.header));
+                self.state = State::Window {
+                    since: Instant::now(),
+                    query: true,
+                };
+            }
+            _ => return Ok(()),
+        }
+        device.write_all(&frames)?;
+        device.flush()
+    }
+
+    fn done(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+}
+
+pub fn run(
+    ports: &dyn PortProvider,
+    device: &DeviceArgs,
+    keys: &Keys,
+    nodes: &Registry,
+    args: &OtaArgs,
+    out: impl Write,
+) -> io::Result<()> {
+    nodes.check(args.uplink.node)?;
+    let image = std::fs::read(&args.image)?;
+    if image.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "The image is empty",
+        ));
+    }
+    let session = OtaSession::new(
+        keys,
+        Uplink::new(keys, &args.uplink),
+        nodes.name(args.uplink.node),
+        image,
+        args,
+        out,
+    );
+    supervisor::run(ports, device, session)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{
+        device_args, sequence_file, test_keys, MockPorts, SimulatedNode, Step, PID, VID,
+    };
+    use crate::supervisor::supervise;
+    use common::{Boot, Flash};
+    #[test]
+    fn ota_round_trip() {
+        let keys = test_keys();
+        let mut ports = MockPorts::default();
+        let device = ports.add("/dev/ttyACM0", VID, PID, "A");
+        let state = SimulatedNode::attach(&device, test_keys());
+        let seq = sequence_file("facilitador-ota");
+        // About the size of the node's firmware, and an odd length, so that the last fragment is
+        // not whole words
+        let image: Vec<u8> = (0..30_001u32).map(|i| (i * 7) as u8).collect();
+
+        device.push(Step::Timeout);
+        let args = OtaArgs {
+            image: "firmware.bin".into(),
+            version: 2,
+            window: 64,
+            timeout: 1,
+            uplink: UplinkArgs {
+                sequence_file: seq.clone().into(),
+                node: 0,
+            },
+        };
+        let mut printed = Vec::new();
+        let session = OtaSession::new(
+            &keys,
+            Uplink::new(&keys, &args.uplink),
+            "node 0".into(),
+            image.clone(),
+            &args,
+            &mut printed,
+        );
+        let device_args = device_args(None);
+        supervise(
+            &ports,
+            &device_args,
+            device_args.resolve(&ports).unwrap(),
+            Duration::ZERO,
+            session,
+        )
+        .unwrap();
+        let printed = String::from_utf8(printed).unwrap();
+        let lines: Vec<&str> = printed.lines().collect();
+        assert_eq!(lines.len(), 10, "{}", printed);
+        assert_eq!(lines[0], "node 0: 3072 of 30001 bytes");
+        assert_eq!(lines[9], "node 0: has version 2, restarting into it");
+
+        // What the bootloader does after the restart
+        let mut state = state.lock().unwrap();
+        let flash = state.ota.flash_mut();
+        assert_eq!(common::boot(flash, &keys.auth_key), Ok(Boot::Installed));
+        assert_eq!(flash.read(0, 0), u32::from_le_bytes([0, 7, 14, 21]));
+        // Without using any of the uplink's pads
+        assert!(!std::path::Path::new(&seq).exists());
+    }
+}
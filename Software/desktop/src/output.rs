@@ -0,0 +1,146 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+
+use clap::ValueEnum;
+use common::{ReplayCheck, ResetReason};
+use serde_json::json;
+
+use crate::decode::Event;
+use crate::nodes::Registry;
+use crate::pcapng::{PcapngArgs, PcapngWriter};
+
+/// How results are printed to stdout
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human readable text
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+pub fn print_event(format: OutputFormat, nodes: &Registry, event: &Event) {
+    match format {
+        OutputFormat::Text => match event {
+            Event::Block(block) => match (block.node, block.sequence, block.replay) {
+                (Some(node), Some(sequence), ReplayCheck::Fresh) => println!(
+                    "{}: block #{} tag {} data {:08x?}",
+                    nodes.name(node),
+                    sequence,
+                    block.tag,
+                    block.data
+                ),
+                (Some(node), Some(sequence), replay) => println!(
+                    "{}: block #{} tag {} data {:08x?} ({:?})",
+                    nodes.name(node),
+                    sequence,
+                    block.tag,
+                    block.data,
+                    replay
+                ),
+                _ => println!(
+                    "block with index {:08x} outside the downlink regions tag {} data {:08x?}",
+                    block.index, block.tag, block.data
+                ),
+            },
+            Event::Text(text) => println!("text: {}", text),
+            Event::Counters(c) => println!(
+                "receiver: received {} malformed {} dropped {} radio errors {}",
+                c.received, c.malformed, c.dropped, c.radio_errors
+            ),
+            Event::Radio(r) => println!(
+                "receiver: {} = {}",
+                r.setting.name(),
+                crate::config::format_value(r.setting, r.value)
+            ),
+            Event::Survey { first, occupancy } => println!(
+                "receiver: survey of channels {} to {}",
+                first,
+                *first as usize + occupancy.len() - 1
+            ),
+            Event::Fault(fault) => println!("receiver: reset after a {}", fault),
+            Event::Reset(report) => println!("receiver: {}", report),
+            Event::Error(err) => println!("error: {}", err),
+        },
+        OutputFormat::Json => {
+            let value = match event {
+                Event::Block(block) => json!({
+                    "type": "block",
+                    "index": block.index,
+                    "node": block.node,
+                    "name": block.node.map(|node| nodes.name(node)),
+                    "sequence": block.sequence,
+                    "tag": block.tag,
+                    "data": block.data,
+                    "replay": format!("{:?}", block.replay),
+                }),
+                Event::Text(text) => json!({ "type": "text", "text": text }),
+                Event::Counters(c) => json!({
+                    "type": "counters",
+                    "received": c.received,
+                    "malformed": c.malformed,
+                    "dropped": c.dropped,
+                    "radio_errors": c.radio_errors,
+                }),
+                Event::Radio(r) => json!({
+                    "type": "radio",
+                    "setting": r.setting.name(),
+                    "value": r.value,
+                }),
+                Event::Survey { first, occupancy } => json!({
+                    "type": "survey",
+                    "first": first,
+                    "occupancy": occupancy,
+                }),
+                Event::Fault(fault) => json!({
+                    "type": "fault",
+                    "class": fault.class().name(),
+                    "fault": fault.to_string(),
+                }),
+                Event::Reset(report) => json!({
+                    "type": "reset",
+                    "reason": report.reason.name(),
+                    "counts": ResetReason::ALL
+                        .iter()
+                        .map(|&reason| (reason.name(), report.counts.get(reason)))
+                        .collect::<std::collections::BTreeMap<_, _>>(),
+                }),
+                Event::Error(err) => json!({ "type": "error", "error": err.to_string() }),
+            };
+            println!("{}", value);
+        }
+    }
+}
+
+/// Where decoded events go: stdout in the chosen format, and a pcapng file if one was asked for
+pub struct Output {
+    format: OutputFormat,
+    nodes: Registry,
+    pcapng: Option<PcapngWriter<BufWriter<File>>>,
+}
+
+impl Output {
+    pub fn new(format: OutputFormat, nodes: Registry, pcapng: &PcapngArgs) -> io::Result<Self> {
+        Ok(Self {
+            format,
+            nodes,
+            pcapng: pcapng.create()?,
+        })
+    }
+
+    pub fn event(&mut self, timestamp_us: u64, event: &Event) -> io::Result<()> {
+        print_event(self.format, &self.nodes, event);
+        match &mut self.pcapng {
+            Some(pcapng) => pcapng.write_event(timestamp_us, event),
+            None => Ok(()),
+        }
+    }
+
+    /// Flushes buffered files. Called after every read so that little is lost when the tool is
+    /// killed
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.pcapng {
+            Some(pcapng) => pcapng.flush(),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! Writes received blocks as pcapng so that traffic can be looked at in Wireshark.
+//!
+//! Interface 0 carries every [`common::VarBlock`] exactly as it came over the air using
+//! `LINKTYPE_USER0`. When plaintext is enabled, interface 1 carries the same block after
+//! decryption using `LINKTYPE_USER1`. Index, tag and decrypt status go in the packet comment.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::Args;
+
+use crate::decode::{DecodeError, DecodedBlock, Event};
+
+const LINKTYPE_USER0: u16 = 147;
+const LINKTYPE_USER1: u16 = 148;
+
+const SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const SHB_USERAPPL: u16 = 4;
+const IF_NAME: u16 = 2;
+
+const CIPHERTEXT_INTERFACE: u32 = 0;
+const PLAINTEXT_INTERFACE: u32 = 1;
+
+#[derive(Args, Debug, Clone)]
+pub struct PcapngArgs {
+    /// Also write received blocks to this pcapng file
+    #[clap(long)]
+    pub pcapng: Option<PathBuf>,
+
+    /// Add the decrypted block as a second packet after each received block
+    #[clap(long, requires = "pcapng")]
+    pub pcapng_plaintext: bool,
+}
+
+impl PcapngArgs {
+    pub fn create(&self) -> io::Result<Option<PcapngWriter<BufWriter<File>>>> {
+        self.pcapng
+            .as_ref()
+            .map(|path| {
+                PcapngWriter::new(BufWriter::new(File::create(path)?), self.pcapng_plaintext)
+            })
+            .transpose()
+    }
+}
+
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+fn pad(body: &mut Vec<u8>) {
+    body.resize(body.len().next_multiple_of(4), 0);
+}
+
+pub struct PcapngWriter<W: Write> {
+    out: W,
+    plaintext: bool,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    /// Writes the section header and interface descriptions.
+    /// The plaintext interface is only described when `plaintext` is set
+    pub fn new(out: W, plaintext: bool) -> io::Result<Self> {
+        let mut this = Self { out, plaintext };
+
+        let mut shb = Vec::new();
+        shb.extend_from_slice(&0x1A2B_3C4Du32.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        // Section length is unknown
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        push_option(&mut shb, SHB_USERAPPL, b"facilitador desktop");
+        push_option(&mut shb, OPT_END, &[]);
+        this.write_block(SECTION_HEADER, &shb)?;
+
+        this.write_interface(LINKTYPE_USER0, "radio ciphertext")?;
+        if plaintext {
+            this.write_interface(LINKTYPE_USER1, "radio plaintext")?;
+        }
+        Ok(this)
+    }
+
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> io::Result<()> {
+        // Type and both length fields
+        let total_len = (body.len() + 12) as u32;
+        self.out.write_all(&block_type.to_le_bytes())?;
+        self.out.write_all(&total_len.to_le_bytes())?;
+        self.out.write_all(body)?;
+        self.out.write_all(&total_len.to_le_bytes())
+    }
+
+    fn write_interface(&mut self, link_type: u16, name: &str) -> io::Result<()> {
+        let mut idb = Vec::new();
+        idb.extend_from_slice(&link_type.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        // No snap length limit. The timestamp resolution defaults to microseconds
+        idb.extend_from_slice(&0u32.to_le_bytes());
+        push_option(&mut idb, IF_NAME, name.as_bytes());
+        push_option(&mut idb, OPT_END, &[]);
+        self.write_block(INTERFACE_DESCRIPTION, &idb)
+    }
+
+    fn write_packet(
+        &mut self,
+        interface: u32,
+        timestamp_us: u64,
+        data: &[u8],
+        comment: &str,
+    ) -> io::Result<()> {
+        let mut epb = Vec::new();
+        epb.extend_from_slice(&interface.to_le_bytes());
+        epb.extend_from_slice(&((timestamp_us >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(timestamp_us as u32).to_le_bytes());
+        epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        epb.extend_from_slice(data);
+        pad(&mut epb);
+        push_option(&mut epb, OPT_COMMENT, comment.as_bytes());
+        push_option(&mut epb, OPT_END, &[]);
+        self.write_block(ENHANCED_PACKET, &epb)
+    }
+
+    pub fn write_block_packets(
+        &mut self,
+        timestamp_us: u64,
+        block: &DecodedBlock,
+    ) -> io::Result<()> {
+        let sequence = match block.sequence {
+            Some(sequence) => sequence.to_string(),
+            None => "none".to_owned(),
+        };
+        let node = match block.node {
+            Some(node) => node.to_string(),
+            None => "none".to_owned(),
+        };
+        let comment = format!(
+            "index={} node={} sequence={} tag={} decrypt=ok replay={:?}",
+            block.index, node, sequence, block.tag, block.replay
+        );
+        self.write_packet(
+            CIPHERTEXT_INTERFACE,
+            timestamp_us,
+            &block.ciphertext,
+            &comment,
+        )?;
+        if self.plaintext {
+            self.write_packet(
+                PLAINTEXT_INTERFACE,
+                timestamp_us,
+                &block.plaintext(),
+                &comment,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes a packet for `event` if it contains a block. Other events are ignored
+    pub fn write_event(&mut self, timestamp_us: u64, event: &Event) -> io::Result<()> {
+        match event {
+            Event::Block(block) => self.write_block_packets(timestamp_us, block),
+            Event::Error(DecodeError::BlockLength(raw)) => {
+                let comment = format!("decrypt=failed length={}", raw.len());
+                self.write_packet(CIPHERTEXT_INTERFACE, timestamp_us, raw, &comment)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::ReplayCheck;
+
+    /// Splits a pcapng file into (block type, body) pairs, checking the framing on the way
+    fn blocks(mut file: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut blocks = Vec::new();
+        while !file.is_empty() {
+            let block_type = u32::from_le_bytes(file[0..4].try_into().unwrap());
+            let len = u32::from_le_bytes(file[4..8].try_into().unwrap()) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(&file[len - 4..len], &file[4..8]);
+            blocks.push((block_type, file[8..len - 4].to_vec()));
+            file = &file[len..];
+        }
+        blocks
+    }
+
+    fn block() -> DecodedBlock {
+        DecodedBlock {
+            index: 5,
+            node: Some(0),
+            sequence: Some(3),
+            tag: 1,
+            data: [1, 2, 3, 4, 5, 6, 7],
+            ciphertext: vec![0xAB; 32],
+            replay: ReplayCheck::Fresh,
+        }
+    }
+
+    #[test]
+    fn ciphertext_only() {
+        let mut writer = PcapngWriter::new(Vec::new(), false).unwrap();
+        writer
+            .write_event(1_000_001, &Event::Block(block()))
+            .unwrap();
+        writer
+            .write_event(1_000_002, &Event::Text("ignored".into()))
+            .unwrap();
+        let types: Vec<_> = blocks(&writer.out).into_iter().map(|(t, _)| t).collect();
+        assert_eq!(
+            types,
+            vec![SECTION_HEADER, INTERFACE_DESCRIPTION, ENHANCED_PACKET]
+        );
+    }
+
+    #[test]
+    fn plaintext_packet() {
+        let mut writer = PcapngWriter::new(Vec::new(), true).unwrap();
+        writer.write_event(1 << 33, &Event::Block(block())).unwrap();
+        let blocks = blocks(&writer.out);
+        assert_eq!(blocks.len(), 5);
+
+        let (ty, plaintext) = &blocks[4];
+        assert_eq!(*ty, ENHANCED_PACKET);
+        assert_eq!(plaintext[0..4], PLAINTEXT_INTERFACE.to_le_bytes());
+        // Timestamp high and low words
+        assert_eq!(plaintext[4..8], 2u32.to_le_bytes());
+        assert_eq!(plaintext[8..12], 0u32.to_le_bytes());
+        assert_eq!(plaintext[12..16], 32u32.to_le_bytes());
+        assert_eq!(plaintext[20..52], block().plaintext());
+        let comment = b"index=5 node=0 sequence=3 tag=1 decrypt=ok replay=Fresh";
+        assert_eq!(&plaintext[56..56 + comment.len()], comment);
+    }
+}
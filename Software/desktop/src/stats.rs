@@ -0,0 +1,611 @@
+//! Link quality worked out from block sequence numbers.
+//!
+//! The transmitter increments its sequence number by one for every block, so a jump forward
+//! means blocks were lost, a sequence number below the newest one means a block arrived late,
+//! and one seen before is a duplicate. Every node has its own sequence numbers, so this is
+//! worked out for each node on its own, and the totals are added up over all of them.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use common::{ReceiverCounters, ReplayCheck, MAX_NODES};
+use serde_json::{json, Value};
+
+use crate::decode::Event;
+use crate::nodes::Registry;
+
+/// Payload bytes carried by one block
+const BLOCK_PAYLOAD: u64 = 28;
+
+/// Event counts over some span of time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counts {
+    /// Blocks with a sequence number that had not been seen before
+    pub blocks: u64,
+    /// Sequence numbers skipped over when the sequence jumped forward
+    pub skipped: u64,
+    /// Blocks that arrived after a newer block, filling in a skipped sequence number
+    pub reordered: u64,
+    pub duplicates: u64,
+    /// Blocks too far behind the newest sequence number to tell whether they are duplicates
+    pub too_old: u64,
+    pub texts: u64,
+    /// Frames that could not be decoded, and blocks that were not sent by a node
+    pub errors: u64,
+}
+
+impl Counts {
+    fn add(&mut self, other: &Counts) {
+        self.blocks += other.blocks;
+        self.skipped += other.skipped;
+        self.reordered += other.reordered;
+        self.duplicates += other.duplicates;
+        self.too_old += other.too_old;
+        self.texts += other.texts;
+        self.errors += other.errors;
+    }
+
+    fn sub(&mut self, other: &Counts) {
+        self.blocks -= other.blocks;
+        self.skipped -= other.skipped;
+        self.reordered -= other.reordered;
+        self.duplicates -= other.duplicates;
+        self.too_old -= other.too_old;
+        self.texts -= other.texts;
+        self.errors -= other.errors;
+    }
+
+    /// Skipped indices that never showed up
+    pub fn lost(&self) -> u64 {
+        self.skipped.saturating_sub(self.reordered)
+    }
+
+    /// The fraction of sent blocks that were lost, between 0 and 1
+    pub fn loss_ratio(&self) -> f64 {
+        let sent = self.blocks + self.lost();
+        if sent == 0 {
+            0.0
+        } else {
+            self.lost() as f64 / sent as f64
+        }
+    }
+}
+
+/// Counts over a span of time, with rates derived from them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub span: Duration,
+    pub counts: Counts,
+}
+
+impl Summary {
+    pub fn blocks_per_sec(&self) -> f64 {
+        match self.span.as_secs_f64() {
+            secs if secs > 0.0 => self.counts.blocks as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    /// Goodput in payload bytes per second, not counting duplicates
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.blocks_per_sec() * BLOCK_PAYLOAD as f64
+    }
+}
+
+/// Tracks link quality over a sliding window and over the whole session
+pub struct LinkStats {
+    window_us: u64,
+    start_us: Option<u64>,
+    now_us: u64,
+    /// The newest sequence number seen from each node
+    newest: [Option<u32>; MAX_NODES],
+    session: Counts,
+    /// The part of `session` each node is responsible for
+    nodes: [Counts; MAX_NODES],
+    window: Counts,
+    /// What each event added to `window`, oldest first
+    recent: VecDeque<(u64, Counts)>,
+    receiver: Option<ReceiverCounters>,
+}
+
+impl LinkStats {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window_us: window.as_micros() as u64,
+            start_us: None,
+            now_us: 0,
+            newest: [None; MAX_NODES],
+            session: Counts::default(),
+            nodes: [Counts::default(); MAX_NODES],
+            window: Counts::default(),
+            recent: VecDeque::new(),
+            receiver: None,
+        }
+    }
+
+    /// Accounts for an event decoded at `timestamp_us`
+    pub fn record(&mut self, timestamp_us: u64, event: &Event) {
+        let mut delta = Counts::default();
+        match event {
+            Event::Block(block) => match (block.node, block.sequence) {
+                (Some(node), Some(sequence)) => {
+                    let newest = &mut self.newest[node as usize];
+                    match block.replay {
+                        ReplayCheck::Fresh => {
+                            delta.blocks = 1;
+                            match *newest {
+                                Some(n) if sequence < n => delta.reordered = 1,
+                                Some(n) => {
+                                    delta.skipped = u64::from((sequence - n).saturating_sub(1));
+                                    *newest = Some(sequence);
+                                }
+                                None => *newest = Some(sequence),
+                            }
+                        }
+                        ReplayCheck::Duplicate => delta.duplicates = 1,
+                        ReplayCheck::TooOld => delta.too_old = 1,
+                    }
+                    self.nodes[node as usize].add(&delta);
+                }
+                // Not sent by a node, or decrypted with the wrong key
+                _ => delta.errors = 1,
+            },
+            Event::Text(_) => delta.texts = 1,
+            Event::Counters(counters) => self.receiver = Some(*counters),
+            Event::Radio(_) | Event::Survey { .. } | Event::Fault(_) | Event::Reset(_) => {}
+            Event::Error(_) => delta.errors = 1,
+        }
+
+        self.start_us.get_or_insert(timestamp_us);
+        self.advance(timestamp_us);
+        self.session.add(&delta);
+        self.window.add(&delta);
+        self.recent.push_back((timestamp_us, delta));
+    }
+
+    /// Moves the window forward to `timestamp_us`, forgetting events that fall out of it
+    pub fn advance(&mut self, timestamp_us: u64) {
+        self.now_us = self.now_us.max(timestamp_us);
+        let Some(cutoff) = self.now_us.checked_sub(self.window_us) else {
+            return;
+        };
+        while let Some((ts, delta)) = self.recent.front() {
+            if *ts > cutoff {
+                break;
+            }
+            self.window.sub(delta);
+            self.recent.pop_front();
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        let start = self.start_us.unwrap_or(self.now_us);
+        Duration::from_micros(self.now_us - start)
+    }
+
+    /// Counts for the last window. Early in a session the window is only as long as the session
+    pub fn window(&self) -> Summary {
+        Summary {
+            span: self.elapsed().min(Duration::from_micros(self.window_us)),
+            counts: self.window,
+        }
+    }
+
+    pub fn session(&self) -> Summary {
+        Summary {
+            span: self.elapsed(),
+            counts: self.session,
+        }
+    }
+
+    /// The session counts of every node that has sent a block
+    pub fn nodes(&self) -> impl Iterator<Item = (u8, Summary)> + '_ {
+        let span = self.elapsed();
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, counts)| **counts != Counts::default())
+            .map(move |(node, &counts)| (node as u8, Summary { span, counts }))
+    }
+
+    /// The latest counters reported by the receiver, if it has sent any
+    pub fn receiver(&self) -> Option<ReceiverCounters> {
+        self.receiver
+    }
+}
+
+fn summary_json(scope: &str, summary: &Summary) -> Value {
+    let c = &summary.counts;
+    json!({
+        "scope": scope,
+        "span_s": summary.span.as_secs_f64(),
+        "blocks": c.blocks,
+        "lost": c.lost(),
+        "loss_ratio": c.loss_ratio(),
+        "duplicates": c.duplicates,
+        "reordered": c.reordered,
+        "too_old": c.too_old,
+        "texts": c.texts,
+        "errors": c.errors,
+        "blocks_per_s": summary.blocks_per_sec(),
+        "bytes_per_s": summary.bytes_per_sec(),
+    })
+}
+
+fn receiver_json(receiver: Option<ReceiverCounters>) -> Value {
+    match receiver {
+        Some(r) => json!({
+            "received": r.received,
+            "malformed": r.malformed,
+            "dropped": r.dropped,
+            "radio_errors": r.radio_errors,
+        }),
+        None => Value::Null,
+    }
+}
+
+/// Formats the window, session and each node's session as one JSON object
+pub fn stats_json(timestamp_us: u64, stats: &LinkStats, nodes: &Registry) -> Value {
+    let per_node: Vec<Value> = stats
+        .nodes()
+        .map(|(node, summary)| {
+            let mut value = summary_json("node", &summary);
+            value["node"] = json!(node);
+            value["name"] = json!(nodes.name(node));
+            value
+        })
+        .collect();
+    json!({
+        "timestamp_us": timestamp_us,
+        "window": summary_json("window", &stats.window()),
+        "session": summary_json("session", &stats.session()),
+        "nodes": per_node,
+        "receiver": receiver_json(stats.receiver()),
+    })
+}
+
+fn summary_text(name: &str, summary: &Summary) -> String {
+    let c = &summary.counts;
+    format!(
+        "{} {:.0}s: {:.1} blocks/s {:.0} B/s, loss {:.2}% ({} lost), {} dup, {} reordered, {} old, {} errors",
+        name,
+        summary.span.as_secs_f64(),
+        summary.blocks_per_sec(),
+        summary.bytes_per_sec(),
+        c.loss_ratio() * 100.0,
+        c.lost(),
+        c.duplicates,
+        c.reordered,
+        c.too_old,
+        c.errors,
+    )
+}
+
+/// Formats the window, session and each node's session as human readable lines
+pub fn stats_text(stats: &LinkStats, nodes: &Registry) -> String {
+    let mut text = format!(
+        "{}\n{}",
+        summary_text("last", &stats.window()),
+        summary_text("session", &stats.session())
+    );
+    for (node, summary) in stats.nodes() {
+        text += "\n";
+        text += &summary_text(&nodes.name(node), &summary);
+    }
+    if let Some(r) = stats.receiver() {
+        text += &format!(
+            "\nreceiver: {} received, {} malformed, {} dropped, {} radio errors",
+            r.received, r.malformed, r.dropped, r.radio_errors
+        );
+    }
+    text
+}
+
+/// File formats that summaries can be exported in
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// One row per summary and scope, with a `node<id>` scope for each node's session
+    Csv,
+    /// One JSON object per line
+    Json,
+}
+
+const CSV_HEADER: &str = "timestamp_us,scope,span_s,blocks,lost,loss_ratio,duplicates,reordered,too_old,texts,errors,blocks_per_s,bytes_per_s,rx_received,rx_malformed,rx_dropped,rx_radio_errors";
+
+/// Appends summaries to a file as they are made
+pub struct StatsExport<W: Write> {
+    out: W,
+    format: ExportFormat,
+}
+
+impl StatsExport<BufWriter<File>> {
+    pub fn create(path: &Path, format: ExportFormat) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), format)
+    }
+}
+
+impl<W: Write> StatsExport<W> {
+    pub fn new(mut out: W, format: ExportFormat) -> io::Result<Self> {
+        if format == ExportFormat::Csv {
+            writeln!(out, "{}", CSV_HEADER)?;
+        }
+        Ok(Self { out, format })
+    }
+
+    /// Writes one summary. `nodes` names the nodes in JSON, CSV only has their ids
+    pub fn write(
+        &mut self,
+        timestamp_us: u64,
+        stats: &LinkStats,
+        nodes: &Registry,
+    ) -> io::Result<()> {
+        match self.format {
+            ExportFormat::Json => writeln!(self.out, "{}", stats_json(timestamp_us, stats, nodes))?,
+            ExportFormat::Csv => {
+                // Unknown receiver counters are left empty
+                let receiver = match stats.receiver() {
+                    Some(r) => format!(
+                        "{},{},{},{}",
+                        r.received, r.malformed, r.dropped, r.radio_errors
+                    ),
+                    None => ",,,".to_owned(),
+                };
+                let scopes = [
+                    ("window".to_owned(), stats.window()),
+                    ("session".to_owned(), stats.session()),
+                ];
+                let per_node = stats
+                    .nodes()
+                    .map(|(node, summary)| (format!("node{}", node), summary));
+                for (scope, summary) in scopes.into_iter().chain(per_node) {
+                    let c = &summary.counts;
+                    writeln!(
+                        self.out,
+                        "{},{},{:.3},{},{},{:.6},{},{},{},{},{},{:.3},{:.3},{}",
+                        timestamp_us,
+                        scope,
+                        summary.span.as_secs_f64(),
+                        c.blocks,
+                        c.lost(),
+                        c.loss_ratio(),
+                        c.duplicates,
+                        c.reordered,
+                        c.too_old,
+                        c.texts,
+                        c.errors,
+                        summary.blocks_per_sec(),
+                        summary.bytes_per_sec(),
+                        receiver,
+                    )?;
+                }
+            }
+        }
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::{DecodeError, DecodedBlock, Pipeline};
+    use crate::keys::Keys;
+    use crate::mock::{sequence_file, test_keys, RadioState};
+    use common::{ChannelModel, MessageKind, Reassembler, ReplayWindow, SimAir, MAX_MESSAGE};
+
+    /// Runs node 0's indices through a replay window like the pipeline does, one every 10ms
+    fn feed(stats: &mut LinkStats, window: &mut ReplayWindow, start_us: u64, indices: &[u32]) {
+        feed_node(stats, window, 0, start_us, indices)
+    }
+
+    fn feed_node(
+        stats: &mut LinkStats,
+        window: &mut ReplayWindow,
+        node: u8,
+        start_us: u64,
+        indices: &[u32],
+    ) {
+        for (i, &index) in indices.iter().enumerate() {
+            let block = DecodedBlock {
+                index,
+                node: Some(node),
+                sequence: Some(index),
+                tag: 0,
+                data: [0; 7],
+                ciphertext: vec![0; 32],
+                replay: window.check(index),
+            };
+            stats.record(start_us + i as u64 * 10_000, &Event::Block(block));
+        }
+    }
+
+    #[test]
+    fn loss_duplicates_and_reordering() {
+        let mut stats = LinkStats::new(Duration::from_secs(10));
+        let mut window = ReplayWindow::new();
+        // 3 and 7 are lost, 5 arrives late, 8 is duplicated
+        feed(&mut stats, &mut window, 0, &[0, 1, 2, 4, 6, 5, 8, 8, 9, 10]);
+        stats.record(100_000, &Event::Error(DecodeError::CountersLength(3)));
+
+        let c = stats.session().counts;
+        assert_eq!(c.blocks, 9);
+        assert_eq!(c.lost(), 2);
+        assert_eq!(c.duplicates, 1);
+        assert_eq!(c.reordered, 1);
+        assert_eq!(c.errors, 1);
+        assert!((c.loss_ratio() - 2.0 / 11.0).abs() < 1e-9);
+        assert_eq!(stats.session().span, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn window_slides() {
+        let mut stats = LinkStats::new(Duration::from_secs(1));
+        let mut window = ReplayWindow::new();
+        // A lossy first second, then a clean one
+        let lossy: Vec<u32> = (0..200).step_by(2).collect();
+        feed(&mut stats, &mut window, 0, &lossy);
+        let clean: Vec<u32> = (200..300).collect();
+        feed(&mut stats, &mut window, 1_000_000, &clean);
+
+        let w = stats.window();
+        assert_eq!(w.counts.blocks, 100);
+        // The jump from 198 to 200 is charged to the window it was noticed in
+        assert_eq!(w.counts.lost(), 1);
+        assert!((w.blocks_per_sec() - 100.0).abs() < 1e-9);
+
+        let s = stats.session();
+        assert_eq!(s.counts.blocks, 200);
+        // Every odd index below 199, plus the gap from 198 to 200
+        assert_eq!(s.counts.lost(), 100);
+
+        // Nothing arrives for a while, so the window empties
+        stats.advance(5_000_000);
+        assert_eq!(stats.window().counts, Counts::default());
+        assert_eq!(stats.session().counts.blocks, 200);
+    }
+
+    #[test]
+    fn nodes_are_counted_apart() {
+        let mut stats = LinkStats::new(Duration::from_secs(10));
+        let (mut window0, mut window3) = (ReplayWindow::new(), ReplayWindow::new());
+        // Interleaved, node 3 being far ahead of node 0 does not look like loss
+        feed_node(&mut stats, &mut window0, 0, 0, &[0, 1]);
+        feed_node(&mut stats, &mut window3, 3, 0, &[500, 502]);
+        feed_node(&mut stats, &mut window0, 0, 20_000, &[2, 3, 3]);
+
+        let c = stats.session().counts;
+        assert_eq!(
+            (c.blocks, c.lost(), c.reordered, c.duplicates),
+            (6, 1, 0, 1)
+        );
+        let nodes: Vec<_> = stats
+            .nodes()
+            .map(|(node, s)| (node, s.counts.blocks, s.counts.lost()))
+            .collect();
+        assert_eq!(nodes, [(0, 4, 0), (3, 2, 1)]);
+
+        let registry = Registry::parse(
+            "0 roof
+3 shed",
+        )
+        .unwrap();
+        let text = stats_text(&stats, &registry);
+        assert!(
+            text.lines().nth(3).unwrap().starts_with("shed 0s:"),
+            "{}",
+            text
+        );
+        let json = stats_json(0, &stats, &registry);
+        assert_eq!(json["nodes"][1]["name"], "shed");
+        assert_eq!(json["nodes"][1]["lost"], 1);
+    }
+
+    #[test]
+    fn csv_export() {
+        let mut stats = LinkStats::new(Duration::from_secs(10));
+        let mut window = ReplayWindow::new();
+        feed(&mut stats, &mut window, 0, &[0, 1, 3]);
+        stats.record(
+            20_000,
+            &Event::Counters(ReceiverCounters {
+                received: 4,
+                malformed: 1,
+                dropped: 0,
+                radio_errors: 2,
+            }),
+        );
+
+        let mut export = StatsExport::new(Vec::new(), ExportFormat::Csv).unwrap();
+        export.write(42, &stats, &Registry::default()).unwrap();
+        let text = String::from_utf8(export.out).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], CSV_HEADER);
+        let columns = CSV_HEADER.split(',').count();
+        for line in &lines[1..] {
+            assert_eq!(line.split(',').count(), columns);
+            assert!(line.ends_with(",4,1,0,2"));
+        }
+        assert!(lines[1].starts_with("42,window,0.020,3,1,0.250000,"));
+        assert!(lines[3].starts_with("42,node0,0.020,3,1,0.250000,"));
+    }
+    #[test]
+    fn lossy_link() {
+        use crate::uplink::{Uplink, UplinkArgs};
+
+        // The node and receiver logic over a bad link, decoded like the desktop does. Nothing is
+        // corrupted, a flipped index bit can land anywhere and would throw off the loss count
+        let keys: &'static Keys = Box::leak(Box::new(test_keys()));
+        let model = ChannelModel {
+            loss: 0.1,
+            burst_start: 0.02,
+            burst_end: 0.3,
+            corruption: 0.0,
+            latency_ms: 2,
+            jitter_ms: 15,
+            path_loss_db: 0.0,
+        };
+        let mut radio = RadioState::new(keys, SimAir::new(model, 7));
+        let mut pipeline = Pipeline::new(keys);
+        let args = UplinkArgs {
+            sequence_file: sequence_file("facilitador-lossy").into(),
+            node: 0,
+        };
+        let mut uplink = Uplink::new(keys, &args);
+        let mut stats = LinkStats::new(Duration::from_secs(1));
+        let mut messages = Reassembler::<{ MAX_MESSAGE + 1 }>::new();
+        let mut echoed = None;
+        let mut frames = 0;
+
+        for ms in 0..10_000u64 {
+            if ms % 10 == 0 {
+                radio.transmit();
+            }
+            // Keep sending until the message makes it there and back
+            if ms % 500 == 0 && echoed.is_none() {
+                let blocks = uplink.encode(MessageKind::Text, b"over the air").unwrap();
+                radio.host_writes(&blocks);
+            }
+            if ms % 1000 == 999 {
+                radio.relay.send_counters();
+            }
+            let bytes = radio.advance(1);
+            pipeline
+                .feed(&bytes, |event| {
+                    stats.record(ms * 1000, &event);
+                    match &event {
+                        Event::Block(block) => {
+                            frames += 1;
+                            if let (Some(sequence), ReplayCheck::Fresh) =
+                                (block.sequence, block.replay)
+                            {
+                                if let Some((MessageKind::Text, text)) =
+                                    messages.push(sequence, &block.data)
+                                {
+                                    echoed = Some(text.to_vec());
+                                }
+                            }
+                        }
+                        Event::Error(_) => frames += 1,
+                        _ => {}
+                    }
+                    Ok::<_, io::Error>(())
+                })
+                .unwrap();
+        }
+
+        assert_eq!(echoed.as_deref(), Some(&b"over the air"[..]));
+        let air = radio.air.stats();
+        assert!(air.lost > 100, "{:?}", air);
+        // Everything the receiver heard reached the desktop
+        assert_eq!(frames, radio.relay.counters().received);
+        // Blocks the node had to send again do not show up as lost
+        let session = stats.session().counts;
+        assert!(session.blocks > 700, "{:?}", session);
+        assert!(session.loss_ratio() < 0.05, "{:?}", session);
+        std::fs::remove_file(args.sequence_file).unwrap();
+    }
+}
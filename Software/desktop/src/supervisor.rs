@@ -0,0 +1,226 @@
+//! Keeps a session going while the receiver is unplugged and plugged back in.
+//!
+//! Everything the caller keeps outside of [`supervise`] (decode pipeline, replay window, capture
+//! writer) lives on across reconnects, so a session picks up where it left off.
+
+use std::io::{self, ErrorKind, Write};
+use std::time::{Duration, Instant};
+
+use crate::device::{DeviceArgs, ResolvedPort};
+use crate::transport::{PortProvider, Transport};
+
+/// How often to look for the device again after it disappears
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What [`supervise`] drives. Closures that only read from the device implement this
+pub trait Session {
+    fn on_bytes(&mut self, bytes: &[u8]) -> io::Result<()>;
+
+    /// Called after every read, including ones that time out, so that the session can write to
+    /// the device
+    fn poll(&mut self, _device: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Checked after every poll. Returning true ends the session as if the stream had ended
+    fn done(&self) -> bool {
+        false
+    }
+}
+
+impl<F: FnMut(&[u8]) -> io::Result<()>> Session for F {
+    fn on_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self(bytes)
+    }
+}
+
+/// Finds the device and runs `session` on it with [`supervise`], polling every
+/// [`POLL_INTERVAL`]
+pub fn run(ports: &dyn PortProvider, args: &DeviceArgs, session: impl Session) -> io::Result<()> {
+    let port = args.resolve(ports)?;
+    supervise(ports, args, port, POLL_INTERVAL, session)
+}
+
+/// Reads from `port` until the stream ends, passing every chunk of bytes to `session`.
+///
+/// When a read or write fails the device is assumed to be gone. Unless `--no-reconnect` was
+/// given, the ports are polled every `poll` for a device with the same VID/PID and serial number
+/// (or the same path when `--port` was given), which is then opened and used as before.
+/// Other errors returned by `session` are never retried
+pub fn supervise(
+    ports: &dyn PortProvider,
+    args: &DeviceArgs,
+    mut port: ResolvedPort,
+    poll: Duration,
+    mut session: impl Session,
+) -> io::Result<()> {
+    let mut device = port.open(ports, args.baud)?;
+    loop {
+        let err = match pump(device.as_mut(), &mut session)? {
+            Some(err) => err,
+            None => return Ok(()),
+        };
+        if args.no_reconnect {
+            return Err(err);
+        }
+        eprintln!("Lost {} ({}), waiting for it to come back", port.path, err);
+        let lost_at = Instant::now();
+        device = loop {
+            match reopen(ports, args, &mut port) {
+                Ok(device) => break device,
+                Err(e) if e.kind() == ErrorKind::NotFound => std::thread::sleep(poll),
+                // The port showed up but could not be opened yet, udev is often still busy
+                Err(e) => {
+                    eprintln!("Failed to reopen {}: {}", port.path, e);
+                    std::thread::sleep(poll);
+                }
+            }
+        };
+        eprintln!(
+            "Reconnected to {} after {:.1}s",
+            port.path,
+            lost_at.elapsed().as_secs_f64()
+        );
+    }
+}
+
+/// Remembers whether writing to the device failed, so that a write error coming back out of
+/// [`Session::poll`] is treated as the device going away
+struct DeviceWriter<'a> {
+    device: &'a mut dyn Transport,
+    failed: bool,
+}
+
+impl Write for DeviceWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.device.write(buf);
+        self.failed |= result.is_err();
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = self.device.flush();
+        self.failed |= result.is_err();
+        result
+    }
+}
+
+/// Runs the session until the stream or the session ends (`Ok(None)`) or the transport fails
+/// (`Ok(Some(err))`). Only errors from the session itself are returned as `Err`
+fn pump(device: &mut dyn Transport, session: &mut impl Session) -> io::Result<Option<io::Error>> {
+    let mut data = [0u8; 1024];
+    loop {
+        match device.read(&mut data) {
+            Ok(0) => return Ok(None),
+            Ok(n) => session.on_bytes(&data[..n])?,
+            // A timeout just means nothing was sent recently
+            Err(e) if e.kind() == ErrorKind::TimedOut => {}
+            Err(e) => return Ok(Some(e)),
+        }
+        let mut writer = DeviceWriter {
+            device: &mut *device,
+            failed: false,
+        };
+        match session.poll(&mut writer) {
+            Ok(()) if session.done() => return Ok(None),
+            Ok(()) => {}
+            Err(e) if writer.failed => return Ok(Some(e)),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Looks for the device that was lost and opens it. The path is updated if the device came back
+/// under a different name
+fn reopen(
+    ports: &dyn PortProvider,
+    args: &DeviceArgs,
+    port: &mut ResolvedPort,
+) -> io::Result<Box<dyn Transport>> {
+    if args.port.is_none() {
+        let found = args
+            .find_all(ports)?
+            .into_iter()
+            .find(|found| match &port.serial_number {
+                Some(serial) => found.usb.serial_number.as_ref() == Some(serial),
+                None => true,
+            })
+            .ok_or_else(|| io::Error::from(ErrorKind::NotFound))?;
+        port.path = found.port.port_name;
+    }
+    port.open(ports, args.baud)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::Event;
+    use crate::mock::{
+        device_args, listen, listen_with, test_keys, MockPorts, SimulatedDevice, Step, PID, VID,
+    };
+    use common::ReplayCheck;
+    #[test]
+    fn reconnects_after_unplug() {
+        let keys = test_keys();
+        let mut ports = MockPorts::default();
+        ports.add("/dev/ttyACM0", VID, PID, "A");
+        let mut sim = SimulatedDevice::new(ports.add("/dev/ttyACM1", VID, PID, "B"), &keys);
+
+        // Half a frame is lost with the connection, and the device resends its last block
+        // after coming back
+        sim.send_block();
+        let partial = sim.block_frame(1, [1; 7]);
+        sim.device.push_bytes(&partial[..12]);
+        sim.device.push(Step::Unplug { polls: 3 });
+        sim.device.push_bytes(&[0]);
+        sim.device
+            .push_bytes(&sim.block_frame(0, [0, 1, 2, 3, 4, 5, 6]));
+        sim.send_block();
+
+        let args = DeviceArgs {
+            no_reconnect: false,
+            ..device_args(Some("B"))
+        };
+        let (events, result) = listen_with(&ports, &keys, &args);
+        result.unwrap();
+        assert_eq!(sim.device.opens(), 2);
+
+        let blocks: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Block(block) => Some((block.sequence.unwrap(), block.replay)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            blocks,
+            [
+                (0, ReplayCheck::Fresh),
+                (0, ReplayCheck::Duplicate),
+                (1, ReplayCheck::Fresh)
+            ]
+        );
+        assert_eq!(
+            events
+                .iter()
+                .filter(|e| matches!(e, Event::Error(_)))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn no_reconnect_stops_at_unplug() {
+        let keys = test_keys();
+        let mut ports = MockPorts::default();
+        let mut sim = SimulatedDevice::new(ports.add("/dev/ttyACM0", VID, PID, "A"), &keys);
+        sim.send_block();
+        sim.device.push(Step::Unplug { polls: 1 });
+        sim.send_block();
+
+        let (events, result) = listen(&ports, &keys);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::BrokenPipe);
+        assert_eq!(events.len(), 1);
+        assert_eq!(sim.device.opens(), 1);
+    }
+}
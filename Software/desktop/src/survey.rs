@@ -0,0 +1,367 @@
+//! Spectrum surveys from the receiver board, for picking a quiet channel.
+//!
+//! The receiver samples the received power detector on every channel, see [`common::Survey`].
+//! The results are drawn as a text heatmap and the quietest channels are recommended. Channels
+//! are scored together with their neighbours, since a transmission spreads over 1MHz at 1Mbps
+//! and 2MHz at 2Mbps and a busy channel next door leaks into a quiet one.
+
+use std::fmt::Write as _;
+use std::io::{self, ErrorKind, Write};
+use std::time::{Duration, Instant};
+
+use clap::Args;
+use common::{
+    encode_frame, survey_chunk, FrameDecoder, FrameKind, MAX_ENCODED_FRAME, MAX_SWEEPS,
+    SURVEY_CHANNELS,
+};
+use serde_json::json;
+
+use crate::device::DeviceArgs;
+use crate::output::OutputFormat;
+use crate::supervisor::{self, Session};
+use crate::transport::PortProvider;
+
+#[derive(Args, Debug, Clone)]
+pub struct SurveyArgs {
+    /// Times to sweep every channel. A sweep takes about 150ms
+    #[clap(long, default_value_t = 50, value_parser = clap::value_parser!(u16).range(1..=MAX_SWEEPS as i64))]
+    pub sweeps: u16,
+    /// Number of channels to recommend
+    #[clap(long, default_value_t = 3)]
+    pub recommend: usize,
+    /// Seconds to wait for the results on top of the time the sweeps take
+    #[clap(long, default_value_t = 10)]
+    pub timeout: u64,
+}
+
+/// The highest channel inside the 2.4GHz ISM band, which ends at 2483.5MHz in most places.
+/// Channels above it are surveyed but never recommended
+pub const ISM_MAX_CHANNEL: u8 = 83;
+
+/// How much a channel and its neighbours two either side count towards its score
+const NEIGHBOUR_WEIGHTS: [u32; 5] = [1, 2, 4, 2, 1];
+
+/// Recommended channels are at least this far apart, so that one source of interference does
+/// not spoil them all
+const MIN_SPACING: u8 = 3;
+
+/// Levels of the heatmap from idle to always busy
+const RAMP: &[u8] = b" .:-=+*#%@";
+
+/// Channels in each row of the heatmap
+const ROW: usize = 42;
+
+/// Collects the results frames of a survey
+pub struct SurveyResults {
+    occupancy: [u8; SURVEY_CHANNELS],
+    seen: [bool; SURVEY_CHANNELS],
+}
+
+impl SurveyResults {
+    pub fn new() -> Self {
+        Self {
+            occupancy: [0; SURVEY_CHANNELS],
+            seen: [false; SURVEY_CHANNELS],
+        }
+    }
+
+    /// Adds the occupancy of the channels from `first` on
+    pub fn add(&mut self, first: u8, occupancy: &[u8]) {
+        let first = first as usize;
+        self.occupancy[first..first + occupancy.len()].copy_from_slice(occupancy);
+        self.seen[first..first + occupancy.len()].fill(true);
+    }
+
+    /// True once every channel has been heard about
+    pub fn complete(&self) -> bool {
+        self.seen.iter().all(|&seen| seen)
+    }
+
+    pub fn occupancy(&self) -> &[u8; SURVEY_CHANNELS] {
+        &self.occupancy
+    }
+}
+
+/// The occupancy of every channel with its neighbours counted in. Lower is better
+pub fn scores(occupancy: &[u8; SURVEY_CHANNELS]) -> [u32; SURVEY_CHANNELS] {
+    std::array::from_fn(|channel| {
+        NEIGHBOUR_WEIGHTS
+            .iter()
+            .enumerate()
+            .filter_map(|(i, weight)| {
+                let neighbour = (channel + i).checked_sub(2)?;
+                occupancy.get(neighbour).map(|&o| o as u32 * weight)
+            })
+            .sum()
+    })
+}
+
+/// Up to `count` channels inside the ISM band, quietest first
+pub fn recommend(occupancy: &[u8; SURVEY_CHANNELS], count: usize) -> Vec<u8> {
+    let scores = scores(occupancy);
+    let mut candidates: Vec<u8> = (0..=ISM_MAX_CHANNEL).collect();
+    candidates.sort_by_key(|&c| (scores[c as usize], c));
+    let mut picked: Vec<u8> = Vec::new();
+    for channel in candidates {
+        if picked.len() == count {
+            break;
+        }
+        if picked.iter().all(|&p| p.abs_diff(channel) >= MIN_SPACING) {
+            picked.push(channel);
+        }
+    }
+    picked
+}
+
+/// Draws the occupancy with a character a channel, [`ROW`] channels a line
+pub fn heatmap(occupancy: &[u8; SURVEY_CHANNELS]) -> String {
+    let mut out = String::new();
+    let levels = RAMP.len() - 1;
+    writeln!(
+        out,
+        "channel  ('{}' idle to '{}' always busy, | marks the end of the ISM band)",
+        RAMP[0] as char, RAMP[levels] as char
+    )
+    .unwrap();
+    for (row, chunk) in occupancy.chunks(ROW).enumerate() {
+        let first = row * ROW;
+        let mut line = String::new();
+        for (i, &o) in chunk.iter().enumerate() {
+            // Anything heard at all shows up
+            let level = (o as usize * levels).div_ceil(u8::MAX as usize);
+            line.push(RAMP[level] as char);
+            if first + i == ISM_MAX_CHANNEL as usize {
+                line.push('|');
+            }
+        }
+        writeln!(out, "{:>5}  {}", first, line).unwrap();
+    }
+    out
+}
+
+enum State {
+    Unsent,
+    Waiting { since: Instant },
+    Done,
+}
+
+/// Asks the receiver for a survey and prints the results
+pub struct SurveySession<W: Write> {
+    frames: FrameDecoder,
+    args: SurveyArgs,
+    format: OutputFormat,
+    results: SurveyResults,
+    state: State,
+    out: W,
+}
+
+impl<W: Write> SurveySession<W> {
+    pub fn new(args: SurveyArgs, format: OutputFormat, out: W) -> Self {
+        Self {
+            frames: FrameDecoder::new(),
+            args,
+            format,
+            results: SurveyResults::new(),
+            state: State::Unsent,
+            out,
+        }
+    }
+
+    fn print(&mut self) -> io::Result<()> {
+        let occupancy = self.results.occupancy();
+        let recommended = recommend(occupancy, self.args.recommend);
+        match self.format {
+            OutputFormat::Text => {
+                write!(self.out, "{}", heatmap(occupancy))?;
+                let channels: Vec<_> = recommended.iter().map(u8::to_string).collect();
+                writeln!(self.out, "quietest channels: {}", channels.join(", "))?;
+            }
+            OutputFormat::Json => writeln!(
+                self.out,
+                "{}",
+                json!({
+                    "type": "survey",
+                    "sweeps": self.args.sweeps,
+                    "occupancy": occupancy.to_vec(),
+                    "recommended": recommended,
+                })
+            )?,
+        }
+        self.out.flush()
+    }
+}
+
+impl<W: Write> Session for SurveySession<W> {
+    fn on_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        for &b in bytes {
+            let frame = match self.frames.push(b) {
+                Some(Ok(frame)) => frame,
+                _ => continue,
+            };
+            match frame.kind {
+                FrameKind::Survey => match survey_chunk(frame.payload) {
+                    Some((first, occupancy)) => self.results.add(first, occupancy),
+                    None => eprintln!("error: bad survey frame {:02x?}", frame.payload),
+                },
+                FrameKind::Text => {
+                    eprintln!("receiver: {}", String::from_utf8_lossy(frame.payload))
+                }
+                _ => {}
+            }
+        }
+        if matches!(self.state, State::Waiting { .. }) && self.results.complete() {
+            self.state = State::Done;
+            self.print()?;
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self, device: &mut dyn Write) -> io::Result<()> {
+        match self.state {
+            State::Unsent => {
+                let mut frame = [0u8; MAX_ENCODED_FRAME];
+                let len = encode_frame(
+                    FrameKind::Survey,
+                    &self.args.sweeps.to_le_bytes(),
+                    &mut frame,
+                );
+                device.write_all(&frame[..len])?;
+                device.flush()?;
+                self.state = State::Waiting {
+                    since: Instant::now(),
+                };
+            }
+            State::Waiting { since } => {
+                let sweeps = Duration::from_millis(150) * self.args.sweeps as u32;
+                let timeout = sweeps + Duration::from_secs(self.args.timeout);
+                if since.elapsed() >= timeout {
+                    return Err(io::Error::new(
+                        ErrorKind::TimedOut,
+                        format!("No survey from the receiver after {:?}", timeout),
+                    ));
+                }
+            }
+            State::Done => {}
+        }
+        Ok(())
+    }
+
+    fn done(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+}
+
+pub fn run(
+    ports: &dyn PortProvider,
+    device: &DeviceArgs,
+    args: &SurveyArgs,
+    format: OutputFormat,
+    out: impl Write,
+) -> io::Result<()> {
+    supervisor::run(ports, device, SurveySession::new(args.clone(), format, out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{device_args, test_keys, MockPorts, SimulatedNode, Step, PID, VID};
+    use crate::supervisor::supervise;
+
+    /// Busy around the centres of Wi-Fi channels 1, 6 and 11, 22MHz wide, plus a few spikes
+    fn wifi() -> [u8; SURVEY_CHANNELS] {
+        let mut occupancy = [0u8; SURVEY_CHANNELS];
+        for centre in [12usize, 37, 62] {
+            let hump = &mut occupancy[centre - 11..=centre + 11];
+            for (i, o) in hump.iter_mut().enumerate() {
+                *o = (200 - (i as i32 - 11).unsigned_abs() * 15) as u8;
+            }
+        }
+        for spike in [80, 100] {
+            occupancy[spike] = 255;
+        }
+        occupancy
+    }
+
+    #[test]
+    fn recommends_the_gaps() {
+        let occupancy = wifi();
+        let scores = scores(&occupancy);
+        // 76, 77 and 83 are clear of Wi-Fi and of the spike at 80, neighbours included. 77 is
+        // too close to 76, so the next best is 0 at the edge of Wi-Fi channel 1. The spike at
+        // 100 is outside the band, so 95 is never picked however quiet it is
+        assert_eq!(
+            (scores[76], scores[77], scores[83], scores[95]),
+            (0, 0, 0, 0)
+        );
+        assert_eq!(recommend(&occupancy, 3), [76, 83, 0]);
+        assert_eq!(recommend(&occupancy, 1), [76]);
+        // Every channel is busy so the least busy win
+        let mut busy = [255; SURVEY_CHANNELS];
+        busy[40] = 10;
+        assert_eq!(recommend(&busy, 2), [40, 0]);
+    }
+
+    #[test]
+    fn draws_a_heatmap() {
+        let mut occupancy = [0u8; SURVEY_CHANNELS];
+        occupancy[0] = 1;
+        occupancy[1] = 128;
+        occupancy[2] = 255;
+        occupancy[125] = 255;
+        let map = heatmap(&occupancy);
+        let lines: Vec<&str> = map.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].starts_with("    0  .+@ "), "{:?}", lines[1]);
+        assert_eq!(lines[2].len(), 7 + ROW + 1);
+        assert_eq!(lines[2].find('|'), Some(7 + ROW));
+        assert!(lines[3].starts_with("   84  "));
+        assert!(lines[3].ends_with('@'));
+    }
+
+    #[test]
+    fn collects_results() {
+        let mut results = SurveyResults::new();
+        results.add(0, &[7; 63]);
+        assert!(!results.complete());
+        results.add(63, &[9; 63]);
+        assert!(results.complete());
+        assert_eq!(results.occupancy()[62..64], [7, 9]);
+    }
+    #[test]
+    fn survey() {
+        let mut ports = MockPorts::default();
+        let device = ports.add("/dev/ttyACM0", VID, PID, "A");
+        let state = SimulatedNode::attach(&device, test_keys());
+        // Wi-Fi channel 6 and something narrow on 80
+        let busy: Vec<u8> = (26..=48).chain([80]).collect();
+        state.lock().unwrap().air.jam(&busy);
+
+        device.push(Step::Timeout);
+        let args = SurveyArgs {
+            sweeps: 5,
+            recommend: 2,
+            timeout: 1,
+        };
+        let mut printed = Vec::new();
+        let session = SurveySession::new(args, OutputFormat::Text, &mut printed);
+        let device_args = device_args(None);
+        supervise(
+            &ports,
+            &device_args,
+            device_args.resolve(&ports).unwrap(),
+            Duration::ZERO,
+            session,
+        )
+        .unwrap();
+        let printed = String::from_utf8(printed).unwrap();
+        let lines: Vec<&str> = printed.lines().collect();
+        assert_eq!(lines.len(), 5, "{}", printed);
+        assert_eq!(
+            lines[1],
+            format!("    0  {}{}", " ".repeat(26), "@".repeat(16))
+        );
+        assert_eq!(lines[2].matches('@').count(), 8, "{}", printed);
+        assert_eq!(lines[4], "quietest channels: 0, 3");
+        assert!(!state.lock().unwrap().relay.surveying());
+    }
+}
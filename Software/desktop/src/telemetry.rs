@@ -0,0 +1,266 @@
+//! Battery telemetry from the nodes, see [`common::Telemetry`].
+//!
+//! Every reading is printed as it arrives, followed by a sparkline of the node's recent battery
+//! voltages so that a draining battery stands out. Readings can also be appended to a CSV log
+//! for graphing over longer runs.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::Args;
+use common::{MessageKind, Reassembler, Telemetry, MAX_MESSAGE, MAX_NODES, TAG_DATA};
+use serde_json::json;
+
+use crate::capture;
+use crate::decode::{Event, Pipeline};
+use crate::nodes::Registry;
+use crate::output::OutputFormat;
+use crate::supervisor::Session;
+
+#[derive(Args, Debug, Clone)]
+pub struct TelemetryArgs {
+    /// Also append every reading to this CSV file
+    #[clap(long)]
+    pub log: Option<PathBuf>,
+    /// Readings shown in each node's graph
+    #[clap(long, default_value_t = 40)]
+    pub history: usize,
+}
+
+const CSV_HEADER: &str = "timestamp_us,node,battery_mv,supply_mv,temperature_c";
+
+/// Levels of the graph from the lowest reading shown to the highest
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Draws `values` with a character each, scaled between the lowest and the highest
+pub fn sparkline(values: &VecDeque<u16>) -> String {
+    let min = values.iter().copied().min().unwrap_or(0);
+    let max = values.iter().copied().max().unwrap_or(0);
+    let span = (max - min).max(1) as usize;
+    values
+        .iter()
+        .map(|&v| BARS[(v - min) as usize * (BARS.len() - 1) / span])
+        .collect()
+}
+
+fn volts(mv: u16) -> String {
+    format!("{}.{:03}", mv / 1000, mv % 1000)
+}
+
+/// Prints and logs the telemetry every node sends
+pub struct TelemetryLog<'k, W: Write> {
+    pipeline: Pipeline<'k>,
+    readings: Readings<W>,
+}
+
+/// Everything but the pipeline, so that it can be borrowed while the pipeline is fed
+struct Readings<W: Write> {
+    /// One for each node, since each has its own sequence numbers
+    messages: [Reassembler<{ MAX_MESSAGE + 1 }>; MAX_NODES],
+    /// The last battery voltages of each node, oldest first
+    history: [VecDeque<u16>; MAX_NODES],
+    shown: usize,
+    nodes: Registry,
+    format: OutputFormat,
+    log: Option<BufWriter<File>>,
+    out: W,
+}
+
+impl<'k, W: Write> TelemetryLog<'k, W> {
+    pub fn new(
+        pipeline: Pipeline<'k>,
+        nodes: Registry,
+        args: &TelemetryArgs,
+        format: OutputFormat,
+        out: W,
+    ) -> io::Result<Self> {
+        let log = match &args.log {
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                let new = file.metadata()?.len() == 0;
+                let mut log = BufWriter::new(file);
+                if new {
+                    writeln!(log, "{}", CSV_HEADER)?;
+                }
+                Some(log)
+            }
+            None => None,
+        };
+        Ok(Self {
+            pipeline,
+            readings: Readings {
+                messages: std::array::from_fn(|_| Reassembler::new()),
+                history: std::array::from_fn(|_| VecDeque::new()),
+                shown: args.history.max(1),
+                nodes,
+                format,
+                log,
+                out,
+            },
+        })
+    }
+}
+
+impl<W: Write> Readings<W> {
+    fn event(&mut self, timestamp_us: u64, event: &Event) -> io::Result<()> {
+        match event {
+            Event::Block(block) => match (block.node, block.sequence) {
+                (Some(node), Some(sequence)) if block.tag == TAG_DATA => {
+                    match self.messages[node as usize].push(sequence, &block.data) {
+                        Some((MessageKind::Telemetry, bytes)) => match Telemetry::from_bytes(bytes)
+                        {
+                            Some(telemetry) => self.reading(timestamp_us, node, telemetry),
+                            None => {
+                                eprintln!("error: bad telemetry {:02x?}", bytes);
+                                Ok(())
+                            }
+                        },
+                        _ => Ok(()),
+                    }
+                }
+                _ => Ok(()),
+            },
+            Event::Text(text) => {
+                eprintln!("receiver: {}", text);
+                Ok(())
+            }
+            Event::Error(err) => {
+                eprintln!("error: {}", err);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn reading(&mut self, timestamp_us: u64, node: u8, telemetry: Telemetry) -> io::Result<()> {
+        let history = &mut self.history[node as usize];
+        if history.len() == self.shown {
+            history.pop_front();
+        }
+        history.push_back(telemetry.battery_mv);
+        if let Some(log) = &mut self.log {
+            writeln!(
+                log,
+                "{},{},{},{},{}",
+                timestamp_us,
+                node,
+                telemetry.battery_mv,
+                telemetry.supply_mv,
+                telemetry.temperature_c
+            )?;
+            log.flush()?;
+        }
+        let name = self.nodes.name(node);
+        match self.format {
+            OutputFormat::Text => {
+                let min = history.iter().copied().min().unwrap_or(0);
+                let max = history.iter().copied().max().unwrap_or(0);
+                writeln!(
+                    self.out,
+                    "{}: {}  {} ({} to {} V)",
+                    name,
+                    telemetry,
+                    sparkline(history),
+                    volts(min),
+                    volts(max)
+                )
+            }
+            OutputFormat::Json => writeln!(
+                self.out,
+                "{}",
+                json!({
+                    "type": "telemetry",
+                    "timestamp_us": timestamp_us,
+                    "node": node,
+                    "name": name,
+                    "battery_mv": telemetry.battery_mv,
+                    "supply_mv": telemetry.supply_mv,
+                    "temperature_c": telemetry.temperature_c,
+                })
+            ),
+        }
+    }
+}
+
+impl<W: Write> Session for TelemetryLog<'_, W> {
+    fn on_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let now = capture::unix_us();
+        let Self { pipeline, readings } = self;
+        pipeline.feed(bytes, |event| readings.event(now, &event))?;
+        readings.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{
+        device_args, sequence_file, test_keys, MockPorts, SimulatedDevice, PID, VID,
+    };
+    use crate::supervisor::supervise;
+    use std::time::Duration;
+
+    #[test]
+    fn draws_a_sparkline() {
+        let values: VecDeque<u16> = [4200, 4100, 3900, 3500, 3500].into();
+        assert_eq!(sparkline(&values), "█▇▅▁▁");
+        // A flat line sits at the bottom
+        let flat: VecDeque<u16> = [3300; 3].into();
+        assert_eq!(sparkline(&flat), "▁▁▁");
+        assert_eq!(sparkline(&VecDeque::new()), "");
+        assert_eq!(volts(3050), "3.050");
+    }
+    #[test]
+    fn telemetry_log() {
+        let keys = test_keys();
+        let mut ports = MockPorts::default();
+        let mut sim = SimulatedDevice::new(ports.add("/dev/ttyACM0", VID, PID, "A"), &keys);
+        for battery_mv in [4100, 3700, 3300] {
+            sim.send_telemetry(&Telemetry {
+                battery_mv,
+                supply_mv: 3300,
+                temperature_c: 21,
+            });
+        }
+        // Other messages are left to the chat
+        sim.send_message("hi");
+
+        let log = sequence_file("facilitador-telemetry");
+        let args = TelemetryArgs {
+            log: Some(log.clone().into()),
+            history: 2,
+        };
+        let mut printed = Vec::new();
+        let session = TelemetryLog::new(
+            Pipeline::new(&keys),
+            crate::nodes::Registry::default(),
+            &args,
+            OutputFormat::Text,
+            &mut printed,
+        )
+        .unwrap();
+        supervise(
+            &ports,
+            &device_args(None),
+            device_args(None).resolve(&ports).unwrap(),
+            Duration::ZERO,
+            session,
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(printed).unwrap(),
+            "node 0: battery 4.100 V, supply 3.300 V, 21 C  ▁ (4.100 to 4.100 V)\n\
+             node 0: battery 3.700 V, supply 3.300 V, 21 C  █▁ (3.700 to 4.100 V)\n\
+             node 0: battery 3.300 V, supply 3.300 V, 21 C  █▁ (3.300 to 3.700 V)\n"
+        );
+        let csv = std::fs::read_to_string(&log).unwrap();
+        let rows: Vec<Vec<&str>> = csv.lines().map(|l| l.split(',').collect()).collect();
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[0][1], "node");
+        assert_eq!(rows[3][1..], ["0", "3300", "3300", "21"]);
+        std::fs::remove_file(log).unwrap();
+    }
+}
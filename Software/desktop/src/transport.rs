@@ -0,0 +1,35 @@
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use serialport::SerialPortInfo;
+
+/// A byte stream to a receiver board.
+/// Reads should fail with [`io::ErrorKind::TimedOut`] when nothing arrives for a while, and
+/// return `Ok(0)` once the stream has ended for good
+pub trait Transport: Read + Write + Send {}
+
+impl<T: Read + Write + Send + ?Sized> Transport for T {}
+
+/// Finds and opens ports. Implemented by [`SystemPorts`] for real hardware, and by the mock
+/// device in tests
+pub trait PortProvider {
+    fn available_ports(&self) -> io::Result<Vec<SerialPortInfo>>;
+
+    fn open(&self, path: &str, baud: u32) -> io::Result<Box<dyn Transport>>;
+}
+
+/// The serial ports of this machine
+pub struct SystemPorts;
+
+impl PortProvider for SystemPorts {
+    fn available_ports(&self) -> io::Result<Vec<SerialPortInfo>> {
+        Ok(serialport::available_ports()?)
+    }
+
+    fn open(&self, path: &str, baud: u32) -> io::Result<Box<dyn Transport>> {
+        let port = serialport::new(path, baud)
+            .timeout(Duration::from_millis(100))
+            .open()?;
+        Ok(Box::new(port))
+    }
+}
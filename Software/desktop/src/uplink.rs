@@ -0,0 +1,311 @@
+//! Encrypting messages for nodes. The receiver transmits every addressed frame the host writes to
+//! it to the node the frame names.
+
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use common::{
+    chunk_words, encode_chunks, encode_frame, Control, FrameKind, IndexedBlock, MessageKind,
+    PadPosition, PadRegion, Tag, VarBlock, AUTH_KEY_SIZE, BLOCK_SIZE, CONTROL_WORDS,
+    MAX_ENCODED_FRAME, MAX_MESSAGE, MAX_NODES, TAG_CONTROL, TAG_DATA,
+};
+
+use crate::keys::{Cipher, Keys};
+
+#[derive(Args, Debug, Clone)]
+pub struct UplinkArgs {
+    /// File holding how far the uplink has got through its pads: the next sequence number and
+    /// the key words used. Keep it with the keys: if it is lost, pads get reused
+    #[clap(long, default_value = "uplink-sequence.txt")]
+    pub sequence_file: PathBuf,
+
+    /// Id of the node to send to. Nodes other than 0 keep their position next to the sequence
+    /// file, with the id added to its name
+    #[clap(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(..MAX_NODES as i64))]
+    pub node: u8,
+}
+
+impl UplinkArgs {
+    /// The file holding the position of the chosen node's uplink
+    pub fn node_sequence_file(&self) -> PathBuf {
+        if self.node == 0 {
+            return self.sequence_file.clone();
+        }
+        let mut name = self
+            .sequence_file
+            .file_stem()
+            .unwrap_or_default()
+            .to_owned();
+        name.push(format!("-{}", self.node));
+        if let Some(extension) = self.sequence_file.extension() {
+            name.push(".");
+            name.push(extension);
+        }
+        self.sequence_file.with_file_name(name)
+    }
+}
+
+/// Reserves pads for blocks of `words` data words each from the file at `path`, and returns
+/// their positions. The file is updated before anything is sent, so a crash can only skip pads,
+/// not reuse them
+fn reserve(path: &Path, words: &[usize]) -> io::Result<Vec<PadPosition>> {
+    let mut next = match std::fs::read_to_string(path) {
+        Ok(text) => parse_position(&text).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} does not hold a sequence number and key word",
+                    path.display()
+                ),
+            )
+        })?,
+        Err(e) if e.kind() == ErrorKind::NotFound => PadPosition::default(),
+        Err(e) => return Err(e),
+    };
+    let total: usize = words.iter().sum();
+    if next.sequence.checked_add(words.len() as u32).is_none()
+        || next.word.checked_add(total as u32).is_none()
+    {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Uplink sequence numbers ran out",
+        ));
+    }
+    let positions = words.iter().map(|&words| next.take(words)).collect();
+
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, format!("{} {}\n", next.sequence, next.word))?;
+    std::fs::rename(&tmp, path)?;
+    Ok(positions)
+}
+
+fn parse_position(text: &str) -> Option<PadPosition> {
+    let mut numbers = text.split_whitespace().map(str::parse);
+    let position = PadPosition {
+        sequence: numbers.next()?.ok()?,
+        word: numbers.next()?.ok()?,
+    };
+    numbers.next().is_none().then_some(position)
+}
+
+/// Turns messages into encrypted block frames for one node, using its uplink pad region
+pub struct Uplink<'k> {
+    node: u8,
+    cipher: Cipher<'k>,
+    index_key: u32,
+    auth_key: [u8; AUTH_KEY_SIZE],
+    region: PadRegion,
+    sequence_file: PathBuf,
+}
+
+impl<'k> Uplink<'k> {
+    pub fn new(keys: &'k Keys, args: &UplinkArgs) -> Self {
+        Self {
+            node: args.node,
+            cipher: keys.cipher(),
+            index_key: keys.index_key,
+            auth_key: keys.auth_key,
+            region: keys.uplink(args.node),
+            sequence_file: args.node_sequence_file(),
+        }
+    }
+
+    /// The node messages are sent to
+    pub fn node(&self) -> u8 {
+        self.node
+    }
+
+    /// Returns the frames to write to the receiver to send `message`
+    pub fn encode(&mut self, kind: MessageKind, message: &[u8]) -> io::Result<Vec<u8>> {
+        if message.len() > MAX_MESSAGE {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Message is {} bytes, nodes can only take {}",
+                    message.len(),
+                    MAX_MESSAGE
+                ),
+            ));
+        }
+        let chunks: Vec<_> = encode_chunks(kind, message).collect();
+        let words: Vec<_> = chunks.iter().map(chunk_words).collect();
+        let positions = self.reserve(&words)?;
+        let mut frames = Vec::new();
+        for ((position, words), data) in positions.into_iter().zip(words).zip(chunks) {
+            let index = self.region.index_for(position, self.index_key);
+            self.push_block(&mut frames, index, TAG_DATA, data, words);
+        }
+        Ok(frames)
+    }
+
+    /// Returns the frame to write to the receiver to send `control`, and the sequence number the
+    /// node's reply will refer to
+    pub fn encode_control(&mut self, control: &Control) -> io::Result<(u32, Vec<u8>)> {
+        let position = self.reserve(&[CONTROL_WORDS])?[0];
+        let index = self.region.index_for(position, self.index_key);
+        let mut frames = Vec::new();
+        let data = control.seal(index, &self.auth_key);
+        self.push_block(&mut frames, index, TAG_CONTROL, data, CONTROL_WORDS);
+        Ok((position.sequence, frames))
+    }
+
+    /// Returns the frame to write to the receiver to send `packet` to the node as it is, outside
+    /// the pads. Only for messages that carry their own MAC and hold nothing secret
+    pub fn encode_packet(&self, packet: &[u8]) -> Vec<u8> {
+        let mut frames = Vec::new();
+        self.push_packet(&mut frames, packet);
+        frames
+    }
+
+    /// Reserves pads for blocks of `words` data words each, as long as they fit in what is left
+    /// of the region. Starting over would send two messages with the same pads
+    fn reserve(&self, words: &[usize]) -> io::Result<Vec<PadPosition>> {
+        let positions = reserve(&self.sequence_file, words)?;
+        let fits = positions
+            .iter()
+            .zip(words)
+            .all(|(&position, &words)| self.region.fits(position, words));
+        if !fits {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "The uplink to node {} has used all {} of its pad words, it needs a new key",
+                    self.node,
+                    self.region.len()
+                ),
+            ));
+        }
+        Ok(positions)
+    }
+
+    /// Adds the frame for a block sent with `index`, of which the first `words` data words are
+    /// sent
+    fn push_block(
+        &self,
+        frames: &mut Vec<u8>,
+        index: u32,
+        tag: usize,
+        data: [u32; 7],
+        words: usize,
+    ) {
+        let mut block = IndexedBlock::new();
+        *block.data_mut() = data;
+        block.tag().set_tag(tag);
+        block.tag().set_index(index);
+        let mut block = VarBlock::new(block, words);
+        block.do_cipher(&self.cipher);
+        self.push_packet(frames, block.as_bytes());
+    }
+
+    /// Adds the frame that has the receiver send `packet`, at most [`BLOCK_SIZE`] bytes
+    fn push_packet(&self, frames: &mut Vec<u8>, packet: &[u8]) {
+        let mut payload = [0u8; 1 + BLOCK_SIZE];
+        payload[0] = self.node;
+        payload[1..1 + packet.len()].copy_from_slice(packet);
+        let mut frame = [0u8; MAX_ENCODED_FRAME];
+        let len = encode_frame(
+            FrameKind::Addressed,
+            &payload[..1 + packet.len()],
+            &mut frame,
+        );
+        frames.extend_from_slice(&frame[..len]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{auth_key_file, load_keys, MockPorts, SimulatedDevice, PID, VID};
+
+    #[test]
+    fn sequence_file() {
+        let path = std::env::temp_dir().join(format!("facilitador-seq-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let at = |sequence, word| PadPosition { sequence, word };
+        assert_eq!(
+            reserve(&path, &[3, 0, 7]).unwrap(),
+            [at(0, 0), at(1, 3), at(2, 3)]
+        );
+        assert_eq!(reserve(&path, &[5]).unwrap(), [at(3, 10)]);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "4 15\n");
+
+        // Including a sequence number on its own, as the file held before pads were allocated
+        // by the word
+        for nonsense in ["nonsense", "4", "4 15 1"] {
+            std::fs::write(&path, nonsense).unwrap();
+            assert_eq!(
+                reserve(&path, &[1]).unwrap_err().kind(),
+                ErrorKind::InvalidData
+            );
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stops_when_the_pads_run_out() {
+        let keys = crate::mock::test_keys();
+        let path = std::env::temp_dir().join(format!("facilitador-pads-{}", std::process::id()));
+        let mut uplink = Uplink::new(
+            &keys,
+            &UplinkArgs {
+                sequence_file: path.clone(),
+                node: 0,
+            },
+        );
+        let last = keys.uplink(0).len() - 1;
+        // A message of two blocks does not fit in the last word, one of a single word does
+        std::fs::write(&path, format!("0 {}\n", last)).unwrap();
+        assert!(uplink.encode(MessageKind::Text, &[b'a'; 40]).is_err());
+        std::fs::write(&path, format!("0 {}\n", last)).unwrap();
+        assert!(uplink.encode(MessageKind::Text, b"hi").is_ok());
+        assert!(uplink.encode(MessageKind::Text, b"hi").is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn every_node_has_its_own_sequence_file() {
+        let args = |node| UplinkArgs {
+            sequence_file: "keys/uplink-sequence.txt".into(),
+            node,
+        };
+        assert_eq!(
+            args(0).node_sequence_file(),
+            Path::new("keys/uplink-sequence.txt")
+        );
+        assert_eq!(
+            args(3).node_sequence_file(),
+            Path::new("keys/uplink-sequence-3.txt")
+        );
+    }
+    #[test]
+    fn send_command() {
+        use clap::Parser;
+
+        let auth = auth_key_file("facilitador-send-auth");
+        let keys = load_keys(&auth);
+        let mut ports = MockPorts::default();
+        let sim = SimulatedDevice::new(ports.add("/dev/ttyACM0", VID, PID, "A"), &keys);
+        let seq = crate::mock::sequence_file("facilitador-send");
+        let long = "a message that is long enough to need a few blocks";
+        for message in ["hi there", long] {
+            let cli = crate::Cli::parse_from([
+                "desktop",
+                "send",
+                message,
+                "--sequence-file",
+                &seq,
+                "--auth-key",
+                &auth,
+            ]);
+            crate::run(&cli, &ports).unwrap();
+        }
+
+        assert_eq!(sim.device.opens(), 2);
+        assert_eq!(sim.uplink_messages(), ["hi there", long]);
+        // One block of 3 words for the short message, and two of 7 for the long one
+        assert_eq!(std::fs::read_to_string(&seq).unwrap(), "3 17\n");
+        std::fs::remove_file(seq).unwrap();
+        std::fs::remove_file(auth).unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+#![no_std]
+//! The panic and HardFault handlers for the BluePill firmwares. Both write a [`Fault`] into RAM
+//! that survives the reset that follows, where [`take`] finds it on the next boot. The same RAM
+//! counts resets since power on, see [`count_reset`].
+//!
+//! The record lives at `_fault_start`, which each firmware's memory.x places past the end of the
+//! RAM the program uses. Nothing else touches it, so it keeps its contents across a reset but not
+//! across a power cycle. [`Fault::load`] tells a record from what RAM holds at power on.
+
+#[cfg(feature = "message")]
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::ptr;
+
+use common::{
+    Fault, HardFaultRegisters, PanicText, ResetCounts, ResetReason, FAULT_WORDS, RESET_WORDS,
+};
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::{exception, ExceptionFrame};
+
+extern "C" {
+    static mut _fault_start: u32;
+    static mut _fault_end: u32;
+}
+
+/// The words memory.x sets aside for the record, from `_fault_start` up to `_fault_end`
+// Taking the address of an extern static no longer needs `unsafe` since Rust 1.82, but still
+// does on the nightly the firmware is built with
+#[allow(unused_unsafe)]
+fn bounds() -> (*mut u32, *mut u32) {
+    // SAFETY: only the addresses are taken
+    unsafe {
+        (
+            ptr::addr_of_mut!(_fault_start),
+            ptr::addr_of_mut!(_fault_end),
+        )
+    }
+}
+
+fn record() -> *mut u32 {
+    bounds().0
+}
+
+/// Checked at boot rather than in the handlers, where a panic would have nowhere to go
+fn check_size() {
+    let (start, end) = bounds();
+    assert!(end as usize - start as usize >= (FAULT_WORDS + RESET_WORDS) * 4);
+}
+
+/// Returns why the firmware last went down, if it was a panic or HardFault since power on, and
+/// clears the record so that it is only reported once
+///
+/// # Panics
+/// If memory.x sets aside less than [`FAULT_WORDS`] and [`RESET_WORDS`] words
+pub fn take() -> Option<Fault> {
+    check_size();
+    let record = record();
+    let mut words = [0u32; FAULT_WORDS];
+    for (i, word) in words.iter_mut().enumerate() {
+        // SAFETY: memory.x reserves the record for us, and it is read as plain words
+        *word = unsafe { ptr::read_volatile(record.add(i)) };
+    }
+    // SAFETY: as above. Clearing the magic number is enough
+    unsafe { ptr::write_volatile(record, 0) };
+    Fault::load(&words)
+}
+
+/// Counts a reset for `reason`, from [`ResetReason::from_csr`], and returns the resets since
+/// power on. Call it once at boot
+///
+/// # Panics
+/// If memory.x sets aside less than [`FAULT_WORDS`] and [`RESET_WORDS`] words
+pub fn count_reset(reason: ResetReason) -> ResetCounts {
+    check_size();
+    // SAFETY: the counts follow the fault record in the RAM memory.x reserves
+    let counts = unsafe { record().add(FAULT_WORDS) };
+    let mut words = [0u32; RESET_WORDS];
+    for (i, word) in words.iter_mut().enumerate() {
+        // SAFETY: as above, read and written as plain words
+        *word = unsafe { ptr::read_volatile(counts.add(i)) };
+    }
+    let total = ResetCounts::record(&mut words, reason);
+    for (i, &word) in words.iter().enumerate() {
+        // SAFETY: as above
+        unsafe { ptr::write_volatile(counts.add(i), word) };
+    }
+    total
+}
+
+fn store_and_reset(fault: &Fault) -> ! {
+    let mut words = [0u32; FAULT_WORDS];
+    fault.store(&mut words);
+    let record = record();
+    for (i, &word) in words.iter().enumerate() {
+        // SAFETY: memory.x reserves the record for us, and interrupts are off
+        unsafe { ptr::write_volatile(record.add(i), word) };
+    }
+    SCB::sys_reset()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    #[allow(unused_mut)]
+    let mut text = PanicText::new();
+    // Never fails, what does not fit is left off
+    #[cfg(feature = "message")]
+    let _ = write!(text, "{}", info);
+    // Without the message, only that there was a panic is recorded
+    #[cfg(not(feature = "message"))]
+    let _ = info;
+    store_and_reset(&Fault::Panic(text))
+}
+
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    cortex_m::interrupt::disable();
+    // SAFETY: only the fault status registers are read
+    let scb = unsafe { &*SCB::PTR };
+    let registers = HardFaultRegisters {
+        r0: frame.r0(),
+        r1: frame.r1(),
+        r2: frame.r2(),
+        r3: frame.r3(),
+        r12: frame.r12(),
+        lr: frame.lr(),
+        pc: frame.pc(),
+        xpsr: frame.xpsr(),
+        cfsr: scb.cfsr.read(),
+        hfsr: scb.hfsr.read(),
+        mmfar: scb.mmfar.read(),
+        bfar: scb.bfar.read(),
+    };
+    store_and_reset(&Fault::HardFault(registers))
+}
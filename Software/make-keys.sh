@@ -0,0 +1,19 @@
+#!/bin/sh
+# Makes the keys in private/, which is kept out of git. The firmware compiles them in and the
+# desktop reads them, so every board and the desktop have to be built from the same files: copy
+# the directory to another machine rather than running this there. Keys already there are kept
+set -e
+cd "$(dirname "$0")"
+mkdir -p private
+
+generate() {
+    if [ ! -e "private/$1" ]; then
+        head -c "$2" /dev/urandom > "private/$1"
+        echo "made private/$1"
+    fi
+}
+
+# Sizes from common: KEY_SIZE, the index key's u32 and AUTH_KEY_SIZE
+generate key.bin 53280
+generate index-key.bin 4
+generate auth-key.bin 16
@@ -0,0 +1,608 @@
+#![cfg_attr(not(test), no_std)]
+//! [`common::Radio`] for the nRF24L01+, shared by the node and receiver firmware
+//!
+//! This drives the chip's registers directly: the settings and readings used here (RF_DR_LOW,
+//! dynamic payloads, OBSERVE_TX, RPD) are not reachable through the published drivers
+
+use common::{DataRate, NodeConfig, PaLevel, Radio, ADDRESS_SIZE, MAX_NODES};
+use embedded_hal::blocking::{
+    delay::{DelayMs, DelayUs},
+    spi::Transfer,
+};
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::{Mode, Phase, Polarity};
+
+/// The SPI mode the chip expects, for setting up the bus
+pub const SPI_MODE: Mode = Mode {
+    polarity: Polarity::IdleLow,
+    phase: Phase::CaptureOnFirstTransition,
+};
+
+/// The largest payload the chip carries
+const MAX_PAYLOAD: usize = 32;
+
+/// Register addresses
+mod reg {
+    pub const CONFIG: u8 = 0x00;
+    pub const EN_AA: u8 = 0x01;
+    pub const EN_RXADDR: u8 = 0x02;
+    pub const SETUP_AW: u8 = 0x03;
+    pub const SETUP_RETR: u8 = 0x04;
+    pub const RF_CH: u8 = 0x05;
+    pub const RF_SETUP: u8 = 0x06;
+    pub const STATUS: u8 = 0x07;
+    pub const OBSERVE_TX: u8 = 0x08;
+    pub const RPD: u8 = 0x09;
+    pub const RX_ADDR_P0: u8 = 0x0A;
+    pub const TX_ADDR: u8 = 0x10;
+    pub const FIFO_STATUS: u8 = 0x17;
+    pub const DYNPD: u8 = 0x1C;
+    pub const FEATURE: u8 = 0x1D;
+}
+
+/// SPI commands
+mod cmd {
+    pub const R_REGISTER: u8 = 0x00;
+    pub const W_REGISTER: u8 = 0x20;
+    pub const R_RX_PL_WID: u8 = 0x60;
+    pub const R_RX_PAYLOAD: u8 = 0x61;
+    pub const W_TX_PAYLOAD: u8 = 0xA0;
+    pub const FLUSH_TX: u8 = 0xE1;
+    pub const FLUSH_RX: u8 = 0xE2;
+    pub const NOP: u8 = 0xFF;
+}
+
+// CONFIG
+const MASK_TX_DS: u8 = 1 << 5;
+const MASK_MAX_RT: u8 = 1 << 4;
+const EN_CRC: u8 = 1 << 3;
+const CRCO: u8 = 1 << 2;
+const PWR_UP: u8 = 1 << 1;
+const PRIM_RX: u8 = 1 << 0;
+// STATUS
+const RX_DR: u8 = 1 << 6;
+const TX_DS: u8 = 1 << 5;
+const MAX_RT: u8 = 1 << 4;
+// RF_SETUP
+const RF_DR_LOW: u8 = 1 << 5;
+const RF_DR_HIGH: u8 = 1 << 3;
+// FIFO_STATUS
+const RX_EMPTY: u8 = 1 << 0;
+// FEATURE
+const EN_DPL: u8 = 1 << 2;
+
+/// Two byte CRC, and the IRQ pin only goes low for received payloads: [`Radio::send`] polls for
+/// the end of a transmission itself
+const CONFIG: u8 = MASK_TX_DS | MASK_MAX_RT | EN_CRC | CRCO;
+/// Five byte addresses
+const AW_5: u8 = 0b11;
+/// Auto acknowledge and dynamic payloads on all six pipes
+const ALL_PIPES: u8 = 0x3F;
+/// 500us between attempts, which 250kbps needs for the ack to arrive, and 15 retries
+const RETR: u8 = 0x1 << 4 | 15;
+
+/// How often and how long [`Radio::send`] polls for the end of a transmission. 15 retries at
+/// 250kbps take about 30ms, so this only runs out if the chip is gone
+const SEND_POLLS: u32 = 2000;
+const SEND_POLL_US: u8 = 50;
+
+/// Either bus the chip hangs off failed
+#[derive(Debug)]
+pub enum Error<SPIErr, PinErr> {
+    Spi(SPIErr),
+    Pin(PinErr),
+}
+
+/// The chip and the delay it needs while sending, along with everything it was set up with so
+/// that it can be set up again
+pub struct Nrf24<SPI, CE, NCS, D> {
+    spi: SPI,
+    ce: CE,
+    ncs: NCS,
+    delay: D,
+    config: NodeConfig,
+    to: [u8; ADDRESS_SIZE],
+    from: [[u8; ADDRESS_SIZE]; MAX_NODES],
+    listen: usize,
+}
+
+impl<SPI, CE, NCS, D, SPIErr, PinErr> Nrf24<SPI, CE, NCS, D>
+where
+    SPI: Transfer<u8, Error = SPIErr>,
+    CE: OutputPin<Error = PinErr>,
+    NCS: OutputPin<Error = PinErr>,
+    D: DelayMs<u8> + DelayUs<u8>,
+{
+    /// Sets up the chip on the settings in `config` with dynamic payload lengths. It sends to
+    /// `to` and listens on every address in `from`, which must differ only in the first byte
+    ///
+    /// # Panics
+    /// If there are more addresses to listen on than [`MAX_NODES`], or the chip does not answer
+    pub fn new(
+        spi: SPI,
+        ce: CE,
+        ncs: NCS,
+        mut delay: D,
+        config: &NodeConfig,
+        to: &[u8; ADDRESS_SIZE],
+        from: &[[u8; ADDRESS_SIZE]],
+    ) -> Result<Self, Error<SPIErr, PinErr>> {
+        assert!(from.len() <= MAX_NODES);
+        // The chip needs 100ms from power on before it takes commands
+        for _ in 0..10 {
+            delay.delay_ms(10);
+        }
+        let mut addresses = [[0; ADDRESS_SIZE]; MAX_NODES];
+        addresses[..from.len()].copy_from_slice(from);
+        let mut radio = Self {
+            spi,
+            ce,
+            ncs,
+            delay,
+            config: *config,
+            to: *to,
+            from: addresses,
+            listen: from.len(),
+        };
+        radio.ce.set_low().map_err(Error::Pin)?;
+        radio.ncs.set_high().map_err(Error::Pin)?;
+        radio.reinit()?;
+        if !radio.is_connected()? {
+            panic!("Chip is not connected.");
+        }
+        Ok(radio)
+    }
+
+    /// False if the chip does not answer over SPI as it should, after a brown out or a loose wire
+    pub fn is_connected(&mut self) -> Result<bool, Error<SPIErr, PinErr>> {
+        Ok(self.read_register(reg::SETUP_AW)? == AW_5)
+    }
+
+    /// Sets the chip up again from scratch on the settings it was last given, for when
+    /// [`Nrf24::is_connected`] fails while running. The chip may have lost all of them
+    pub fn reinit(&mut self) -> Result<(), Error<SPIErr, PinErr>> {
+        self.ce.set_low().map_err(Error::Pin)?;
+        self.write_register(reg::CONFIG, &[CONFIG])?;
+        self.write_register(reg::SETUP_AW, &[AW_5])?;
+        self.write_register(reg::SETUP_RETR, &[RETR])?;
+        self.write_register(reg::EN_AA, &[ALL_PIPES])?;
+        // Blocks only carry the data words they need, see `common::VarBlock`
+        self.write_register(reg::FEATURE, &[EN_DPL])?;
+        self.write_register(reg::DYNPD, &[ALL_PIPES])?;
+        let config = self.config;
+        self.configure(&config)?;
+        self.command(&mut [cmd::FLUSH_TX])?;
+        self.command(&mut [cmd::FLUSH_RX])?;
+        self.write_register(reg::STATUS, &[RX_DR | TX_DS | MAX_RT])?;
+        self.open_pipes()?;
+        self.power_up()
+    }
+
+    /// Stops the chip between bursts, where it draws about 1uA. It keeps its settings but hears
+    /// nothing until [`Nrf24::power_up`]
+    pub fn power_down(&mut self) -> Result<(), Error<SPIErr, PinErr>> {
+        self.ce.set_low().map_err(Error::Pin)?;
+        self.write_register(reg::CONFIG, &[CONFIG])
+    }
+
+    /// Waits out the 1.5ms the chip's oscillator needs to start and listens again
+    pub fn power_up(&mut self) -> Result<(), Error<SPIErr, PinErr>> {
+        self.write_register(reg::CONFIG, &[CONFIG | PWR_UP])?;
+        self.delay.delay_ms(2);
+        self.start_listening()
+    }
+
+    fn start_listening(&mut self) -> Result<(), Error<SPIErr, PinErr>> {
+        self.write_register(reg::CONFIG, &[CONFIG | PWR_UP | PRIM_RX])?;
+        self.ce.set_high().map_err(Error::Pin)
+    }
+
+    fn stop_listening(&mut self) -> Result<(), Error<SPIErr, PinErr>> {
+        self.ce.set_low().map_err(Error::Pin)?;
+        self.write_register(reg::CONFIG, &[CONFIG | PWR_UP])
+    }
+
+    /// Pipe 0 takes the acks for `to`, pipe 1 the first address in `from` and pipes 2 to 5 the
+    /// first byte of the rest
+    fn open_pipes(&mut self) -> Result<(), Error<SPIErr, PinErr>> {
+        let to = self.to;
+        self.write_register(reg::TX_ADDR, &to)?;
+        self.write_register(reg::RX_ADDR_P0, &to)?;
+        for pipe in 0..self.listen {
+            let address = self.from[pipe];
+            let len = if pipe == 0 { ADDRESS_SIZE } else { 1 };
+            self.write_register(reg::RX_ADDR_P0 + 1 + pipe as u8, &address[..len])?;
+        }
+        let enabled = (1 << (self.listen + 1)) - 1;
+        self.write_register(reg::EN_RXADDR, &[enabled])
+    }
+
+    fn read_register(&mut self, register: u8) -> Result<u8, Error<SPIErr, PinErr>> {
+        let mut buf = [cmd::R_REGISTER | register, cmd::NOP];
+        self.command(&mut buf)?;
+        Ok(buf[1])
+    }
+
+    fn write_register(&mut self, register: u8, value: &[u8]) -> Result<(), Error<SPIErr, PinErr>> {
+        let mut buf = [0; ADDRESS_SIZE + 1];
+        buf[0] = cmd::W_REGISTER | register;
+        buf[1..=value.len()].copy_from_slice(value);
+        self.command(&mut buf[..=value.len()])?;
+        Ok(())
+    }
+
+    /// Clocks `buf` out and what the chip answers back into it, returning STATUS, which the chip
+    /// always sends first
+    fn command(&mut self, buf: &mut [u8]) -> Result<u8, Error<SPIErr, PinErr>> {
+        self.ncs.set_low().map_err(Error::Pin)?;
+        let result = self.spi.transfer(buf).map(|_| ()).map_err(Error::Spi);
+        self.ncs.set_high().map_err(Error::Pin)?;
+        result?;
+        Ok(buf[0])
+    }
+}
+
+impl<SPI, CE, NCS, D, SPIErr, PinErr> Radio for Nrf24<SPI, CE, NCS, D>
+where
+    SPI: Transfer<u8, Error = SPIErr>,
+    CE: OutputPin<Error = PinErr>,
+    NCS: OutputPin<Error = PinErr>,
+    D: DelayMs<u8> + DelayUs<u8>,
+{
+    type Error = Error<SPIErr, PinErr>;
+
+    fn configure(&mut self, config: &NodeConfig) -> Result<(), Self::Error> {
+        self.config = *config;
+        let setup = rf_setup(config.pa_level, config.data_rate);
+        self.write_register(reg::RF_CH, &[config.channel])?;
+        self.write_register(reg::RF_SETUP, &[setup])
+    }
+
+    /// Also moves pipe 0, where the acknowledgements come back from
+    fn set_destination(&mut self, address: &[u8; ADDRESS_SIZE]) -> Result<(), Self::Error> {
+        self.to = *address;
+        self.write_register(reg::TX_ADDR, address)?;
+        self.write_register(reg::RX_ADDR_P0, address)
+    }
+
+    /// Polls STATUS until the chip either has the ack or has given up, and listens again
+    fn send(&mut self, payload: &[u8]) -> Result<bool, Self::Error> {
+        self.stop_listening()?;
+        let mut buf = [0; MAX_PAYLOAD + 1];
+        let len = payload.len().min(MAX_PAYLOAD);
+        buf[0] = cmd::W_TX_PAYLOAD;
+        buf[1..=len].copy_from_slice(&payload[..len]);
+        self.command(&mut buf[..=len])?;
+        // A 10us pulse sends one payload along with its retries
+        self.ce.set_high().map_err(Error::Pin)?;
+        self.delay.delay_us(15);
+        self.ce.set_low().map_err(Error::Pin)?;
+        let mut acked = false;
+        for _ in 0..SEND_POLLS {
+            let status = self.command(&mut [cmd::NOP])?;
+            if status & (TX_DS | MAX_RT) != 0 {
+                acked = status & TX_DS != 0;
+                break;
+            }
+            self.delay.delay_us(SEND_POLL_US);
+        }
+        if !acked {
+            // The payload stays at the head of the FIFO after MAX_RT
+            self.command(&mut [cmd::FLUSH_TX])?;
+        }
+        self.write_register(reg::STATUS, &[TX_DS | MAX_RT])?;
+        self.start_listening()?;
+        Ok(acked)
+    }
+
+    /// ARC_CNT, the bottom four bits of OBSERVE_TX. Writing the next payload clears it
+    fn retries(&mut self) -> Result<u8, Self::Error> {
+        Ok(self.read_register(reg::OBSERVE_TX)? & 0x0F)
+    }
+
+    fn data_available(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.read_register(reg::FIFO_STATUS)? & RX_EMPTY == 0)
+    }
+
+    /// Returns the length the payload was sent with, from R_RX_PL_WID. The chip can report a
+    /// width over 32 for a corrupt payload, which is flushed and returned as 0
+    fn receive(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let mut width = [cmd::R_RX_PL_WID, cmd::NOP];
+        self.command(&mut width)?;
+        let width = width[1] as usize;
+        if width > MAX_PAYLOAD {
+            self.command(&mut [cmd::FLUSH_RX])?;
+            self.write_register(reg::STATUS, &[RX_DR])?;
+            return Ok(0);
+        }
+        let mut payload = [cmd::NOP; MAX_PAYLOAD + 1];
+        payload[0] = cmd::R_RX_PAYLOAD;
+        self.command(&mut payload[..=width])?;
+        let len = width.min(buf.len());
+        buf[..len].copy_from_slice(&payload[1..=len]);
+        // Lets the IRQ pin go high again
+        self.write_register(reg::STATUS, &[RX_DR])?;
+        Ok(width)
+    }
+
+    /// Leaving receive mode latches the detector, so listening starts again afterwards for the
+    /// next sample. It needs 170us of listening to be valid
+    fn carrier(&mut self) -> Result<bool, Self::Error> {
+        self.ce.set_low().map_err(Error::Pin)?;
+        let rpd = self.read_register(reg::RPD)?;
+        self.ce.set_high().map_err(Error::Pin)?;
+        Ok(rpd & 1 != 0)
+    }
+}
+
+/// RF_SETUP for a power and rate: RF_PWR in bits 2:1, and the rate in RF_DR_LOW and RF_DR_HIGH
+fn rf_setup(pa_level: PaLevel, data_rate: DataRate) -> u8 {
+    let rate = match data_rate {
+        DataRate::Kbps250 => RF_DR_LOW,
+        DataRate::Mbps1 => 0,
+        DataRate::Mbps2 => RF_DR_HIGH,
+    };
+    rate | (pa_level as u8) << 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::convert::Infallible;
+    use std::rc::Rc;
+
+    /// The chip's registers and FIFOs as seen over SPI
+    #[derive(Default)]
+    struct Chip {
+        registers: [[u8; ADDRESS_SIZE]; 0x20],
+        rx: VecDeque<Vec<u8>>,
+        tx: Vec<Vec<u8>>,
+        /// Whether a payload written to the TX FIFO gets its ack, and after how many retries
+        ack: bool,
+        arc: u8,
+        ce: bool,
+    }
+
+    impl Chip {
+        fn status(&self) -> u8 {
+            self.registers[reg::STATUS as usize][0]
+        }
+
+        fn register(&self, register: u8) -> u8 {
+            self.registers[register as usize][0]
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Spi(Rc<RefCell<Chip>>);
+
+    impl Transfer<u8> for Spi {
+        type Error = Infallible;
+
+        fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Infallible> {
+            let mut chip = self.0.borrow_mut();
+            let command = words[0];
+            words[0] = chip.status();
+            let data = &mut words[1..];
+            match command {
+                0x00..=0x1F => {
+                    let register = command as usize;
+                    if register == reg::FIFO_STATUS as usize {
+                        chip.registers[register][0] = chip.rx.is_empty() as u8 * RX_EMPTY;
+                    }
+                    let len = data.len();
+                    data.copy_from_slice(&chip.registers[register][..len]);
+                }
+                0x20..=0x3F => {
+                    let register = (command & 0x1F) as usize;
+                    if register == reg::STATUS as usize {
+                        chip.registers[register][0] &= !data[0];
+                    } else {
+                        chip.registers[register][..data.len()].copy_from_slice(data);
+                    }
+                }
+                cmd::R_RX_PL_WID => data[0] = chip.rx.front().map_or(0, |p| p.len() as u8),
+                cmd::R_RX_PAYLOAD => {
+                    let payload = chip.rx.pop_front().unwrap();
+                    let len = data.len().min(payload.len());
+                    data[..len].copy_from_slice(&payload[..len]);
+                }
+                cmd::W_TX_PAYLOAD => {
+                    chip.tx.push(data.to_vec());
+                    let status = if chip.ack { TX_DS } else { MAX_RT };
+                    chip.registers[reg::STATUS as usize][0] |= status;
+                    chip.registers[reg::OBSERVE_TX as usize][0] = chip.arc;
+                }
+                cmd::FLUSH_TX => chip.tx.clear(),
+                cmd::FLUSH_RX => chip.rx.clear(),
+                cmd::NOP => {}
+                _ => panic!("unknown command {command:#x}"),
+            }
+            Ok(words)
+        }
+    }
+
+    struct Ce(Rc<RefCell<Chip>>);
+
+    impl OutputPin for Ce {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.0.borrow_mut().ce = false;
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.0.borrow_mut().ce = true;
+            Ok(())
+        }
+    }
+
+    struct Ncs;
+
+    impl OutputPin for Ncs {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    struct Delay;
+
+    impl DelayMs<u8> for Delay {
+        fn delay_ms(&mut self, _: u8) {}
+    }
+
+    impl DelayUs<u8> for Delay {
+        fn delay_us(&mut self, _: u8) {}
+    }
+
+    type TestRadio = Nrf24<Spi, Ce, Ncs, Delay>;
+
+    fn radio(config: &NodeConfig) -> (TestRadio, Rc<RefCell<Chip>>) {
+        let chip = Rc::new(RefCell::new(Chip::default()));
+        let spi = Spi(chip.clone());
+        let from = [common::node_address(0), common::node_address(1)];
+        let radio = Nrf24::new(
+            spi,
+            Ce(chip.clone()),
+            Ncs,
+            Delay,
+            config,
+            &common::host_address(0),
+            &from,
+        )
+        .unwrap();
+        (radio, chip)
+    }
+
+    #[test]
+    fn opens_pipes() {
+        let (_, chip) = radio(&NodeConfig::DEFAULT);
+        let chip = chip.borrow();
+        assert_eq!(
+            chip.registers[reg::TX_ADDR as usize],
+            common::host_address(0)
+        );
+        assert_eq!(
+            chip.registers[reg::RX_ADDR_P0 as usize],
+            common::host_address(0)
+        );
+        assert_eq!(chip.registers[0x0B], common::node_address(0));
+        assert_eq!(chip.register(0x0C), common::node_address(1)[0]);
+        assert_eq!(chip.register(reg::EN_RXADDR), 0b111);
+        assert_eq!(chip.register(reg::CONFIG), CONFIG | PWR_UP | PRIM_RX);
+        assert!(chip.ce);
+    }
+
+    #[test]
+    fn send_reports_the_ack_and_retries() {
+        let (mut radio, chip) = radio(&NodeConfig::DEFAULT);
+        chip.borrow_mut().ack = true;
+        chip.borrow_mut().arc = 3;
+        assert!(radio.send(&[1, 2, 3]).unwrap());
+        assert_eq!(radio.retries().unwrap(), 3);
+        assert_eq!(chip.borrow().tx, [vec![1, 2, 3]]);
+
+        chip.borrow_mut().ack = false;
+        chip.borrow_mut().arc = 15;
+        assert!(!radio.send(&[4]).unwrap());
+        assert_eq!(radio.retries().unwrap(), 15);
+        let chip = chip.borrow();
+        // The lost payload is flushed and the flags cleared for the next one
+        assert!(chip.tx.is_empty());
+        assert_eq!(chip.status() & (TX_DS | MAX_RT), 0);
+        assert_eq!(chip.register(reg::CONFIG), CONFIG | PWR_UP | PRIM_RX);
+        assert!(chip.ce);
+    }
+
+    #[test]
+    fn configure_writes_channel_and_power() {
+        let (mut radio, chip) = radio(&NodeConfig::DEFAULT);
+        for (pa_level, bits) in [
+            (PaLevel::Min, 0b000),
+            (PaLevel::Low, 0b010),
+            (PaLevel::High, 0b100),
+            (PaLevel::Max, 0b110),
+        ] {
+            let config = NodeConfig {
+                channel: 76,
+                pa_level,
+                ..NodeConfig::DEFAULT
+            };
+            radio.configure(&config).unwrap();
+            assert_eq!(chip.borrow().register(reg::RF_CH), 76);
+            assert_eq!(chip.borrow().register(reg::RF_SETUP) & 0b110, bits);
+        }
+        // A reinit puts back what was last configured
+        chip.borrow_mut().registers = Default::default();
+        radio.reinit().unwrap();
+        assert_eq!(chip.borrow().register(reg::RF_CH), 76);
+        assert_eq!(chip.borrow().register(reg::RF_SETUP) & 0b110, 0b110);
+    }
+
+    #[test]
+    fn data_rate_bits() {
+        // RF_DR_LOW is bit 5 and RF_DR_HIGH bit 3; 250kbps sets the first, 2Mbps the second
+        assert_eq!(rf_setup(PaLevel::Min, DataRate::Kbps250), 0b10_0000);
+        assert_eq!(rf_setup(PaLevel::Min, DataRate::Mbps1), 0b00_0000);
+        assert_eq!(rf_setup(PaLevel::Min, DataRate::Mbps2), 0b00_1000);
+        assert_eq!(rf_setup(PaLevel::Max, DataRate::Kbps250), 0b10_0110);
+
+        let (mut radio, chip) = radio(&NodeConfig::DEFAULT);
+        let config = NodeConfig {
+            data_rate: DataRate::Kbps250,
+            ..NodeConfig::DEFAULT
+        };
+        radio.configure(&config).unwrap();
+        assert_eq!(chip.borrow().register(reg::RF_SETUP) & 0b10_1000, 0b10_0000);
+    }
+
+    #[test]
+    fn receives_dynamic_payloads() {
+        let (mut radio, chip) = radio(&NodeConfig::DEFAULT);
+        assert_eq!(chip.borrow().register(reg::FEATURE), EN_DPL);
+        assert_eq!(chip.borrow().register(reg::DYNPD), ALL_PIPES);
+        assert_eq!(chip.borrow().register(reg::EN_AA), ALL_PIPES);
+        assert!(!radio.data_available().unwrap());
+
+        chip.borrow_mut().rx.push_back(vec![7; 12]);
+        chip.borrow_mut().rx.push_back(vec![9; 32]);
+        chip.borrow_mut().registers[reg::STATUS as usize][0] |= RX_DR;
+        assert!(radio.data_available().unwrap());
+        let mut buf = [0; 32];
+        assert_eq!(radio.receive(&mut buf).unwrap(), 12);
+        assert_eq!(buf[..12], [7; 12]);
+        assert_eq!(chip.borrow().status() & RX_DR, 0);
+        assert_eq!(radio.receive(&mut buf).unwrap(), 32);
+        assert_eq!(buf, [9; 32]);
+        assert!(!radio.data_available().unwrap());
+
+        // A corrupt width is flushed rather than read
+        chip.borrow_mut().rx.push_back(vec![0; 40]);
+        chip.borrow_mut().rx.push_back(vec![1; 4]);
+        assert_eq!(radio.receive(&mut buf).unwrap(), 0);
+        assert!(!radio.data_available().unwrap());
+    }
+
+    #[test]
+    fn carrier_reads_rpd() {
+        let (mut radio, chip) = radio(&NodeConfig::DEFAULT);
+        assert!(!radio.carrier().unwrap());
+        chip.borrow_mut().registers[reg::RPD as usize][0] = 1;
+        assert!(radio.carrier().unwrap());
+        // Still listening for the next sample
+        assert!(chip.borrow().ce);
+        assert_eq!(
+            chip.borrow().register(reg::CONFIG),
+            CONFIG | PWR_UP | PRIM_RX
+        );
+    }
+}
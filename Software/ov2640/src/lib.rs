@@ -0,0 +1,555 @@
+#![cfg_attr(not(test), no_std)]
+//! Driver for the OV2640 camera sensor over its SCCB control bus, which an I2C peripheral can
+//! talk to.
+//!
+//! SCCB has no repeated start, so a register is read with a write of its address followed by a
+//! separate read. The chip only answers while it gets a clock on XCLK.
+
+use embedded_hal::blocking::{
+    delay::DelayMs,
+    i2c::{Read, Write},
+};
+
+mod regs;
+
+use regs::*;
+
+/// The 7 bit address, 0x60 and 0x61 once shifted
+pub const ADDRESS: u8 = 0x30;
+
+/// The quantization scale set by [`Ov2640::init`], see [`Ov2640::set_jpeg_quality`]
+pub const DEFAULT_JPEG_QUALITY: u8 = 12;
+
+/// The registers behind each address, see [`Ov2640::write_register`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Bank {
+    /// Image processing and the output format
+    Dsp = 0,
+    /// The sensor's window, timing and orientation
+    Sensor = 1,
+}
+
+/// Output sizes. Up to SVGA the sensor runs in its faster SVGA mode, above it in UXGA mode, and
+/// the DSP scales the window down to the size asked for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resolution {
+    /// 160x120
+    Qqvga,
+    /// 176x144
+    Qcif,
+    /// 320x240
+    Qvga,
+    /// 400x296
+    Cif,
+    /// 640x480
+    Vga,
+    /// 800x600
+    Svga,
+    /// 1024x768
+    Xga,
+    /// 1280x1024
+    Sxga,
+    /// 1600x1200
+    Uxga,
+}
+
+impl Resolution {
+    /// Width and height in pixels
+    pub fn size(self) -> (u16, u16) {
+        match self {
+            Resolution::Qqvga => (160, 120),
+            Resolution::Qcif => (176, 144),
+            Resolution::Qvga => (320, 240),
+            Resolution::Cif => (400, 296),
+            Resolution::Vga => (640, 480),
+            Resolution::Svga => (800, 600),
+            Resolution::Xga => (1024, 768),
+            Resolution::Sxga => (1280, 1024),
+            Resolution::Uxga => (1600, 1200),
+        }
+    }
+}
+
+/// What the DVP port sends for each frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Two bytes a pixel
+    Rgb565,
+    /// Two bytes a pixel, luma with alternating chroma
+    Yuv422,
+    /// A compressed frame of varying length
+    Jpeg,
+}
+
+/// The manufacturer and product ids the chip reports
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChipId {
+    /// 0x7FA2 for OmniVision
+    pub manufacturer: u16,
+    /// 0x2641 or 0x2642 depending on the revision
+    pub product: u16,
+}
+
+impl ChipId {
+    pub fn is_ov2640(&self) -> bool {
+        self.product >> 8 == 0x26
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error<E> {
+    /// The bus failed, or nothing acknowledged the address
+    Bus(E),
+    /// Something other than an OV2640 answered
+    WrongChip(ChipId),
+}
+
+/// An OV2640 on the bus `i2c`
+pub struct Ov2640<I2C> {
+    i2c: I2C,
+    /// The bank last selected, unknown until the first access and after a reset
+    bank: Option<Bank>,
+    /// The special digital effects turned on, see `set_effect`
+    effects: u8,
+}
+
+impl<I2C, E> Ov2640<I2C>
+where
+    I2C: Write<Error = E> + Read<Error = E>,
+{
+    pub fn new(i2c: I2C) -> Self {
+        Self {
+            i2c,
+            bank: None,
+            effects: 0,
+        }
+    }
+
+    pub fn release(self) -> I2C {
+        self.i2c
+    }
+
+    /// Checks that an OV2640 is there, resets it and sets it up to send frames of `resolution`
+    /// in `format`
+    pub fn init(
+        &mut self,
+        delay: &mut impl DelayMs<u32>,
+        resolution: Resolution,
+        format: Format,
+    ) -> Result<(), Error<E>> {
+        let id = self.chip_id()?;
+        if !id.is_ov2640() {
+            return Err(Error::WrongChip(id));
+        }
+        self.reset(delay)?;
+        self.write_all(INIT)?;
+        self.effects = 0;
+        self.set_jpeg_quality(DEFAULT_JPEG_QUALITY)?;
+        self.set_resolution(resolution)?;
+        self.set_format(format)
+    }
+
+    pub fn chip_id(&mut self) -> Result<ChipId, Error<E>> {
+        let mut word = |high, low| -> Result<u16, Error<E>> {
+            let high = self.read_register(Bank::Sensor, high)?;
+            Ok(u16::from_be_bytes([
+                high,
+                self.read_register(Bank::Sensor, low)?,
+            ]))
+        };
+        Ok(ChipId {
+            manufacturer: word(MIDH, MIDL)?,
+            product: word(PIDH, PIDL)?,
+        })
+    }
+
+    /// Puts every register back to its power on value
+    pub fn reset(&mut self, delay: &mut impl DelayMs<u32>) -> Result<(), Error<E>> {
+        self.write_register(Bank::Sensor, COM7, COM7_SRST)?;
+        self.bank = None;
+        delay.delay_ms(10);
+        Ok(())
+    }
+
+    pub fn read_register(&mut self, bank: Bank, register: u8) -> Result<u8, Error<E>> {
+        self.select(bank)?;
+        let mut value = [0];
+        self.i2c.write(ADDRESS, &[register]).map_err(Error::Bus)?;
+        self.i2c.read(ADDRESS, &mut value).map_err(Error::Bus)?;
+        Ok(value[0])
+    }
+
+    pub fn write_register(&mut self, bank: Bank, register: u8, value: u8) -> Result<(), Error<E>> {
+        self.select(bank)?;
+        self.write(register, value)
+    }
+
+    fn write(&mut self, register: u8, value: u8) -> Result<(), Error<E>> {
+        self.i2c
+            .write(ADDRESS, &[register, value])
+            .map_err(Error::Bus)
+    }
+
+    /// Switches banks unless `bank` is already selected
+    fn select(&mut self, bank: Bank) -> Result<(), Error<E>> {
+        if self.bank != Some(bank) {
+            self.write(BANK_SEL, bank as u8)?;
+            self.bank = Some(bank);
+        }
+        Ok(())
+    }
+
+    /// Writes a table, whose bank switches go through `select`
+    fn write_all(&mut self, writes: &[regs::Write]) -> Result<(), Error<E>> {
+        for &(register, value) in writes {
+            match register {
+                BANK_SEL if value == Bank::Dsp as u8 => self.select(Bank::Dsp)?,
+                BANK_SEL => self.select(Bank::Sensor)?,
+                _ => self.write(register, value)?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_resolution(&mut self, resolution: Resolution) -> Result<(), Error<E>> {
+        let (width, height) = resolution.size();
+        let (sensor, (in_width, in_height)) = if width <= 800 && height <= 600 {
+            (SENSOR_SVGA, (800, 600))
+        } else {
+            (SENSOR_UXGA, (1600, 1200))
+        };
+        self.write_all(sensor)?;
+        // The DSP takes the whole window, divides it down by a power of two and then zooms it
+        // down to the output size. Sizes are in units of 8 or 4 pixels
+        let (h_div, v_div) = (divider(in_width, width), divider(in_height, height));
+        let ctrli = if h_div == 0 && v_div == 0 {
+            0
+        } else {
+            CTRLI_LP_DP | v_div << 3 | h_div
+        };
+        let (in_width, in_height) = (in_width / 4, in_height / 4);
+        let (width, height) = (width / 4, height / 4);
+        self.write_all(&[
+            (BANK_SEL, Bank::Dsp as u8),
+            (RESET, RESET_DVP),
+            (HSIZE8, (in_width / 2) as u8),
+            (VSIZE8, (in_height / 2) as u8),
+            (SIZEL, 0),
+            (CTRL2, CTRL2_DEFAULT),
+            (HSIZE, in_width as u8),
+            (VSIZE, in_height as u8),
+            (XOFFL, 0),
+            (YOFFL, 0),
+            (
+                VHYX,
+                ((in_height >> 8) as u8 & 1) << 7 | ((in_width >> 8) as u8 & 1) << 3,
+            ),
+            (TEST, ((in_width >> 9) as u8 & 1) << 7),
+            (CTRLI, ctrli),
+            (ZMOW, width as u8),
+            (ZMOH, height as u8),
+            (
+                ZMHH,
+                ((height >> 8) as u8 & 1) << 2 | ((width >> 8) as u8 & 3),
+            ),
+            (RESET, 0),
+        ])
+    }
+
+    pub fn set_format(&mut self, format: Format) -> Result<(), Error<E>> {
+        let (reset, mode) = match format {
+            Format::Rgb565 => (RESET_DVP, IMAGE_MODE_RGB565),
+            Format::Yuv422 => (RESET_DVP, IMAGE_MODE_YUV422),
+            Format::Jpeg => (RESET_JPEG | RESET_DVP, IMAGE_MODE_JPEG | IMAGE_MODE_YUV422),
+        };
+        self.write_all(&[
+            (BANK_SEL, Bank::Dsp as u8),
+            (RESET, reset),
+            (IMAGE_MODE, mode),
+            (RESET, 0),
+        ])
+    }
+
+    /// Sets the JPEG quantization scale, from 2 for the best quality and largest frames to 63
+    pub fn set_jpeg_quality(&mut self, scale: u8) -> Result<(), Error<E>> {
+        self.write_register(Bank::Dsp, QS, scale.clamp(2, 63))
+    }
+
+    /// Levels go from -2 to 2, with 0 leaving the image as it is. Others are clamped
+    pub fn set_brightness(&mut self, level: i8) -> Result<(), Error<E>> {
+        let level = level.clamp(-2, 2);
+        self.set_effect(
+            SDE_EN_CONTRAST_BRIGHTNESS,
+            SDE_BRIGHTNESS,
+            &[(0x20 + 0x10 * level) as u8, 0x00],
+        )
+    }
+
+    /// Levels go from -2 to 2, with 0 leaving the image as it is. Others are clamped
+    pub fn set_contrast(&mut self, level: i8) -> Result<(), Error<E>> {
+        let level = level.clamp(-2, 2);
+        self.set_effect(
+            SDE_EN_CONTRAST_BRIGHTNESS,
+            SDE_CONTRAST,
+            &[
+                0x20,
+                (0x20 + 4 * level) as u8,
+                (0x20 - 10 * level) as u8,
+                0x06,
+            ],
+        )
+    }
+
+    /// Levels go from -2 to 2, with 0 leaving the image as it is. Others are clamped
+    pub fn set_saturation(&mut self, level: i8) -> Result<(), Error<E>> {
+        let level = level.clamp(-2, 2);
+        let gain = (0x48 + 0x10 * level) as u8;
+        self.set_effect(SDE_EN_SATURATION, SDE_SATURATION, &[gain, gain])
+    }
+
+    /// Turns on effect `enable` and writes `values` from the effect register `address` on
+    fn set_effect(&mut self, enable: u8, address: u8, values: &[u8]) -> Result<(), Error<E>> {
+        self.effects |= enable;
+        self.write_all(&[
+            (BANK_SEL, Bank::Dsp as u8),
+            (BPADDR, SDE_ENABLE),
+            (BPDATA, self.effects),
+            (BPADDR, address),
+        ])?;
+        // The address moves on after each value
+        for &value in values {
+            self.write(BPDATA, value)?;
+        }
+        Ok(())
+    }
+
+    /// Mirrors the image left to right and upside down
+    pub fn set_flip(&mut self, horizontal: bool, vertical: bool) -> Result<(), Error<E>> {
+        let mut reg04 = self.read_register(Bank::Sensor, REG04)?;
+        reg04 &= !(REG04_HFLIP | REG04_VFLIP | REG04_VREF);
+        if horizontal {
+            reg04 |= REG04_HFLIP;
+        }
+        if vertical {
+            reg04 |= REG04_VFLIP | REG04_VREF;
+        }
+        self.write(REG04, reg04)
+    }
+}
+
+/// The power of two, up to 8, that `input` can be divided by and still be at least `output`
+fn divider(input: u16, output: u16) -> u8 {
+    (0..=3).rev().find(|&n| input >> n >= output).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Registers that keep what was written, with every write recorded
+    struct Bus {
+        registers: [[u8; 256]; 2],
+        bank: usize,
+        /// The register the next read returns, set by a write of just the address
+        pointer: u8,
+        writes: Vec<(u8, u8)>,
+        present: bool,
+    }
+
+    impl Bus {
+        fn new() -> Self {
+            let mut registers = [[0; 256]; 2];
+            registers[1][MIDH as usize] = 0x7F;
+            registers[1][MIDL as usize] = 0xA2;
+            registers[1][PIDH as usize] = 0x26;
+            registers[1][PIDL as usize] = 0x42;
+            registers[1][REG04 as usize] = 0x28;
+            Self {
+                registers,
+                bank: 0,
+                pointer: 0,
+                writes: Vec::new(),
+                present: true,
+            }
+        }
+
+        fn register(&self, bank: Bank, register: u8) -> u8 {
+            self.registers[bank as usize][register as usize]
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Nack;
+
+    impl Write for Bus {
+        type Error = Nack;
+
+        fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Nack> {
+            assert_eq!(address, ADDRESS);
+            if !self.present {
+                return Err(Nack);
+            }
+            match *bytes {
+                [register] => self.pointer = register,
+                [BANK_SEL, bank] => {
+                    self.writes.push((BANK_SEL, bank));
+                    self.bank = bank as usize;
+                }
+                [register, value] => {
+                    self.writes.push((register, value));
+                    self.registers[self.bank][register as usize] = value;
+                }
+                _ => panic!("Wrote {:02x?}", bytes),
+            }
+            Ok(())
+        }
+    }
+
+    impl Read for Bus {
+        type Error = Nack;
+
+        fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Nack> {
+            assert_eq!((address, buffer.len()), (ADDRESS, 1));
+            buffer[0] = self.registers[self.bank][self.pointer as usize];
+            Ok(())
+        }
+    }
+
+    struct NoDelay;
+
+    impl DelayMs<u32> for NoDelay {
+        fn delay_ms(&mut self, _: u32) {}
+    }
+
+    #[test]
+    fn detects_the_chip() {
+        let mut camera = Ov2640::new(Bus::new());
+        assert_eq!(
+            camera.chip_id(),
+            Ok(ChipId {
+                manufacturer: 0x7FA2,
+                product: 0x2642
+            })
+        );
+        // Selected once for all four reads
+        assert_eq!(camera.release().writes, [(BANK_SEL, 1)]);
+
+        let mut bus = Bus::new();
+        bus.registers[1][PIDH as usize] = 0x77;
+        let mut camera = Ov2640::new(bus);
+        let result = camera.init(&mut NoDelay, Resolution::Vga, Format::Jpeg);
+        assert!(matches!(result, Err(Error::WrongChip(id)) if id.product == 0x7742));
+        // Nothing was written past the bank switch
+        assert_eq!(camera.release().writes.len(), 1);
+
+        let mut bus = Bus::new();
+        bus.present = false;
+        let mut camera = Ov2640::new(bus);
+        let result = camera.init(&mut NoDelay, Resolution::Vga, Format::Jpeg);
+        assert_eq!(result, Err(Error::Bus(Nack)));
+    }
+
+    #[test]
+    fn init() {
+        let mut camera = Ov2640::new(Bus::new());
+        camera
+            .init(&mut NoDelay, Resolution::Qvga, Format::Jpeg)
+            .unwrap();
+        let bus = camera.release();
+        // Reset first, after which the bank is selected again
+        assert_eq!(
+            bus.writes[..3],
+            [(BANK_SEL, 1), (COM7, COM7_SRST), (BANK_SEL, 0)]
+        );
+        assert_eq!(bus.register(Bank::Sensor, COM7), COM7_SVGA);
+        assert_eq!(bus.register(Bank::Dsp, QS), DEFAULT_JPEG_QUALITY);
+        assert_eq!(
+            bus.register(Bank::Dsp, IMAGE_MODE),
+            IMAGE_MODE_JPEG | IMAGE_MODE_YUV422
+        );
+        assert_eq!(bus.register(Bank::Dsp, RESET), 0);
+        // Every bank switch in the tables that was already in effect was skipped
+        let mut bank = None;
+        for &(register, value) in &bus.writes {
+            if register == BANK_SEL {
+                assert_ne!(bank, Some(value));
+                bank = Some(value);
+            }
+        }
+    }
+
+    #[test]
+    fn resolutions() {
+        let mut camera = Ov2640::new(Bus::new());
+        camera.set_resolution(Resolution::Uxga).unwrap();
+        let bus = camera.release();
+        assert_eq!(bus.register(Bank::Sensor, COM7), COM7_UXGA);
+        let dsp = |register| bus.register(Bank::Dsp, register);
+        assert_eq!((dsp(HSIZE8), dsp(VSIZE8)), (0xC8, 0x96));
+        assert_eq!((dsp(HSIZE), dsp(VSIZE), dsp(VHYX)), (0x90, 0x2C, 0x88));
+        assert_eq!((dsp(ZMOW), dsp(ZMOH), dsp(ZMHH)), (0x90, 0x2C, 0x05));
+        assert_eq!(dsp(CTRLI), 0);
+
+        // 800x600 divided by 4 to 200x150, then zoomed to 160x120
+        let mut camera = Ov2640::new(Bus::new());
+        camera.set_resolution(Resolution::Qqvga).unwrap();
+        let bus = camera.release();
+        let dsp = |register| bus.register(Bank::Dsp, register);
+        assert_eq!(bus.register(Bank::Sensor, COM7), COM7_SVGA);
+        assert_eq!((dsp(HSIZE8), dsp(VSIZE8)), (100, 75));
+        assert_eq!(dsp(CTRLI), CTRLI_LP_DP | 2 << 3 | 2);
+        assert_eq!((dsp(ZMOW), dsp(ZMOH), dsp(ZMHH)), (40, 30, 0));
+
+        assert_eq!(divider(800, 400), 1);
+        assert_eq!(divider(600, 296), 1);
+        assert_eq!(divider(1600, 1280), 0);
+        assert_eq!(divider(800, 10), 3);
+    }
+
+    #[test]
+    fn effects_and_flip() {
+        let mut camera = Ov2640::new(Bus::new());
+        camera.set_brightness(1).unwrap();
+        camera.set_saturation(-5).unwrap();
+        camera.set_contrast(2).unwrap();
+        camera.set_flip(false, true).unwrap();
+        let bus = camera.release();
+        assert_eq!(
+            bus.writes[..6],
+            [
+                (BANK_SEL, 0),
+                (BPADDR, SDE_ENABLE),
+                (BPDATA, SDE_EN_CONTRAST_BRIGHTNESS),
+                (BPADDR, SDE_BRIGHTNESS),
+                (BPDATA, 0x30),
+                (BPDATA, 0x00),
+            ]
+        );
+        // Saturation keeps brightness on, and is clamped to -2
+        assert_eq!(
+            bus.writes[6..11],
+            [
+                (BPADDR, SDE_ENABLE),
+                (BPDATA, SDE_EN_CONTRAST_BRIGHTNESS | SDE_EN_SATURATION),
+                (BPADDR, SDE_SATURATION),
+                (BPDATA, 0x28),
+                (BPDATA, 0x28),
+            ]
+        );
+        assert_eq!(
+            bus.writes[14..18],
+            [
+                (BPDATA, 0x20),
+                (BPDATA, 0x28),
+                (BPDATA, 0x0C),
+                (BPDATA, 0x06)
+            ]
+        );
+        // The other bits of REG04 are kept
+        assert_eq!(
+            bus.register(Bank::Sensor, REG04),
+            0x28 | REG04_VFLIP | REG04_VREF
+        );
+    }
+}
@@ -0,0 +1,255 @@
+//! Register addresses and the tables written at start up. The OV2640 has two banks of registers
+//! behind the same addresses, switched with [`BANK_SEL`]. The tables come from OmniVision's
+//! application notes, as used by most OV2640 drivers.
+
+use crate::Bank;
+
+/// Selects the bank the other addresses refer to. Present in both
+pub const BANK_SEL: u8 = 0xFF;
+
+// Sensor bank
+pub const COM1: u8 = 0x03;
+pub const REG04: u8 = 0x04;
+pub const PIDH: u8 = 0x0A;
+pub const PIDL: u8 = 0x0B;
+pub const COM7: u8 = 0x12;
+pub const HREFST: u8 = 0x17;
+pub const HREFEND: u8 = 0x18;
+pub const VSTRT: u8 = 0x19;
+pub const VEND: u8 = 0x1A;
+pub const MIDH: u8 = 0x1C;
+pub const MIDL: u8 = 0x1D;
+pub const REG32: u8 = 0x32;
+
+pub const REG04_HFLIP: u8 = 0x80;
+pub const REG04_VFLIP: u8 = 0x40;
+/// Has to be set along with [`REG04_VFLIP`], or the rows come out shifted by one
+pub const REG04_VREF: u8 = 0x10;
+pub const COM7_SRST: u8 = 0x80;
+pub const COM7_SVGA: u8 = 0x40;
+pub const COM7_UXGA: u8 = 0x00;
+
+// DSP bank
+pub const R_BYPASS: u8 = 0x05;
+pub const QS: u8 = 0x44;
+pub const CTRLI: u8 = 0x50;
+pub const HSIZE: u8 = 0x51;
+pub const VSIZE: u8 = 0x52;
+pub const XOFFL: u8 = 0x53;
+pub const YOFFL: u8 = 0x54;
+pub const VHYX: u8 = 0x55;
+pub const TEST: u8 = 0x57;
+pub const ZMOW: u8 = 0x5A;
+pub const ZMOH: u8 = 0x5B;
+pub const ZMHH: u8 = 0x5C;
+pub const BPADDR: u8 = 0x7C;
+pub const BPDATA: u8 = 0x7D;
+pub const CTRL2: u8 = 0x86;
+pub const SIZEL: u8 = 0x8C;
+pub const HSIZE8: u8 = 0xC0;
+pub const VSIZE8: u8 = 0xC1;
+pub const IMAGE_MODE: u8 = 0xDA;
+pub const RESET: u8 = 0xE0;
+
+pub const CTRLI_LP_DP: u8 = 0x80;
+pub const CTRL2_DEFAULT: u8 = 0x3D;
+pub const IMAGE_MODE_JPEG: u8 = 0x10;
+pub const IMAGE_MODE_RGB565: u8 = 0x08;
+pub const IMAGE_MODE_YUV422: u8 = 0x00;
+pub const RESET_JPEG: u8 = 0x10;
+pub const RESET_DVP: u8 = 0x04;
+pub const R_BYPASS_DSP: u8 = 0x00;
+
+/// Special digital effects, reached through [`BPADDR`] and [`BPDATA`]
+pub const SDE_ENABLE: u8 = 0x00;
+pub const SDE_SATURATION: u8 = 0x03;
+pub const SDE_CONTRAST: u8 = 0x07;
+pub const SDE_BRIGHTNESS: u8 = 0x09;
+/// Enables for [`SDE_ENABLE`]
+pub const SDE_EN_SATURATION: u8 = 0x02;
+pub const SDE_EN_CONTRAST_BRIGHTNESS: u8 = 0x04;
+
+/// A register write, or a bank switch when `.0` is [`BANK_SEL`]
+pub type Write = (u8, u8);
+
+const DSP: u8 = Bank::Dsp as u8;
+const SENSOR: u8 = Bank::Sensor as u8;
+
+/// Written after a reset. Leaves the sensor in SVGA mode, the DSP on and every effect neutral
+pub const INIT: &[Write] = &[
+    (BANK_SEL, DSP),
+    (0x2C, 0xFF),
+    (0x2E, 0xDF),
+    (BANK_SEL, SENSOR),
+    (0x3C, 0x32),
+    (0x11, 0x00),
+    (0x09, 0x02),
+    (REG04, 0x28),
+    (0x13, 0xE5),
+    (0x14, 0x48),
+    (0x2C, 0x0C),
+    (0x33, 0x78),
+    (0x3A, 0x33),
+    (0x3B, 0xFB),
+    (0x3E, 0x00),
+    (0x43, 0x11),
+    (0x16, 0x10),
+    (0x39, 0x92),
+    (0x35, 0xDA),
+    (0x22, 0x1A),
+    (0x37, 0xC3),
+    (0x23, 0x00),
+    (0x34, 0xC0),
+    (0x06, 0x88),
+    (0x07, 0xC0),
+    (0x0D, 0x87),
+    (0x0E, 0x41),
+    (0x4C, 0x00),
+    (0x4A, 0x81),
+    (0x21, 0x99),
+    (0x24, 0x40),
+    (0x25, 0x38),
+    (0x26, 0x82),
+    (0x5C, 0x00),
+    (0x63, 0x00),
+    (0x61, 0x70),
+    (0x62, 0x80),
+    (0x7C, 0x05),
+    (0x20, 0x80),
+    (0x28, 0x30),
+    (0x6C, 0x00),
+    (0x6D, 0x80),
+    (0x6E, 0x00),
+    (0x70, 0x02),
+    (0x71, 0x94),
+    (0x73, 0xC1),
+    (0x3D, 0x34),
+    (0x5A, 0x57),
+    (0x4F, 0xBB),
+    (0x50, 0x9C),
+    (BANK_SEL, DSP),
+    (0xE5, 0x7F),
+    (0xF9, 0xC0),
+    (0x41, 0x24),
+    (RESET, RESET_JPEG | RESET_DVP),
+    (0x76, 0xFF),
+    (0x33, 0xA0),
+    (0x42, 0x20),
+    (0x43, 0x18),
+    (0x4C, 0x00),
+    (0x87, 0xD0),
+    (0x88, 0x3F),
+    (0xD7, 0x03),
+    (0xD9, 0x10),
+    (0xD3, 0x82),
+    (0xC8, 0x08),
+    (0xC9, 0x80),
+    (BPADDR, 0x00),
+    (BPDATA, 0x00),
+    (BPADDR, 0x03),
+    (BPDATA, 0x48),
+    (BPDATA, 0x48),
+    (BPADDR, 0x08),
+    (BPDATA, 0x20),
+    (BPDATA, 0x10),
+    (BPDATA, 0x0E),
+    // Gamma
+    (0x90, 0x00),
+    (0x91, 0x0E),
+    (0x91, 0x1A),
+    (0x91, 0x31),
+    (0x91, 0x5A),
+    (0x91, 0x69),
+    (0x91, 0x75),
+    (0x91, 0x7E),
+    (0x91, 0x88),
+    (0x91, 0x8F),
+    (0x91, 0x96),
+    (0x91, 0xA3),
+    (0x91, 0xAF),
+    (0x91, 0xC4),
+    (0x91, 0xD7),
+    (0x91, 0xE8),
+    (0x91, 0x20),
+    // Color matrix
+    (0x92, 0x00),
+    (0x93, 0x06),
+    (0x93, 0xE3),
+    (0x93, 0x05),
+    (0x93, 0x05),
+    (0x93, 0x00),
+    (0x93, 0x04),
+    (0x93, 0x00),
+    (0x93, 0x00),
+    (0x93, 0x00),
+    (0x93, 0x00),
+    (0x93, 0x00),
+    (0x93, 0x00),
+    (0x93, 0x00),
+    // Lens correction
+    (0x96, 0x00),
+    (0x97, 0x08),
+    (0x97, 0x19),
+    (0x97, 0x02),
+    (0x97, 0x0C),
+    (0x97, 0x24),
+    (0x97, 0x30),
+    (0x97, 0x28),
+    (0x97, 0x26),
+    (0x97, 0x02),
+    (0x97, 0x98),
+    (0x97, 0x80),
+    (0x97, 0x00),
+    (0x97, 0x00),
+    // White balance
+    (0xC3, 0xED),
+    (0xA4, 0x00),
+    (0xA8, 0x00),
+    (0xC5, 0x11),
+    (0xC6, 0x51),
+    (0xBF, 0x80),
+    (0xC7, 0x10),
+    (0xB6, 0x66),
+    (0xB8, 0xA5),
+    (0xB7, 0x64),
+    (0xB9, 0x7C),
+    (0xB3, 0xAF),
+    (0xB4, 0x97),
+    (0xB5, 0xFF),
+    (0xB0, 0xC5),
+    (0xB1, 0x94),
+    (0xB2, 0x0F),
+    (0xC4, 0x5C),
+    (0xC3, 0xFD),
+    (0x7F, 0x00),
+    (0xE5, 0x1F),
+    (0xE1, 0x67),
+    (0xDD, 0x7F),
+    (IMAGE_MODE, IMAGE_MODE_YUV422),
+    (RESET, 0x00),
+    (R_BYPASS, R_BYPASS_DSP),
+];
+
+/// The sensor's window in SVGA mode, 800x600 at up to 30 frames a second
+pub const SENSOR_SVGA: &[Write] = &[
+    (BANK_SEL, SENSOR),
+    (COM7, COM7_SVGA),
+    (COM1, 0x0A),
+    (REG32, 0x09),
+    (HREFST, 0x11),
+    (HREFEND, 0x43),
+    (VSTRT, 0x00),
+    (VEND, 0x4B),
+];
+
+/// The sensor's full window in UXGA mode, 1600x1200 at up to 15 frames a second
+pub const SENSOR_UXGA: &[Write] = &[
+    (BANK_SEL, SENSOR),
+    (COM7, COM7_UXGA),
+    (COM1, 0x0F),
+    (REG32, 0x36),
+    (HREFST, 0x11),
+    (HREFEND, 0x75),
+    (VSTRT, 0x01),
+    (VEND, 0x97),
+];
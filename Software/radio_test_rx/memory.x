@@ -1,11 +1,22 @@
-
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
+  /* Needs a 128K part: the key takes the 53K after the first 64K */
   FLASH : ORIGIN = 0x08000000, LENGTH = 64K
-  RAM : ORIGIN = 0x20000000, LENGTH = 20K
+  KEY : ORIGIN = 0x08010000, LENGTH = 53K
+  /* The last 128 bytes of RAM hold the fault record, see the fault_log crate */
+  RAM : ORIGIN = 0x20000000, LENGTH = 20K - 128
+  FAULT : ORIGIN = 0x20004F80, LENGTH = 128
 }
 
+SECTIONS
+{
+  .key : { KEEP(*(.key .key.*)); } > KEY
+} INSERT AFTER .rodata;
+
+_fault_start = ORIGIN(FAULT);
+_fault_end = ORIGIN(FAULT) + LENGTH(FAULT);
+
 /* This is where the call stack will be allocated. */
 /* The stack is of the full descending type. */
 /* NOTE Do NOT modify `_stack_start` unless you know what you are doing */
@@ -2,231 +2,521 @@
 #![no_main]
 #![feature(bench_black_box)]
 
-use core::panic::PanicInfo;
-use cortex_m_rt::entry;
+use embedded_hal::blocking::delay::{DelayMs, DelayUs};
 use hal::{
-    device::gpioa::CRH,
-    gpio::{Output, Pin, PushPull},
+    gpio::{
+        gpioa::{PA3, PA4, PA5, PA6, PA7},
+        gpiob::PB3,
+        Alternate, Floating, Input, Output, Pin, PullUp, PushPull, CRH,
+    },
     prelude::*,
-    usb::{Peripheral, UsbBus},
+    usb::{Peripheral, UsbBus, UsbBusType},
 };
-use usb_device::prelude::*;
-use usbd_serial::{SerialPort, USB_CLASS_CDC};
 use stm32f1xx_hal as hal;
+use usb_device::{bus::UsbBusAllocator, prelude::*};
+use usbd_serial::{SerialPort, USB_CLASS_CDC};
 
-static mut PANIC_LED: *mut Pin<Output<PushPull>, CRH, 'C', 13> = core::ptr::null_mut();
-
-#[panic_handler]
-fn panic_handler(_: &PanicInfo) -> ! {
-    // We assume that `PANIC_LED` has been set
-    let led = unsafe { PANIC_LED };
-    if led.is_null() {
-        loop {}
-    } else {
-        // SAFETY: led is non null, so it must have been initialized
-        let led = unsafe { &mut *PANIC_LED };
-        loop {
-            for _ in 0..1000 {
-                cortex_m::asm::delay(70_200_000);
-                // 72_000_000 / 7_200_000 ~= 10Hz
+type Spi1 = hal::spi::Spi<
+    hal::pac::SPI1,
+    hal::spi::Spi1NoRemap,
+    (
+        PA5<Alternate<PushPull>>,
+        PA6<Input<Floating>>,
+        PA7<Alternate<PushPull>>,
+    ),
+    u8,
+>;
+type Radio = nrf24_radio::Nrf24<Spi1, PA3<Output<PushPull>>, PA4<Output<PushPull>>, AsmDelay>;
+type Led = Pin<Output<PushPull>, CRH, 'C', 13>;
+type Relay = common::Relay<1024>;
+
+/// The data rate to start on, set with `DATA_RATE=250k cargo build`. The node and receiver have
+/// to be built with the same one. The desktop tool can change it later, or turn on auto rate
+const DATA_RATE: common::DataRate = match option_env!("DATA_RATE") {
+    Some(name) => match common::DataRate::from_name(name) {
+        Some(rate) => rate,
+        None => panic!("DATA_RATE must be 250k, 1m or 2m"),
+    },
+    None => common::NodeConfig::DEFAULT.data_rate,
+};
+
+/// Placed after the code by memory.x, as it does not fit in the first 64K along with it
+#[link_section = ".key"]
+static RECEIVER_KEY: common::Key<{ common::KEY_SIZE }> = common::KEY;
+
+/// Busy waits on the core clock. SysTick belongs to RTIC for scheduling tasks
+#[derive(Clone, Copy)]
+pub struct AsmDelay {
+    cycles_per_us: u32,
+}
+
+macro_rules! impl_delay {
+    ($($ty:ty),*) => {$(
+        impl DelayUs<$ty> for AsmDelay {
+            fn delay_us(&mut self, us: $ty) {
+                cortex_m::asm::delay((us as u32).saturating_mul(self.cycles_per_us));
+            }
+        }
+
+        impl DelayMs<$ty> for AsmDelay {
+            fn delay_ms(&mut self, ms: $ty) {
+                for _ in 0..ms {
+                    self.delay_us(1000u32);
+                }
             }
-            led.toggle();
+        }
+    )*};
+}
+impl_delay!(u8, u16, u32);
+
+/// Gives the host as much of the queue as the endpoint takes right now. Whatever is left goes out
+/// on the next USB interrupt
+fn flush(relay: &mut Relay, serial: &mut SerialPort<'static, UsbBusType>) {
+    let to_host = relay.to_host();
+    while !to_host.is_empty() {
+        match serial.write(to_host.front()) {
+            Ok(written) if written > 0 => to_host.consume(written),
+            _ => break,
         }
     }
 }
 
-#[entry]
-fn main() -> ! {
-    let dp = hal::pac::Peripherals::take().unwrap();
-    let core = cortex_m::Peripherals::take().unwrap();
-    let mut flash = dp.FLASH.constrain();
-    let mut afio = dp.AFIO.constrain();
-
-    let rcc = dp.RCC.constrain();
-    let clocks = rcc
-        .cfgr
-        .use_hse(8.mhz())
-        .sysclk(72.mhz())
-        .hclk(72.mhz())
-        .freeze(&mut flash.acr);
-
-    // Initialize the different pins
-    let mut gpioa = dp.GPIOA.split();
-    let mut gpioc = dp.GPIOC.split();
-
-    let mut led = gpioc.pc13.into_push_pull_output(&mut gpioc.crh);
-    led.set_high();
-
-    // So so unsafe. I'm not gonna try to make a safety argument here because so many things are wrong
-    // This works on the current compiler to allow us to easily control the LED from the panic
-    // handler
-    let led_ptr: *mut Pin<Output<PushPull>, CRH, 'C', 13> =
-        unsafe { core::mem::transmute(&mut led) };
-    unsafe { PANIC_LED = led_ptr };
-
-    let cs = gpioa.pa4.into_push_pull_output(&mut gpioa.crl);
-    //let mut data_int = gpiob.pb3.into_pull_up_input();
-    //data_int.make_interrupt_source(&mut syscfg);
-    //data_int.enable_interrupt(&mut device.EXTI);
-    //data_int.trigger_on_edge(&mut device.EXTI, Edge::Falling);
-
-    let ce = gpioa.pa3.into_push_pull_output(&mut gpioa.crl);
-
-    let mosi = gpioa.pa7.into_alternate_push_pull(&mut gpioa.crl);
-    let miso = gpioa.pa6;
-    let sclk = gpioa.pa5.into_alternate_push_pull(&mut gpioa.crl);
-
-    let spi = hal::spi::Spi::spi1(
-        dp.SPI1,
-        (sclk, miso, mosi),
-        &mut afio.mapr,
-        nrf24_rs::SPI_MODE,
-        1.mhz(),
-        clocks,
-    );
-
-    let mut delay = hal::delay::Delay::new(core.SYST, clocks);
-
-    // Setup some configuration values
-    let config = nrf24_rs::config::NrfConfig::default()
-        .channel(8)
-        .pa_level(nrf24_rs::config::PALevel::Max)
-        // We will use a payload size the size of our message
-        .payload_size(nrf24_rs::MAX_PAYLOAD_SIZE);
-
-    // Initialize the chip
-    let mut nrf_chip = nrf24_rs::Nrf24l01::new(spi, ce, cs, &mut delay, config).unwrap();
-    if !nrf_chip.is_connected().unwrap() {
-        panic!("Chip is not connected.");
+#[rtic::app(device = stm32f1xx_hal::pac, peripherals = true, dispatchers = [SPI2])]
+mod app {
+    use super::*;
+    use common::{
+        host_address, node_address, ArqReceiver, CheckIns, FaultClass, FrameKind, HopFollower,
+        NodeConfig, ReceiverSetting, ResetReason, ResetReport, Setting, VarBlock, KEY_SIZE,
+        MAX_NODES,
+    };
+    use hal::gpio::{Edge, ExtiPin};
+    use hal::watchdog::IndependentWatchdog;
+    use systick_monotonic::{fugit::ExtU64, Systick};
+
+    #[monotonic(binds = SysTick, default = true)]
+    type Mono = Systick<1000>;
+
+    /// How long the watchdog waits to be fed before resetting us
+    const WATCHDOG_MS: u32 = 2000;
+    /// How often `supervise` checks on the tasks and the radio
+    const SUPERVISE_MS: u64 = 250;
+
+    /// The tasks that check in, see `check_ins`
+    const FOLLOW: usize = 0;
+    const COUNTERS: usize = 1;
+    const TASKS: usize = 2;
+    /// In milliseconds. `follow` does not run during a survey sweep
+    const TIMEOUTS: [u64; TASKS] = [500, 2000];
+
+    // Every task runs at the same priority, so none of them can interrupt another
+    #[shared]
+    struct Shared {
+        #[lock_free]
+        radio: Radio,
+        #[lock_free]
+        relay: Relay,
+        #[lock_free]
+        follower: HopFollower<'static, KEY_SIZE>,
+        /// One for each node, since each has its own sequence numbers
+        #[lock_free]
+        arq: [ArqReceiver; MAX_NODES],
+        #[lock_free]
+        usb_dev: UsbDevice<'static, UsbBusType>,
+        #[lock_free]
+        serial: SerialPort<'static, UsbBusType>,
+        #[lock_free]
+        led: Led,
+        /// Set after a panic or HardFault until a host is attached to hear about it
+        #[lock_free]
+        fault: Option<FaultClass>,
+        /// The watchdog is only fed while these keep coming in
+        #[lock_free]
+        check_ins: CheckIns<TASKS>,
     }
 
+    #[local]
+    struct Local {
+        irq: PB3<Input<PullUp>>,
+        watchdog: IndependentWatchdog,
+    }
 
-    // BluePill board has a pull-up resistor on the D+ line.
-    // Pull the D+ pin down to send a RESET condition to the USB bus.
-    // This forced reset is needed only for development, without it host
-    // will not reset your device when you upload new firmware.
-    let mut usb_dp = gpioa.pa12.into_push_pull_output(&mut gpioa.crh);
-    usb_dp.set_low();
-    delay.delay_us(1u16);
+    #[init(local = [usb_bus: Option<UsbBusAllocator<UsbBusType>> = None])]
+    fn init(cx: init::Context) -> (Shared, Local, init::Monotonics) {
+        let dp = cx.device;
+        let mut flash = dp.FLASH.constrain();
+        let mut afio = dp.AFIO.constrain();
+
+        // Cleared once read, so that the next boot only sees its own reset
+        let reason = ResetReason::from_csr(dp.RCC.csr.read().bits());
+        dp.RCC.csr.modify(|_, w| w.rmvf().set_bit());
+        let rcc = dp.RCC.constrain();
+        let clocks = rcc
+            .cfgr
+            .use_hse(8.mhz())
+            .sysclk(72.mhz())
+            .hclk(72.mhz())
+            .freeze(&mut flash.acr);
+        let mono = Systick::new(cx.core.SYST, clocks.sysclk().0);
+        let mut delay = AsmDelay {
+            cycles_per_us: clocks.sysclk().0 / 1_000_000,
+        };
+
+        // Initialize the different pins
+        let mut gpioa = dp.GPIOA.split();
+        let mut gpiob = dp.GPIOB.split();
+        let mut gpioc = dp.GPIOC.split();
+
+        let mut led = gpioc.pc13.into_push_pull_output(&mut gpioc.crh);
+        led.set_high();
+
+        let cs = gpioa.pa4.into_push_pull_output(&mut gpioa.crl);
+        let ce = gpioa.pa3.into_push_pull_output(&mut gpioa.crl);
+
+        // The nRF24 pulls IRQ low when a payload arrives. PB3 is a JTAG pin until JTAG is turned
+        // off, SWD still works
+        let (_pa15, pb3, _pb4) = afio.mapr.disable_jtag(gpioa.pa15, gpiob.pb3, gpiob.pb4);
+        let mut irq = pb3.into_pull_up_input(&mut gpiob.crl);
+        irq.make_interrupt_source(&mut afio);
+        irq.trigger_on_edge(&dp.EXTI, Edge::Falling);
+        irq.enable_interrupt(&dp.EXTI);
+
+        let mosi = gpioa.pa7.into_alternate_push_pull(&mut gpioa.crl);
+        let miso = gpioa.pa6;
+        let sclk = gpioa.pa5.into_alternate_push_pull(&mut gpioa.crl);
+
+        let spi = hal::spi::Spi::spi1(
+            dp.SPI1,
+            (sclk, miso, mosi),
+            &mut afio.mapr,
+            nrf24_radio::SPI_MODE,
+            1.mhz(),
+            clocks,
+        );
+
+        // The host moves us along with the node, see `FrameKind::Radio`
+        let mut relay = Relay::new();
+        relay.start_on(NodeConfig {
+            data_rate: DATA_RATE,
+            ..NodeConfig::DEFAULT
+        });
+        // Works out where a hopping node is from the blocks it sends. One radio can only be on one
+        // channel, so only node 0 can hop
+        let index_key = u32::from_ne_bytes(*include_bytes!("../../private/index-key.bin"));
+        let auth_key = *include_bytes!("../../private/auth-key.bin");
+        let follower = HopFollower::new(&RECEIVER_KEY, index_key, auth_key, 0);
+        // Tells each node which blocks to send again when ARQ is on
+        let offsets = RECEIVER_KEY.subkey_count::<u32, 7>();
+        let arq = core::array::from_fn(|n| ArqReceiver::new(index_key, offsets, auth_key, n as u8));
+
+        // Sent to the host once it is attached. After a fault the LED shows its blink code until
+        // then
+        relay.report_reset(&ResetReport {
+            reason,
+            counts: fault_log::count_reset(reason),
+        });
+        let fault = fault_log::take();
+        if let Some(fault) = &fault {
+            relay.report_fault(fault);
+            blink::spawn().unwrap();
+        }
+        let fault = fault.map(|fault| fault.class());
+
+        // Blocks from node n arrive on pipe n + 1 at its node address. Blocks from the host go to
+        // the host address of the node they are for, where it listens between its own
+        // transmissions
+        let listen: [_; MAX_NODES] = core::array::from_fn(|n| node_address(n as u8));
+        let radio = Radio::new(
+            spi,
+            ce,
+            cs,
+            delay,
+            relay.config(),
+            &host_address(0),
+            &listen,
+        )
+        .unwrap();
+
+        // BluePill board has a pull-up resistor on the D+ line.
+        // Pull the D+ pin down to send a RESET condition to the USB bus.
+        // This forced reset is needed only for development, without it host
+        // will not reset your device when you upload new firmware.
+        let mut usb_dp = gpioa.pa12.into_push_pull_output(&mut gpioa.crh);
+        usb_dp.set_low();
+        delay.delay_us(1u16);
+
+        let usb = Peripheral {
+            usb: dp.USB,
+            pin_dm: gpioa.pa11,
+            pin_dp: usb_dp.into_floating_input(&mut gpioa.crh),
+        };
+        let usb_bus: &'static _ = cx.local.usb_bus.insert(UsbBus::new(usb));
+
+        let serial = SerialPort::new(usb_bus);
+
+        let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27dd))
+            .manufacturer("Fake Company")
+            .product("Serial Port")
+            .serial_number("TEST")
+            .device_class(USB_CLASS_CDC)
+            .build();
+
+        // Resets us unless `supervise` feeds it, which it only does while every task checks in
+        let mut watchdog = IndependentWatchdog::new(dp.IWDG);
+        watchdog.start(WATCHDOG_MS.ms());
+        let check_ins = CheckIns::new(TIMEOUTS, 0);
+
+        counters::spawn_after(1.secs()).unwrap();
+        follow::spawn_after(FOLLOW_MS.millis()).unwrap();
+        supervise::spawn_after(SUPERVISE_MS.millis()).unwrap();
+        (
+            Shared {
+                radio,
+                relay,
+                follower,
+                arq,
+                usb_dev,
+                serial,
+                led,
+                fault,
+                check_ins,
+            },
+            Local { irq, watchdog },
+            init::Monotonics(mono),
+        )
+    }
 
-    let usb = Peripheral {
-        usb: dp.USB,
-        pin_dm: gpioa.pa11,
-        pin_dp: usb_dp.into_floating_input(&mut gpioa.crh),
-    };
-    let usb_bus = UsbBus::new(usb);
-
-    let mut serial = SerialPort::new(&usb_bus);
-
-    let mut usb_device = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16c0, 0x27dd))
-        .manufacturer("Fake Company")
-        .product("Serial Port")
-        .serial_number("TEST")
-        .device_class(USB_CLASS_CDC)
-        .build();
-
-    use common::{IndexedBlock, MainCipher, KEY, Tag};
-
-    // We also need an index key that is used to encrypt the index when sent in the clear
-    let index_key: [u8; 4] = *include_bytes!("../../private/index-key.bin");
-    let index_key = u32::from_ne_bytes(index_key);
-    let cipher = MainCipher::new(&KEY, index_key);
-     
-    let mut block = IndexedBlock::new();     
-
-    // Open a writing pipe on address "Node1".
-    // The listener will have to open a reading pipe with the same address
-    // in order to receive this message.
-    nrf_chip.open_reading_pipe(nrf24_rs::config::DataPipe::DP0, b"Node1").unwrap();
-
-    nrf_chip.start_listening().unwrap();
-
-    loop {
-        usb_device.poll(&mut [&mut serial]);
-
-        delay.delay_us(2u16);
-        let msg = "TEST _ A ";
-        let buf = msg.as_bytes();
-        let mut write_offset = 0;
-        while write_offset < buf.len() {
-            match serial.write(&buf[write_offset..]) {
-                Ok(len) if len > 0 => {
-                    write_offset += len;
-                }
-                _ => {}
-            }
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            cortex_m::asm::wfi();
         }
+    }
 
-        led.toggle();
+    #[task(binds = USB_HP_CAN_TX, shared = [radio, relay, follower, usb_dev, serial, led])]
+    fn usb_tx(cx: usb_tx::Context) {
+        let usb_tx::SharedResources {
+            radio,
+            relay,
+            follower,
+            usb_dev,
+            serial,
+            led,
+        } = cx.shared;
+        usb_poll(radio, relay, follower, usb_dev, serial, led);
     }
 
-    let mut x = 0;
-    loop {
+    #[task(binds = USB_LP_CAN_RX0, shared = [radio, relay, follower, usb_dev, serial, led])]
+    fn usb_rx(cx: usb_rx::Context) {
+        let usb_rx::SharedResources {
+            radio,
+            relay,
+            follower,
+            usb_dev,
+            serial,
+            led,
+        } = cx.shared;
+        usb_poll(radio, relay, follower, usb_dev, serial, led);
+    }
 
-        x += 1;
-        if x == 10_000 {
-            led.toggle();
-            let msg = "TEST _ A ";
-            serial.write(msg.as_bytes()).unwrap();
-            x = 0;
-            led.toggle();
+    /// Handles everything the host sent and sends it whatever is waiting
+    fn usb_poll(
+        radio: &mut Radio,
+        relay: &mut Relay,
+        follower: &mut HopFollower<'static, KEY_SIZE>,
+        usb_dev: &mut UsbDevice<'static, UsbBusType>,
+        serial: &mut SerialPort<'static, UsbBusType>,
+        led: &mut Led,
+    ) {
+        if usb_dev.poll(&mut [serial]) {
+            let mut buf = [0u8; 64];
+            if let Ok(count) = serial.read(&mut buf) {
+                // A radio that stopped answering is set up again by `supervise`
+                if relay.host_bytes(&buf[..count], radio).is_err() {
+                    relay.radio_error();
+                }
+                // Turning hopping on or off takes effect here
+                retune(radio, relay, follower);
+                if relay.surveying() {
+                    // Already running if the host asked again
+                    let _ = survey::spawn();
+                }
+                led.toggle();
+            }
         }
+        flush(relay, serial);
+    }
 
-        if !usb_device.poll(&mut [&mut serial]) {
-            continue;
+    /// Queues every payload the node sent since the last interrupt for the host
+    #[task(
+        binds = EXTI3,
+        shared = [radio, relay, follower, arq, serial, led, fault],
+        local = [irq]
+    )]
+    fn radio_irq(cx: radio_irq::Context) {
+        cx.local.irq.clear_interrupt_pending_bit();
+        let radio_irq::SharedResources {
+            radio,
+            relay,
+            follower,
+            arq,
+            serial,
+            led,
+            fault,
+        } = cx.shared;
+        let now = monotonics::now().ticks();
+        // With the `decrypt` feature blocks are decrypted here and sent as `FrameKind::Plain`
+        #[cfg(feature = "decrypt")]
+        let open = {
+            let index_key = u32::from_ne_bytes(*include_bytes!("../../private/index-key.bin"));
+            let cipher = common::MainCipher::new(&RECEIVER_KEY, index_key);
+            let (follower, arq) = (&mut *follower, &mut *arq);
+            move |block: &mut VarBlock| {
+                follower.heard(block, now);
+                arq.iter_mut().for_each(|arq| arq.heard(block));
+                block.do_cipher(&cipher);
+                FrameKind::Plain
+            }
+        };
+        // Forward the block still encrypted, the host has the keys to decrypt it
+        #[cfg(not(feature = "decrypt"))]
+        let open = |block: &mut VarBlock| {
+            follower.heard(block, now);
+            arq.iter_mut().for_each(|arq| arq.heard(block));
+            FrameKind::Block
+        };
+        if relay.poll_radio(radio, open).is_err() {
+            relay.radio_error();
         }
-        let mut buf = [0u8; 128];
-        match serial.read(&mut buf) {
-            Ok(count) if count > 0 => {
-                led.set_low(); // Turn on
-
-                // Echo back in upper case
-                for c in buf[0..count].iter_mut() {
-                    if 0x61 <= *c && *c <= 0x7a {
-                        *c &= !0x20;
-                    }
-                }
-
-                let mut write_offset = 0;
-                while write_offset < count {
-                    match serial.write(&buf[write_offset..count]) {
-                        Ok(len) if len > 0 => {
-                            write_offset += len;
-                        }
-                        _ => {}
+        retune(radio, relay, follower);
+        // Sent on the channel the node listens on after its transmission
+        if relay.config().arq_retries > 0 {
+            for (node, arq) in arq.iter_mut().enumerate() {
+                if let Some(ack) = arq.ack() {
+                    if relay.send_to(radio, node as u8, &ack).is_err() {
+                        relay.radio_error();
                     }
                 }
             }
-            _ => {}
         }
+        if fault.is_none() {
+            led.toggle();
+        }
+        flush(relay, serial);
+    }
 
-        led.set_high(); // Turn off
+    /// Shows the blink code of the fault from before the last reset, until a host is attached
+    #[task(shared = [usb_dev, led, fault], local = [step: u32 = 0])]
+    fn blink(cx: blink::Context) {
+        let blink::SharedResources {
+            usb_dev,
+            led,
+            fault,
+        } = cx.shared;
+        let class = match fault {
+            Some(class) if usb_dev.state() != UsbDeviceState::Configured => *class,
+            _ => {
+                *fault = None;
+                led.set_high();
+                return;
+            }
+        };
+        let (on, ms) = class.blink(*cx.local.step);
+        *cx.local.step += 1;
+        // The LED is on when the pin is low
+        if on {
+            led.set_low();
+        } else {
+            led.set_high();
+        }
+        blink::spawn_after((ms as u64).millis()).unwrap();
+    }
 
-        /*
-        if !nrf_chip.data_available().unwrap() {
-            // No data availble, wait 1ms, then check again
-            delay.delay_ms(1u16);
-            continue;
+    /// How often the receiver checks whether a hopping node has moved on
+    const FOLLOW_MS: u64 = 5;
+
+    /// Moves the radio to where a hopping node sends next, or back to the configured channel, and
+    /// to the data rate node 0 moved to with auto rate on
+    fn retune(radio: &mut Radio, relay: &mut Relay, follower: &mut HopFollower<'static, KEY_SIZE>) {
+        let now = monotonics::now().ticks();
+        if let Some(rate) = follower.data_rate(relay.config(), now) {
+            let setting = ReceiverSetting {
+                setting: Setting::DataRate,
+                value: rate as u32,
+            };
+            if relay.apply(radio, setting).is_err() {
+                relay.radio_error();
+            }
+        }
+        let channel = follower.channel(relay.config(), now);
+        if relay.hop_to(radio, channel).is_err() {
+            relay.radio_error();
         }
-        led.toggle();
-        // Now there is some data availble to read
+    }
 
-        let base_msg = b"This is a test message      "; 
+    /// Keeps up with a hopping node between the blocks it sends
+    #[task(shared = [radio, relay, follower, check_ins])]
+    fn follow(cx: follow::Context) {
+        let follow::SharedResources {
+            radio,
+            relay,
+            follower,
+            check_ins,
+        } = cx.shared;
+        retune(radio, relay, follower);
+        check_ins.check_in(FOLLOW, monotonics::now().ticks());
+        follow::spawn_after(FOLLOW_MS.millis()).unwrap();
+    }
 
+    /// Time between sweeps of a survey, for relaying what the node sent in the meantime
+    const SWEEP_GAP_MS: u64 = 20;
+
+    /// 200us for the received power detector to settle, at 72MHz
+    const DWELL_CYCLES: u32 = 200 * 72;
+
+    /// Runs one sweep of the survey the host asked for, about 130ms
+    #[task(shared = [radio, relay, serial])]
+    fn survey(cx: survey::Context) {
+        let survey::SharedResources {
+            radio,
+            relay,
+            serial,
+        } = cx.shared;
+        if relay
+            .survey_sweep(radio, || cortex_m::asm::delay(DWELL_CYCLES))
+            .is_err()
         {
-            let buffer = block.as_bytes_mut();
-            let len = nrf_chip.read(buffer).unwrap();
-            assert_eq!(nrf24_rs::MAX_PAYLOAD_SIZE, len as u8);
+            relay.radio_error();
         }
-        let index = block.tag().get_index();
-        //block.do_cipher(&cipher);
-        //assert_eq!(&block.as_bytes()[4..], base_msg);
-        for i in 0..7 {
-            assert_eq!(block.data()[i], i as u32);
+        if relay.surveying() {
+            survey::spawn_after(SWEEP_GAP_MS.millis()).unwrap();
+        } else {
+            flush(relay, serial);
         }
+    }
 
-        delay.delay_ms(200u16);
-        led.toggle();
-        */
+    /// Sends the counters once a second
+    #[task(shared = [relay, serial, check_ins])]
+    fn counters(cx: counters::Context) {
+        cx.shared.relay.send_counters();
+        flush(cx.shared.relay, cx.shared.serial);
+        cx.shared
+            .check_ins
+            .check_in(COUNTERS, monotonics::now().ticks());
+        counters::spawn_after(1.secs()).unwrap();
+    }
+
+    /// Feeds the watchdog while every task keeps checking in, and sets the radio up again if it
+    /// stops answering. A task stuck on the SPI bus keeps this from running at all
+    #[task(shared = [radio, check_ins], local = [watchdog])]
+    fn supervise(cx: supervise::Context) {
+        let supervise::SharedResources { radio, check_ins } = cx.shared;
+        let radio_ok = match radio.is_connected() {
+            Ok(true) => true,
+            _ => radio.reinit().is_ok(),
+        };
+        if radio_ok && check_ins.healthy(monotonics::now().ticks()) {
+            cx.local.watchdog.feed();
+        }
+        supervise::spawn_after(SUPERVISE_MS.millis()).unwrap();
     }
 }
@@ -1,11 +1,28 @@
-
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 64K
-  RAM : ORIGIN = 0x20000000, LENGTH = 20K
+  /* Needs a 128K part. The first 6K hold the bootloader, see the bootloader crate. This image
+     runs from the first slot, which is followed by the staging slot, the scratch page, the
+     update log and the 2K message index log, see src/flash.rs. The last 16 bytes of the slot
+     hold the header of the image in it */
+  FLASH : ORIGIN = 0x08001800, LENGTH = 32K - 16
+  /* The key stays put when the image is updated, so it lives outside the slots, in the last
+     53K */
+  KEY : ORIGIN = 0x08012C00, LENGTH = 53K
+  /* The last 128 bytes of RAM hold the fault record, see the fault_log crate */
+  RAM : ORIGIN = 0x20000000, LENGTH = 20K - 128
+  FAULT : ORIGIN = 0x20004F80, LENGTH = 128
 }
 
+/* Updates are built without it: objcopy -O binary -R .key */
+SECTIONS
+{
+  .key : { KEEP(*(.key .key.*)); } > KEY
+} INSERT AFTER .rodata;
+
+_fault_start = ORIGIN(FAULT);
+_fault_end = ORIGIN(FAULT) + LENGTH(FAULT);
+
 /* This is where the call stack will be allocated. */
 /* The stack is of the full descending type. */
 /* NOTE Do NOT modify `_stack_start` unless you know what you are doing */
@@ -0,0 +1,163 @@
+//! The pages of the STM32F103's flash that memory.x leaves out of the image: the slots firmware
+//! updates are written to, and the message index log that follows them
+
+use common::{Flash, BOOT_LOG_PAGES};
+use stm32f1xx_hal::flash::{Error, FlashSize, FlashWriter, Parts, SectorSize};
+use stm32f1xx_hal::pac::{flash::RegisterBlock, FLASH};
+
+/// Must match the space memory.x leaves out of FLASH
+const LOG_PAGES: usize = 2;
+const PAGE_SIZE: usize = 1024;
+/// Right before the key, see memory.x
+const LOG_START: usize = 0x1_2C00 - LOG_PAGES * PAGE_SIZE;
+
+pub struct LogFlash<'a> {
+    writer: FlashWriter<'a>,
+}
+
+impl<'a> LogFlash<'a> {
+    pub fn new(parts: &'a mut Parts) -> Self {
+        Self {
+            writer: parts.writer(SectorSize::Sz1K, FlashSize::Sz128K),
+        }
+    }
+
+    fn offset(page: usize, word: usize) -> u32 {
+        (LOG_START + page * PAGE_SIZE + word * 4) as u32
+    }
+}
+
+impl Flash for LogFlash<'_> {
+    type Error = Error;
+
+    fn pages(&self) -> usize {
+        LOG_PAGES
+    }
+
+    fn page_words(&self) -> usize {
+        PAGE_SIZE / 4
+    }
+
+    fn read(&self, page: usize, word: usize) -> u32 {
+        // Reading only fails outside of the flash
+        let bytes = self.writer.read(Self::offset(page, word), 4).unwrap();
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    fn write(&mut self, page: usize, word: usize, value: u32) -> Result<(), Error> {
+        // Written a half word at a time, the log checks for words cut short
+        self.writer
+            .write(Self::offset(page, word), &value.to_le_bytes())
+    }
+
+    fn erase(&mut self, page: usize) -> Result<(), Error> {
+        self.writer.erase(Self::offset(page, 0), PAGE_SIZE)
+    }
+}
+
+/// Where the running image's slot starts, after the bootloader. Must match memory.x and the
+/// bootloader's
+const SLOTS_START: usize = 0x0800_1800;
+/// The two slots, the scratch page and the update log, which end where the index log starts
+const SLOT_PAGES: usize = 32;
+const OTA_PAGES: usize = 2 * SLOT_PAGES + 1 + BOOT_LOG_PAGES;
+const _: () = assert!(SLOTS_START + OTA_PAGES * PAGE_SIZE == 0x0800_0000 + LOG_START);
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+/// EOP, WRPRTERR and PGERR, which are cleared by writing ones
+const SR_FLAGS: u32 = 0x34;
+const SR_ERRORS: u32 = 0x14;
+
+/// The firmware update slots, see [`common::OtaReceiver`]. Written through the registers, since
+/// the HAL's writer stays borrowed by the [`LogFlash`]
+pub struct OtaFlash {
+    _private: (),
+}
+
+impl OtaFlash {
+    /// # Safety
+    /// Only one may exist. The [`LogFlash`] may too, since neither leaves the flash unlocked
+    /// between calls and tasks do not interrupt each other
+    pub unsafe fn new() -> Self {
+        Self { _private: () }
+    }
+
+    fn address(page: usize, word: usize) -> usize {
+        SLOTS_START + page * PAGE_SIZE + word * 4
+    }
+
+    fn regs() -> &'static RegisterBlock {
+        // SAFETY: See `new`
+        unsafe { &*FLASH::ptr() }
+    }
+
+    /// Runs `op` with the flash unlocked and returns the error flags it left
+    fn unlocked(op: impl FnOnce(&RegisterBlock)) -> Result<u32, Error> {
+        let regs = Self::regs();
+        while regs.sr.read().bsy().bit_is_set() {}
+        // SAFETY: The keys only unlock the control register, in this order
+        regs.keyr.write(|w| unsafe { w.bits(KEY1) });
+        regs.keyr.write(|w| unsafe { w.bits(KEY2) });
+        if regs.cr.read().lock().bit_is_set() {
+            return Err(Error::UnlockError);
+        }
+        regs.sr.write(|w| unsafe { w.bits(SR_FLAGS) });
+        op(regs);
+        while regs.sr.read().bsy().bit_is_set() {}
+        let errors = regs.sr.read().bits() & SR_ERRORS;
+        regs.sr.write(|w| unsafe { w.bits(SR_FLAGS) });
+        regs.cr
+            .modify(|_, w| w.pg().clear_bit().per().clear_bit().lock().set_bit());
+        Ok(errors)
+    }
+}
+
+impl Flash for OtaFlash {
+    type Error = Error;
+
+    fn pages(&self) -> usize {
+        OTA_PAGES
+    }
+
+    fn page_words(&self) -> usize {
+        PAGE_SIZE / 4
+    }
+
+    fn read(&self, page: usize, word: usize) -> u32 {
+        // SAFETY: Inside the flash, which reads like memory
+        unsafe { core::ptr::read_volatile(Self::address(page, word) as *const u32) }
+    }
+
+    fn write(&mut self, page: usize, word: usize, value: u32) -> Result<(), Error> {
+        let address = Self::address(page, word);
+        // Programmed a half word at a time
+        for (i, half) in [value as u16, (value >> 16) as u16].into_iter().enumerate() {
+            let errors = Self::unlocked(|regs| {
+                regs.cr.modify(|_, w| w.pg().set_bit());
+                // SAFETY: A half word of our pages, which programming mode lets us write
+                unsafe { core::ptr::write_volatile((address + i * 2) as *mut u16, half) };
+            })?;
+            if errors != 0 {
+                return Err(Error::ProgrammingError);
+            }
+        }
+        if self.read(page, word) != value {
+            return Err(Error::VerifyError);
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, page: usize) -> Result<(), Error> {
+        let errors = Self::unlocked(|regs| {
+            regs.cr.modify(|_, w| w.per().set_bit());
+            regs.ar
+                .write(|w| unsafe { w.bits(Self::address(page, 0) as u32) });
+            regs.cr.modify(|_, w| w.strt().set_bit());
+        })?;
+        if errors != 0 || (0..PAGE_SIZE / 4).any(|word| self.read(page, word) != u32::MAX) {
+            return Err(Error::EraseError);
+        }
+        Ok(())
+    }
+}
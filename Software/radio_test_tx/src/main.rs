@@ -2,149 +2,517 @@
 #![no_main]
 #![feature(bench_black_box)]
 
-use core::panic::PanicInfo;
-use cortex_m_rt::entry;
+use embedded_hal::blocking::delay::{DelayMs, DelayUs};
 use hal::{
-    device::gpioa::CRH,
-    gpio::{Output, Pin, PushPull},
+    gpio::{
+        gpioa::{PA0, PA3, PA4, PA5, PA6, PA7},
+        gpiob::PB3,
+        Alternate, Analog, Floating, Input, Output, Pin, PullUp, PushPull, CRH,
+    },
     prelude::*,
 };
 use stm32f1xx_hal as hal;
 
-static mut PANIC_LED: *mut Pin<Output<PushPull>, CRH, 'C', 13> = core::ptr::null_mut();
-
-#[panic_handler]
-fn panic_handler(_: &PanicInfo) -> ! {
-    // We assume that `PANIC_LED` has been set
-    let led = unsafe { PANIC_LED };
-    if led.is_null() {
-        loop {}
-    } else {
-        // SAFETY: led is non null, so it must have been initialized
-        let led = unsafe { &mut *PANIC_LED };
+mod flash;
+
+type Spi1 = hal::spi::Spi<
+    hal::pac::SPI1,
+    hal::spi::Spi1NoRemap,
+    (
+        PA5<Alternate<PushPull>>,
+        PA6<Input<Floating>>,
+        PA7<Alternate<PushPull>>,
+    ),
+    u8,
+>;
+type Radio = nrf24_radio::Nrf24<Spi1, PA3<Output<PushPull>>, PA4<Output<PushPull>>, AsmDelay>;
+type Led = Pin<Output<PushPull>, CRH, 'C', 13>;
+
+/// This node's id, set with `NODE_ID=2 cargo build`. Every node sending to the same receiver
+/// needs its own, below [`common::MAX_NODES`]
+const NODE_ID: u8 = match option_env!("NODE_ID") {
+    Some(id) => id.as_bytes()[0] - b'0',
+    None => 0,
+};
+const _: () = assert!((NODE_ID as usize) < common::MAX_NODES);
+
+/// The data rate to start on, set with `DATA_RATE=250k cargo build`. The node and receiver have
+/// to be built with the same one. The desktop tool can change it later, or turn on auto rate
+const DATA_RATE: common::DataRate = match option_env!("DATA_RATE") {
+    Some(name) => match common::DataRate::from_name(name) {
+        Some(rate) => rate,
+        None => panic!("DATA_RATE must be 250k, 1m or 2m"),
+    },
+    None => common::NodeConfig::DEFAULT.data_rate,
+};
+
+/// The battery reaches PA0 through a divider that halves it, keeping a full cell under the 3.3V
+/// the ADC can measure
+const BATTERY_DIVIDER: u32 = 2;
+
+/// Placed outside the image's slot by memory.x, so that firmware updates do not carry it
+#[link_section = ".key"]
+static NODE_KEY: common::Key<{ common::KEY_SIZE }> = common::KEY;
+
+/// Busy waits on the core clock. SysTick belongs to RTIC for scheduling tasks
+#[derive(Clone, Copy)]
+pub struct AsmDelay {
+    cycles_per_us: u32,
+}
+
+macro_rules! impl_delay {
+    ($($ty:ty),*) => {$(
+        impl DelayUs<$ty> for AsmDelay {
+            fn delay_us(&mut self, us: $ty) {
+                cortex_m::asm::delay((us as u32).saturating_mul(self.cycles_per_us));
+            }
+        }
+
+        impl DelayMs<$ty> for AsmDelay {
+            fn delay_ms(&mut self, ms: $ty) {
+                for _ in 0..ms {
+                    self.delay_us(1000u32);
+                }
+            }
+        }
+    )*};
+}
+impl_delay!(u8, u16, u32);
+
+/// STOP mode turns the PLL and HSE off and wakes on the HSI, so they are turned back on and the
+/// core moved back to the PLL, with the same settings `init` froze
+fn restore_clocks() {
+    // SAFETY: Only `init` and this change the clock enables and the switch, and init has returned
+    let rcc = unsafe { &*hal::pac::RCC::ptr() };
+    rcc.cr.modify(|_, w| w.hseon().set_bit());
+    while rcc.cr.read().hserdy().bit_is_clear() {}
+    rcc.cr.modify(|_, w| w.pllon().set_bit());
+    while rcc.cr.read().pllrdy().bit_is_clear() {}
+    rcc.cfgr.modify(|_, w| w.sw().pll());
+    while !rcc.cfgr.read().sws().is_pll() {}
+}
+
+#[rtic::app(device = stm32f1xx_hal::pac, peripherals = true, dispatchers = [SPI2])]
+mod app {
+    use super::*;
+    use crate::flash::{LogFlash, OtaFlash};
+    use common::{
+        host_address, node_address, CheckIns, FaultClass, Node, NodeConfig, OtaReceiver, OtaState,
+        PersistentError, PersistentIndex, ResetReason, ResetReport, Telemetry, KEY_SIZE,
+        MAX_MESSAGE,
+    };
+    use cortex_m::peripheral::SCB;
+    use hal::adc::Adc;
+    use hal::gpio::{Edge, ExtiPin};
+    use hal::pac::{ADC1, PWR};
+    use hal::rtc::Rtc;
+    use hal::watchdog::IndependentWatchdog;
+    use systick_monotonic::{fugit::ExtU64, Systick};
+
+    #[monotonic(binds = SysTick, default = true)]
+    type Mono = Systick<1000>;
+
+    /// How long the watchdog waits to be fed before resetting us
+    const WATCHDOG_MS: u32 = 2000;
+    /// How often `supervise` checks on the tasks and the radio
+    const SUPERVISE_MS: u64 = 250;
+    /// The longest `idle` stays in STOP mode at once, so that it can feed the watchdog in between
+    const STOP_CHUNK_MS: u32 = 1000;
+
+    /// The tasks that check in, see `check_ins`
+    const TRANSMIT: usize = 0;
+    const TASKS: usize = 1;
+
+    /// How long `transmit` may go between runs. It runs every transmit interval, more often while
+    /// blocks go unacknowledged
+    fn transmit_timeout(config: &NodeConfig) -> u64 {
+        config.tx_interval_ms as u64 + 1000
+    }
+
+    // Every task runs at the same priority, so none of them can interrupt another
+    #[shared]
+    struct Shared {
+        #[lock_free]
+        radio: Radio,
+        #[lock_free]
+        node: Node<'static, KEY_SIZE>,
+        #[lock_free]
+        led: Led,
+        /// Set after a panic or HardFault until the receiver has the report
+        #[lock_free]
+        fault: Option<FaultClass>,
+        /// The watchdog is only fed while these keep coming in
+        #[lock_free]
+        check_ins: CheckIns<TASKS>,
+        /// Writes firmware updates from the host to the staging slot
+        #[lock_free]
+        ota: OtaReceiver<OtaFlash>,
+        /// Shared with `idle`, which feeds it while the tasks stand still in STOP mode
+        watchdog: IndependentWatchdog,
+        /// Set by `power_down` to how long `idle` should sleep for
+        sleep: Option<u32>,
+    }
+
+    #[local]
+    struct Local {
+        irq: PB3<Input<PullUp>>,
+        index: PersistentIndex<LogFlash<'static>>,
+        adc: Adc<ADC1>,
+        battery: PA0<Analog>,
+        rtc: Rtc,
+        pwr: PWR,
+        scb: SCB,
+    }
+
+    #[init(local = [flash: Option<hal::flash::Parts> = None])]
+    fn init(cx: init::Context) -> (Shared, Local, init::Monotonics) {
+        let dp = cx.device;
+        let flash = cx.local.flash.insert(dp.FLASH.constrain());
+        let mut afio = dp.AFIO.constrain();
+
+        // Cleared once read, so that the next boot only sees its own reset
+        let reason = ResetReason::from_csr(dp.RCC.csr.read().bits());
+        dp.RCC.csr.modify(|_, w| w.rmvf().set_bit());
+        let rcc = dp.RCC.constrain();
+        let mut pwr = dp.PWR;
+        let mut backup_domain = rcc.bkp.constrain(dp.BKP, &mut pwr);
+        let clocks = rcc
+            .cfgr
+            .use_hse(8.mhz())
+            .sysclk(72.mhz())
+            .hclk(72.mhz())
+            .freeze(&mut flash.acr);
+        let mono = Systick::new(cx.core.SYST, clocks.sysclk().0);
+        let delay = AsmDelay {
+            cycles_per_us: clocks.sysclk().0 / 1_000_000,
+        };
+
+        // Initialize the different pins
+        let mut gpioa = dp.GPIOA.split();
+        let mut gpiob = dp.GPIOB.split();
+        let mut gpioc = dp.GPIOC.split();
+
+        let mut led = gpioc.pc13.into_push_pull_output(&mut gpioc.crh);
+        led.set_high();
+
+        let adc = Adc::adc1(dp.ADC1, clocks);
+        let battery = gpioa.pa0.into_analog(&mut gpioa.crl);
+
+        // Wakes `idle` from STOP mode. It runs from the 32.768kHz crystal, which keeps going in STOP
+        let mut rtc = Rtc::new(dp.RTC, &mut backup_domain);
+        rtc.select_frequency(1.khz());
+        rtc.listen_alarm();
+        // The alarm reaches the core as an event on EXTI line 17, so no interrupt handler runs
+        dp.EXTI.emr.modify(|_, w| w.mr17().set_bit());
+        dp.EXTI.rtsr.modify(|_, w| w.tr17().set_bit());
+
+        let cs = gpioa.pa4.into_push_pull_output(&mut gpioa.crl);
+        let ce = gpioa.pa3.into_push_pull_output(&mut gpioa.crl);
+
+        // The nRF24 pulls IRQ low when a payload arrives. PB3 is a JTAG pin until JTAG is turned
+        // off, SWD still works
+        let (_pa15, pb3, _pb4) = afio.mapr.disable_jtag(gpioa.pa15, gpiob.pb3, gpiob.pb4);
+        let mut irq = pb3.into_pull_up_input(&mut gpiob.crl);
+        irq.make_interrupt_source(&mut afio);
+        irq.trigger_on_edge(&dp.EXTI, Edge::Falling);
+        irq.enable_interrupt(&dp.EXTI);
+
+        let mosi = gpioa.pa7.into_alternate_push_pull(&mut gpioa.crl);
+        let miso = gpioa.pa6;
+        let sclk = gpioa.pa5.into_alternate_push_pull(&mut gpioa.crl);
+
+        let spi = hal::spi::Spi::spi1(
+            dp.SPI1,
+            (sclk, miso, mosi),
+            &mut afio.mapr,
+            nrf24_radio::SPI_MODE,
+            1.mhz(),
+            clocks,
+        );
+
+        // We also need an index key that is used to encrypt the index when sent in the clear
+        let index_key = u32::from_ne_bytes(*include_bytes!("../../private/index-key.bin"));
+        // Control messages from the host are only applied if they were signed with this
+        let auth_key = *include_bytes!("../../private/auth-key.bin");
+        let mut node = Node::new(&NODE_KEY, index_key, auth_key, NODE_ID);
+        node.start_on(NodeConfig {
+            data_rate: DATA_RATE,
+            ..NodeConfig::DEFAULT
+        });
+        // Carries on from where the pads were before the reboot, at most 64 sequence numbers ahead
+        // and without skipping any key words
+        let index = PersistentIndex::open(LogFlash::new(flash), 64);
+        // SAFETY: The only one, and the index log's writer never leaves the flash unlocked
+        let ota = OtaReceiver::new(unsafe { OtaFlash::new() }, auth_key);
+
+        // Why we were reset goes up in place of the first heartbeats. After a fault the LED shows
+        // its blink code until the receiver has it
+        node.report_reset(&ResetReport {
+            reason,
+            counts: fault_log::count_reset(reason),
+        });
+        let fault = fault_log::take();
+        if let Some(fault) = &fault {
+            node.report_fault(fault);
+            blink::spawn().unwrap();
+        }
+        let fault = fault.map(|fault| fault.class());
+
+        // We send to our node address, where the receiver listens for us, and the receiver sends
+        // blocks from the host to our host address
+        let (to, from) = (node_address(NODE_ID), host_address(NODE_ID));
+        let radio = Radio::new(spi, ce, cs, delay, node.config(), &to, &[from]).unwrap();
+
+        // Resets us unless `supervise` feeds it, which it only does while every task checks in
+        let mut watchdog = IndependentWatchdog::new(dp.IWDG);
+        watchdog.start(WATCHDOG_MS.ms());
+        let check_ins = CheckIns::new([transmit_timeout(node.config())], 0);
+
+        transmit::spawn().unwrap();
+        supervise::spawn_after(SUPERVISE_MS.millis()).unwrap();
+        (
+            Shared {
+                radio,
+                node,
+                led,
+                fault,
+                check_ins,
+                ota,
+                watchdog,
+                sleep: None,
+            },
+            Local {
+                irq,
+                index,
+                adc,
+                battery,
+                rtc,
+                pwr,
+                scb: cx.core.SCB,
+            },
+            init::Monotonics(mono),
+        )
+    }
+
+    /// Sleeps in STOP mode when `power_down` asks it to, then wakes the radio for the next
+    /// transmission
+    #[idle(shared = [sleep, watchdog], local = [rtc, pwr, scb])]
+    fn idle(cx: idle::Context) -> ! {
+        let idle::SharedResources {
+            mut sleep,
+            mut watchdog,
+        } = cx.shared;
+        let idle::LocalResources { rtc, pwr, scb } = cx.local;
         loop {
-            for _ in 0..1000 {
-                cortex_m::asm::delay(70_200_000);
-                // 72_000_000 / 7_200_000 ~= 10Hz
+            let mut left = match sleep.lock(|sleep| sleep.take()) {
+                Some(ms) => ms,
+                None => {
+                    cortex_m::asm::wfi();
+                    continue;
+                }
+            };
+            // SysTick stops along with the core clock, so the tasks waiting on it stand still and
+            // none of them can check in. The watchdog's clock keeps running
+            while left > 0 {
+                let ms = left.min(STOP_CHUNK_MS);
+                stop(rtc, pwr, scb, ms);
+                watchdog.lock(|watchdog| watchdog.feed());
+                left -= ms;
             }
-            led.toggle();
+            wake::spawn().unwrap();
         }
     }
-}
 
-#[entry]
-fn main() -> ! {
-    let dp = hal::pac::Peripherals::take().unwrap();
-    let core = cortex_m::Peripherals::take().unwrap();
-    let mut flash = dp.FLASH.constrain();
-    let mut afio = dp.AFIO.constrain();
-
-    let rcc = dp.RCC.constrain();
-    let clocks = rcc
-        .cfgr
-        .use_hse(8.mhz())
-        .sysclk(72.mhz())
-        .hclk(72.mhz())
-        .freeze(&mut flash.acr);
-
-    // Initialize the different pins
-    let mut gpioa = dp.GPIOA.split();
-    let mut gpioc = dp.GPIOC.split();
-
-    let mut led = gpioc.pc13.into_push_pull_output(&mut gpioc.crh);
-    led.set_high();
-
-    // So so unsafe. I'm not gonna try to make a safety argument here because so many things are wrong
-    // This works on the current compiler to allow us to easily control the LED from the panic
-    // handler
-    let led_ptr: *mut Pin<Output<PushPull>, CRH, 'C', 13> =
-        unsafe { core::mem::transmute(&mut led) };
-    unsafe { PANIC_LED = led_ptr };
-
-    let cs = gpioa.pa4.into_push_pull_output(&mut gpioa.crl);
-    //let mut data_int = gpiob.pb3.into_pull_up_input();
-    //data_int.make_interrupt_source(&mut syscfg);
-    //data_int.enable_interrupt(&mut device.EXTI);
-    //data_int.trigger_on_edge(&mut device.EXTI, Edge::Falling);
-
-    let ce = gpioa.pa3.into_push_pull_output(&mut gpioa.crl);
-
-    let mosi = gpioa.pa7.into_alternate_push_pull(&mut gpioa.crl);
-    let miso = gpioa.pa6;
-    let sclk = gpioa.pa5.into_alternate_push_pull(&mut gpioa.crl);
-
-    let spi = hal::spi::Spi::spi1(
-        dp.SPI1,
-        (sclk, miso, mosi),
-        &mut afio.mapr,
-        nrf24_rs::SPI_MODE,
-        1.mhz(),
-        clocks,
-    );
-
-    let mut delay = hal::delay::Delay::new(core.SYST, clocks);
-
-    let message = b"Hello world! I need to fill all the bytes please!"; // The message we will be sending
-    let message = &message[..nrf24_rs::MAX_PAYLOAD_SIZE as usize];
-
-
-    use common::{IndexedBlock, MainCipher, KEY, Tag};
-
-    // We also need an index key that is used to encrypt the index when sent in the clear
-    let index_key: [u8; 4] = *include_bytes!("../../private/index-key.bin");
-    let index_key = u32::from_ne_bytes(index_key);
-    let cipher = MainCipher::new(&KEY, index_key);
-     
-    let mut block = IndexedBlock::new();
-     
-    // Setup some configuration values
-    let config = nrf24_rs::config::NrfConfig::default()
-        .channel(8)
-        .pa_level(nrf24_rs::config::PALevel::Max)
-        // We will use a payload size the size of our message
-        .payload_size(nrf24_rs::MAX_PAYLOAD_SIZE);
-
-    // Initialize the chip
-    let mut nrf_chip = nrf24_rs::Nrf24l01::new(spi, ce, cs, &mut delay, config).unwrap();
-    if !nrf_chip.is_connected().unwrap() {
-        panic!("Chip is not connected.");
+    /// Stops the core for `ms`, until the RTC alarm
+    fn stop(rtc: &mut Rtc, pwr: &mut PWR, scb: &mut SCB, ms: u32) {
+        rtc.set_time(0);
+        rtc.set_alarm(ms);
+        rtc.clear_alarm_flag();
+        // STOP rather than STANDBY, with the regulator in low power mode
+        pwr.cr.modify(|_, w| w.pdds().clear_bit().lpds().set_bit());
+        scb.set_sleepdeep();
+        // The first WFE clears the event register, which may already be set
+        cortex_m::asm::sev();
+        cortex_m::asm::wfe();
+        cortex_m::asm::wfe();
+        scb.clear_sleepdeep();
+        restore_clocks();
+        rtc.clear_alarm_flag();
     }
 
-    // Open a writing pipe on address "Node1".
-    // The listener will have to open a reading pipe with the same address
-    // in order to receive this message.
-    nrf_chip.open_writing_pipe(b"Node1").unwrap();
-
-    let mut index = 0;
-    // Message should now successfully have been sent!
-    loop {
-
-        let bytes = block.as_bytes_mut();
-
-        //Write message
-        // let base_msg = b"This is a test message      ";
-        // for (i, b) in base_msg.iter().enumerate() {
-        //     bytes[i] = *b;
-        // }
-        //Write index
-        for i in 0..7 {
-            block.data_mut()[i] = i as u32;
+    /// Ends the listen window after a transmission in low power mode. `idle` sleeps for `sleep_ms`.
+    /// SPI failures are counted in `errors` as in `radio_irq`, and it sleeps anyway
+    #[task(shared = [radio, sleep], local = [errors: u32 = 0])]
+    fn power_down(mut cx: power_down::Context, sleep_ms: u32) {
+        let failed = cx.shared.radio.power_down().is_err();
+        *cx.local.errors = cx.local.errors.wrapping_add(failed as u32);
+        cx.shared.sleep.lock(|sleep| *sleep = Some(sleep_ms));
+    }
+
+    /// A radio that fails to power up is counted in `errors` and left to `supervise`, which sets
+    /// it up again
+    #[task(shared = [radio], local = [errors: u32 = 0])]
+    fn wake(cx: wake::Context) {
+        let failed = cx.shared.radio.power_up().is_err();
+        *cx.local.errors = cx.local.errors.wrapping_add(failed as u32);
+        transmit::spawn().unwrap();
+    }
+
+    /// Sends the node's next block and schedules the one after it. Radio and index log failures
+    /// are counted in `errors` and the block is tried again: `supervise` sets a radio that stopped
+    /// answering up again
+    #[task(
+        shared = [radio, node, led, fault, check_ins, ota],
+        local = [index, adc, battery, errors: u32 = 0]
+    )]
+    fn transmit(cx: transmit::Context) {
+        let transmit::SharedResources {
+            radio,
+            node,
+            led,
+            fault,
+            check_ins,
+            ota,
+        } = cx.shared;
+        let transmit::LocalResources {
+            index,
+            adc,
+            battery,
+            errors,
+        } = cx.local;
+        let now = monotonics::now().ticks();
+        let mut failed = false;
+        let sent = node.transmit(radio, now, |words| {
+            let position = index.next(words);
+            failed = matches!(position, Err(PersistentError::Flash(_)));
+            position
+        });
+        let sent = match sent {
+            Ok(sent) => sent,
+            Err(_) => {
+                failed = true;
+                false
+            }
+        };
+        *errors = errors.wrapping_add(failed as u32);
+        check_ins.check_in(TRANSMIT, now);
+        if node.exhausted() {
+            // Every pad has been used, so the node goes quiet for good with the LED on until it
+            // is flashed with a new key. The watchdog stops waiting for it
+            check_ins.set_timeout(TRANSMIT, u64::MAX);
+            led.set_low();
+            return;
+        }
+        // The host may have changed the interval
+        check_ins.set_timeout(TRANSMIT, transmit_timeout(node.config()));
+        if !sent {
+            // Not acknowledged, try again in 50ms
+            transmit::spawn_after(50.millis()).unwrap();
+            return;
+        }
+        // An image that gets as far as a transmission keeps itself over the one before it
+        ota.confirm().unwrap();
+        // The bootloader installs a new image once the host knows it arrived
+        if ota.status().state == OtaState::Ready && !node.has_pending() {
+            SCB::sys_reset();
         }
-        block.tag().set_index(index);
-        index += 1;
 
-        block.do_cipher(&cipher);
-        let message = block.as_bytes();
-        assert_eq!(message.len() as u8, nrf24_rs::MAX_PAYLOAD_SIZE);
+        let config = *node.config();
+        if fault.is_some() {
+            if !node.has_pending() {
+                // The receiver has the report
+                *fault = None;
+            }
+        } else if !config.low_power {
+            led.set_low();
+            led_off::spawn_after(200.millis()).unwrap();
+        }
+        // Measured now so that it goes up with one of the next transmissions
+        if node.telemetry_due(config.tx_interval_ms) {
+            let battery: u16 = adc.read(battery).unwrap();
+            let telemetry = Telemetry::from_adc(
+                battery,
+                adc.read_vref(),
+                BATTERY_DIVIDER,
+                adc.read_temp() as i8,
+            );
+            node.report_telemetry(&telemetry);
+        }
+        match config.sleep_ms() {
+            Some(sleep_ms) => {
+                power_down::spawn_after((config.listen_ms as u64).millis(), sleep_ms).unwrap();
+            }
+            None => {
+                transmit::spawn_after((config.tx_interval_ms as u64).millis()).unwrap();
+            }
+        }
+    }
+
+    #[task(shared = [led])]
+    fn led_off(cx: led_off::Context) {
+        cx.shared.led.set_high();
+    }
 
-        // Keep trying to send the message
-        while nrf_chip.write(&mut delay, message).is_err() {
-            // Something went wrong while writing, try again in 50ms
-            delay.delay_ms(50u16);
+    /// Shows the blink code of the fault from before the last reset, until it has been reported
+    #[task(shared = [led, fault], local = [step: u32 = 0])]
+    fn blink(cx: blink::Context) {
+        let blink::SharedResources { led, fault } = cx.shared;
+        let class = match fault {
+            Some(class) => *class,
+            None => {
+                led.set_high();
+                return;
+            }
+        };
+        let (on, ms) = class.blink(*cx.local.step);
+        *cx.local.step += 1;
+        // The LED is on when the pin is low
+        if on {
+            led.set_low();
+        } else {
+            led.set_high();
+        }
+        blink::spawn_after((ms as u64).millis()).unwrap();
+    }
+
+    /// Feeds the watchdog while every task keeps checking in, and sets the radio up again if it
+    /// stops answering. A task stuck on the SPI bus keeps this from running at all
+    #[task(shared = [radio, check_ins, watchdog])]
+    fn supervise(cx: supervise::Context) {
+        let supervise::SharedResources {
+            radio,
+            check_ins,
+            mut watchdog,
+        } = cx.shared;
+        let radio_ok = match radio.is_connected() {
+            Ok(true) => true,
+            _ => radio.reinit().is_ok(),
+        };
+        if radio_ok && check_ins.healthy(monotonics::now().ticks()) {
+            watchdog.lock(|watchdog| watchdog.feed());
+        }
+        supervise::spawn_after(SUPERVISE_MS.millis()).unwrap();
+    }
+
+    /// Handles every payload the host sent since the last interrupt. Failures are only counted
+    /// in `errors`, for a debugger to read: `supervise` sets a radio that stopped answering up
+    /// again, and the host sends an update message again when its status does not come back
+    #[task(binds = EXTI3, shared = [radio, node, ota], local = [irq, errors: u32 = 0])]
+    fn radio_irq(cx: radio_irq::Context) {
+        cx.local.irq.clear_interrupt_pending_bit();
+        let radio_irq::SharedResources { radio, node, ota } = cx.shared;
+        let errors = cx.local.errors;
+        let mut count = |failed: bool| *errors = errors.wrapping_add(failed as u32);
+        count(node.poll(radio).is_err());
+        // The node holds one update message at a time, the rest wait in the radio
+        let mut message = [0u8; MAX_MESSAGE];
+        while let Some(len) = node.take_ota(&mut message) {
+            match ota.receive(&message[..len]) {
+                Ok(Some(status)) => node.report_ota(&status),
+                Ok(None) => {}
+                Err(_) => count(true),
+            }
+            count(node.poll(radio).is_err());
         }
-        led.toggle();
-        delay.delay_ms(200u16);
-        led.toggle();
-        delay.delay_ms(800u16);
     }
 }
@@ -0,0 +1,287 @@
+#![cfg_attr(not(test), no_std)]
+//! Works out how to make the camera's XCLK with the STM32F4's MCO1 output, which is one of the
+//! clock sources divided by 1 to 5. Kept out of the firmware so that it can be tested on the host.
+//!
+//! Also parses the line the host sends to change it, `xclk` to ask for the current setting and
+//! `xclk <Hz>` to pick a new one.
+
+use core::fmt;
+
+/// The fastest MCO1 can drive PA8, with the pin at its highest speed
+pub const MAX_HZ: u32 = 100_000_000;
+
+/// The largest MCO1 prescaler
+pub const MAX_PRESCALER: u8 = 5;
+
+/// What MCO1 divides down. LSE can be picked too, but is far too slow for a camera
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// The internal 16 MHz RC oscillator
+    Hsi,
+    /// The crystal
+    Hse,
+    /// The main PLL's P output, the system clock when that runs off the PLL
+    Pll,
+}
+
+impl Source {
+    /// In order of preference when two give the same frequency. The crystal is the most exact
+    /// and does not change with the system clock, the RC oscillator is only good to 1%
+    const ALL: [Source; 3] = [Source::Hse, Source::Pll, Source::Hsi];
+
+    /// The value of RCC_CFGR's MCO1 field
+    pub fn bits(self) -> u32 {
+        match self {
+            Source::Hsi => 0b00,
+            Source::Hse => 0b10,
+            Source::Pll => 0b11,
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Source::Hsi => "HSI",
+            Source::Hse => "HSE",
+            Source::Pll => "PLL",
+        })
+    }
+}
+
+/// How fast each source runs, in Hz. 0 for one that is not running
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sources {
+    pub hsi: u32,
+    pub hse: u32,
+    pub pll: u32,
+}
+
+impl Sources {
+    /// Always the same on the F4
+    pub const HSI_HZ: u32 = 16_000_000;
+
+    pub fn get(&self, source: Source) -> u32 {
+        match source {
+            Source::Hsi => self.hsi,
+            Source::Hse => self.hse,
+            Source::Pll => self.pll,
+        }
+    }
+
+    /// The setting closest to `target` Hz, or `None` if no source is running
+    pub fn choose(&self, target: u32) -> Option<Setting> {
+        let mut best: Option<Setting> = None;
+        for source in Source::ALL {
+            let source_hz = self.get(source);
+            if source_hz == 0 {
+                continue;
+            }
+            for prescaler in 1..=MAX_PRESCALER {
+                let hz = source_hz / prescaler as u32;
+                if hz > MAX_HZ {
+                    continue;
+                }
+                let setting = Setting {
+                    source,
+                    prescaler,
+                    hz,
+                };
+                // Only strictly better, so that ties go to the preferred source
+                match best {
+                    Some(best) if best.error(target) <= setting.error(target) => {}
+                    _ => best = Some(setting),
+                }
+            }
+        }
+        best
+    }
+}
+
+/// A source and prescaler, and the frequency they give
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Setting {
+    pub source: Source,
+    pub prescaler: u8,
+    /// Rounded down if the source does not divide evenly
+    pub hz: u32,
+}
+
+impl Setting {
+    /// The value of RCC_CFGR's MCO1PRE field
+    pub fn prescaler_bits(&self) -> u32 {
+        match self.prescaler {
+            1 => 0b000,
+            prescaler => 0b100 | (prescaler as u32 - 2),
+        }
+    }
+
+    /// How far off `target` Hz this is
+    pub fn error(&self, target: u32) -> u32 {
+        self.hz.abs_diff(target)
+    }
+}
+
+/// The reply to the host, ex. `xclk 24000000 Hz from HSE/1`
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "xclk {} Hz from {}/{}",
+            self.hz, self.source, self.prescaler
+        )
+    }
+}
+
+/// A line from the host
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Report the current setting
+    Get,
+    /// Get as close to this many Hz as possible
+    Set(u32),
+}
+
+impl Command {
+    /// `None` for anything that is not an `xclk` command
+    pub fn parse(line: &str) -> Option<Command> {
+        let mut words = line.split_whitespace();
+        if words.next()? != "xclk" {
+            return None;
+        }
+        let command = match words.next() {
+            None => Command::Get,
+            Some(hz) => Command::Set(hz.parse().ok()?),
+        };
+        match words.next() {
+            None => Some(command),
+            Some(_) => None,
+        }
+    }
+}
+
+/// The line to send, without the newline
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Get => f.write_str("xclk"),
+            Command::Set(hz) => write!(f, "xclk {}", hz),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The camera firmware's clocks, a 16 MHz crystal and the PLL at 168 MHz
+    const CAMERA: Sources = Sources {
+        hsi: Sources::HSI_HZ,
+        hse: 16_000_000,
+        pll: 168_000_000,
+    };
+
+    fn setting(source: Source, prescaler: u8, hz: u32) -> Option<Setting> {
+        Some(Setting {
+            source,
+            prescaler,
+            hz,
+        })
+    }
+
+    #[test]
+    fn exact() {
+        assert_eq!(
+            CAMERA.choose(42_000_000),
+            setting(Source::Pll, 4, 42_000_000)
+        );
+        assert_eq!(
+            CAMERA.choose(56_000_000),
+            setting(Source::Pll, 3, 56_000_000)
+        );
+        // The crystal wins over HSI, which gives the same
+        assert_eq!(CAMERA.choose(8_000_000), setting(Source::Hse, 2, 8_000_000));
+    }
+
+    #[test]
+    fn closest() {
+        // 16 MHz is 8 MHz off, 33.6 MHz is 9.6 MHz off
+        assert_eq!(
+            CAMERA.choose(24_000_000),
+            setting(Source::Hse, 1, 16_000_000)
+        );
+        assert_eq!(
+            CAMERA.choose(30_000_000),
+            setting(Source::Pll, 5, 33_600_000)
+        );
+        assert_eq!(CAMERA.choose(0), setting(Source::Hse, 5, 3_200_000));
+        // The PLL undivided is past MAX_HZ
+        assert_eq!(
+            CAMERA.choose(168_000_000),
+            setting(Source::Pll, 2, 84_000_000)
+        );
+
+        let crystal = Sources {
+            hse: 25_000_000,
+            ..CAMERA
+        };
+        assert_eq!(
+            crystal.choose(24_000_000),
+            setting(Source::Hse, 1, 25_000_000)
+        );
+        assert_eq!(
+            crystal.choose(12_000_000),
+            setting(Source::Hse, 2, 12_500_000)
+        );
+    }
+
+    #[test]
+    fn stopped_sources() {
+        let hsi = Sources {
+            hsi: Sources::HSI_HZ,
+            hse: 0,
+            pll: 0,
+        };
+        assert_eq!(hsi.choose(8_000_000), setting(Source::Hsi, 2, 8_000_000));
+        let none = Sources { hsi: 0, ..hsi };
+        assert_eq!(none.choose(8_000_000), None);
+    }
+
+    #[test]
+    fn register_bits() {
+        let bits: Vec<_> = (1..=MAX_PRESCALER)
+            .map(|prescaler| {
+                Setting {
+                    source: Source::Pll,
+                    prescaler,
+                    hz: 0,
+                }
+                .prescaler_bits()
+            })
+            .collect();
+        assert_eq!(bits, [0b000, 0b100, 0b101, 0b110, 0b111]);
+        assert_eq!(Source::Hse.bits(), 0b10);
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(Command::parse("xclk"), Some(Command::Get));
+        assert_eq!(
+            Command::parse(" xclk  24000000\r"),
+            Some(Command::Set(24_000_000))
+        );
+        assert_eq!(Command::parse("xclk 24MHz"), None);
+        assert_eq!(Command::parse("xclk 1 2"), None);
+        assert_eq!(Command::parse("jpeg 12"), None);
+        assert_eq!(Command::parse(""), None);
+        for command in [Command::Get, Command::Set(20_000_000)] {
+            assert_eq!(Command::parse(&command.to_string()), Some(command));
+        }
+    }
+
+    #[test]
+    fn reply() {
+        let reply = CAMERA.choose(42_000_000).unwrap().to_string();
+        assert_eq!(reply, "xclk 42000000 Hz from PLL/4");
+    }
+}